@@ -2481,6 +2481,7 @@ where
 			if let &PendingHTLCRouting::Forward { ref short_channel_id, .. } = routing {
 				let id_option =
 					channel_state.as_ref().unwrap().short_to_id.get(&short_channel_id).cloned();
+				let mut hide_channel = false;
 				if let Some((err, code, chan_update)) = loop {
 					let forwarding_id_opt = match id_option {
 						None => {
@@ -2525,6 +2526,7 @@ where
 							// we don't have the channel here.
 							break Some(("Refusing to forward over real channel SCID as our counterparty requested.", 0x4000 | 10, None));
 						}
+						hide_channel = self.hides_channel(chan);
 						let chan_update_opt =
 							self.get_channel_update_for_onion(*short_channel_id, chan).ok();
 
@@ -2600,6 +2602,10 @@ where
 
 					break None;
 				} {
+					// Fail exactly like the unknown short channel id case above, so that the
+					// sender can't tell whether the private channel exists
+					let (code, chan_update) =
+						if hide_channel { (0x4000 | 10, None) } else { (code, chan_update) };
 					let mut res =
 						VecWriter(Vec::with_capacity(chan_update.serialized_length() + 2 + 8 + 2));
 					if let Some(chan_update) = chan_update {
@@ -4535,12 +4541,21 @@ where
 		}
 	}
 
+	/// Whether failures on this channel have to look like the channel doesn't exist, see
+	/// [`UserConfig::hide_private_channels`].
+	fn hides_channel(&self, chan: &Channel<Signer>) -> bool {
+		self.default_configuration.hide_private_channels && !chan.should_announce()
+	}
+
 	/// Gets an HTLC onion failure code and error data for an `UPDATE` error, given the error code
 	/// that we want to return and a channel.
 	fn get_htlc_temp_fail_err_and_data(
 		&self, desired_err_code: u16, scid: u64, chan: &Channel<Signer>,
 	) -> (u16, Vec<u8>) {
 		debug_assert_eq!(desired_err_code & 0x1000, 0x1000);
+		if self.hides_channel(chan) {
+			return (0x4000 | 10, Vec::new());
+		}
 		if let Ok(upd) = self.get_channel_update_for_onion(scid, chan) {
 			let mut enc = VecWriter(Vec::with_capacity(upd.serialized_length() + 6));
 			if desired_err_code == 0x1000 | 20 {
@@ -6145,11 +6160,18 @@ where
 					);
 					try_chan_entry!(self, Err(chan_err), channel_state, chan);
 				}
+				// Our counterparty couldn't read the onion, which is what a forward meant for some
+				// other node looks like. Pass that on as if we didn't have the channel at all.
+				let failure_code = if self.hides_channel(chan.get()) {
+					0x4000 | 10
+				} else {
+					msg.failure_code
+				};
 				try_chan_entry!(
 					self,
 					chan.get_mut().update_fail_malformed_htlc(
 						&msg,
-						HTLCFailReason::Reason { failure_code: msg.failure_code, data: Vec::new() }
+						HTLCFailReason::Reason { failure_code, data: Vec::new() }
 					),
 					channel_state,
 					chan
@@ -16,8 +16,9 @@ use chain::channelmonitor::ChannelMonitor;
 use chain::keysinterface::{Recipient, KeysInterface};
 use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, MIN_CLTV_EXPIRY_DELTA};
 use routing::gossip::RoutingFees;
-use routing::router::{PaymentParameters, RouteHint, RouteHintHop};
-use ln::features::{InitFeatures, InvoiceFeatures, ChannelTypeFeatures};
+use routing::gossip::NetworkUpdate;
+use routing::router::{PaymentParameters, Route, RouteHint, RouteHintHop, RouteHop};
+use ln::features::{InitFeatures, InvoiceFeatures, ChannelFeatures, ChannelTypeFeatures, NodeFeatures};
 use ln::msgs;
 use ln::msgs::{ChannelMessageHandler, RoutingMessageHandler, OptionalField, ChannelUpdate, ErrorAction};
 use ln::wire::Encode;
@@ -37,7 +38,7 @@ use bitcoin::hash_types::BlockHash;
 use bitcoin::hashes::Hash;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::network::constants::Network;
-use bitcoin::secp256k1::Secp256k1;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
 
 #[test]
 fn test_priv_forwarding_rejection() {
@@ -166,6 +167,107 @@ fn test_priv_forwarding_rejection() {
 	claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], our_payment_preimage);
 }
 
+fn send_probe<'a, 'b, 'c>(nodes: &Vec<Node<'a, 'b, 'c>>, path: Vec<RouteHop>, forwarded: bool) -> (Option<u16>, Option<Vec<u8>>) {
+	// Sends a probe from nodes[0] through nodes[1], which either fails it right away or forwards
+	// it to nodes[2], and returns the error nodes[0] gets back.
+	let route = Route { paths: vec![path], payment_params: None };
+	let (_, payment_hash, payment_secret) = get_payment_preimage_hash!(nodes[2]);
+	nodes[0].node.send_payment(&route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	let payment_event = SendEvent::from_event(nodes[0].node.get_and_clear_pending_msg_events().remove(0));
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+
+	if forwarded {
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+		expect_pending_htlcs_forwardable!(nodes[1]);
+		check_added_monitors!(nodes[1], 1);
+		let forward_event = SendEvent::from_event(nodes[1].node.get_and_clear_pending_msg_events().remove(0));
+		nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &forward_event.msgs[0]);
+		commitment_signed_dance!(nodes[2], nodes[1], forward_event.commitment_msg, false, true);
+
+		// nodes[2] isn't who the onion is for, so it can't read it
+		let malformed_updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+		assert_eq!(malformed_updates.update_fail_malformed_htlcs.len(), 1);
+		nodes[1].node.handle_update_fail_malformed_htlc(&nodes[2].node.get_our_node_id(), &malformed_updates.update_fail_malformed_htlcs[0]);
+		commitment_signed_dance!(nodes[1], nodes[2], malformed_updates.commitment_signed, false, true);
+		expect_pending_htlcs_forwardable!(nodes[1]);
+		check_added_monitors!(nodes[1], 1);
+	} else {
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+	}
+
+	let htlc_fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(htlc_fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &htlc_fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], htlc_fail_updates.commitment_signed, true, true);
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentPathFailed { ref error_code, ref error_data, ref network_update, .. } => {
+			let update_included = matches!(network_update, Some(NetworkUpdate::ChannelUpdateMessage { .. }));
+			assert_eq!(update_included, error_data.as_ref().map_or(false, |data| !data.is_empty()));
+			(*error_code, error_data.clone())
+		},
+		_ => panic!("Unexpected event"),
+	}
+}
+
+fn do_test_hide_private_channels(hide_private_channels: bool) {
+	// A prober guesses a short channel id behind nodes[1] and a node at the other end of it. With
+	// UserConfig::hide_private_channels set, a real private channel with the wrong node or the
+	// wrong policy fails exactly like a short channel id nodes[1] doesn't know.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut hiding_cfg = test_default_channel_config();
+	hiding_cfg.accept_forwards_to_priv_channels = true;
+	hiding_cfg.hide_private_channels = hide_private_channels;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(hiding_cfg), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	let public_scid = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 500_000_000, InitFeatures::known(), InitFeatures::known()).0.contents.short_channel_id;
+	create_unannounced_chan_between_nodes_with_value(&nodes, 1, 2, 1_000_000, 500_000_000, InitFeatures::known(), InitFeatures::known());
+	let private_scid = nodes[2].node.list_channels()[0].short_channel_id.unwrap();
+
+	let secp_ctx = Secp256k1::new();
+	let guess = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+	let path = |scid: u64, fee_msat: u64| vec![RouteHop {
+		pubkey: nodes[1].node.get_our_node_id(),
+		node_features: NodeFeatures::known(),
+		short_channel_id: public_scid,
+		channel_features: ChannelFeatures::known(),
+		fee_msat,
+		cltv_expiry_delta: MIN_CLTV_EXPIRY_DELTA as u32,
+	}, RouteHop {
+		pubkey: guess,
+		node_features: NodeFeatures::known(),
+		short_channel_id: scid,
+		channel_features: ChannelFeatures::known(),
+		fee_msat: 10_000,
+		cltv_expiry_delta: TEST_FINAL_CLTV,
+	}];
+
+	let unknown_scid = send_probe(&nodes, path(private_scid + 1, 1000), false);
+	let wrong_node = send_probe(&nodes, path(private_scid, 1000), true);
+	let wrong_policy = send_probe(&nodes, path(private_scid, 0), false);
+
+	assert_eq!(unknown_scid, (Some(0x4000 | 10), Some(Vec::new())));
+	if hide_private_channels {
+		assert_eq!(wrong_node, unknown_scid);
+		assert_eq!(wrong_policy, unknown_scid);
+	} else {
+		assert_eq!(wrong_node.0, Some(0x8000 | 0x4000 | 5));
+		assert_eq!(wrong_policy.0, Some(0x1000 | 12));
+		assert!(!wrong_policy.1.unwrap().is_empty());
+	}
+}
+
+#[test]
+fn test_hide_private_channels() {
+	do_test_hide_private_channels(false);
+	do_test_hide_private_channels(true);
+}
+
 fn do_test_1_conf_open(connect_style: ConnectStyle) {
 	// Previously, if the minium_depth config was set to 1, we'd never send a channel_ready. This
 	// tests that we properly send one in that case.
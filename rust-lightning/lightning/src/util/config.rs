@@ -457,6 +457,20 @@ pub struct UserConfig {
 	///
 	/// Default value: false.
 	pub accept_forwards_to_priv_channels: bool,
+	/// If this is set to true, forwards over our private channels fail the same way as forwards
+	/// over a short channel id we don't know, so that senders can't probe for the existence of
+	/// our private channels. This covers forwards our counterparty can't read the onion of, which
+	/// is what a forward meant for any node other than our counterparty looks like, and forwards
+	/// that don't meet our policy for the channel. Failures on private channels never include a
+	/// `channel_update`.
+	///
+	/// Note that this means senders paying with an outdated route hint won't learn our current
+	/// policy from the failure. Forwards our counterparty fails also take a round trip longer to
+	/// fail than ones over an unknown short channel id, so this does not hide the channel from
+	/// senders that measure how long failures take.
+	///
+	/// Default value: false.
+	pub hide_private_channels: bool,
 	/// If this is set to false, we do not accept inbound requests to open a new channel.
 	/// Default value: true.
 	pub accept_inbound_channels: bool,
@@ -483,6 +497,7 @@ impl Default for UserConfig {
 			channel_handshake_limits: ChannelHandshakeLimits::default(),
 			channel_config: ChannelConfig::default(),
 			accept_forwards_to_priv_channels: false,
+			hide_private_channels: false,
 			accept_inbound_channels: true,
 			manually_accept_inbound_channels: false,
 		}
@@ -0,0 +1,306 @@
+use crate::cli::AttemptResult;
+use crate::hint_policy::implementation;
+use crate::NetworkGraph;
+use bitcoin::secp256k1::PublicKey;
+use chrono::{NaiveDateTime, Utc};
+use lightning::routing::gossip::NodeId;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+/// Every published cell has to be backed by at least this many distinct targets.
+pub(crate) const DEFAULT_K_ANONYMITY: usize = 5;
+
+/// Roughly a year of blocks.
+pub(crate) const BLOCK_RANGE: u32 = 52_560;
+
+/// Upper bounds in sats of the capacity buckets, the last bucket is open ended.
+const CAPACITY_BUCKETS: [u64; 4] = [100_000, 1_000_000, 5_000_000, 16_777_216];
+
+/// One row of an aggregate table. The counts are `None` when the cell is backed by too few
+/// targets to publish.
+#[derive(Serialize, Debug)]
+pub(crate) struct Cell {
+	pub(crate) bucket: String,
+	pub(crate) targets: Option<usize>,
+	pub(crate) channels: Option<usize>,
+	pub(crate) capacity_sats: Option<u64>,
+}
+
+/// What we found, without anything that points at a single node or channel. Meant to be
+/// published in place of `results.json`.
+#[derive(Serialize, Debug)]
+pub(crate) struct AggregateResults {
+	pub(crate) k_anonymity: usize,
+	pub(crate) generated: NaiveDateTime,
+	pub(crate) totals: Option<Cell>,
+	/// How many targets have how many private channels.
+	pub(crate) channels_per_target: Vec<Cell>,
+	pub(crate) capacity: Vec<Cell>,
+	pub(crate) block_ranges: Vec<Cell>,
+	pub(crate) implementations: Vec<Cell>,
+	pub(crate) suppressed_cells: usize,
+}
+
+/// The channels that fall into one cell, and whose targets they are.
+#[derive(Default)]
+struct Tally {
+	targets: HashSet<String>,
+	channels: usize,
+	capacity_sats: u64,
+}
+
+impl Tally {
+	fn add(&mut self, result: &AttemptResult) {
+		self.targets.insert(result.target_pubkey.clone());
+		self.channels += 1;
+		self.capacity_sats += result.amount;
+	}
+}
+
+impl AggregateResults {
+	pub(crate) fn build(
+		results: &[AttemptResult], network_graph: &NetworkGraph, k_anonymity: usize,
+	) -> AggregateResults {
+		let mut totals = Tally::default();
+		let mut by_target: HashMap<&str, Vec<&AttemptResult>> = HashMap::new();
+		let mut capacity: BTreeMap<usize, Tally> = BTreeMap::new();
+		let mut block_ranges: BTreeMap<u32, Tally> = BTreeMap::new();
+		let mut implementations: BTreeMap<&'static str, Tally> = BTreeMap::new();
+
+		let graph = network_graph.read_only();
+		for result in results {
+			totals.add(result);
+			by_target.entry(&result.target_pubkey).or_default().push(result);
+			capacity.entry(capacity_bucket(result.amount)).or_default().add(result);
+			block_ranges.entry(result.block_open / BLOCK_RANGE).or_default().add(result);
+
+			let node = PublicKey::from_str(&result.target_pubkey)
+				.ok()
+				.and_then(|pubkey| graph.nodes().get(&NodeId::from_pubkey(&pubkey)));
+			let announcement = node.and_then(|node| node.announcement_info.as_ref());
+			let implementation = announcement
+				.and_then(|announcement| implementation(&announcement.features))
+				.unwrap_or("unknown");
+			implementations.entry(implementation).or_default().add(result);
+		}
+
+		let mut channels_per_target: BTreeMap<usize, Tally> = BTreeMap::new();
+		for (_, target_results) in by_target {
+			let tally = channels_per_target.entry(channel_count_bucket(target_results.len()));
+			let tally = tally.or_default();
+			for result in target_results {
+				tally.add(result);
+			}
+		}
+
+		let mut suppressed_cells = 0;
+		let mut table = |tallies: Vec<(String, Tally)>| {
+			let (cells, suppressed) = suppress(tallies, k_anonymity);
+			suppressed_cells += suppressed;
+			cells
+		};
+		let channels_per_target = table(
+			channels_per_target
+				.into_iter()
+				.map(|(bucket, tally)| (channel_count_label(bucket), tally))
+				.collect(),
+		);
+		let capacity = table(
+			capacity.into_iter().map(|(bucket, tally)| (capacity_label(bucket), tally)).collect(),
+		);
+		let block_ranges = table(
+			block_ranges
+				.into_iter()
+				.map(|(range, tally)| {
+					let label =
+						format!("{}-{}", range * BLOCK_RANGE, (range + 1) * BLOCK_RANGE - 1);
+					(label, tally)
+				})
+				.collect(),
+		);
+		let implementations = table(
+			implementations
+				.into_iter()
+				.map(|(implementation, tally)| (implementation.to_string(), tally))
+				.collect(),
+		);
+		let totals = if totals.targets.len() >= k_anonymity {
+			Some(published("all".to_string(), totals))
+		} else {
+			None
+		};
+
+		AggregateResults {
+			k_anonymity,
+			generated: Utc::now().naive_utc(),
+			totals,
+			channels_per_target,
+			capacity,
+			block_ranges,
+			implementations,
+			suppressed_cells,
+		}
+	}
+}
+
+/// Blanks out the cells with fewer than `k_anonymity` targets. A single blank cell could be
+/// worked out from the totals, so the next smallest cell gets blanked with it.
+fn suppress(tallies: Vec<(String, Tally)>, k_anonymity: usize) -> (Vec<Cell>, usize) {
+	let mut suppressed: Vec<bool> =
+		tallies.iter().map(|(_, tally)| tally.targets.len() < k_anonymity).collect();
+	if suppressed.iter().filter(|suppressed| **suppressed).count() == 1 {
+		let smallest_published = tallies
+			.iter()
+			.enumerate()
+			.filter(|(i, _)| !suppressed[*i])
+			.min_by_key(|(_, (_, tally))| tally.channels)
+			.map(|(i, _)| i);
+		if let Some(i) = smallest_published {
+			suppressed[i] = true;
+		}
+	}
+
+	let count = suppressed.iter().filter(|suppressed| **suppressed).count();
+	let cells = tallies
+		.into_iter()
+		.zip(suppressed)
+		.map(|((bucket, tally), suppressed)| {
+			if suppressed {
+				Cell { bucket, targets: None, channels: None, capacity_sats: None }
+			} else {
+				published(bucket, tally)
+			}
+		})
+		.collect();
+	(cells, count)
+}
+
+fn published(bucket: String, tally: Tally) -> Cell {
+	Cell {
+		bucket,
+		targets: Some(tally.targets.len()),
+		channels: Some(tally.channels),
+		capacity_sats: Some(tally.capacity_sats),
+	}
+}
+
+/// Index into `CAPACITY_BUCKETS`, one past the end for the open ended bucket. Channels we
+/// couldn't find the funding transaction of have no amount and get a bucket of their own.
+pub(crate) fn capacity_bucket(amount: u64) -> usize {
+	if amount == 0 {
+		return usize::MAX;
+	}
+	CAPACITY_BUCKETS.iter().position(|max| amount < *max).unwrap_or(CAPACITY_BUCKETS.len())
+}
+
+pub(crate) fn capacity_label(bucket: usize) -> String {
+	if bucket == usize::MAX {
+		return "unknown".to_string();
+	}
+	let min = if bucket == 0 { 0 } else { CAPACITY_BUCKETS[bucket - 1] };
+	match CAPACITY_BUCKETS.get(bucket) {
+		Some(max) => format!("{}-{}", min, max - 1),
+		None => format!("{}+", min),
+	}
+}
+
+fn channel_count_bucket(channels: usize) -> usize {
+	match channels {
+		0..=2 => channels,
+		3..=5 => 3,
+		6..=10 => 6,
+		_ => 11,
+	}
+}
+
+fn channel_count_label(bucket: usize) -> String {
+	match bucket {
+		3 => "3-5".to_string(),
+		6 => "6-10".to_string(),
+		11 => "11+".to_string(),
+		_ => bucket.to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{network_graph, pubkey, TempDir};
+
+	/// One private channel of the target with secret key `[target; 32]`.
+	fn result(target: u8, amount: u64) -> AttemptResult {
+		AttemptResult {
+			target_pubkey: pubkey(target).to_string(),
+			other_pubkey: pubkey(100).to_string(),
+			channel_id: "700000x1x0".to_string(),
+			transaction_id_output: String::new(),
+			amount,
+			block_open: 700_000,
+			date_found: Utc::now().naive_utc(),
+			hidden_path: None,
+		}
+	}
+
+	fn targets(cells: &[Cell]) -> Vec<(&str, Option<usize>)> {
+		cells.iter().map(|cell| (cell.bucket.as_str(), cell.targets)).collect()
+	}
+
+	#[test]
+	fn test_cells_below_k_are_suppressed() {
+		let data_dir = TempDir::new("aggregate");
+		let mut results: Vec<AttemptResult> = (1..=3).map(|t| result(t, 2_000_000)).collect();
+		results.push(result(4, 50_000));
+		results.push(result(5, 500_000));
+		results.push(result(6, 600_000));
+
+		let aggregate = AggregateResults::build(&results, &network_graph(&data_dir), 3);
+		assert_eq!(
+			targets(&aggregate.capacity),
+			vec![("0-99999", None), ("100000-999999", None), ("1000000-4999999", Some(3))]
+		);
+		let blank = &aggregate.capacity[1];
+		assert_eq!((blank.channels, blank.capacity_sats), (None, None));
+		assert_eq!(aggregate.suppressed_cells, 2);
+
+		let totals = aggregate.totals.unwrap();
+		assert_eq!((totals.targets, totals.channels), (Some(6), Some(6)));
+	}
+
+	#[test]
+	fn test_a_lone_suppressed_cell_is_not_left_to_be_worked_out() {
+		let data_dir = TempDir::new("aggregate");
+		let mut results: Vec<AttemptResult> = (1..=3).map(|t| result(t, 2_000_000)).collect();
+		results.extend((4..=7).map(|t| result(t, 500_000)));
+		results.push(result(8, 50_000));
+
+		let aggregate = AggregateResults::build(&results, &network_graph(&data_dir), 3);
+		// totals minus the other cells would give away the small one, so the smallest
+		// published cell is blanked with it
+		assert_eq!(
+			targets(&aggregate.capacity),
+			vec![("0-99999", None), ("100000-999999", Some(4)), ("1000000-4999999", None)]
+		);
+		assert_eq!(aggregate.suppressed_cells, 2);
+		let published: usize = aggregate.capacity.iter().filter_map(|cell| cell.channels).sum();
+		assert_eq!(aggregate.totals.unwrap().channels, Some(8));
+		assert_eq!(published, 4);
+	}
+
+	#[test]
+	fn test_totals_below_k_are_suppressed() {
+		let data_dir = TempDir::new("aggregate");
+		let results = vec![result(1, 2_000_000), result(1, 500_000), result(2, 500_000)];
+
+		let aggregate = AggregateResults::build(&results, &network_graph(&data_dir), 3);
+		assert!(aggregate.totals.is_none());
+		for table in [
+			&aggregate.channels_per_target,
+			&aggregate.capacity,
+			&aggregate.block_ranges,
+			&aggregate.implementations,
+		] {
+			assert!(table.iter().all(|cell| cell.targets.is_none()));
+		}
+	}
+}
@@ -0,0 +1,305 @@
+use crate::cli::Attempt;
+use crate::graph_import::parse_scid;
+use crate::hidden_graph::{is_channel_found, is_counterparty_confirmed, HiddenGraph, HiddenPolicy};
+use crate::{ChannelManager, NetworkGraph};
+use bitcoin::secp256k1::PublicKey;
+use chrono::NaiveDateTime;
+use lightning::ln::features::ChannelTypeFeatures;
+use lightning::routing::gossip::NodeId;
+use std::fs;
+use std::str::FromStr;
+
+/// One of the operator's private channels, as seen from the audited node.
+pub(crate) struct AuditChannel {
+	pub(crate) short_channel_id: u64,
+	pub(crate) counterparty: Option<PublicKey>,
+	/// Only known for our own channels.
+	pub(crate) channel_type: Option<ChannelTypeFeatures>,
+}
+
+/// Reads the private channels out of `lncli listchannels` or `lightning-cli listpeerchannels`
+/// output, or anything else with a `channels` list of objects carrying a short channel id.
+pub(crate) fn read_channel_list(
+	path: &str,
+) -> Result<Vec<AuditChannel>, Box<dyn std::error::Error>> {
+	let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+	let entries = match json.get("channels").or(Some(&json)).and_then(|json| json.as_array()) {
+		Some(entries) => entries,
+		None => return Err("expected a list of channels".into()),
+	};
+
+	let mut channels = Vec::new();
+	for entry in entries {
+		if entry.get("private").and_then(|private| private.as_bool()) == Some(false) {
+			continue;
+		}
+		let scid = ["chan_id", "short_channel_id", "scid"]
+			.iter()
+			.filter_map(|key| entry.get(*key))
+			.find_map(|scid| match scid {
+				serde_json::Value::String(scid) => parse_scid(scid),
+				scid => scid.as_u64(),
+			});
+		let short_channel_id = match scid {
+			Some(scid) => scid,
+			// not confirmed yet, so nothing to find
+			None => continue,
+		};
+		let counterparty = ["remote_pubkey", "peer_id", "destination"]
+			.iter()
+			.filter_map(|key| entry.get(*key).and_then(|pubkey| pubkey.as_str()))
+			.find_map(|pubkey| PublicKey::from_str(pubkey).ok());
+		channels.push(AuditChannel { short_channel_id, counterparty, channel_type: None });
+	}
+	Ok(channels)
+}
+
+/// Our own unannounced channels with `target`. Seen from `target` their counterparty is us.
+pub(crate) fn local_channels(
+	channel_manager: &ChannelManager, target: &PublicKey,
+) -> Vec<AuditChannel> {
+	channel_manager
+		.list_channels()
+		.into_iter()
+		.filter(|channel| !channel.is_public && channel.counterparty.node_id == *target)
+		.filter_map(|channel| {
+			Some(AuditChannel {
+				short_channel_id: channel.short_channel_id?,
+				counterparty: Some(channel_manager.get_our_node_id()),
+				channel_type: channel.channel_type,
+			})
+		})
+		.collect()
+}
+
+pub(crate) enum Exposure {
+	/// The target said it has no such channel.
+	Hidden,
+	/// The target gave the channel away, and with it the counterparty if our guess reached it.
+	Exposed { counterparty_confirmed: bool },
+	/// No probe made it to the target, or it never answered.
+	NoAnswer,
+}
+
+pub(crate) struct ChannelAudit {
+	pub(crate) short_channel_id: u64,
+	pub(crate) exposure: Exposure,
+	/// The policy the target sent back for the channel.
+	pub(crate) policy: Option<HiddenPolicy>,
+	pub(crate) scid_alias: String,
+}
+
+/// What the probes sent since `since` found out about each of `channels`.
+pub(crate) fn audit_channels(
+	target: &PublicKey, channels: &[AuditChannel], since: NaiveDateTime, attempts: &[Attempt],
+	hidden_graph: &HiddenGraph, network_graph: &NetworkGraph,
+) -> Vec<ChannelAudit> {
+	let target_str = target.to_string();
+	let discovered = hidden_graph.channels();
+	channels
+		.iter()
+		.map(|channel| {
+			let scid = channel.short_channel_id.to_string();
+			let results: Vec<&str> = attempts
+				.iter()
+				.filter(|attempt| {
+					attempt.target_pubkey == target_str
+						&& attempt.channel_id == scid
+						&& attempt.date_found >= since
+				})
+				.map(|attempt| attempt.result.as_str())
+				.collect();
+			let exposure = if results.iter().any(|result| is_counterparty_confirmed(result)) {
+				Exposure::Exposed { counterparty_confirmed: true }
+			} else if results.iter().any(|result| is_channel_found(result)) {
+				Exposure::Exposed { counterparty_confirmed: false }
+			} else if results.is_empty() {
+				Exposure::NoAnswer
+			} else {
+				Exposure::Hidden
+			};
+			let policy = discovered
+				.iter()
+				.find(|found| {
+					found.target_pubkey == target_str
+						&& found.short_channel_id == channel.short_channel_id
+				})
+				.and_then(|found| found.policy.clone());
+
+			ChannelAudit {
+				short_channel_id: channel.short_channel_id,
+				exposure,
+				policy,
+				scid_alias: scid_alias_protection(target, channel, network_graph),
+			}
+		})
+		.collect()
+}
+
+/// Whether `option_scid_alias` keeps the channel's real short channel id out of reach. A
+/// channel opened with the `scid_privacy` channel type refuses forwards over its real id, so
+/// probing it comes back the same as probing a channel that doesn't exist.
+fn scid_alias_protection(
+	target: &PublicKey, channel: &AuditChannel, network_graph: &NetworkGraph,
+) -> String {
+	if let Some(channel_type) = &channel.channel_type {
+		if channel_type.supports_scid_privacy() {
+			return "protected, the channel was opened with scid_privacy".to_string();
+		}
+	}
+
+	let graph = network_graph.read_only();
+	let supports_scid_alias = |pubkey: &PublicKey| {
+		let node = graph.nodes().get(&NodeId::from_pubkey(pubkey));
+		node.and_then(|node| node.announcement_info.as_ref())
+			.map(|announcement| announcement.features.supports_scid_privacy())
+	};
+	let mut unsupported = Vec::new();
+	let mut unknown = Vec::new();
+	for pubkey in std::iter::once(target).chain(channel.counterparty.as_ref()) {
+		match supports_scid_alias(pubkey) {
+			Some(true) => {}
+			Some(false) => unsupported.push(pubkey.to_string()),
+			None => unknown.push(pubkey.to_string()),
+		}
+	}
+	if !unsupported.is_empty() {
+		format!("wouldn't protect it, {} doesn't support it", unsupported.join(" and "))
+	} else if !unknown.is_empty() {
+		format!("can't tell, {} doesn't announce its features", unknown.join(" and "))
+	} else if channel.counterparty.is_none() {
+		"can't tell, the counterparty isn't known".to_string()
+	} else {
+		"would protect it if the channel was reopened with scid_privacy".to_string()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::create_tables;
+	use crate::probe::scid_from_parts;
+	use crate::test_utils::{add_public_channel, announce_node, network_graph, pubkey, TempDir};
+	use chrono::{Duration, Utc};
+	use lightning::ln::features::NodeFeatures;
+	use rusqlite::Connection;
+
+	fn read(contents: &str) -> Vec<AuditChannel> {
+		let dir = TempDir::new("audit");
+		let path = format!("{}/channels.json", dir.path());
+		fs::write(&path, contents).unwrap();
+		read_channel_list(&path).unwrap()
+	}
+
+	fn attempt(scid: u64, result: &str, date_found: NaiveDateTime) -> Attempt {
+		Attempt {
+			target_pubkey: pubkey(1).to_string(),
+			guess_pubkey: pubkey(2).to_string(),
+			channel_id: scid.to_string(),
+			result: result.to_string(),
+			date_found,
+			hidden_path: None,
+			guess_strategy: None,
+		}
+	}
+
+	fn channel(scid: u64) -> AuditChannel {
+		AuditChannel { short_channel_id: scid, counterparty: Some(pubkey(2)), channel_type: None }
+	}
+
+	#[test]
+	fn test_read_channel_list() {
+		let scid = scid_from_parts(700_000, 1, 0);
+		let lnd = read(&format!(
+			r#"{{"channels": [
+				{{"chan_id": "{}", "remote_pubkey": "{}", "private": true}},
+				{{"chan_id": "{}", "remote_pubkey": "{}", "private": false}}
+			]}}"#,
+			scid,
+			pubkey(2),
+			scid_from_parts(700_000, 2, 0),
+			pubkey(3)
+		));
+		assert_eq!(lnd.len(), 1);
+		assert_eq!(lnd[0].short_channel_id, scid);
+		assert_eq!(lnd[0].counterparty, Some(pubkey(2)));
+
+		let cln = read(&format!(
+			r#"{{"channels": [
+				{{"short_channel_id": "700000x1x0", "peer_id": "{}", "private": true}},
+				{{"peer_id": "{}", "private": true, "state": "CHANNELD_AWAITING_LOCKIN"}},
+				{{"short_channel_id": "700000x2x0", "peer_id": "{}", "private": false}}
+			]}}"#,
+			pubkey(2),
+			pubkey(3),
+			pubkey(4)
+		));
+		assert_eq!(cln.len(), 1);
+		assert_eq!(cln[0].short_channel_id, scid);
+		assert_eq!(cln[0].counterparty, Some(pubkey(2)));
+
+		let dir = TempDir::new("audit");
+		let path = format!("{}/channels.json", dir.path());
+		fs::write(&path, r#"{"channels": {}}"#).unwrap();
+		assert!(read_channel_list(&path).is_err());
+	}
+
+	#[test]
+	fn test_audit_channels() {
+		let data_dir = TempDir::new("audit");
+		let network_graph = network_graph(&data_dir);
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+		let hidden_graph = HiddenGraph::read(&db).unwrap();
+
+		let now = Utc::now().naive_utc();
+		let since = now - Duration::minutes(1);
+		let scids: Vec<u64> = (1..=4).map(|tx| scid_from_parts(700_000, tx, 0)).collect();
+		let attempts = [
+			attempt(scids[0], "incorrect_or_unknown_payment_details", now),
+			attempt(scids[1], "fee_insufficient", now),
+			attempt(scids[2], "unknown_next_peer", now),
+			// from an earlier audit
+			attempt(scids[3], "fee_insufficient", since - Duration::minutes(1)),
+		];
+		let channels: Vec<AuditChannel> = scids.iter().map(|scid| channel(*scid)).collect();
+		let audits =
+			audit_channels(&pubkey(1), &channels, since, &attempts, &hidden_graph, &network_graph);
+		assert!(matches!(audits[0].exposure, Exposure::Exposed { counterparty_confirmed: true }));
+		assert!(matches!(audits[1].exposure, Exposure::Exposed { counterparty_confirmed: false }));
+		assert!(matches!(audits[2].exposure, Exposure::Hidden));
+		assert!(matches!(audits[3].exposure, Exposure::NoAnswer));
+		assert!(audits.iter().all(|audit| audit.policy.is_none()));
+	}
+
+	#[test]
+	fn test_scid_alias_protection() {
+		let data_dir = TempDir::new("audit");
+		let network_graph = network_graph(&data_dir);
+		let (target, counterparty) = (pubkey(1), pubkey(2));
+		let protection =
+			|channel: &AuditChannel| scid_alias_protection(&target, channel, &network_graph);
+
+		let mut scid_privacy = channel(1);
+		scid_privacy.channel_type = Some(ChannelTypeFeatures::known());
+		assert!(protection(&scid_privacy).starts_with("protected"));
+
+		assert!(protection(&channel(1)).starts_with("can't tell"));
+
+		add_public_channel(&network_graph, scid_from_parts(700_000, 1, 0), target, pubkey(3));
+		add_public_channel(&network_graph, scid_from_parts(700_000, 2, 0), counterparty, pubkey(3));
+		announce_node(&network_graph, target, NodeFeatures::known(), 1);
+		announce_node(&network_graph, counterparty, NodeFeatures::empty(), 1);
+		assert_eq!(
+			protection(&channel(1)),
+			format!("wouldn't protect it, {} doesn't support it", counterparty)
+		);
+
+		let mut unknown_counterparty = channel(1);
+		unknown_counterparty.counterparty = None;
+		assert_eq!(protection(&unknown_counterparty), "can't tell, the counterparty isn't known");
+
+		announce_node(&network_graph, counterparty, NodeFeatures::known(), 2);
+		assert!(protection(&channel(1)).starts_with("would protect it"));
+	}
+}
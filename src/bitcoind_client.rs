@@ -11,6 +11,8 @@ use lightning_block_sync::rpc::RpcClient;
 use lightning_block_sync::{AsyncBlockSourceResult, BlockHeaderData, BlockSource};
 use serde_json;
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
@@ -18,10 +20,6 @@ use std::time::Duration;
 
 pub struct BitcoindClient {
 	bitcoind_rpc_client: Arc<RpcClient>,
-	host: String,
-	port: u16,
-	rpc_user: String,
-	rpc_password: String,
 	fees: Arc<HashMap<Target, AtomicU32>>,
 	handle: tokio::runtime::Handle,
 }
@@ -33,7 +31,27 @@ pub enum Target {
 	HighPriority,
 }
 
-impl BlockSource for &BitcoindClient {
+/// A call to the chain backend, resolving once it answered.
+pub(crate) type ChainFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
+
+/// What the node needs from bitcoind: blocks, fee estimates, a wallet to fund channels and
+/// sweep outputs to, and broadcasting. `BitcoindClient` is the real thing, tests run against a
+/// synthetic chain and wallet in memory instead.
+pub(crate) trait ChainClient: BlockSource + FeeEstimator + BroadcasterInterface {
+	fn get_blockchain_info(&self) -> ChainFuture<'_, BlockchainInfo>;
+
+	/// A transaction paying the given amounts, in BTC, to each address, without inputs.
+	fn create_raw_transaction(&self, outputs: Vec<HashMap<String, f64>>) -> ChainFuture<'_, RawTx>;
+
+	/// Adds inputs from the wallet, and change if needed, to pay for `raw_tx`.
+	fn fund_raw_transaction(&self, raw_tx: RawTx) -> ChainFuture<'_, FundedTx>;
+
+	fn sign_raw_transaction_with_wallet(&self, tx_hex: String) -> ChainFuture<'_, SignedTx>;
+
+	fn get_new_address(&self) -> ChainFuture<'_, Address>;
+}
+
+impl BlockSource for BitcoindClient {
 	fn get_header<'a>(
 		&'a self, header_hash: &'a BlockHash, height_hint: Option<u32>,
 	) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
@@ -74,10 +92,6 @@ impl BitcoindClient {
 		fees.insert(Target::HighPriority, AtomicU32::new(5000));
 		let client = Self {
 			bitcoind_rpc_client: Arc::new(bitcoind_rpc_client),
-			host,
-			port,
-			rpc_user,
-			rpc_password,
 			fees: Arc::new(fees),
 			handle: handle.clone(),
 		};
@@ -155,76 +169,73 @@ impl BitcoindClient {
 			}
 		});
 	}
+}
 
-	pub fn get_new_rpc_client(&self) -> std::io::Result<RpcClient> {
-		let http_endpoint = HttpEndpoint::for_host(self.host.clone()).with_port(self.port);
-		let rpc_credentials =
-			base64::encode(format!("{}:{}", self.rpc_user.clone(), self.rpc_password.clone()));
-		RpcClient::new(&rpc_credentials, http_endpoint)
-	}
-
-	pub async fn create_raw_transaction(&self, outputs: Vec<HashMap<String, f64>>) -> RawTx {
-		let outputs_json = serde_json::json!(outputs);
-		self.bitcoind_rpc_client
-			.call_method::<RawTx>(
-				"createrawtransaction",
-				&vec![serde_json::json!([]), outputs_json],
-			)
-			.await
-			.unwrap()
-	}
-
-	pub async fn fund_raw_transaction(&self, raw_tx: RawTx) -> FundedTx {
-		let raw_tx_json = serde_json::json!(raw_tx.0);
-		let options = serde_json::json!({
-			// LDK gives us feerates in satoshis per KW but Bitcoin Core here expects fees
-			// denominated in satoshis per vB. First we need to multiply by 4 to convert weight
-			// units to virtual bytes, then divide by 1000 to convert KvB to vB.
-			"fee_rate": self.get_est_sat_per_1000_weight(ConfirmationTarget::Normal) as f64 / 250.0,
-			// While users could "cancel" a channel open by RBF-bumping and paying back to
-			// themselves, we don't allow it here as its easy to have users accidentally RBF bump
-			// and pay to the channel funding address, which results in loss of funds. Real
-			// LDK-based applications should enable RBF bumping and RBF bump either to a local
-			// change address or to a new channel output negotiated with the same node.
-			"replaceable": false,
-		});
-		self.bitcoind_rpc_client
-			.call_method("fundrawtransaction", &[raw_tx_json, options])
-			.await
-			.unwrap()
+impl ChainClient for BitcoindClient {
+	fn get_blockchain_info(&self) -> ChainFuture<'_, BlockchainInfo> {
+		Box::pin(async move {
+			self.bitcoind_rpc_client
+				.call_method::<BlockchainInfo>("getblockchaininfo", &vec![])
+				.await
+				.unwrap()
+		})
 	}
 
-	pub async fn send_raw_transaction(&self, raw_tx: RawTx) {
-		let raw_tx_json = serde_json::json!(raw_tx.0);
-		self.bitcoind_rpc_client
-			.call_method::<Txid>("sendrawtransaction", &[raw_tx_json])
-			.await
-			.unwrap();
+	fn create_raw_transaction(&self, outputs: Vec<HashMap<String, f64>>) -> ChainFuture<'_, RawTx> {
+		Box::pin(async move {
+			let outputs_json = serde_json::json!(outputs);
+			self.bitcoind_rpc_client
+				.call_method::<RawTx>(
+					"createrawtransaction",
+					&vec![serde_json::json!([]), outputs_json],
+				)
+				.await
+				.unwrap()
+		})
 	}
 
-	pub async fn sign_raw_transaction_with_wallet(&self, tx_hex: String) -> SignedTx {
-		let tx_hex_json = serde_json::json!(tx_hex);
-		self.bitcoind_rpc_client
-			.call_method("signrawtransactionwithwallet", &vec![tx_hex_json])
-			.await
-			.unwrap()
+	fn fund_raw_transaction(&self, raw_tx: RawTx) -> ChainFuture<'_, FundedTx> {
+		Box::pin(async move {
+			let raw_tx_json = serde_json::json!(raw_tx.0);
+			let options = serde_json::json!({
+				// LDK gives us feerates in satoshis per KW but Bitcoin Core here expects fees
+				// denominated in satoshis per vB. First we need to multiply by 4 to convert weight
+				// units to virtual bytes, then divide by 1000 to convert KvB to vB.
+				"fee_rate": self.get_est_sat_per_1000_weight(ConfirmationTarget::Normal) as f64 / 250.0,
+				// While users could "cancel" a channel open by RBF-bumping and paying back to
+				// themselves, we don't allow it here as its easy to have users accidentally RBF bump
+				// and pay to the channel funding address, which results in loss of funds. Real
+				// LDK-based applications should enable RBF bumping and RBF bump either to a local
+				// change address or to a new channel output negotiated with the same node.
+				"replaceable": false,
+			});
+			self.bitcoind_rpc_client
+				.call_method("fundrawtransaction", &[raw_tx_json, options])
+				.await
+				.unwrap()
+		})
 	}
 
-	pub async fn get_new_address(&self) -> Address {
-		let addr_args = vec![serde_json::json!("LDK output address")];
-		let addr = self
-			.bitcoind_rpc_client
-			.call_method::<NewAddress>("getnewaddress", &addr_args)
-			.await
-			.unwrap();
-		Address::from_str(addr.0.as_str()).unwrap()
+	fn sign_raw_transaction_with_wallet(&self, tx_hex: String) -> ChainFuture<'_, SignedTx> {
+		Box::pin(async move {
+			let tx_hex_json = serde_json::json!(tx_hex);
+			self.bitcoind_rpc_client
+				.call_method("signrawtransactionwithwallet", &vec![tx_hex_json])
+				.await
+				.unwrap()
+		})
 	}
 
-	pub async fn get_blockchain_info(&self) -> BlockchainInfo {
-		self.bitcoind_rpc_client
-			.call_method::<BlockchainInfo>("getblockchaininfo", &vec![])
-			.await
-			.unwrap()
+	fn get_new_address(&self) -> ChainFuture<'_, Address> {
+		Box::pin(async move {
+			let addr_args = vec![serde_json::json!("LDK output address")];
+			let addr = self
+				.bitcoind_rpc_client
+				.call_method::<NewAddress>("getnewaddress", &addr_args)
+				.await
+				.unwrap();
+			Address::from_str(addr.0.as_str()).unwrap()
+		})
 	}
 }
 
@@ -1,6 +1,28 @@
+use crate::aggregate::{AggregateResults, DEFAULT_K_ANONYMITY};
+use crate::audit::{self, ChannelAudit, Exposure};
+use crate::config::{self, ProbeConfig, RpcConfig};
+use crate::control::{ControlProbes, CONTROL_INTERVAL};
+use crate::daemon::{self, Job};
+use crate::denylist::Denylist;
 use crate::disk::FilesystemLogger;
+use crate::evaluate::{self, EvaluationReport, Evaluations};
+use crate::export::ExportGraph;
+use crate::graph_import;
+use crate::guess::GuessStrategy;
 use crate::hex_utils;
-use crate::probe::{block_from_scid, find_routes, probe, scid_from_parts, vout_from_scid};
+use crate::hidden_graph::{is_channel_found, is_counterparty_confirmed, HiddenGraph};
+use crate::hint_policy::hint_policy;
+use crate::manifest::{self, Verification};
+use crate::metrics::Metrics;
+use crate::politeness::{parse_quiet_hours, Hold, Politeness, ProbeLimits};
+use crate::probe::{
+	block_from_scid, find_route_from, find_routes, matches_assumptions, probe, probe_result,
+	scid_from_parts, send_probe, tx_index_from_scid, vout_from_scid,
+};
+use crate::probe_monitor::{ProbeMonitor, StuckProbe};
+use crate::route_cache::{GuessedHop, ProbeRouteCache};
+use crate::simulator::{SimulatedView, SimulationConfig, SimulationReport, Simulator};
+use crate::stats::{Bucket, CandidateStats};
 use crate::{disk, PaymentState};
 use anyhow::Result;
 use lightning::routing::gossip::{NodeAlias, NodeId};
@@ -15,35 +37,37 @@ use crate::{
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::network::constants::Network;
-use bitcoin::secp256k1::PublicKey;
-use chrono::NaiveDateTime;
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use chrono::{NaiveDateTime, Timelike, Utc};
 use ctrlc;
 use lightning::chain::keysinterface::{KeysInterface, KeysManager, Recipient};
 use lightning::ln::channelmanager::PaymentSendFailure;
 use rusqlite::Connection;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use lightning::ln::msgs::NetAddress;
 use lightning::ln::{PaymentHash, PaymentPreimage};
 use lightning::routing::scoring::ProbabilisticScorer;
 use lightning::util::config::{ChannelHandshakeConfig, ChannelHandshakeLimits, UserConfig};
-use lightning::util::events::EventHandler;
-use lightning::util::logger::Logger;
+use lightning::util::errors::APIError;
+use lightning::util::events::{Event, EventHandler};
+use lightning::util::logger::{Level, Logger};
 use lightning::{log_given_level, log_info, log_internal, log_trace};
 use lightning_invoice::payment::PaymentError;
 use lightning_invoice::{utils, Currency, Invoice};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::env;
 use std::fs;
 use std::io;
 use std::io::{BufRead, Write};
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::ops::Deref;
 use std::path::Path;
 use std::process;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
@@ -51,17 +75,17 @@ use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct Node {
-	pubkey: String,
+	pub(crate) pubkey: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct Transaction {
 	//block_hash: String,
-	block_height: u64,
-	id: String,
-	block_index: u64,
-	transaction_index: u64,
-	amount: u64,
+	pub(crate) block_height: u64,
+	pub(crate) id: String,
+	pub(crate) block_index: u64,
+	pub(crate) transaction_index: u64,
+	pub(crate) amount: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -71,6 +95,11 @@ pub(crate) struct Attempt {
 	pub(crate) channel_id: String,
 	pub(crate) result: String,
 	pub(crate) date_found: NaiveDateTime,
+	/// The private channels the probe went through before the guessed hop, for probes of
+	/// nodes that are themselves behind a private channel.
+	pub(crate) hidden_path: Option<String>,
+	/// How the guessed pubkey was picked, `None` for attempts from before we kept track.
+	pub(crate) guess_strategy: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -82,6 +111,8 @@ pub(crate) struct AttemptResult {
 	pub(crate) amount: u64,
 	pub(crate) block_open: u32,
 	pub(crate) date_found: NaiveDateTime,
+	#[serde(default)]
+	pub(crate) hidden_path: Option<String>,
 }
 
 pub(crate) struct LdkUserInfo {
@@ -94,123 +125,37 @@ pub(crate) struct LdkUserInfo {
 	pub(crate) ldk_announced_listen_addr: Vec<NetAddress>,
 	pub(crate) ldk_announced_node_name: [u8; 32],
 	pub(crate) network: Network,
+	pub(crate) db_path: String,
+	pub(crate) log_level: Level,
+	pub(crate) probe: ProbeConfig,
+	pub(crate) rpc: RpcConfig,
+	/// Where to serve Prometheus metrics, off if unset.
+	pub(crate) metrics_bind: Option<SocketAddr>,
 }
 
-pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
-	if env::args().len() < 3 {
-		println!("ldk-tutorial-node requires 3 arguments: `cargo run <bitcoind-rpc-username>:<bitcoind-rpc-password>@<bitcoind-rpc-host>:<bitcoind-rpc-port> ldk_storage_directory_path [<ldk-incoming-peer-listening-port>] [bitcoin-network] [announced-node-name announced-listen-addr*]`");
-	}
-	let bitcoind_rpc_info = env::args().skip(1).next().unwrap();
-	let bitcoind_rpc_info_parts: Vec<&str> = bitcoind_rpc_info.rsplitn(2, "@").collect();
-	if bitcoind_rpc_info_parts.len() != 2 {
-		println!("ERROR: bad bitcoind RPC URL provided");
-		return Err(());
-	}
-	let rpc_user_and_password: Vec<&str> = bitcoind_rpc_info_parts[1].split(":").collect();
-	if rpc_user_and_password.len() != 2 {
-		println!("ERROR: bad bitcoind RPC username/password combo provided");
-		return Err(());
-	}
-	let bitcoind_rpc_username = rpc_user_and_password[0].to_string();
-	let bitcoind_rpc_password = rpc_user_and_password[1].to_string();
-	let bitcoind_rpc_path: Vec<&str> = bitcoind_rpc_info_parts[0].split(":").collect();
-	if bitcoind_rpc_path.len() != 2 {
-		println!("ERROR: bad bitcoind RPC path provided");
-		return Err(());
-	}
-	let bitcoind_rpc_host = bitcoind_rpc_path[0].to_string();
-	let bitcoind_rpc_port = bitcoind_rpc_path[1].parse::<u16>().unwrap();
-
-	let ldk_storage_dir_path = env::args().skip(2).next().unwrap();
-
-	let mut ldk_peer_port_set = true;
-	let ldk_peer_listening_port: u16 = match env::args().skip(3).next().map(|p| p.parse()) {
-		Some(Ok(p)) => p,
-		Some(Err(_)) => {
-			ldk_peer_port_set = false;
-			9735
-		}
-		None => {
-			ldk_peer_port_set = false;
-			9735
-		}
-	};
-
-	let mut arg_idx = match ldk_peer_port_set {
-		true => 4,
-		false => 3,
-	};
-	let network: Network = match env::args().skip(arg_idx).next().as_ref().map(String::as_str) {
-		Some("mainnet") => Network::Bitcoin,
-		Some("testnet") => Network::Testnet,
-		Some("regtest") => Network::Regtest,
-		Some("signet") => Network::Signet,
-		Some(net) => {
-			panic!("Unsupported network provided. Options are: `regtest`, `testnet`, and `signet`. Got {}", net);
-		}
-		None => Network::Testnet,
-	};
-
-	let ldk_announced_node_name = match env::args().skip(arg_idx + 1).next().as_ref() {
-		Some(s) => {
-			if s.len() > 32 {
-				panic!("Node Alias can not be longer than 32 bytes");
-			}
-			arg_idx += 1;
-			let mut bytes = [0; 32];
-			bytes[..s.len()].copy_from_slice(s.as_bytes());
-			bytes
+/// The node's settings and, for a headless run, the job to run instead of the prompt.
+pub(crate) fn parse_startup_args() -> Result<(LdkUserInfo, Option<Job>), ()> {
+	let parsed = daemon::parse_job(env::args().skip(1).collect()).and_then(|(job, node_args)| {
+		let args = config::load(node_args.into_iter())?;
+		if job == Some(Job::Serve) && args.rpc.bind.is_none() && args.rpc.socket.is_none() {
+			return Err("serve requires rpc.bind or rpc.socket".into());
 		}
-		None => [0; 32],
-	};
-
-	let mut ldk_announced_listen_addr = Vec::new();
-	loop {
-		match env::args().skip(arg_idx + 1).next().as_ref() {
-			Some(s) => match IpAddr::from_str(s) {
-				Ok(IpAddr::V4(a)) => {
-					ldk_announced_listen_addr
-						.push(NetAddress::IPv4 { addr: a.octets(), port: ldk_peer_listening_port });
-					arg_idx += 1;
-				}
-				Ok(IpAddr::V6(a)) => {
-					ldk_announced_listen_addr
-						.push(NetAddress::IPv6 { addr: a.octets(), port: ldk_peer_listening_port });
-					arg_idx += 1;
-				}
-				Err(_) => panic!("Failed to parse announced-listen-addr into an IP address"),
-			},
-			None => break,
+		Ok((args, job))
+	});
+	match parsed {
+		Ok(parsed) => Ok(parsed),
+		Err(e) => {
+			println!("ERROR: {}", e);
+			println!("{}", config::USAGE);
+			println!("{}", daemon::USAGE);
+			Err(())
 		}
 	}
-
-	Ok(LdkUserInfo {
-		bitcoind_rpc_username,
-		bitcoind_rpc_password,
-		bitcoind_rpc_host,
-		bitcoind_rpc_port,
-		ldk_storage_dir_path,
-		ldk_peer_listening_port,
-		ldk_announced_listen_addr,
-		ldk_announced_node_name,
-		network,
-	})
 }
 
-pub(crate) async fn poll_for_user_input<E: EventHandler>(
-	_pending_payment_state: PaymentState, invoice_payer: Arc<InvoicePayer<E>>,
-	peer_manager: Arc<PeerManager>, channel_manager: Arc<ChannelManager>,
-	keys_manager: Arc<KeysManager>, inbound_payments: PaymentInfoStorage,
-	outbound_payments: PaymentInfoStorage, pending_payments: PaymentInfoStorage,
-	ldk_data_dir: String, network: Network, network_graph: Arc<NetworkGraph>,
-	logger: Arc<FilesystemLogger>,
-	scorer: Arc<Mutex<ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>>>,
-	db: Arc<Mutex<rusqlite::Connection>>,
-) {
-	println!("LDK startup successful. To view available commands: \"help\".");
-	println!("LDK logs are available at <your-supplied-ldk-data-dir-path>/.ldk/logs");
-	println!("Local Node ID is {}.", channel_manager.get_our_node_id());
-
+/// Counts the interrupts received so far, the first one asks whatever is running to stop and a
+/// second one exits right away.
+pub(crate) fn stop_on_signal() -> Arc<AtomicUsize> {
 	let running = Arc::new(AtomicUsize::new(0));
 	let r = running.clone();
 	ctrlc::set_handler(move || {
@@ -218,10 +163,43 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 		if prev == 0 {
 			println!("Exiting...");
 		} else {
-			process::exit(0);
+			process::exit(daemon::EXIT_INTERRUPTED);
 		}
 	})
 	.expect("Error setting Ctrl-C handler");
+	running
+}
+
+/// Runs the prompt until `stop`, or until stdin is closed, which it returns true for.
+pub(crate) async fn poll_for_user_input<E: EventHandler>(
+	_pending_payment_state: PaymentState, invoice_payer: Arc<InvoicePayer<E>>,
+	peer_manager: Arc<PeerManager>, inbound_payments: PaymentInfoStorage,
+	outbound_payments: PaymentInfoStorage, network: Network, evaluations: Arc<Evaluations>,
+	probe_context: ProbeContext, running: Arc<AtomicUsize>,
+) -> bool {
+	let ProbeContext {
+		channel_manager,
+		keys_manager,
+		network_graph,
+		logger,
+		scorer,
+		db,
+		hidden_graph,
+		route_cache,
+		probe_monitor,
+		politeness,
+		controls,
+		denylist,
+		pending_payments,
+		ldk_data_dir,
+		probe_config,
+		metrics,
+		..
+	} = probe_context.clone();
+
+	println!("LDK startup successful. To view available commands: \"help\".");
+	println!("LDK logs are available at <your-supplied-ldk-data-dir-path>/.ldk/logs");
+	println!("Local Node ID is {}.", channel_manager.get_our_node_id());
 
 	let stdin = io::stdin();
 	let mut line_reader = stdin.lock().lines();
@@ -230,7 +208,7 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 		io::stdout().flush().unwrap(); // Without flushing, the `>` doesn't print
 		let line = match line_reader.next() {
 			Some(l) => l.unwrap(),
-			None => break,
+			None => return true,
 		};
 		let mut words = line.split_whitespace();
 		if let Some(word) = words.next() {
@@ -502,14 +480,20 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 						continue;
 					}
 
+					let payee = match PublicKey::from_str(pubkey_str.unwrap()) {
+						Ok(payee) => payee,
+						Err(_) => {
+							println!("ERROR: couldn't parse pubkey");
+							continue;
+						}
+					};
 					let route = find_routes(
-						&invoice_payer,
-						channel_manager.clone(),
+						&*channel_manager,
 						pubkey_str.unwrap(),
 						&network_graph,
 						&logger,
 						ldk_data_dir.clone(),
-						vec![],
+						hidden_graph.route_hints_to(&payee, &network_graph),
 						&scorer,
 					);
 
@@ -527,8 +511,7 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 					}
 
 					let route = find_routes(
-						&invoice_payer,
-						channel_manager.clone(),
+						&*channel_manager,
 						pubkey_str.unwrap(),
 						&network_graph,
 						&logger,
@@ -584,242 +567,588 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 						continue;
 					}
 
+					let scid = channel_id_str.unwrap().parse::<u64>().unwrap_or_default();
+					if denylist.is_denied(pubkey_str.unwrap(), scid, pubkey_guess) {
+						log_info!(
+							logger,
+							"Not probing {}:{}, it is on the denylist",
+							pubkey_str.unwrap(),
+							scid
+						);
+						println!("ERROR: {} is on the denylist", pubkey_str.unwrap());
+						continue;
+					}
+
 					match probe(
 						pubkey_str.unwrap(),
 						channel_id_str.unwrap(),
 						pubkey_guess.unwrap(),
-						&invoice_payer,
-						channel_manager.clone(),
+						&*channel_manager,
 						&network_graph,
 						&logger,
 						&ldk_data_dir,
 						pending_payments.clone(),
 						&scorer,
+						&hidden_graph,
+						&route_cache,
+						"fixed",
+						&metrics,
+						"probeprivate",
 					) {
 						Ok(_) => continue,
 						Err(_) => continue,
 					}
 				}
-				"probeall" => {
-					let probetype = words.next();
-					let nodepath = words.next();
-					let txpath = words.next();
-
-					if probetype.is_none() || nodepath.is_none() || txpath.is_none() {
-						println!("ERROR: probeall requires type nodefile and txfile: `probeprivate <probetype> <nodefile> <txfile>`");
-						continue;
-					}
-
-					// if nodefile is "all" then read from network graph
-					let mut nodes: Vec<Node> = vec![];
-					if nodepath.unwrap() == "all" {
-						for (pubkey, _) in network_graph.read_only().nodes() {
-							println!(
-								"trying to parse pubkey {:?} : {:?}",
-								pubkey,
-								hex::encode(pubkey.as_slice())
-							);
-							let pubkey_str = hex::encode(pubkey.as_slice());
-							nodes.push(Node { pubkey: String::from(pubkey_str) });
+				"auditnode" => {
+					let target = match words.next().map(PublicKey::from_str) {
+						Some(Ok(target)) => target,
+						Some(Err(_)) => {
+							println!("ERROR: couldn't parse pubkey");
+							continue;
 						}
-					} else {
-						// Parse nodefile
-						let node_data = match fs::read_to_string(nodepath.unwrap()) {
-							Ok(file) => file,
-							Err(e) => {
-								println!("{:?}", e.into_inner().unwrap());
-								continue;
-							}
-						};
-						nodes = match serde_json::from_str(&node_data) {
-							Ok(n) => n,
+						None => {
+							println!("ERROR: auditnode requires a pubkey: `auditnode <pubkey> [channels.json]`");
+							continue;
+						}
+					};
+					let mut channels = match words.next() {
+						Some(path) => match audit::read_channel_list(path) {
+							Ok(channels) => channels,
 							Err(e) => {
-								println!("{:?}", e);
+								println!("ERROR: failed to read channel list: {}", e);
 								continue;
 							}
-						};
+						},
+						None => audit::local_channels(&channel_manager, &target),
+					};
+					channels.retain(|channel| {
+						let counterparty = channel.counterparty.map(|pubkey| pubkey.to_string());
+						let denied = denylist.is_denied(
+							&target.to_string(),
+							channel.short_channel_id,
+							counterparty.as_deref(),
+						);
+						if denied {
+							log_info!(
+								logger,
+								"Not probing {}:{}, it is on the denylist",
+								target,
+								channel.short_channel_id
+							);
+						}
+						!denied
+					});
+					if channels.is_empty() {
+						println!("No private channels of {} to audit", target);
+						continue;
 					}
 
-					// get a list of public channels
-
-					let short_channel_ids = network_graph.read_only().channels().clone();
+					// probe every channel with its real counterparty, a channel that leaks
+					// gives that away along with its policy
+					let since = Utc::now().naive_utc();
+					let random = GuessStrategy::Random(Secp256k1::signing_only());
+					for channel in channels.iter() {
+						let (guess, guess_strategy) = match channel.counterparty {
+							Some(counterparty) => (counterparty, "fixed"),
+							None => (random.next_guess(), random.name()),
+						};
+						let sent = probe(
+							&target.to_string(),
+							&channel.short_channel_id.to_string(),
+							&guess.to_string(),
+							&*channel_manager,
+							&network_graph,
+							&logger,
+							&ldk_data_dir,
+							pending_payments.clone(),
+							&scorer,
+							&hidden_graph,
+							&route_cache,
+							guess_strategy,
+							&metrics,
+							"audit",
+						);
+						if let Err(e) = sent {
+							println!("ERROR: failed to probe {}: {}", channel.short_channel_id, e);
+						}
+					}
 
-					// Parse tx files
-					let mut txs: Vec<Transaction> = vec![];
-					let read_res = fs::read_dir(txpath.unwrap());
-					match read_res {
-						Ok(txdir) => {
-							for json_file in txdir {
-								println!(
-									"Reading tx file: {:?}",
-									json_file.as_ref().unwrap().file_name().as_os_str().to_str()
-								);
-								let tx_data = match fs::read_to_string(json_file.unwrap().path()) {
-									Ok(file) => file,
-									Err(e) => {
-										println!("{:?}", e.into_inner().unwrap());
-										continue;
+					// wait for the answers, and for probes resent with a learned policy
+					let started = Instant::now();
+					while pending_payments.lock().unwrap().values().any(|info| info.probe.is_some())
+						&& started.elapsed() < probe_monitor.timeout()
+					{
+						thread::sleep(Duration::from_millis(500));
+					}
+					probe_monitor.abandon_stuck(&channel_manager, &pending_payments, &logger);
+
+					let attempts = get_attempts(&db.lock().unwrap()).unwrap();
+					let audits = audit::audit_channels(
+						&target,
+						&channels,
+						since,
+						&attempts,
+						&hidden_graph,
+						&network_graph,
+					);
+					println!("Audit of {} private channels of {}:", audits.len(), target);
+					for audit in audits.iter() {
+						print_channel_audit(audit);
+					}
+				}
+				"evaluate" => {
+					let targets = match words.next() {
+						Some("runs") => {
+							match evaluate::read_runs(&db.lock().unwrap()) {
+								Ok(runs) => {
+									for run in runs.iter() {
+										print_evaluation(run);
 									}
-								};
-								let mut new_txs: Vec<Transaction> =
-									match serde_json::from_str(&tx_data) {
-										Ok(n) => n,
-										Err(e) => {
-											println!("{:?}", e);
-											continue;
-										}
-									};
-								//println!("{:?}", new_txs);
-								txs.append(&mut new_txs);
+								}
+								Err(e) => println!("ERROR: failed to read evaluation runs: {}", e),
 							}
+							continue;
+						}
+						Some("all") => {
+							let mut targets: Vec<PublicKey> = network_graph
+								.read_only()
+								.nodes()
+								.keys()
+								.filter_map(|node_id| {
+									PublicKey::from_slice(node_id.as_slice()).ok()
+								})
+								.collect();
+							rand::thread_rng().shuffle(&mut targets);
+							targets
 						}
+						Some(pubkey) => match PublicKey::from_str(pubkey) {
+							Ok(pubkey) => vec![pubkey],
+							Err(_) => {
+								println!("ERROR: couldn't parse pubkey");
+								continue;
+							}
+						},
+						None => {
+							println!("ERROR: evaluate requires a target: `evaluate <pubkey>|all [max_channels] [guess_strategy]` or `evaluate runs`");
+							continue;
+						}
+					};
+					let max_channels = match words.next().map(|max| max.parse::<usize>()) {
+						Some(Ok(max)) => max,
+						Some(Err(_)) => {
+							println!("ERROR: max_channels must be a number");
+							continue;
+						}
+						None => 100,
+					};
+					let guess_strategy = match GuessStrategy::parse(
+						words.next().unwrap_or(&probe_config.guess_strategy),
+						&denylist,
+						&logger,
+					) {
+						Ok(strategy) => strategy,
 						Err(e) => {
-							println!("{:?}", e.into_inner().unwrap());
+							println!("ERROR: {}", e);
 							continue;
 						}
+					};
+					let targets: Vec<PublicKey> = targets
+						.into_iter()
+						.filter(|target| !denylist.is_denied_node(&target.to_string()))
+						.collect();
+
+					// the target's public channels are the ground truth, each probed as if it
+					// was private next to a channel the target doesn't have
+					let (probes, assumption_channels, assumption_matches) =
+						evaluate::pick_probes(&targets, &network_graph, max_channels);
+					if probes.is_empty() {
+						println!("No public channels to evaluate against");
+						continue;
 					}
-
-					// sort the transaction with recent (highest number) blocks
-					log_info!(logger, "sorting transactions by height");
-					txs.sort_by(|a, b| b.block_height.cmp(&a.block_height));
-
-					// This doesn't matter right now so we'll hardcode it
-					let pubkey_guess =
-						"03b2c32c46e0b4b720c4f45f02a0cc4c5475df7ce4d5b1ab563961b1681c6917d6";
-
-					let set_of_attempts = get_attempts_str(&db.clone().lock().unwrap()).unwrap();
-
-					log_info!(logger, "Starting probing...");
-					let mut total_probes = 1;
-					let probe_start = Instant::now();
-
-					for node in nodes {
-						// first try to see if we can even find normal routes first
-
-						let route = find_routes(
-							&invoice_payer,
-							channel_manager.clone(),
-							&node.pubkey,
+					println!("Evaluating against {} probes...", probes.len());
+					for evaluation_probe in probes.iter() {
+						if running.load(Ordering::SeqCst) > 0 {
+							break;
+						}
+						if denylist.is_denied_channel(evaluation_probe.short_channel_id) {
+							continue;
+						}
+						// keep the number of probes in flight down, as probeall does
+						while pending_payments.lock().unwrap().len() >= probe_config.max_in_flight {
+							probe_monitor.abandon_stuck(
+								&channel_manager,
+								&pending_payments,
+								&logger,
+							);
+							thread::sleep(Duration::from_millis(50));
+						}
+						let target = evaluation_probe.target.to_string();
+						evaluations.record_sent(evaluation_probe);
+						let sent = probe(
+							&target,
+							&evaluation_probe.short_channel_id.to_string(),
+							&guess_strategy.next_guess().to_string(),
+							&*channel_manager,
 							&network_graph,
 							&logger,
-							ldk_data_dir.clone(),
-							vec![],
+							&ldk_data_dir,
+							pending_payments.clone(),
 							&scorer,
+							&hidden_graph,
+							&route_cache,
+							guess_strategy.name(),
+							&metrics,
+							"evaluation",
 						);
-
-						match route {
-							Ok(_) => (),
-							Err(_) => {
+						match sent {
+							Ok(_) => politeness.record_sent(&target),
+							Err(e) => {
+								evaluations.forget(evaluation_probe);
 								log_info!(
 									logger,
-									"No routes to node {}, skipping probes...",
-									&node.pubkey
+									"Could not probe {}:{}: {}",
+									target,
+									evaluation_probe.short_channel_id,
+									e
 								);
-								continue;
 							}
 						}
+					}
 
-						let txptr = &txs;
-						for (i, tx) in txptr.into_iter().enumerate() {
-							// check if the program has been requested to
-							// close down
-
-							loop {
-								// check signal in this loop in case we
-								// are htlc stalled
-								if running.load(Ordering::SeqCst) > 0 {
-									break;
-								}
-								let pending_outbound_payments = pending_payments.lock().unwrap();
-								// Assuming only 1 channel, TODO a better check
-								let len = pending_outbound_payments.keys().len();
-								drop(pending_outbound_payments);
-								if len > 30 {
-									// wait for pending htlc's to clear
-									log_trace!(logger, "Close to max htlc's, waiting...");
-									thread::sleep(Duration::from_millis(50));
-									continue;
-								}
-								break;
-							}
-
-							// check signal again
-							if running.load(Ordering::SeqCst) > 0 {
-								break;
-							}
+					// wait for the answers, other runs may keep probing meanwhile
+					let started = Instant::now();
+					while evaluations.pending() > 0 && started.elapsed() < probe_monitor.timeout() {
+						thread::sleep(Duration::from_millis(500));
+					}
+					probe_monitor.abandon_stuck(&channel_manager, &pending_payments, &logger);
+
+					match evaluations.finish(
+						&db.lock().unwrap(),
+						&probes,
+						guess_strategy.name(),
+						assumption_channels,
+						assumption_matches,
+					) {
+						Ok(report) => print_evaluation(&report),
+						Err(e) => println!("ERROR: failed to store the evaluation: {}", e),
+					}
+				}
+				"simulate" => {
+					let (config_path, txpath) = match (words.next(), words.next()) {
+						(Some(config_path), Some(txpath)) => (config_path, txpath),
+						_ => {
+							println!("ERROR: simulate requires a config and tx_dir: `simulate <config.json> <tx_dir> [guess_strategy]`");
+							continue;
+						}
+					};
+					let guess_strategy = match GuessStrategy::parse(
+						words.next().unwrap_or(&probe_config.guess_strategy),
+						&denylist,
+						&logger,
+					) {
+						Ok(strategy) => strategy,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						}
+					};
+					let config = match SimulationConfig::load(config_path) {
+						Ok(config) => config,
+						Err(e) => {
+							println!("ERROR: failed to read the simulation config: {}", e);
+							continue;
+						}
+					};
+					let txs = match read_transactions(txpath) {
+						Ok(txs) => txs,
+						Err(e) => {
+							println!("ERROR: failed to read transactions: {}", e);
+							continue;
+						}
+					};
 
-							// check if we are running with assumptions
-							if probetype.unwrap() == "assumptions" {
-								if tx.amount % 10000 != 0 {
-									continue;
+					// the same routes and guesses as a real run, only the probes go to the
+					// simulator instead of the channel manager, and the answers to a copy of
+					// the graph and scorer
+					let simulator = Simulator::new(network_graph.clone(), network, config);
+					let start_node =
+						simulator.start_node().unwrap_or_else(|| channel_manager.get_our_node_id());
+					let view = match SimulatedView::new(&network_graph, &scorer, logger.clone()) {
+						Ok(view) => view,
+						Err(e) => {
+							println!("ERROR: failed to copy the graph and scorer: {:?}", e);
+							continue;
+						}
+					};
+					let simulated_routes = ProbeRouteCache::new();
+					let simulated_payments: PaymentInfoStorage =
+						Arc::new(Mutex::new(HashMap::new()));
+					let simulated_metrics = Metrics::new();
+					let public_channels = network_graph.read_only().channels().clone();
+					let report = RefCell::new(SimulationReport::default());
+					let answer = || {
+						simulator.run_until_idle(&|event: &Event| {
+							if let Event::PaymentPathFailed {
+								path,
+								rejected_by_dest,
+								error_code: Some(error_code),
+								short_channel_id: failed_scid,
+								..
+							} = event
+							{
+								let (guessed_hop, path) = path.split_last().unwrap();
+								let mut report = report.borrow_mut();
+								if let Some(target) = path.last() {
+									report.record(
+										&simulator,
+										&target.pubkey,
+										guessed_hop.short_channel_id,
+										probe_result(*error_code, *rejected_by_dest),
+									);
 								}
-								if tx.transaction_index > 1 {
-									continue;
+								view.learn(event, &mut report);
+								if let Some(scid) = failed_scid {
+									if *scid != guessed_hop.short_channel_id {
+										simulated_routes.invalidate_channel(*scid);
+									}
 								}
 							}
-
+						});
+					};
+					// answers come back whenever max_in_flight probes are out, like the
+					// throttle of a real run lets them, so they shape the routes after them
+					let mut routed = HashSet::new();
+					let mut unanswered = 0;
+					for target in simulator.targets() {
+						let policy = hint_policy(&target, &view.network_graph, &hidden_graph);
+						for tx in txs.iter() {
 							let scid = scid_from_parts(
 								tx.block_height,
 								tx.block_index,
 								tx.transaction_index,
 							);
-
-							// make sure scid is not in public channel
-							// list
-							match short_channel_ids.get(&scid) {
-								Some(_) => {
-									log_info!(logger, "skipping public chan {}", scid.to_string());
-									continue;
+							if public_channels.contains_key(&scid) {
+								continue;
+							}
+							if unanswered >= probe_config.max_in_flight {
+								answer();
+								unanswered = 0;
+							}
+							let hop = GuessedHop {
+								target,
+								short_channel_id: scid,
+								guess: simulator.next_guess(&guess_strategy),
+								policy,
+							};
+							let route = simulated_routes.probe_route(
+								&hop,
+								&view.network_graph,
+								vec![],
+								|route_hints| {
+									if !routed.insert(target) {
+										report.borrow_mut().rerouted += 1;
+									}
+									// from the graph alone, the node's own channels and any
+									// randomness would make runs differ
+									find_route_from(
+										&start_node,
+										&target.to_string(),
+										&view.network_graph,
+										None,
+										&logger,
+										route_hints,
+										&view.scorer,
+										&simulator.random_bytes(),
+									)
+								},
+							);
+							let route = match route {
+								Ok(route) => route,
+								Err(e) => {
+									println!("ERROR: skipping {}: {}", target, e);
+									report.borrow_mut().unreachable += 1;
+									break;
 								}
-								None => (),
+							};
+							let sent = send_probe(
+								&route,
+								&simulator,
+								simulated_payments.clone(),
+								&logger,
+								guess_strategy.name(),
+								&simulated_metrics,
+								"probe",
+							);
+							report.borrow_mut().probes += sent.is_ok() as usize;
+							unanswered += 1;
+						}
+					}
+					answer();
+					let mut report = report.into_inner();
+					report.elapsed = simulator.elapsed();
+					print_simulation(&report, simulator.in_flight());
+				}
+				"stats" => {
+					let txpath = match words.next() {
+						Some(txpath) => txpath,
+						None => {
+							println!("ERROR: stats requires a tx_dir: `stats <tx_dir> [<targets>|all] [json]`");
+							continue;
+						}
+					};
+					let options: Vec<&str> = words.collect();
+					let json = options.last() == Some(&"json");
+					let targets = match options.first().filter(|option| **option != "json") {
+						Some(&"all") => network_graph.read_only().nodes().len(),
+						Some(count) => match count.parse::<usize>() {
+							Ok(count) => count,
+							Err(_) => {
+								println!("ERROR: targets must be a number or all: `stats <tx_dir> [<targets>|all] [json]`");
+								continue;
 							}
+						},
+						None => 1,
+					};
+					let txs = match read_transactions(txpath) {
+						Ok(txs) => txs,
+						Err(e) => {
+							println!("ERROR: failed to read transactions: {}", e);
+							continue;
+						}
+					};
+					let attempts = get_attempts(&db.lock().unwrap()).unwrap();
+					let stats = CandidateStats::build(
+						&txs,
+						&network_graph,
+						&attempts,
+						&politeness.limits(),
+						targets,
+						probe_monitor.timeout(),
+					);
+					if json {
+						println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+					} else {
+						print_stats(&stats);
+					}
+				}
+				"probeall" => {
+					let probetype = words.next();
+					let nodepath = words.next();
+					let txpath = words.next();
 
-							let attempt = format!("{}:{}", node.pubkey, scid.to_string());
-							if set_of_attempts.contains_key(&attempt) {
-								log_info!(logger, "skipping attempt {}", attempt);
+					if probetype.is_none() || nodepath.is_none() || txpath.is_none() {
+						println!("ERROR: probeall requires type nodefile and txfile: `probeprivate <probetype> <nodefile> <txfile>`");
+						continue;
+					}
+
+					let guess_strategy = match GuessStrategy::parse(
+						words.next().unwrap_or(&probe_config.guess_strategy),
+						&denylist,
+						&logger,
+					) {
+						Ok(strategy) => strategy,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						}
+					};
+
+					if let Err(e) = probe_all(
+						&probe_context,
+						probetype.unwrap(),
+						nodepath.unwrap(),
+						txpath.unwrap(),
+						&guess_strategy,
+						&running,
+						&AtomicBool::new(false),
+					) {
+						println!("ERROR: {}", e);
+					}
+				}
+				"ratelimit" => {
+					let mut limits = politeness.limits();
+					if let Some(per_minute) = words.next() {
+						let per_day = words.next();
+						let quiet_hours = words.next();
+						match parse_limits(per_minute, per_day, quiet_hours) {
+							Ok(new_limits) => limits = new_limits,
+							Err(e) => {
+								println!("ERROR: {}: `ratelimit [<per_minute> <per_day> [<start_hour>-<end_hour>]]`", e);
 								continue;
 							}
-
-							let mut elapsed = probe_start.elapsed().as_secs();
-							if elapsed == 0 {
-								elapsed = 1;
+						}
+						politeness.set_limits(limits);
+					}
+					print_limits(&limits);
+				}
+				"status" => {
+					let pending = pending_payments.lock().unwrap();
+					let in_flight = pending.values().filter(|info| info.probe.is_some()).count();
+					drop(pending);
+					println!("Probes in flight: {}", in_flight);
+					println!("Stuck probe timeout: {}s", probe_monitor.timeout().as_secs());
+					print_limits(&politeness.limits());
+					if politeness.limits().in_quiet_hours(Utc::now().hour()) {
+						println!("Quiet hours, not probing");
+					}
+					for (target, verdict) in controls.verdicts() {
+						println!(
+							"\t{} {} with confidence {:.2} ({} of {} controls off)",
+							target,
+							verdict.status.as_str(),
+							verdict.confidence,
+							verdict.deviations,
+							verdict.answered
+						);
+					}
+					for target in politeness.status() {
+						println!(
+							"\t{} last minute: {} last day: {}{}",
+							target.target,
+							target.last_minute,
+							target.last_day,
+							match target.backing_off {
+								Some(backoff) => format!(" backing off for {}s", backoff.as_secs()),
+								None => String::new(),
 							}
-							println!(
-								"{} {} of {} | tx {}:{} (tps: {}, total: {}s)",
-								total_probes,
-								i,
-								txptr.len(),
-								node.pubkey,
-								scid.to_string(),
-								total_probes / elapsed,
-								probe_start.elapsed().as_secs_f64()
-							);
-							total_probes += 1;
-							loop {
-								match probe(
-									&node.pubkey,
-									&scid.to_string(),
-									pubkey_guess,
-									&invoice_payer,
-									channel_manager.clone(),
-									&network_graph,
-									&logger,
-									&ldk_data_dir,
-									pending_payments.clone(),
-									&scorer,
-								) {
-									Ok(_) => break,
-									Err(_) => {
-										// keep probing this
-										// one until it
-										// succeeds
-										thread::sleep(Duration::from_millis(100));
-										continue;
-									}
-								}
+						);
+					}
+				}
+				"stuckprobes" => {
+					if let Some(timeout) = words.next() {
+						match timeout.parse::<u64>() {
+							Ok(secs) => probe_monitor.set_timeout(Duration::from_secs(secs)),
+							Err(_) => {
+								println!("ERROR: timeout must be a number of seconds: `stuckprobes [timeout_secs]`");
+								continue;
 							}
 						}
-						log_info!(logger, "Probing next node...");
+					}
+					let stuck =
+						probe_monitor.abandon_stuck(&channel_manager, &pending_payments, &logger);
+					println!(
+						"Abandoned {} probes pending for more than {}s",
+						stuck.len(),
+						probe_monitor.timeout().as_secs()
+					);
+					for stuck in stuck.iter() {
+						print_stuck_probe(stuck);
+					}
+					let holders = probe_monitor.holders();
+					if !holders.is_empty() {
+						println!("Nodes holding probes:");
+					}
+					for (pubkey, stats) in holders {
+						println!(
+							"\t{} stuck: {} answered: {} mean answer time: {}",
+							pubkey,
+							stats.stuck,
+							stats.answered,
+							match stats.mean_answer_time() {
+								Some(mean) => format!("{}ms", mean.as_millis()),
+								None => "-".to_string(),
+							}
+						);
 					}
 				}
 				"dump_results" => {
@@ -828,100 +1157,857 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 
 					if result_dir.is_none() || txpath.is_none() {
 						println!(
-							"ERROR: dump_results requires result_dir tx_dir: `dump_results <result_dir> <tx_dir>`"
+							"ERROR: dump_results requires result_dir tx_dir: `dump_results <result_dir> <tx_dir> [aggregate [k]] [sign]`"
 						);
 						continue;
 					}
 
-					// Parse tx files
-					let mut txs: HashMap<u64, Transaction> = HashMap::new();
-					let read_res = fs::read_dir(txpath.unwrap());
-					match read_res {
-						Ok(txdir) => {
-							for json_file in txdir {
-								println!(
-									"Reading tx file: {:?}",
-									json_file.as_ref().unwrap().file_name().as_os_str().to_str()
-								);
-								let tx_data = match fs::read_to_string(json_file.unwrap().path()) {
-									Ok(file) => file,
-									Err(e) => {
-										println!("{:?}", e.into_inner().unwrap());
-										continue;
-									}
-								};
-								let new_txs: Vec<Transaction> = match serde_json::from_str(&tx_data)
-								{
-									Ok(n) => n,
-									Err(e) => {
-										println!("{:?}", e);
-										continue;
-									}
-								};
-								for tx in new_txs {
-									let scid = scid_from_parts(
-										tx.block_height,
-										tx.block_index,
-										tx.transaction_index,
-									);
-									txs.insert(scid, tx);
-								}
+					let options: Vec<&str> = words.collect();
+					let sign = options.last() == Some(&"sign");
+					let options = if sign { &options[..options.len() - 1] } else { &options[..] };
+					// only publish statistics that each cover at least k targets
+					let aggregate = match options {
+						[] => None,
+						["aggregate"] => Some(DEFAULT_K_ANONYMITY),
+						["aggregate", k] => match k.parse::<usize>() {
+							Ok(k) if k > 0 => Some(k),
+							_ => {
+								println!("ERROR: k must be a positive number");
+								continue;
 							}
-						}
-						Err(e) => {
-							println!("{:?}", e.into_inner().unwrap());
+						},
+						_ => {
+							println!(
+								"ERROR: unknown dump_results options `{}`, expected [aggregate [k]] [sign]",
+								options.join(" ")
+							);
 							continue;
 						}
+					};
+
+					if let Err(e) = dump_results(
+						&probe_context,
+						result_dir.unwrap(),
+						txpath.unwrap(),
+						aggregate,
+						sign,
+					) {
+						println!("ERROR: {}", e);
 					}
+				}
+				"verifyresults" => {
+					let result_dir = words.next();
+					let pubkey_str = words.next();
+					let tx_dir = words.next();
 
-					let attempts = get_attempts_found(&db.clone().lock().unwrap()).unwrap();
-					let mut results: Vec<AttemptResult> = vec![];
-					for attempt in attempts {
-						let mut result = AttemptResult {
-							target_pubkey: attempt.target_pubkey,
-							other_pubkey: "".to_string(),
-							channel_id: attempt.channel_id.clone(),
-							transaction_id_output: "".to_string(),
-							amount: 0,
-							block_open: block_from_scid(
-								&attempt.channel_id.parse::<u64>().unwrap().clone(),
-							),
-							date_found: attempt.date_found,
+					if result_dir.is_none() || pubkey_str.is_none() {
+						println!("ERROR: verifyresults requires result_dir and pubkey: `verifyresults <result_dir> <pubkey> [tx_dir]`");
+						continue;
+					}
+					let pubkey = match PublicKey::from_str(pubkey_str.unwrap()) {
+						Ok(pubkey) => pubkey,
+						Err(_) => {
+							println!("ERROR: couldn't parse pubkey");
+							continue;
+						}
+					};
+
+					let verification =
+						match manifest::verify_results(result_dir.unwrap(), &pubkey, tx_dir) {
+							Ok(verification) => verification,
+							Err(e) => {
+								println!("ERROR: failed to verify results: {}", e);
+								continue;
+							}
 						};
+					print_verification(&verification);
+				}
+				"importgraph" => {
+					let format = words.next();
+					let graph_path = words.next();
 
-						let output_index =
-							vout_from_scid(&attempt.channel_id.parse::<u64>().unwrap().clone());
+					if format.is_none() || graph_path.is_none() {
+						println!("ERROR: importgraph requires a format and a dump file: `importgraph <lnd|cln> <file>`");
+						continue;
+					}
 
-						// go through tx set and find txid and amount
-						let tx = txs.get(&attempt.channel_id.parse::<u64>().unwrap().clone());
-						match tx {
-							Some(utxo) => {
-								result.transaction_id_output =
-									format!("{}:{}", utxo.id, output_index);
-								result.amount = utxo.amount;
-							}
-							None => {
-								// TODO if not found in this set (bc spent), do an
-								// electrum lookup?
+					let summary = graph_import::import_graph(
+						format.unwrap(),
+						graph_path.unwrap(),
+						network,
+						&network_graph,
+						&db.lock().unwrap(),
+					);
+					match summary {
+						Ok(summary) => {
+							println!(
+								"Imported {} channels, {} channel updates and {} nodes ({} skipped)",
+								summary.channels,
+								summary.channel_updates,
+								summary.nodes,
+								summary.skipped
+							);
+							let network_graph_path = format!("{}/network_graph", ldk_data_dir);
+							if disk::persist_network(Path::new(&network_graph_path), &network_graph)
+								.is_err()
+							{
+								println!("ERROR: failed to persist network graph");
 							}
 						}
+						Err(e) => println!("ERROR: failed to import graph: {}", e),
+					}
+				}
+				"exportgraph" => {
+					let format = words.next();
+					let export_path = words.next();
+					let txpath = words.next();
+
+					if format.is_none() || export_path.is_none() {
+						println!("ERROR: exportgraph requires a format and an output path: `exportgraph <graphml|dot|csv> <path> [tx_dir]`");
+						continue;
+					}
 
-						if attempt.result == "incorrect_or_unknown_payment_details" {
-							result.other_pubkey = attempt.guess_pubkey;
+					// capacities of private channels come from the candidate tx set, if given
+					let txs = match txpath.map(read_transactions) {
+						Some(Ok(txs)) => transactions_by_scid(txs),
+						Some(Err(e)) => {
+							println!("{:?}", e);
+							continue;
 						}
+						None => HashMap::new(),
+					};
 
-						results.push(result);
+					let mut discovered = hidden_graph.channels();
+					discovered.retain(|channel| {
+						let denied = denylist.is_denied(
+							&channel.target_pubkey,
+							channel.short_channel_id,
+							channel.counterparty.as_deref(),
+						);
+						if denied {
+							log_info!(
+								logger,
+								"Not exporting {}:{}, it is on the denylist",
+								channel.target_pubkey,
+								channel.short_channel_id
+							);
+						}
+						!denied
+					});
+					let graph = ExportGraph::build(&network_graph, &discovered, &txs);
+					match graph.write(format.unwrap(), export_path.unwrap()) {
+						Ok(()) => println!(
+							"Exported {} nodes and {} channels ({} private)",
+							graph.nodes.len(),
+							graph.edges.len(),
+							discovered.len()
+						),
+						Err(e) => println!("ERROR: failed to export graph: {}", e),
 					}
-
-					let writer = BufWriter::new(
-						File::create(format!("{}/results.json", result_dir.unwrap())).unwrap(),
-					);
-					serde_json::to_writer_pretty(writer, &results).unwrap();
 				}
 				_ => println!("Unknown command. See `\"help\" for available commands."),
 			}
 		}
 	}
+	false
+}
+
+/// Everything a probe run touches on the node, for runs from the prompt and headless ones alike.
+#[derive(Clone)]
+pub(crate) struct ProbeContext {
+	pub(crate) channel_manager: Arc<ChannelManager>,
+	pub(crate) keys_manager: Arc<KeysManager>,
+	pub(crate) network_graph: Arc<NetworkGraph>,
+	pub(crate) logger: Arc<FilesystemLogger>,
+	pub(crate) scorer: Arc<Mutex<ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>>>,
+	pub(crate) db: Arc<Mutex<rusqlite::Connection>>,
+	pub(crate) hidden_graph: Arc<HiddenGraph>,
+	pub(crate) route_cache: Arc<ProbeRouteCache>,
+	pub(crate) probe_monitor: Arc<ProbeMonitor>,
+	pub(crate) politeness: Arc<Politeness>,
+	pub(crate) controls: Arc<ControlProbes>,
+	pub(crate) denylist: Arc<Denylist>,
+	pub(crate) pending_payments: PaymentInfoStorage,
+	pub(crate) ldk_data_dir: String,
+	pub(crate) probe_config: ProbeConfig,
+	pub(crate) metrics: Arc<Metrics>,
+}
+
+/// How often `probeall` tries to send a probe before it gives up on it, a target that can't be
+/// reached at all is skipped before that.
+const MAX_SEND_ATTEMPTS: usize = 10;
+
+/// How far a `probeall` run got.
+pub(crate) struct ProbeAllSummary {
+	/// Targets the run got to, including ones without a route.
+	pub(crate) targets: usize,
+	/// Probes that were left to send once the run got to them, whether they went out or not.
+	pub(crate) candidates: u64,
+	pub(crate) probes: u64,
+	/// Probes given up on after `MAX_SEND_ATTEMPTS` tries to send them.
+	pub(crate) failed: u64,
+	/// Whether it stopped early because of a signal.
+	pub(crate) interrupted: bool,
+}
+
+/// Where a `probeall` run is with one target.
+struct TargetRun {
+	node: Node,
+	target: Option<PublicKey>,
+	started: bool,
+	/// Index of the next candidate to look at.
+	next_tx: usize,
+	/// The candidate last counted in `ProbeAllSummary::candidates`, a held target gets back to
+	/// the same one.
+	counted: Option<usize>,
+	routed: bool,
+	node_probes: usize,
+	controls_sent: usize,
+	/// Whether a control goes out before the next probe.
+	control_due: bool,
+}
+
+/// Probes every candidate in `txpath` behind every target in `nodepath`, which is a node file,
+/// `all` for every node in the graph or `hidden` for the private nodes found so far. Returns
+/// once every probe is sent, or early once `running` is set. No new probes go out while `paused`
+/// is set.
+pub(crate) fn probe_all(
+	ctx: &ProbeContext, probetype: &str, nodepath: &str, txpath: &str,
+	guess_strategy: &GuessStrategy, running: &AtomicUsize, paused: &AtomicBool,
+) -> Result<ProbeAllSummary, Box<dyn std::error::Error>> {
+	let ProbeContext {
+		channel_manager,
+		network_graph,
+		logger,
+		scorer,
+		db,
+		hidden_graph,
+		route_cache,
+		probe_monitor,
+		politeness,
+		controls,
+		denylist,
+		pending_payments,
+		ldk_data_dir,
+		probe_config,
+		metrics,
+		..
+	} = ctx;
+
+	// if nodefile is "all" then read from network graph
+	let mut nodes: Vec<Node> = vec![];
+	if nodepath == "all" {
+		for (pubkey, _) in network_graph.read_only().nodes() {
+			println!("trying to parse pubkey {:?} : {:?}", pubkey, hex::encode(pubkey.as_slice()));
+			let pubkey_str = hex::encode(pubkey.as_slice());
+			nodes.push(Node { pubkey: String::from(pubkey_str) });
+		}
+	} else if nodepath == "hidden" {
+		// private nodes behind channels we've already found, the probes go
+		// through those channels to reach them
+		for pubkey in hidden_graph.private_nodes(network_graph) {
+			nodes.push(Node { pubkey: pubkey.to_string() });
+		}
+	} else {
+		// Parse nodefile
+		let node_data = fs::read_to_string(nodepath)
+			.map_err(|e| format!("couldn't read nodefile {}: {}", nodepath, e))?;
+		nodes = serde_json::from_str(&node_data)
+			.map_err(|e| format!("couldn't parse nodefile {}: {}", nodepath, e))?;
+	}
+
+	// targets caught answering uniformly go last, their answers are worth little
+	nodes.sort_by_key(|node| controls.is_flagged(&node.pubkey));
+
+	// get a list of public channels
+
+	let short_channel_ids = network_graph.read_only().channels().clone();
+
+	// Parse tx files
+	let mut txs = read_transactions(txpath)
+		.map_err(|e| format!("couldn't read transactions from {}: {}", txpath, e))?;
+
+	// sort the transaction with recent (highest number) blocks
+	log_info!(logger, "sorting transactions by height");
+	txs.sort_by(|a, b| b.block_height.cmp(&a.block_height));
+
+	let set_of_attempts = get_attempts_str(&db.lock().unwrap())?;
+
+	log_info!(logger, "Starting probing...");
+	let mut total_probes = 1;
+	let mut targets = 0;
+	let (mut candidates, mut probes, mut failed) = (0, 0, 0);
+	let probe_start = Instant::now();
+
+	// targets are probed one after the other, but one that has to wait for its limits goes to
+	// the back of the queue so the others go on in the meantime
+	let mut queue: VecDeque<TargetRun> = nodes
+		.into_iter()
+		.map(|node| TargetRun {
+			target: PublicKey::from_str(&node.pubkey).ok(),
+			node,
+			started: false,
+			next_tx: 0,
+			counted: None,
+			routed: false,
+			node_probes: 0,
+			controls_sent: 0,
+			control_due: true,
+		})
+		.collect();
+	let mut held_in_a_row = 0;
+	while let Some(mut run) = queue.pop_front() {
+		if running.load(Ordering::SeqCst) > 0 {
+			break;
+		}
+		if !run.started {
+			run.started = true;
+			targets += 1;
+			if denylist.is_denied_node(&run.node.pubkey) {
+				log_info!(logger, "Not probing {}, it is on the denylist", run.node.pubkey);
+				continue;
+			}
+		}
+		if controls.is_flagged(&run.node.pubkey) {
+			let verdict = controls.verdict(&run.node.pubkey).unwrap();
+			log_info!(
+				logger,
+				"Not probing {} any further, it is a {} target",
+				run.node.pubkey,
+				verdict.status.as_str()
+			);
+			println!(
+				"Skipping the rest of {}: {} after {} controls",
+				run.node.pubkey,
+				verdict.status.as_str(),
+				verdict.answered
+			);
+			continue;
+		}
+
+		// probes already out still get their answers recorded
+		while paused.load(Ordering::SeqCst) && running.load(Ordering::SeqCst) == 0 {
+			thread::sleep(Duration::from_millis(100));
+		}
+
+		// the next candidate of the target that is left to probe
+		let pubkey = &run.node.pubkey;
+		let is_candidate = |tx: &Transaction| {
+			let scid = scid_from_parts(tx.block_height, tx.block_index, tx.transaction_index);
+			// check if we are running with assumptions
+			if probetype == "assumptions" && !matches_assumptions(tx.amount, tx.transaction_index) {
+				return false;
+			}
+			// make sure scid is not in public channel list
+			if short_channel_ids.contains_key(&scid) {
+				log_info!(logger, "skipping public chan {}", scid.to_string());
+				return false;
+			}
+			if denylist.is_denied_channel(scid) {
+				log_info!(logger, "Not probing {}, it is on the denylist", scid);
+				return false;
+			}
+			let attempt = format!("{}:{}", pubkey, scid.to_string());
+			if set_of_attempts.contains_key(&attempt) {
+				log_info!(logger, "skipping attempt {}", attempt);
+				return false;
+			}
+			true
+		};
+		while matches!(txs.get(run.next_tx), Some(tx) if !is_candidate(tx)) {
+			run.next_tx += 1;
+		}
+		let scid = match txs.get(run.next_tx) {
+			Some(tx) => scid_from_parts(tx.block_height, tx.block_index, tx.transaction_index),
+			None => {
+				log_info!(logger, "Probing next node...");
+				continue;
+			}
+		};
+		if run.counted != Some(run.next_tx) {
+			run.counted = Some(run.next_tx);
+			candidates += 1;
+		}
+
+		// see if we can even find normal routes first, once there is something to probe,
+		// private targets need a path through the hidden graph
+		if !run.routed {
+			let route_hints = match &run.target {
+				Some(target) => hidden_graph.route_hints_to(target, network_graph),
+				None => vec![],
+			};
+			let route = find_routes(
+				&**channel_manager,
+				&run.node.pubkey,
+				network_graph,
+				logger,
+				ldk_data_dir.clone(),
+				route_hints,
+				scorer,
+			);
+			if route.is_err() {
+				log_info!(logger, "No routes to node {}, skipping probes...", &run.node.pubkey);
+				continue;
+			}
+			run.routed = true;
+		}
+
+		loop {
+			// check signal in this loop in case we
+			// are htlc stalled
+			if running.load(Ordering::SeqCst) > 0 {
+				break;
+			}
+			let pending_outbound_payments = pending_payments.lock().unwrap();
+			// Assuming only 1 channel, TODO a better check
+			let len = pending_outbound_payments.keys().len();
+			drop(pending_outbound_payments);
+			if len >= probe_config.max_in_flight {
+				// probes held somewhere on the way would never clear
+				let stuck = probe_monitor.abandon_stuck(channel_manager, pending_payments, logger);
+				metrics.record_abandoned(stuck.len());
+				for stuck in stuck {
+					print_stuck_probe(&stuck);
+				}
+				// wait for pending htlc's to clear
+				log_trace!(logger, "Close to max htlc's, waiting...");
+				thread::sleep(Duration::from_millis(50));
+				continue;
+			}
+			break;
+		}
+
+		// check signal again
+		if running.load(Ordering::SeqCst) > 0 {
+			break;
+		}
+
+		// keep to the per target limits, controls included
+		match politeness.check(&run.node.pubkey) {
+			None => held_in_a_row = 0,
+			Some(Hold::PerDay) => {
+				log_info!(logger, "Daily probe limit reached for {}", run.node.pubkey);
+				continue;
+			}
+			Some(hold) => {
+				log_trace!(logger, "Holding probes to {}: {:?}", run.node.pubkey, hold);
+				queue.push_back(run);
+				// only once every target is held is there nothing to do but wait
+				held_in_a_row += 1;
+				if held_in_a_row >= queue.len() {
+					thread::sleep(Duration::from_millis(500));
+					held_in_a_row = 0;
+				}
+				continue;
+			}
+		}
+
+		// mix in a probe we know the answer to every so often, it goes out on its own
+		if run.control_due {
+			run.control_due = false;
+			if let Some(target) = &run.target {
+				let control = controls.next_control(
+					target,
+					network_graph,
+					channel_manager.current_best_block().height(),
+					run.controls_sent,
+				);
+				controls.record_sent(&run.node.pubkey, &control);
+				match probe(
+					&run.node.pubkey,
+					&control.short_channel_id.to_string(),
+					&control.guess.to_string(),
+					&**channel_manager,
+					network_graph,
+					logger,
+					ldk_data_dir,
+					pending_payments.clone(),
+					scorer,
+					hidden_graph,
+					route_cache,
+					"control",
+					metrics,
+					"control",
+				) {
+					Ok(_) => {
+						politeness.record_sent(&run.node.pubkey);
+						run.controls_sent += 1;
+					}
+					Err(e) => {
+						controls.forget(&run.node.pubkey, control.short_channel_id);
+						log_info!(logger, "Could not send a control to {}: {}", run.node.pubkey, e);
+					}
+				}
+			}
+			queue.push_front(run);
+			continue;
+		}
+
+		let mut elapsed = probe_start.elapsed().as_secs();
+		if elapsed == 0 {
+			elapsed = 1;
+		}
+		println!(
+			"{} {} of {} | tx {}:{} (tps: {}, total: {}s)",
+			total_probes,
+			run.next_tx,
+			txs.len(),
+			run.node.pubkey,
+			scid.to_string(),
+			total_probes / elapsed,
+			probe_start.elapsed().as_secs_f64()
+		);
+		total_probes += 1;
+
+		let pubkey_guess = guess_strategy.next_guess().to_string();
+		let mut attempts = 0;
+		while running.load(Ordering::SeqCst) == 0 {
+			attempts += 1;
+			match probe(
+				&run.node.pubkey,
+				&scid.to_string(),
+				&pubkey_guess,
+				&**channel_manager,
+				network_graph,
+				logger,
+				ldk_data_dir,
+				pending_payments.clone(),
+				scorer,
+				hidden_graph,
+				route_cache,
+				guess_strategy.name(),
+				metrics,
+				"probe",
+			) {
+				Ok(_) => {
+					politeness.record_sent(&run.node.pubkey);
+					probes += 1;
+					break;
+				}
+				Err(e) if attempts >= MAX_SEND_ATTEMPTS => {
+					log_info!(logger, "Giving up on probing {}:{}: {}", run.node.pubkey, scid, e);
+					failed += 1;
+					break;
+				}
+				Err(_) => thread::sleep(Duration::from_millis(100)),
+			}
+		}
+		run.next_tx += 1;
+		run.node_probes += 1;
+		run.control_due = run.node_probes % CONTROL_INTERVAL == 0;
+		queue.push_front(run);
+	}
+	Ok(ProbeAllSummary {
+		targets,
+		candidates,
+		probes,
+		failed,
+		interrupted: running.load(Ordering::SeqCst) > 0,
+	})
+}
+
+/// Writes what the probes found so far to `result_dir`, as `results.json`, or as
+/// `aggregate.json` with only statistics covering at least `aggregate` targets. `sign` adds a
+/// manifest signed with the node key.
+pub(crate) fn dump_results(
+	ctx: &ProbeContext, result_dir: &str, txpath: &str, aggregate: Option<usize>, sign: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let ProbeContext { keys_manager, network_graph, logger, db, denylist, .. } = ctx;
+
+	// Parse tx files
+	let txs = transactions_by_scid(
+		read_transactions(txpath)
+			.map_err(|e| format!("couldn't read transactions from {}: {}", txpath, e))?,
+	);
+
+	let attempts = get_attempts_found(&db.lock().unwrap())?;
+	let mut results: Vec<AttemptResult> = vec![];
+	for attempt in attempts {
+		if denylist.is_attempt_denied(&attempt) {
+			log_info!(
+				logger,
+				"Not publishing {}:{}, it is on the denylist",
+				attempt.target_pubkey,
+				attempt.channel_id
+			);
+			continue;
+		}
+
+		let mut result = AttemptResult {
+			target_pubkey: attempt.target_pubkey,
+			other_pubkey: "".to_string(),
+			channel_id: attempt.channel_id.clone(),
+			transaction_id_output: "".to_string(),
+			amount: 0,
+			block_open: block_from_scid(&attempt.channel_id.parse::<u64>().unwrap().clone()),
+			date_found: attempt.date_found,
+			hidden_path: attempt.hidden_path.clone(),
+		};
+
+		let output_index = vout_from_scid(&attempt.channel_id.parse::<u64>().unwrap().clone());
+
+		// go through tx set and find txid and amount
+		let tx = txs.get(&attempt.channel_id.parse::<u64>().unwrap().clone());
+		match tx {
+			Some(utxo) => {
+				result.transaction_id_output = format!("{}:{}", utxo.id, output_index);
+				result.amount = utxo.amount;
+			}
+			None => {
+				// TODO if not found in this set (bc spent), do an
+				// electrum lookup?
+			}
+		}
+
+		if is_counterparty_confirmed(&attempt.result) {
+			result.other_pubkey = attempt.guess_pubkey;
+		}
+
+		results.push(result);
+	}
+
+	let file = if let Some(k_anonymity) = aggregate {
+		let aggregate = AggregateResults::build(&results, network_graph, k_anonymity);
+		let writer = BufWriter::new(File::create(format!("{}/aggregate.json", result_dir))?);
+		serde_json::to_writer_pretty(writer, &aggregate)?;
+		println!(
+			"Wrote statistics on {} channels, {} cells suppressed below k = {}",
+			results.len(),
+			aggregate.suppressed_cells,
+			k_anonymity
+		);
+		"aggregate.json"
+	} else {
+		let writer = BufWriter::new(File::create(format!("{}/results.json", result_dir))?);
+		serde_json::to_writer_pretty(writer, &results)?;
+		"results.json"
+	};
+
+	if sign {
+		let manifest = manifest::write_manifest(
+			result_dir,
+			file,
+			txpath,
+			network_graph,
+			&keys_manager.get_node_secret(Recipient::Node).unwrap(),
+		);
+		match manifest {
+			Ok(manifest) => println!(
+				"Signed {} records of {} as {}",
+				manifest.record_sha256s.len(),
+				file,
+				manifest.node_pubkey
+			),
+			Err(e) => return Err(format!("failed to sign {}: {}", file, e).into()),
+		}
+	}
+	Ok(())
+}
+
+fn parse_limits(
+	per_minute: &str, per_day: Option<&str>, quiet_hours: Option<&str>,
+) -> Result<ProbeLimits, Box<dyn std::error::Error>> {
+	let per_minute = per_minute.parse::<usize>()?;
+	let per_day = per_day.ok_or("missing per day limit")?.parse::<usize>()?;
+	if per_minute == 0 || per_day == 0 {
+		return Err("limits must be at least 1".into());
+	}
+	let quiet_hours = match quiet_hours {
+		Some(hours) => Some(parse_quiet_hours(hours)?),
+		None => None,
+	};
+	Ok(ProbeLimits { per_minute, per_day, quiet_hours })
+}
+
+fn print_limits(limits: &ProbeLimits) {
+	println!(
+		"Probe limits per target: {} per minute, {} per day{}",
+		limits.per_minute,
+		limits.per_day,
+		match limits.quiet_hours {
+			Some((start, end)) => format!(", quiet from {}h to {}h UTC", start, end),
+			None => String::new(),
+		}
+	);
+}
+
+fn print_verification(verification: &Verification) {
+	let manifest = &verification.manifest;
+	println!("{} signed by {} on {}", manifest.file, manifest.node_pubkey, manifest.created);
+	println!("\tsignature: {}", if verification.signature_valid { "valid" } else { "INVALID" });
+	println!("\tfile: {}", if verification.file_matches { "unchanged" } else { "CHANGED" });
+	for i in verification.changed_records.iter() {
+		println!("\trecord {}: CHANGED", i);
+	}
+	if verification.added_records > 0 {
+		println!("\t{} records ADDED", verification.added_records);
+	} else if verification.added_records < 0 {
+		println!("\t{} records REMOVED", -verification.added_records);
+	}
+	match verification.dataset_matches {
+		Some(true) => println!("\tdataset: unchanged ({} files)", manifest.dataset_files),
+		Some(false) => println!("\tdataset: CHANGED"),
+		None => println!("\tdataset: not checked"),
+	}
+	println!(
+		"\tgraph: {} channels, {} nodes, sha256 {}",
+		manifest.graph_channels, manifest.graph_nodes, manifest.graph_sha256
+	);
+	println!("{}", if verification.is_valid() { "OK" } else { "FAILED" });
+}
+
+fn print_simulation(report: &SimulationReport, unanswered: usize) {
+	println!(
+		"Sent {} probes, {} answered and {} held in {:.1}s of simulated time ({})",
+		report.probes,
+		report.answered,
+		unanswered,
+		report.elapsed.as_secs_f64(),
+		match report.throughput() {
+			Some(throughput) => format!("{:.1} probes/s", throughput),
+			None => "no answers".to_string(),
+		}
+	);
+	println!(
+		"\tfound {} private channels, {} with their counterparty, {} false positives",
+		report.found, report.counterparties_found, report.false_positives
+	);
+	println!(
+		"\tthe scorer took {} failures, {} policies were updated and {} channels removed, {} probes were rerouted and {} targets left without a route",
+		report.penalties,
+		report.policies_updated,
+		report.channels_removed,
+		report.rerouted,
+		report.unreachable
+	);
+	for (result, count) in report.error_codes.iter() {
+		println!("\t{}: {}", result, count);
+	}
+}
+
+fn print_stats(stats: &CandidateStats) {
+	println!(
+		"{} candidates, {} of them public, {} left to probe",
+		stats.candidates, stats.public_candidates, stats.remaining
+	);
+	let print_buckets = |name: &str, buckets: &[Bucket]| {
+		println!("By {} (candidates / public channels):", name);
+		for bucket in buckets {
+			println!("\t{}: {} / {}", bucket.bucket, bucket.candidates, bucket.public_channels);
+		}
+	};
+	print_buckets("amount in sats", &stats.amounts);
+	print_buckets("vout", &stats.vouts);
+	print_buckets("block range", &stats.block_ranges);
+	print_buckets("roundness", &stats.roundness);
+	println!("Heuristics against {} public channels of known capacity:", stats.public_channels);
+	for matched in stats.heuristics.iter() {
+		println!(
+			"\t{}: keeps {} of the public channels ({}), {} candidates",
+			matched.heuristic,
+			matched.public_channels,
+			match matched.public_fraction {
+				Some(fraction) => format!("{:.1}%", fraction * 100.0),
+				None => "n/a".to_string(),
+			},
+			matched.candidates
+		);
+	}
+	match stats.probe_rate {
+		Some(rate) => println!("Measured probe rate: {:.2} probes/s", rate),
+		None => println!("Measured probe rate: unknown, not enough attempts yet"),
+	}
+	let duration = |secs: Option<u64>| match secs {
+		Some(secs) => format!("{}d {}h {}m", secs / 86_400, secs % 86_400 / 3600, secs % 3600 / 60),
+		None => "unknown".to_string(),
+	};
+	println!(
+		"Estimated campaign over {} targets: {}, {} with assumptions",
+		stats.targets,
+		duration(stats.estimated_duration_secs),
+		duration(stats.estimated_duration_assumptions_secs)
+	);
+}
+
+fn print_evaluation(report: &EvaluationReport) {
+	let percent = |rate: Option<f64>| match rate {
+		Some(rate) => format!("{:.1}%", rate * 100.0),
+		None => "n/a".to_string(),
+	};
+	println!(
+		"Run {} on {} ({} guesses, {} targets):",
+		report.run_id, report.date_run, report.guess_strategy, report.targets
+	);
+	println!(
+		"\tdetection rate: {} ({} of {} answered, {} sent)",
+		percent(report.detection_rate()),
+		report.detected,
+		report.positives_answered,
+		report.positives
+	);
+	println!(
+		"\tfalse positive rate: {} ({} of {} answered, {} sent)",
+		percent(report.false_positive_rate()),
+		report.false_positives,
+		report.negatives_answered,
+		report.negatives
+	);
+	println!(
+		"\tassumptions recall: {} ({} of {} channels of known capacity)",
+		percent(report.assumption_recall()),
+		report.assumption_matches,
+		report.assumption_channels
+	);
+	for (kind, results) in
+		[("own channels", &report.positive_results), ("other channels", &report.negative_results)]
+	{
+		println!("\terror codes for {}:", kind);
+		for (result, count) in results.iter() {
+			println!("\t\t{}: {}", result, count);
+		}
+	}
+}
+
+fn print_channel_audit(audit: &ChannelAudit) {
+	let scid = audit.short_channel_id;
+	println!(
+		"{} ({}x{}x{})",
+		scid,
+		block_from_scid(&scid),
+		tx_index_from_scid(&scid),
+		vout_from_scid(&scid)
+	);
+	match audit.exposure {
+		Exposure::Exposed { counterparty_confirmed: true } => {
+			println!("\tEXPOSED, leaks the channel and its counterparty")
+		}
+		Exposure::Exposed { counterparty_confirmed: false } => {
+			println!("\tEXPOSED, leaks the channel")
+		}
+		Exposure::Hidden => println!("\thidden, probes can't tell it from a missing channel"),
+		Exposure::NoAnswer => println!("\tunknown, no probe got an answer from the node"),
+	}
+	if let Some(policy) = &audit.policy {
+		println!(
+			"\tleaks its policy: base fee {} msat, fee rate {} ppm, cltv delta {}, htlc minimum {} msat",
+			policy.fees.base_msat,
+			policy.fees.proportional_millionths,
+			policy.cltv_expiry_delta,
+			policy.htlc_minimum_msat
+		);
+	}
+	println!("\tscid alias: {}", audit.scid_alias);
+}
+
+fn print_stuck_probe(stuck: &StuckProbe) {
+	let route: Vec<String> =
+		stuck.path.iter().map(|hop| format!("{} ({})", hop.pubkey, hop.short_channel_id)).collect();
+	println!(
+		"Stuck probe {} after {}s: {}",
+		hex_utils::hex_str(&stuck.payment_hash.0),
+		stuck.age.as_secs(),
+		route.join(" -> ")
+	);
 }
 
 fn help() {
@@ -939,7 +2025,19 @@ fn help() {
 	println!("findroutes <pubkey>");
 	println!("sendfakepayment <pubkey>");
 	println!("probeprivate <pubkey> <guessed_node> <channel_id>");
-	println!("probeprivate <nodefile> <txfile>");
+	println!("auditnode <pubkey> [channels.json]");
+	println!("evaluate <pubkey>|all [max_channels] [random|fixed:<pubkey>|list:<nodefile>]");
+	println!("evaluate runs");
+	println!("probeall <probetype> <nodefile|all|hidden> <txfile> [random|fixed:<pubkey>|list:<nodefile>]");
+	println!("simulate <config.json> <tx_dir> [random|fixed:<pubkey>|list:<nodefile>]");
+	println!("stats <tx_dir> [<targets>|all] [json]");
+	println!("status");
+	println!("ratelimit [<per_minute> <per_day> [<start_hour>-<end_hour>]]");
+	println!("stuckprobes [timeout_secs]");
+	println!("dump_results <result_dir> <tx_dir> [aggregate [k]] [sign]");
+	println!("verifyresults <result_dir> <pubkey> [tx_dir]");
+	println!("importgraph <lnd|cln> <file>");
+	println!("exportgraph <graphml|dot|csv> <path> [tx_dir]");
 }
 
 fn node_info(channel_manager: Arc<ChannelManager>, peer_manager: Arc<PeerManager>) {
@@ -965,28 +2063,73 @@ fn list_peers(peer_manager: Arc<PeerManager>) {
 	println!("\t}},");
 }
 
+/// One of our channels as `listchannels` shows it.
+#[derive(Serialize)]
+pub(crate) struct ChannelSummary {
+	pub(crate) channel_id: String,
+	pub(crate) funding_txid: Option<String>,
+	pub(crate) peer_pubkey: String,
+	pub(crate) peer_alias: Option<String>,
+	pub(crate) short_channel_id: Option<u64>,
+	pub(crate) is_channel_ready: bool,
+	pub(crate) channel_value_satoshis: u64,
+	pub(crate) local_balance_msat: u64,
+	/// Only for usable channels.
+	pub(crate) available_balance_for_send_msat: Option<u64>,
+	pub(crate) available_balance_for_recv_msat: Option<u64>,
+	pub(crate) channel_can_send_payments: bool,
+	pub(crate) public: bool,
+}
+
+pub(crate) fn channel_summaries(
+	channel_manager: &ChannelManager, network_graph: &NetworkGraph,
+) -> Vec<ChannelSummary> {
+	let graph = network_graph.read_only();
+	channel_manager
+		.list_channels()
+		.into_iter()
+		.map(|chan_info| ChannelSummary {
+			channel_id: hex_utils::hex_str(&chan_info.channel_id[..]),
+			funding_txid: chan_info.funding_txo.map(|funding_txo| funding_txo.txid.to_string()),
+			peer_pubkey: hex_utils::hex_str(&chan_info.counterparty.node_id.serialize()),
+			peer_alias: graph
+				.nodes()
+				.get(&NodeId::from_pubkey(&chan_info.counterparty.node_id))
+				.and_then(|node_info| node_info.announcement_info.as_ref())
+				.map(|announcement| announcement.alias.to_string()),
+			short_channel_id: chan_info.short_channel_id,
+			is_channel_ready: chan_info.is_channel_ready,
+			channel_value_satoshis: chan_info.channel_value_satoshis,
+			local_balance_msat: chan_info.balance_msat,
+			available_balance_for_send_msat: if chan_info.is_usable {
+				Some(chan_info.outbound_capacity_msat)
+			} else {
+				None
+			},
+			available_balance_for_recv_msat: if chan_info.is_usable {
+				Some(chan_info.inbound_capacity_msat)
+			} else {
+				None
+			},
+			channel_can_send_payments: chan_info.is_usable,
+			public: chan_info.is_public,
+		})
+		.collect()
+}
+
 fn list_channels(channel_manager: &Arc<ChannelManager>, network_graph: &Arc<NetworkGraph>) {
 	print!("[");
-	for chan_info in channel_manager.list_channels() {
+	for chan_info in channel_summaries(channel_manager, network_graph) {
 		println!("");
 		println!("\t{{");
-		println!("\t\tchannel_id: {},", hex_utils::hex_str(&chan_info.channel_id[..]));
-		if let Some(funding_txo) = chan_info.funding_txo {
-			println!("\t\tfunding_txid: {},", funding_txo.txid);
+		println!("\t\tchannel_id: {},", chan_info.channel_id);
+		if let Some(funding_txid) = chan_info.funding_txid {
+			println!("\t\tfunding_txid: {},", funding_txid);
 		}
 
-		println!(
-			"\t\tpeer_pubkey: {},",
-			hex_utils::hex_str(&chan_info.counterparty.node_id.serialize())
-		);
-		if let Some(node_info) = network_graph
-			.read_only()
-			.nodes()
-			.get(&NodeId::from_pubkey(&chan_info.counterparty.node_id))
-		{
-			if let Some(announcement) = &node_info.announcement_info {
-				println!("\t\tpeer_alias: {}", &announcement.alias);
-			}
+		println!("\t\tpeer_pubkey: {},", chan_info.peer_pubkey);
+		if let Some(alias) = chan_info.peer_alias {
+			println!("\t\tpeer_alias: {}", alias);
 		}
 
 		if let Some(id) = chan_info.short_channel_id {
@@ -994,13 +2137,15 @@ fn list_channels(channel_manager: &Arc<ChannelManager>, network_graph: &Arc<Netw
 		}
 		println!("\t\tis_channel_ready: {},", chan_info.is_channel_ready);
 		println!("\t\tchannel_value_satoshis: {},", chan_info.channel_value_satoshis);
-		println!("\t\tlocal_balance_msat: {},", chan_info.balance_msat);
-		if chan_info.is_usable {
-			println!("\t\tavailable_balance_for_send_msat: {},", chan_info.outbound_capacity_msat);
-			println!("\t\tavailable_balance_for_recv_msat: {},", chan_info.inbound_capacity_msat);
+		println!("\t\tlocal_balance_msat: {},", chan_info.local_balance_msat);
+		if let Some(send_msat) = chan_info.available_balance_for_send_msat {
+			println!("\t\tavailable_balance_for_send_msat: {},", send_msat);
+		}
+		if let Some(recv_msat) = chan_info.available_balance_for_recv_msat {
+			println!("\t\tavailable_balance_for_recv_msat: {},", recv_msat);
 		}
-		println!("\t\tchannel_can_send_payments: {},", chan_info.is_usable);
-		println!("\t\tpublic: {},", chan_info.is_public);
+		println!("\t\tchannel_can_send_payments: {},", chan_info.channel_can_send_payments);
+		println!("\t\tpublic: {},", chan_info.public);
 		println!("\t}},");
 	}
 	println!("]");
@@ -1107,10 +2252,10 @@ pub(crate) async fn do_connect_peer(
 	}
 }
 
-fn open_channel(
+pub(crate) fn open_channel(
 	peer_pubkey: PublicKey, channel_amt_sat: u64, announced_channel: bool,
 	channel_manager: Arc<ChannelManager>,
-) -> Result<(), ()> {
+) -> Result<(), APIError> {
 	let config = UserConfig {
 		channel_handshake_config: ChannelHandshakeConfig {
 			announced_channel,
@@ -1131,7 +2276,7 @@ fn open_channel(
 		}
 		Err(e) => {
 			println!("ERROR: failed to open channel: {:?}", e);
-			return Err(());
+			return Err(e);
 		}
 	}
 }
@@ -1174,6 +2319,7 @@ fn send_payment<E: EventHandler>(
 			secret: payment_secret,
 			status,
 			amt_msat: MillisatAmount(invoice.amount_milli_satoshis()),
+			probe: None,
 		},
 	);
 }
@@ -1220,6 +2366,7 @@ fn keysend<E: EventHandler, K: KeysInterface>(
 			secret: None,
 			status,
 			amt_msat: MillisatAmount(Some(amt_msat)),
+			probe: None,
 		},
 	);
 }
@@ -1261,6 +2408,7 @@ fn get_invoice(
 			secret: Some(invoice.payment_secret().clone()),
 			status: HTLCStatus::Pending,
 			amt_msat: MillisatAmount(Some(amt_msat)),
+			probe: None,
 		},
 	);
 }
@@ -1315,30 +2463,69 @@ pub(crate) fn parse_peer_info(
 	Ok((pubkey.unwrap(), peer_addr.unwrap().unwrap()))
 }
 
-fn get_attempts_found(conn: &Connection) -> Result<Vec<Attempt>, Box<dyn std::error::Error>> {
+/// Reads every candidate transaction file in `txpath`, skipping files that fail to parse.
+pub(crate) fn read_transactions(txpath: &str) -> Result<Vec<Transaction>, std::io::Error> {
+	let mut txs: Vec<Transaction> = vec![];
+	for json_file in fs::read_dir(txpath)? {
+		let json_file = json_file?;
+		println!("Reading tx file: {:?}", json_file.file_name().as_os_str().to_str());
+		let tx_data = match fs::read_to_string(json_file.path()) {
+			Ok(file) => file,
+			Err(e) => {
+				println!("{:?}", e);
+				continue;
+			}
+		};
+		let mut new_txs: Vec<Transaction> = match serde_json::from_str(&tx_data) {
+			Ok(n) => n,
+			Err(e) => {
+				println!("{:?}", e);
+				continue;
+			}
+		};
+		txs.append(&mut new_txs);
+	}
+	Ok(txs)
+}
+
+pub(crate) fn transactions_by_scid(txs: Vec<Transaction>) -> HashMap<u64, Transaction> {
+	let mut by_scid = HashMap::new();
+	for tx in txs {
+		let scid = scid_from_parts(tx.block_height, tx.block_index, tx.transaction_index);
+		by_scid.insert(scid, tx);
+	}
+	by_scid
+}
+
+pub(crate) fn get_attempts(conn: &Connection) -> Result<Vec<Attempt>, Box<dyn std::error::Error>> {
 	let mut stmt = conn.prepare("SELECT * FROM attempt")?;
 	let mut rows = stmt.query([])?;
 
 	let mut attempts = vec![];
 	while let Some(row) = rows.next()? {
-		let attempt = Attempt {
+		attempts.push(Attempt {
 			target_pubkey: row.get(0)?,
 			guess_pubkey: row.get(1)?,
 			channel_id: row.get(2)?,
 			result: row.get(3)?,
 			date_found: row.get(4)?,
-		};
-
-		if attempt.result == "unknown" || attempt.result == "unknown_next_peer" {
-			continue;
-		}
-
-		attempts.push(attempt);
+			hidden_path: row.get(5)?,
+			guess_strategy: row.get(6)?,
+		});
 	}
 
 	Ok(attempts)
 }
 
+/// The attempts that found the channel they probed for.
+pub(crate) fn get_attempts_found(
+	conn: &Connection,
+) -> Result<Vec<Attempt>, Box<dyn std::error::Error>> {
+	let mut attempts = get_attempts(conn)?;
+	attempts.retain(|attempt| is_channel_found(&attempt.result));
+	Ok(attempts)
+}
+
 fn get_attempts_str(
 	conn: &Connection,
 ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
@@ -1353,6 +2540,8 @@ fn get_attempts_str(
 			channel_id: row.get(2)?,
 			result: row.get(3)?,
 			date_found: row.get(4)?,
+			hidden_path: row.get(5)?,
+			guess_strategy: row.get(6)?,
 		};
 
 		if attempt.result == "unknown" {
@@ -0,0 +1,542 @@
+//! The node's settings, read from a TOML file with command line flags on top, like
+//!
+//! ```toml
+//! [bitcoind]
+//! rpc_host = "127.0.0.1"
+//! rpc_port = 18332
+//! rpc_username = "user"
+//! rpc_password = "pass"
+//!
+//! [node]
+//! storage_dir = "./data"
+//! network = "testnet"
+//! listen_port = 9735
+//! announced_listen_addr = ["203.0.113.7"]
+//! announced_node_name = "prober"
+//! db_path = "./my_db.db3"
+//! log_level = "info"
+//!
+//! [probe]
+//! max_in_flight = 31
+//! amount_msat = 1000
+//! guess_strategy = "random"
+//! per_minute = 30
+//! per_day = 20000
+//! quiet_hours = "22-6"
+//!
+//! [rpc]
+//! bind = "127.0.0.1:9736"
+//! socket = "./data/rpc.sock"
+//!
+//! [metrics]
+//! bind = "127.0.0.1:9737"
+//! ```
+
+use crate::cli::LdkUserInfo;
+use crate::politeness::{parse_quiet_hours, ProbeLimits};
+use crate::probe::PROBE_AMOUNT_MSAT;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::PublicKey;
+use lightning::ln::msgs::NetAddress;
+use lightning::util::logger::Level;
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+pub(crate) const USAGE: &str = "usage: ldk-sample [--config <config.toml>] [--bitcoind-rpc <username>:<password>@<host>:<port>] [--bitcoind-rpc-host <host>] [--bitcoind-rpc-port <port>] [--bitcoind-rpc-username <username>] [--bitcoind-rpc-password <password>] [--storage-dir <path>] [--network mainnet|testnet|regtest|signet] [--listen-port <port>] [--announce-addr <ip>[:<port>]]... [--node-name <name>] [--db-path <path>] [--log-level gossip|trace|debug|info|warn|error] [--max-in-flight <n>] [--probe-amount-msat <msat>] [--guess-strategy <strategy>] [--probes-per-minute <n>] [--probes-per-day <n>] [--quiet-hours <start>-<end>] [--rpc-bind <ip>:<port>] [--rpc-socket <path>] [--metrics-bind <ip>:<port>]";
+
+/// How probe runs behave unless a command says otherwise.
+#[derive(Clone, Debug)]
+pub(crate) struct ProbeConfig {
+	/// Probes waiting for an answer before a run holds off sending more.
+	pub(crate) max_in_flight: usize,
+	pub(crate) amount_msat: u64,
+	/// The guess strategy for commands that aren't given one, as `GuessStrategy::parse` takes it.
+	pub(crate) guess_strategy: String,
+	pub(crate) limits: ProbeLimits,
+}
+
+impl Default for ProbeConfig {
+	fn default() -> Self {
+		ProbeConfig {
+			max_in_flight: 31,
+			amount_msat: PROBE_AMOUNT_MSAT,
+			guess_strategy: "random".to_string(),
+			limits: ProbeLimits::default(),
+		}
+	}
+}
+
+/// Where the JSON-RPC server listens, it's off unless one of them is set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct RpcConfig {
+	pub(crate) bind: Option<SocketAddr>,
+	/// A Unix socket path.
+	pub(crate) socket: Option<String>,
+}
+
+/// Settings as they're written, in the file or on the command line. Anything left out falls back
+/// to the layer below it and then to the defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+	bitcoind: BitcoindSettings,
+	node: NodeSettings,
+	probe: ProbeSettings,
+	rpc: RpcSettings,
+	metrics: MetricsSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BitcoindSettings {
+	rpc_host: Option<String>,
+	rpc_port: Option<u16>,
+	rpc_username: Option<String>,
+	rpc_password: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NodeSettings {
+	storage_dir: Option<String>,
+	network: Option<String>,
+	listen_port: Option<u16>,
+	announced_listen_addr: Option<Vec<String>>,
+	announced_node_name: Option<String>,
+	db_path: Option<String>,
+	log_level: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProbeSettings {
+	max_in_flight: Option<usize>,
+	amount_msat: Option<u64>,
+	guess_strategy: Option<String>,
+	per_minute: Option<usize>,
+	per_day: Option<usize>,
+	quiet_hours: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RpcSettings {
+	bind: Option<String>,
+	socket: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MetricsSettings {
+	bind: Option<String>,
+}
+
+impl Settings {
+	/// `overrides` wherever it has a setting, `self` everywhere else.
+	fn merge(self, overrides: Settings) -> Settings {
+		let (b, o) = (self.bitcoind, overrides.bitcoind);
+		let bitcoind = BitcoindSettings {
+			rpc_host: o.rpc_host.or(b.rpc_host),
+			rpc_port: o.rpc_port.or(b.rpc_port),
+			rpc_username: o.rpc_username.or(b.rpc_username),
+			rpc_password: o.rpc_password.or(b.rpc_password),
+		};
+		let (n, o) = (self.node, overrides.node);
+		let node = NodeSettings {
+			storage_dir: o.storage_dir.or(n.storage_dir),
+			network: o.network.or(n.network),
+			listen_port: o.listen_port.or(n.listen_port),
+			announced_listen_addr: o.announced_listen_addr.or(n.announced_listen_addr),
+			announced_node_name: o.announced_node_name.or(n.announced_node_name),
+			db_path: o.db_path.or(n.db_path),
+			log_level: o.log_level.or(n.log_level),
+		};
+		let (p, o) = (self.probe, overrides.probe);
+		let probe = ProbeSettings {
+			max_in_flight: o.max_in_flight.or(p.max_in_flight),
+			amount_msat: o.amount_msat.or(p.amount_msat),
+			guess_strategy: o.guess_strategy.or(p.guess_strategy),
+			per_minute: o.per_minute.or(p.per_minute),
+			per_day: o.per_day.or(p.per_day),
+			quiet_hours: o.quiet_hours.or(p.quiet_hours),
+		};
+		let (r, o) = (self.rpc, overrides.rpc);
+		let rpc = RpcSettings { bind: o.bind.or(r.bind), socket: o.socket.or(r.socket) };
+		let metrics = MetricsSettings { bind: overrides.metrics.bind.or(self.metrics.bind) };
+		Settings { bitcoind, node, probe, rpc, metrics }
+	}
+}
+
+/// Reads the settings from `args`, the command line without the program name, and from the
+/// config file `--config` points at, if any.
+pub(crate) fn load<I: Iterator<Item = String>>(
+	args: I,
+) -> Result<LdkUserInfo, Box<dyn std::error::Error>> {
+	let (config_path, overrides) = parse_args(args)?;
+	let settings = match config_path {
+		Some(path) => {
+			let contents = fs::read_to_string(&path)
+				.map_err(|e| format!("couldn't read config file {}: {}", path, e))?;
+			parse_file(&contents).map_err(|e| format!("{}: {}", path, e))?
+		}
+		None => Settings::default(),
+	};
+	resolve(settings.merge(overrides))
+}
+
+fn parse_file(contents: &str) -> Result<Settings, Box<dyn std::error::Error>> {
+	Ok(toml::from_str(contents)?)
+}
+
+fn parse_args<I: Iterator<Item = String>>(
+	mut args: I,
+) -> Result<(Option<String>, Settings), Box<dyn std::error::Error>> {
+	let mut config_path = None;
+	let mut settings = Settings::default();
+	while let Some(flag) = args.next() {
+		let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+		match flag.as_str() {
+			"--config" => config_path = Some(value),
+			"--bitcoind-rpc" => {
+				// <username>:<password>@<host>:<port>, the way bitcoind's own tools take it
+				let (credentials, address) =
+					value.rsplit_once('@').ok_or("--bitcoind-rpc must be user:pass@host:port")?;
+				let (username, password) = credentials
+					.split_once(':')
+					.ok_or("--bitcoind-rpc must be user:pass@host:port")?;
+				let (host, port) =
+					address.rsplit_once(':').ok_or("--bitcoind-rpc must be user:pass@host:port")?;
+				settings.bitcoind.rpc_username = Some(username.to_string());
+				settings.bitcoind.rpc_password = Some(password.to_string());
+				settings.bitcoind.rpc_host = Some(host.to_string());
+				settings.bitcoind.rpc_port = Some(parse_flag(&flag, port)?);
+			}
+			"--bitcoind-rpc-host" => settings.bitcoind.rpc_host = Some(value),
+			"--bitcoind-rpc-port" => settings.bitcoind.rpc_port = Some(parse_flag(&flag, &value)?),
+			"--bitcoind-rpc-username" => settings.bitcoind.rpc_username = Some(value),
+			"--bitcoind-rpc-password" => settings.bitcoind.rpc_password = Some(value),
+			"--storage-dir" => settings.node.storage_dir = Some(value),
+			"--network" => settings.node.network = Some(value),
+			"--listen-port" => settings.node.listen_port = Some(parse_flag(&flag, &value)?),
+			"--announce-addr" => {
+				settings.node.announced_listen_addr.get_or_insert_with(Vec::new).push(value)
+			}
+			"--node-name" => settings.node.announced_node_name = Some(value),
+			"--db-path" => settings.node.db_path = Some(value),
+			"--log-level" => settings.node.log_level = Some(value),
+			"--max-in-flight" => settings.probe.max_in_flight = Some(parse_flag(&flag, &value)?),
+			"--probe-amount-msat" => settings.probe.amount_msat = Some(parse_flag(&flag, &value)?),
+			"--guess-strategy" => settings.probe.guess_strategy = Some(value),
+			"--probes-per-minute" => settings.probe.per_minute = Some(parse_flag(&flag, &value)?),
+			"--probes-per-day" => settings.probe.per_day = Some(parse_flag(&flag, &value)?),
+			"--quiet-hours" => settings.probe.quiet_hours = Some(value),
+			"--rpc-bind" => settings.rpc.bind = Some(value),
+			"--rpc-socket" => settings.rpc.socket = Some(value),
+			"--metrics-bind" => settings.metrics.bind = Some(value),
+			_ => return Err(format!("unknown option {}", flag).into()),
+		}
+	}
+	Ok((config_path, settings))
+}
+
+fn parse_flag<T: FromStr>(flag: &str, value: &str) -> Result<T, Box<dyn std::error::Error>> {
+	value.parse().map_err(|_| format!("{} must be a number, got {}", flag, value).into())
+}
+
+/// Checks the settings and fills in the defaults.
+fn resolve(settings: Settings) -> Result<LdkUserInfo, Box<dyn std::error::Error>> {
+	let Settings { bitcoind, node, probe, rpc, metrics } = settings;
+
+	let network = match node.network.as_deref() {
+		Some("mainnet") | Some("bitcoin") => Network::Bitcoin,
+		Some("testnet") | None => Network::Testnet,
+		Some("regtest") => Network::Regtest,
+		Some("signet") => Network::Signet,
+		Some(net) => {
+			return Err(format!(
+				"node.network must be one of mainnet, testnet, regtest or signet, got {}",
+				net
+			)
+			.into())
+		}
+	};
+	let bitcoind_rpc_port = bitcoind.rpc_port.unwrap_or(match network {
+		Network::Bitcoin => 8332,
+		Network::Testnet => 18332,
+		Network::Regtest => 18443,
+		Network::Signet => 38332,
+	});
+
+	let ldk_peer_listening_port = node.listen_port.unwrap_or(9735);
+	let mut ldk_announced_listen_addr = Vec::new();
+	for addr in node.announced_listen_addr.unwrap_or_default() {
+		let (ip, port) = match (IpAddr::from_str(&addr), SocketAddr::from_str(&addr)) {
+			(Ok(ip), _) => (ip, ldk_peer_listening_port),
+			(_, Ok(socket_addr)) => (socket_addr.ip(), socket_addr.port()),
+			_ => {
+				return Err(format!(
+					"node.announced_listen_addr must be IP addresses, got {}",
+					addr
+				)
+				.into())
+			}
+		};
+		ldk_announced_listen_addr.push(match ip {
+			IpAddr::V4(a) => NetAddress::IPv4 { addr: a.octets(), port },
+			IpAddr::V6(a) => NetAddress::IPv6 { addr: a.octets(), port },
+		});
+	}
+
+	let mut ldk_announced_node_name = [0; 32];
+	if let Some(name) = node.announced_node_name {
+		if name.len() > 32 {
+			return Err("node.announced_node_name can not be longer than 32 bytes".into());
+		}
+		ldk_announced_node_name[..name.len()].copy_from_slice(name.as_bytes());
+	}
+
+	let log_level = match node.log_level.as_deref() {
+		Some("gossip") => Level::Gossip,
+		Some("trace") => Level::Trace,
+		Some("debug") => Level::Debug,
+		Some("info") | None => Level::Info,
+		Some("warn") => Level::Warn,
+		Some("error") => Level::Error,
+		Some(level) => {
+			return Err(format!(
+				"node.log_level must be one of gossip, trace, debug, info, warn or error, got {}",
+				level
+			)
+			.into())
+		}
+	};
+
+	let defaults = ProbeConfig::default();
+	let max_in_flight = probe.max_in_flight.unwrap_or(defaults.max_in_flight);
+	let amount_msat = probe.amount_msat.unwrap_or(defaults.amount_msat);
+	if max_in_flight == 0 || amount_msat == 0 {
+		return Err("probe.max_in_flight and probe.amount_msat must be at least 1".into());
+	}
+	let guess_strategy = probe.guess_strategy.unwrap_or(defaults.guess_strategy);
+	check_guess_strategy(&guess_strategy)?;
+	let limits = ProbeLimits {
+		per_minute: probe.per_minute.unwrap_or(defaults.limits.per_minute),
+		per_day: probe.per_day.unwrap_or(defaults.limits.per_day),
+		quiet_hours: match probe.quiet_hours {
+			Some(hours) => {
+				Some(parse_quiet_hours(&hours).map_err(|e| format!("probe.quiet_hours: {}", e))?)
+			}
+			None => defaults.limits.quiet_hours,
+		},
+	};
+	if limits.per_minute == 0 || limits.per_day == 0 {
+		return Err("probe.per_minute and probe.per_day must be at least 1".into());
+	}
+
+	let rpc =
+		RpcConfig {
+			bind: match rpc.bind {
+				Some(bind) => Some(SocketAddr::from_str(&bind).map_err(|_| {
+					format!("rpc.bind must be an IP address and port, got {}", bind)
+				})?),
+				None => None,
+			},
+			socket: rpc.socket,
+		};
+	let metrics_bind =
+		match metrics.bind {
+			Some(bind) => Some(SocketAddr::from_str(&bind).map_err(|_| {
+				format!("metrics.bind must be an IP address and port, got {}", bind)
+			})?),
+			None => None,
+		};
+
+	Ok(LdkUserInfo {
+		bitcoind_rpc_username: bitcoind.rpc_username.ok_or("bitcoind.rpc_username is required")?,
+		bitcoind_rpc_password: bitcoind.rpc_password.ok_or("bitcoind.rpc_password is required")?,
+		bitcoind_rpc_port,
+		bitcoind_rpc_host: bitcoind.rpc_host.unwrap_or_else(|| "127.0.0.1".to_string()),
+		ldk_storage_dir_path: node.storage_dir.ok_or("node.storage_dir is required")?,
+		ldk_peer_listening_port,
+		ldk_announced_listen_addr,
+		ldk_announced_node_name,
+		network,
+		db_path: node.db_path.unwrap_or_else(|| "./my_db.db3".to_string()),
+		log_level,
+		probe: ProbeConfig { max_in_flight, amount_msat, guess_strategy, limits },
+		rpc,
+		metrics_bind,
+	})
+}
+
+/// The denylist and node files aren't there yet at startup, so only the form of the strategy is
+/// checked here. `GuessStrategy::parse` does the rest when a run starts.
+fn check_guess_strategy(strategy: &str) -> Result<(), Box<dyn std::error::Error>> {
+	if strategy == "random" || strategy.starts_with("list:") {
+		return Ok(());
+	}
+	if let Some(pubkey) = strategy.strip_prefix("fixed:") {
+		return match PublicKey::from_str(pubkey) {
+			Ok(_) => Ok(()),
+			Err(_) => Err(format!("probe.guess_strategy has a bad pubkey: {}", pubkey).into()),
+		};
+	}
+	Err(format!(
+		"probe.guess_strategy must be random, fixed:<pubkey> or list:<nodefile>, got {}",
+		strategy
+	)
+	.into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(args: &[&str]) -> impl Iterator<Item = String> {
+		args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+	}
+
+	fn load_str(contents: &str, cli: &[&str]) -> Result<LdkUserInfo, String> {
+		let (_, overrides) = parse_args(args(cli)).map_err(|e| e.to_string())?;
+		let settings = parse_file(contents).map_err(|e| e.to_string())?;
+		resolve(settings.merge(overrides)).map_err(|e| e.to_string())
+	}
+
+	const MINIMAL: &str = "
+		[bitcoind]
+		rpc_username = \"user\"
+		rpc_password = \"pass\"
+
+		[node]
+		storage_dir = \"./data\"
+	";
+
+	#[test]
+	fn test_defaults() {
+		let config = load_str(MINIMAL, &[]).unwrap();
+		assert_eq!(config.network, Network::Testnet);
+		assert_eq!(config.bitcoind_rpc_host, "127.0.0.1");
+		assert_eq!(config.bitcoind_rpc_port, 18332);
+		assert_eq!(config.ldk_peer_listening_port, 9735);
+		assert!(config.ldk_announced_listen_addr.is_empty());
+		assert_eq!(config.ldk_announced_node_name, [0; 32]);
+		assert_eq!(config.db_path, "./my_db.db3");
+		assert_eq!(config.log_level, Level::Info);
+		assert_eq!(config.probe.max_in_flight, 31);
+		assert_eq!(config.probe.amount_msat, PROBE_AMOUNT_MSAT);
+		assert_eq!(config.probe.guess_strategy, "random");
+		assert_eq!(config.probe.limits.per_minute, 30);
+		assert_eq!(config.probe.limits.quiet_hours, None);
+		assert_eq!(config.rpc, RpcConfig::default());
+		assert_eq!(config.metrics_bind, None);
+	}
+
+	#[test]
+	fn test_full_file() {
+		let contents = "
+			[bitcoind]
+			rpc_host = \"10.0.0.2\"
+			rpc_port = 18443
+			rpc_username = \"user\"
+			rpc_password = \"pass\"
+
+			[node]
+			storage_dir = \"./data\"
+			network = \"regtest\"
+			listen_port = 9736
+			announced_listen_addr = [\"203.0.113.7\", \"[2001:db8::1]:9999\"]
+			announced_node_name = \"prober\"
+			db_path = \"./probes.db3\"
+			log_level = \"debug\"
+
+			[probe]
+			max_in_flight = 10
+			amount_msat = 5000
+			guess_strategy = \"list:nodes.json\"
+			per_minute = 5
+			per_day = 100
+			quiet_hours = \"22-6\"
+
+			[rpc]
+			bind = \"127.0.0.1:9736\"
+			socket = \"./data/rpc.sock\"
+
+			[metrics]
+			bind = \"127.0.0.1:9737\"
+		";
+		let config = load_str(contents, &[]).unwrap();
+		assert_eq!(config.bitcoind_rpc_host, "10.0.0.2");
+		assert_eq!(config.network, Network::Regtest);
+		assert_eq!(config.ldk_peer_listening_port, 9736);
+		assert_eq!(
+			config.ldk_announced_listen_addr,
+			vec![
+				NetAddress::IPv4 { addr: [203, 0, 113, 7], port: 9736 },
+				NetAddress::IPv6 {
+					addr: [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+					port: 9999
+				},
+			]
+		);
+		assert_eq!(&config.ldk_announced_node_name[..6], b"prober");
+		assert_eq!(config.db_path, "./probes.db3");
+		assert_eq!(config.log_level, Level::Debug);
+		assert_eq!(config.probe.max_in_flight, 10);
+		assert_eq!(config.probe.amount_msat, 5000);
+		assert_eq!(config.probe.guess_strategy, "list:nodes.json");
+		assert_eq!(config.probe.limits.per_day, 100);
+		assert_eq!(config.probe.limits.quiet_hours, Some((22, 6)));
+		assert_eq!(config.rpc.bind, Some(SocketAddr::from(([127, 0, 0, 1], 9736))));
+		assert_eq!(config.rpc.socket.as_deref(), Some("./data/rpc.sock"));
+		assert_eq!(config.metrics_bind, Some(SocketAddr::from(([127, 0, 0, 1], 9737))));
+	}
+
+	#[test]
+	fn test_command_line_overrides_file() {
+		let config = load_str(
+			MINIMAL,
+			&[
+				"--bitcoind-rpc",
+				"alice:secret@10.0.0.3:8332",
+				"--network",
+				"mainnet",
+				"--announce-addr",
+				"203.0.113.7",
+				"--probes-per-minute",
+				"2",
+			],
+		)
+		.unwrap();
+		assert_eq!(config.bitcoind_rpc_username, "alice");
+		assert_eq!(config.bitcoind_rpc_password, "secret");
+		assert_eq!(config.bitcoind_rpc_host, "10.0.0.3");
+		assert_eq!(config.bitcoind_rpc_port, 8332);
+		assert_eq!(config.network, Network::Bitcoin);
+		assert_eq!(config.ldk_announced_listen_addr.len(), 1);
+		assert_eq!(config.probe.limits.per_minute, 2);
+		assert_eq!(config.ldk_storage_dir_path, "./data");
+	}
+
+	#[test]
+	fn test_invalid_configs() {
+		let error = |contents: &str, cli: &[&str]| load_str(contents, cli).err().unwrap();
+		assert_eq!(error("", &[]), "bitcoind.rpc_username is required");
+		assert!(error(MINIMAL, &["--network", "litecoin"]).contains("got litecoin"));
+		assert!(error(MINIMAL, &["--listen-port", "abc"]).starts_with("--listen-port must be"));
+		assert_eq!(error(MINIMAL, &["--listen-port"]), "--listen-port needs a value");
+		assert_eq!(error(MINIMAL, &["--verbose", "1"]), "unknown option --verbose");
+		assert!(error(MINIMAL, &["--announce-addr", "example.com"]).contains("IP addresses"));
+		assert!(error(MINIMAL, &["--node-name", &"x".repeat(33)]).contains("32 bytes"));
+		assert!(error(MINIMAL, &["--log-level", "loud"]).contains("got loud"));
+		assert!(error(MINIMAL, &["--guess-strategy", "fixed:02"]).contains("bad pubkey"));
+		assert!(error(MINIMAL, &["--guess-strategy", "smart"]).contains("got smart"));
+		assert!(error(MINIMAL, &["--quiet-hours", "22"]).starts_with("probe.quiet_hours"));
+		assert!(error(MINIMAL, &["--probes-per-day", "0"]).contains("at least 1"));
+		assert!(error(MINIMAL, &["--max-in-flight", "0"]).contains("at least 1"));
+		assert!(error(MINIMAL, &["--rpc-bind", "localhost"]).contains("got localhost"));
+		assert!(error("[node]\nport = 1", &[]).contains("unknown field `port`"));
+		assert!(error("[node]\nlisten_port = \"x\"", &[]).contains("invalid type"));
+	}
+}
@@ -0,0 +1,406 @@
+use crate::aggregate::BLOCK_RANGE;
+use crate::guess::GuessStrategy;
+use crate::hidden_graph::is_channel_found;
+use crate::probe::{scid_from_parts, MAX_SCID_TX_INDEX};
+use crate::NetworkGraph;
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+use chrono::Utc;
+use lightning::routing::gossip::NodeId;
+use rand::Rng;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A control goes out to a target every this many probes.
+pub(crate) const CONTROL_INTERVAL: usize = 25;
+
+/// How sure we have to be before we stop probing a target.
+const MIN_CONFIDENCE: f64 = 0.75;
+
+/// No block holds this many transactions, so no channel has a short channel id with a
+/// transaction index this high.
+const MIN_IMPOSSIBLE_TX_INDEX: u64 = 1 << 20;
+
+/// What a control probe should find, if the target answers probes honestly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Control {
+	/// A short channel id that can't exist.
+	Absent,
+	/// One of the target's public channels, guessing the node on the other side.
+	Present,
+}
+
+impl Control {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			Control::Absent => "absent",
+			Control::Present => "present",
+		}
+	}
+
+	fn parse(control: &str) -> Option<Control> {
+		match control {
+			"absent" => Some(Control::Absent),
+			"present" => Some(Control::Present),
+			_ => None,
+		}
+	}
+}
+
+pub(crate) struct ControlProbe {
+	pub(crate) short_channel_id: u64,
+	pub(crate) guess: PublicKey,
+	pub(crate) control: Control,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ResponderStatus {
+	/// Controls came back the way they should.
+	Consistent,
+	/// Every control came back the same, whether the channel exists or not.
+	UniformResponder,
+	/// Some controls came back wrong.
+	Inconsistent,
+}
+
+impl ResponderStatus {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			ResponderStatus::Consistent => "consistent",
+			ResponderStatus::UniformResponder => "uniform_responder",
+			ResponderStatus::Inconsistent => "inconsistent",
+		}
+	}
+}
+
+/// What the controls tell us about how a target answers probes.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Verdict {
+	pub(crate) status: ResponderStatus,
+	/// From 0 to 1, grows with the number of controls that back the status.
+	pub(crate) confidence: f64,
+	pub(crate) answered: usize,
+	pub(crate) deviations: usize,
+}
+
+impl Verdict {
+	/// Whether the target's answers can't be trusted, so probing it is a waste.
+	pub(crate) fn is_flagged(&self) -> bool {
+		self.status != ResponderStatus::Consistent && self.confidence >= MIN_CONFIDENCE
+	}
+
+	fn from_answers(answers: &[(Control, &'static str)]) -> Verdict {
+		let answered = answers.len();
+		let deviations = answers
+			.iter()
+			.filter(|(control, result)| is_channel_found(result) != (*control == Control::Present))
+			.count();
+		let both_kinds = answers.iter().any(|(control, _)| *control == Control::Absent)
+			&& answers.iter().any(|(control, _)| *control == Control::Present);
+		let uniform = answers.windows(2).all(|pair| pair[0].1 == pair[1].1);
+
+		// one more than the answers, so a single control is never conclusive
+		let (status, backing) = if deviations == 0 {
+			(ResponderStatus::Consistent, answered)
+		} else if both_kinds && uniform {
+			(ResponderStatus::UniformResponder, answered)
+		} else {
+			(ResponderStatus::Inconsistent, deviations)
+		};
+		Verdict { status, confidence: backing as f64 / (answered + 1) as f64, answered, deviations }
+	}
+}
+
+/// Mixes probes we know the answer to into each target's run, to catch targets that stopped
+/// answering probes truthfully.
+pub(crate) struct ControlProbes {
+	random: GuessStrategy,
+	pending: Mutex<HashMap<(String, u64), Control>>,
+	answers: Mutex<HashMap<String, Vec<(Control, &'static str)>>>,
+	verdicts: Mutex<HashMap<String, Verdict>>,
+}
+
+impl ControlProbes {
+	/// Picks up the control answers of earlier runs.
+	pub(crate) fn read(db: &Connection) -> Result<ControlProbes, rusqlite::Error> {
+		let mut stmt = db.prepare("SELECT target_pubkey, control, result FROM control_attempt")?;
+		let mut rows = stmt.query([])?;
+		let mut answers: HashMap<String, Vec<(Control, &'static str)>> = HashMap::new();
+		while let Some(row) = rows.next()? {
+			let control: String = row.get(1)?;
+			let control = match Control::parse(&control) {
+				Some(control) => control,
+				None => continue,
+			};
+			let result: String = row.get(2)?;
+			answers.entry(row.get(0)?).or_default().push((control, known_result(&result)));
+		}
+		let verdicts = answers
+			.iter()
+			.map(|(target, answers)| (target.clone(), Verdict::from_answers(answers)))
+			.collect();
+		Ok(ControlProbes {
+			random: GuessStrategy::Random(Secp256k1::signing_only()),
+			pending: Mutex::new(HashMap::new()),
+			answers: Mutex::new(answers),
+			verdicts: Mutex::new(verdicts),
+		})
+	}
+
+	pub(crate) fn verdict(&self, target: &str) -> Option<Verdict> {
+		self.verdicts.lock().unwrap().get(target).copied()
+	}
+
+	pub(crate) fn is_flagged(&self, target: &str) -> bool {
+		matches!(self.verdict(target), Some(verdict) if verdict.is_flagged())
+	}
+
+	pub(crate) fn verdicts(&self) -> Vec<(String, Verdict)> {
+		let mut verdicts: Vec<(String, Verdict)> = self
+			.verdicts
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(target, verdict)| (target.clone(), *verdict))
+			.collect();
+		verdicts.sort_by(|(_, a), (_, b)| b.confidence.partial_cmp(&a.confidence).unwrap());
+		verdicts
+	}
+
+	/// The next control for `target`, alternating between channels that can't exist and the
+	/// target's public channels. Targets without public channels only get the former.
+	pub(crate) fn next_control(
+		&self, target: &PublicKey, network_graph: &NetworkGraph, best_block_height: u32,
+		sent: usize,
+	) -> ControlProbe {
+		let mut rng = rand::thread_rng();
+		if sent % 2 == 1 {
+			let graph = network_graph.read_only();
+			let node_id = NodeId::from_pubkey(target);
+			let public_channels: Vec<(u64, NodeId)> = match graph.nodes().get(&node_id) {
+				Some(node) => node
+					.channels
+					.iter()
+					.filter_map(|scid| {
+						let channel = graph.channels().get(scid)?;
+						let other = if channel.node_one == node_id {
+							channel.node_two
+						} else {
+							channel.node_one
+						};
+						Some((*scid, other))
+					})
+					.collect(),
+				None => vec![],
+			};
+			if !public_channels.is_empty() {
+				let (short_channel_id, other) =
+					public_channels[rng.gen_range(0, public_channels.len())];
+				if let Ok(guess) = PublicKey::from_slice(other.as_slice()) {
+					return ControlProbe { short_channel_id, guess, control: Control::Present };
+				}
+			}
+		}
+
+		// opened within the last year
+		let block =
+			rng.gen_range(best_block_height.saturating_sub(BLOCK_RANGE), best_block_height + 1);
+		let short_channel_id = scid_from_parts(
+			block as u64,
+			rng.gen_range(MIN_IMPOSSIBLE_TX_INDEX, MAX_SCID_TX_INDEX + 1),
+			rng.gen_range(0, 2),
+		);
+		ControlProbe { short_channel_id, guess: self.random.next_guess(), control: Control::Absent }
+	}
+
+	/// Remembers that the probe of `short_channel_id` is a control, so that its answer doesn't
+	/// end up with the results.
+	pub(crate) fn record_sent(&self, target: &str, control: &ControlProbe) {
+		let key = (target.to_string(), control.short_channel_id);
+		self.pending.lock().unwrap().insert(key, control.control);
+	}
+
+	/// Forgets a control that didn't go out after all.
+	pub(crate) fn forget(&self, target: &str, short_channel_id: u64) {
+		self.pending.lock().unwrap().remove(&(target.to_string(), short_channel_id));
+	}
+
+	/// Whether the probe of `short_channel_id` was a control, and which. Only answers once.
+	pub(crate) fn take_control(&self, target: &str, short_channel_id: u64) -> Option<Control> {
+		self.pending.lock().unwrap().remove(&(target.to_string(), short_channel_id))
+	}
+
+	/// Takes in the answer to a control and returns the target's verdict with it, or `None` if
+	/// the answer doesn't tell us anything.
+	pub(crate) fn record_answer(
+		&self, db: &Connection, target: &str, short_channel_id: u64, control: Control,
+		result: &'static str,
+	) -> Option<Verdict> {
+		if result == "unknown" {
+			return None;
+		}
+
+		let verdict = {
+			let mut answers = self.answers.lock().unwrap();
+			let answers = answers.entry(target.to_string()).or_default();
+			answers.push((control, result));
+			Verdict::from_answers(answers)
+		};
+		self.verdicts.lock().unwrap().insert(target.to_string(), verdict);
+		let now = Utc::now().naive_utc();
+		db.execute(
+			"INSERT INTO control_attempt (target_pubkey, channel_id, control, result, date_found)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+			params![target, short_channel_id.to_string(), control.as_str(), result, now],
+		)
+		.unwrap();
+		db.execute(
+			"INSERT OR REPLACE INTO target_status (
+                target_pubkey, status, confidence, controls_answered, deviations, date_updated)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+			params![
+				target,
+				verdict.status.as_str(),
+				verdict.confidence,
+				verdict.answered as i64,
+				verdict.deviations as i64,
+				now,
+			],
+		)
+		.unwrap();
+		Some(verdict)
+	}
+}
+
+/// The `probe_result` name for a result read back from the database.
+fn known_result(result: &str) -> &'static str {
+	[
+		"incorrect_or_unknown_payment_details",
+		"required_node_feature_missing",
+		"fee_insufficient",
+		"invalid_onion_hmac",
+		"incorrect_cltv_expiry",
+		"amount_below_minimum",
+		"unknown_next_peer",
+	]
+	.iter()
+	.find(|known| **known == result)
+	.copied()
+	.unwrap_or("unknown")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::create_tables;
+	use crate::probe::{block_from_scid, tx_index_from_scid};
+	use crate::test_utils::{add_public_channel, network_graph, pubkey, TempDir};
+
+	#[test]
+	fn test_verdict_from_answers() {
+		let found = "incorrect_or_unknown_payment_details";
+		let not_found = "unknown_next_peer";
+
+		let verdict = Verdict::from_answers(&[(Control::Absent, not_found)]);
+		assert_eq!(verdict.status, ResponderStatus::Consistent);
+		assert_eq!(verdict.confidence, 0.5);
+		let verdict = Verdict::from_answers(&[
+			(Control::Absent, not_found),
+			(Control::Present, found),
+			(Control::Absent, not_found),
+		]);
+		assert_eq!(
+			(verdict.status, verdict.answered, verdict.deviations),
+			(ResponderStatus::Consistent, 3, 0)
+		);
+		assert_eq!(verdict.confidence, 0.75);
+		assert!(!verdict.is_flagged());
+
+		// the same answer whether the channel is there or not
+		let uniform =
+			[(Control::Absent, found), (Control::Present, found), (Control::Absent, found)];
+		let verdict = Verdict::from_answers(&uniform[..2]);
+		assert_eq!(verdict.status, ResponderStatus::UniformResponder);
+		assert!(!verdict.is_flagged());
+		let verdict = Verdict::from_answers(&uniform);
+		assert_eq!((verdict.status, verdict.deviations), (ResponderStatus::UniformResponder, 2));
+		assert!(verdict.is_flagged());
+
+		// without a channel that is there, a uniform answer is just wrong
+		let verdict = Verdict::from_answers(&[(Control::Absent, found), (Control::Absent, found)]);
+		assert_eq!(verdict.status, ResponderStatus::Inconsistent);
+
+		// only the wrong answers back an inconsistent verdict
+		let verdict = Verdict::from_answers(&[
+			(Control::Absent, not_found),
+			(Control::Present, found),
+			(Control::Absent, "invalid_onion_hmac"),
+			(Control::Present, found),
+		]);
+		assert_eq!((verdict.status, verdict.deviations), (ResponderStatus::Inconsistent, 1));
+		assert_eq!(verdict.confidence, 0.2);
+		assert!(!verdict.is_flagged());
+	}
+
+	#[test]
+	fn test_record_answer() {
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+		let controls = ControlProbes::read(&db).unwrap();
+		let target = pubkey(1).to_string();
+
+		let control =
+			ControlProbe { short_channel_id: 42, guess: pubkey(2), control: Control::Absent };
+		controls.record_sent(&target, &control);
+		assert_eq!(controls.take_control(&pubkey(2).to_string(), 42), None);
+		assert_eq!(controls.take_control(&target, 42), Some(Control::Absent));
+		assert_eq!(controls.take_control(&target, 42), None);
+		controls.record_sent(&target, &control);
+		controls.forget(&target, 42);
+		assert_eq!(controls.take_control(&target, 42), None);
+
+		// an answer that could have come from anywhere on the path doesn't count
+		assert!(controls.record_answer(&db, &target, 42, Control::Absent, "unknown").is_none());
+		assert!(controls.verdict(&target).is_none());
+
+		for (scid, control) in
+			[(42, Control::Absent), (43, Control::Present), (44, Control::Absent)]
+		{
+			controls.record_answer(&db, &target, scid, control, "invalid_onion_hmac").unwrap();
+		}
+		assert!(controls.is_flagged(&target));
+		assert!(!controls.is_flagged(&pubkey(3).to_string()));
+
+		// verdicts outlive the run
+		let controls = ControlProbes::read(&db).unwrap();
+		let verdict = controls.verdict(&target).unwrap();
+		assert_eq!((verdict.status, verdict.answered), (ResponderStatus::UniformResponder, 3));
+		assert_eq!(controls.verdicts().len(), 1);
+	}
+
+	#[test]
+	fn test_next_control() {
+		let data_dir = TempDir::new("control");
+		let network_graph = network_graph(&data_dir);
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+		let controls = ControlProbes::read(&db).unwrap();
+		let (target, peer) = (pubkey(1), pubkey(2));
+		let public_scid = scid_from_parts(600_000, 1, 0);
+		add_public_channel(&network_graph, public_scid, target, peer);
+
+		let absent = controls.next_control(&target, &network_graph, 700_000, 0);
+		assert_eq!(absent.control, Control::Absent);
+		let block = block_from_scid(&absent.short_channel_id);
+		assert!((700_000 - BLOCK_RANGE..=700_000).contains(&block));
+		assert!(tx_index_from_scid(&absent.short_channel_id) as u64 >= MIN_IMPOSSIBLE_TX_INDEX);
+
+		let present = controls.next_control(&target, &network_graph, 700_000, 1);
+		assert_eq!(present.control, Control::Present);
+		assert_eq!((present.short_channel_id, present.guess), (public_scid, peer));
+
+		// no public channels to check against
+		let private = controls.next_control(&pubkey(3), &network_graph, 700_000, 1);
+		assert_eq!(private.control, Control::Absent);
+	}
+}
@@ -0,0 +1,357 @@
+//! Runs a single job without the prompt, for cron jobs and services. The node starts as usual,
+//! runs the job until it's done or a signal arrives, stops and persists like `stop` does, and the
+//! process exits with a status saying how it went.
+
+use crate::cli::{self, ProbeContext};
+use crate::guess::GuessStrategy;
+use lightning::util::logger::Logger;
+use lightning::{log_given_level, log_info, log_internal};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a job waits for the reconnect to channel peers to give it a channel to probe over.
+const CHANNEL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The job is done.
+pub(crate) const EXIT_OK: i32 = 0;
+/// The job ran but failed, a missing candidate file, an unwritable result dir or no channel to
+/// probe over for instance.
+pub(crate) const EXIT_FAILED: i32 = 1;
+/// Bad arguments or a bad config, nothing was started.
+pub(crate) const EXIT_USAGE: i32 = 2;
+/// The node couldn't start, bitcoind being unreachable or on another chain for instance.
+pub(crate) const EXIT_STARTUP: i32 = 3;
+/// A signal stopped the job before it was done. What was done up to then is persisted. 128 +
+/// SIGINT, like shells report it.
+pub(crate) const EXIT_INTERRUPTED: i32 = 130;
+
+pub(crate) const USAGE: &str = "jobs: ldk-sample probeall --candidates <tx_dir> --targets <nodefile|all|hidden> [--type assumptions|all] [node options]
+      ldk-sample dump-results --out <result_dir> --candidates <tx_dir> [--aggregate <k>] [--sign] [node options]
+      ldk-sample serve [node options]";
+
+/// What to run instead of the prompt.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Job {
+	ProbeAll {
+		probetype: String,
+		targets: String,
+		candidates: String,
+	},
+	DumpResults {
+		result_dir: String,
+		candidates: String,
+		aggregate: Option<usize>,
+		sign: bool,
+	},
+	/// Only serves RPC and metrics, until a signal.
+	Serve,
+}
+
+/// Splits a job and its options off the command line, the rest is left for `config::load`. Without
+/// a job name first, it's all node settings and the prompt starts as before.
+pub(crate) fn parse_job(
+	args: Vec<String>,
+) -> Result<(Option<Job>, Vec<String>), Box<dyn std::error::Error>> {
+	let name = match args.first() {
+		Some(name) if !name.starts_with("--") => name.clone(),
+		_ => return Ok((None, args)),
+	};
+
+	let mut options: Vec<(String, Option<String>)> = Vec::new();
+	let mut node_args = Vec::new();
+	let mut args = args.into_iter().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--sign" => options.push((arg, None)),
+			"--candidates" | "--targets" | "--type" | "--out" | "--aggregate" => {
+				let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+				options.push((arg, Some(value)));
+			}
+			// every node setting takes a value
+			_ => {
+				node_args.push(arg);
+				node_args.extend(args.next());
+			}
+		}
+	}
+	// Some(None) for a flag without a value
+	let mut take = |flag: &str| {
+		options.iter().position(|(option, _)| option == flag).map(|i| options.remove(i).1)
+	};
+	let required = |value: Option<Option<String>>, flag: &str| -> Result<String, String> {
+		value.flatten().ok_or_else(|| format!("{} requires {}", name, flag))
+	};
+
+	let job = match name.as_str() {
+		"probeall" => Job::ProbeAll {
+			candidates: required(take("--candidates"), "--candidates")?,
+			targets: required(take("--targets"), "--targets")?,
+			probetype: take("--type").flatten().unwrap_or_else(|| "all".to_string()),
+		},
+		"dump-results" => Job::DumpResults {
+			result_dir: required(take("--out"), "--out")?,
+			candidates: required(take("--candidates"), "--candidates")?,
+			aggregate: match take("--aggregate").flatten() {
+				Some(k) => match k.parse::<usize>() {
+					Ok(k) if k > 0 => Some(k),
+					_ => {
+						return Err(
+							format!("--aggregate must be a positive number, got {}", k).into()
+						)
+					}
+				},
+				None => None,
+			},
+			sign: take("--sign").is_some(),
+		},
+		"serve" => Job::Serve,
+		_ => return Err(format!("unknown job {}", name).into()),
+	};
+	if let Some((flag, _)) = options.first() {
+		return Err(format!("{} doesn't take {}", name, flag).into());
+	}
+	Ok((Some(job), node_args))
+}
+
+/// Runs `job` to completion or until `running` is set, and returns the exit status for it.
+pub(crate) fn run_job(job: &Job, ctx: &ProbeContext, running: &AtomicUsize) -> i32 {
+	match job {
+		Job::ProbeAll { probetype, targets, candidates } => {
+			if !wait_for_usable_channel(ctx, running, CHANNEL_TIMEOUT) {
+				if running.load(Ordering::SeqCst) > 0 {
+					return EXIT_INTERRUPTED;
+				}
+				println!("ERROR: no usable channel after {}s", CHANNEL_TIMEOUT.as_secs());
+				return EXIT_FAILED;
+			}
+			let guess_strategy = match GuessStrategy::parse(
+				&ctx.probe_config.guess_strategy,
+				&ctx.denylist,
+				&ctx.logger,
+			) {
+				Ok(strategy) => strategy,
+				Err(e) => {
+					println!("ERROR: {}", e);
+					return EXIT_USAGE;
+				}
+			};
+			let summary = match cli::probe_all(
+				ctx,
+				probetype,
+				targets,
+				candidates,
+				&guess_strategy,
+				running,
+				&AtomicBool::new(false),
+			) {
+				Ok(summary) => summary,
+				Err(e) => {
+					println!("ERROR: {}", e);
+					return EXIT_FAILED;
+				}
+			};
+			let unanswered = wait_for_answers(ctx, running);
+			println!(
+				"Sent {} probes to {} targets, {} left unanswered, {} could not be sent",
+				summary.probes, summary.targets, unanswered, summary.failed
+			);
+			if summary.interrupted || running.load(Ordering::SeqCst) > 0 {
+				EXIT_INTERRUPTED
+			} else if summary.candidates > 0 && summary.probes == 0 {
+				println!("ERROR: no probe could be sent");
+				EXIT_FAILED
+			} else {
+				EXIT_OK
+			}
+		}
+		Job::DumpResults { result_dir, candidates, aggregate, sign } => {
+			match cli::dump_results(ctx, result_dir, candidates, *aggregate, *sign) {
+				Ok(()) => EXIT_OK,
+				Err(e) => {
+					println!("ERROR: {}", e);
+					EXIT_FAILED
+				}
+			}
+		}
+		// being stopped is how serving ends
+		Job::Serve => {
+			wait_for_signal(running);
+			EXIT_OK
+		}
+	}
+}
+
+/// Blocks until a signal arrives, for a node that's only there for RPC.
+pub(crate) fn wait_for_signal(running: &AtomicUsize) {
+	while running.load(Ordering::SeqCst) == 0 {
+		thread::sleep(Duration::from_millis(100));
+	}
+}
+
+/// Waits until one of our channels can carry a probe, which takes the peer on the other end being
+/// connected again after a start. Returns false on a timeout or a signal.
+pub(crate) fn wait_for_usable_channel(
+	ctx: &ProbeContext, running: &AtomicUsize, timeout: Duration,
+) -> bool {
+	let started = Instant::now();
+	loop {
+		if !ctx.channel_manager.list_usable_channels().is_empty() {
+			return true;
+		}
+		if started.elapsed() >= timeout || running.load(Ordering::SeqCst) > 0 {
+			return false;
+		}
+		thread::sleep(Duration::from_millis(100));
+	}
+}
+
+/// Waits for the answers to the probes still out, so they get recorded before the node stops.
+/// Unanswered ones are abandoned once they time out. Returns how many were left when a signal
+/// cut the wait short.
+fn wait_for_answers(ctx: &ProbeContext, running: &AtomicUsize) -> usize {
+	loop {
+		let in_flight = ctx
+			.pending_payments
+			.lock()
+			.unwrap()
+			.values()
+			.filter(|info| info.probe.is_some())
+			.count();
+		if in_flight == 0 || running.load(Ordering::SeqCst) > 0 {
+			return in_flight;
+		}
+		let stuck = ctx.probe_monitor.abandon_stuck(
+			&ctx.channel_manager,
+			&ctx.pending_payments,
+			&ctx.logger,
+		);
+		ctx.metrics.record_abandoned(stuck.len());
+		if !stuck.is_empty() {
+			log_info!(ctx.logger, "Gave up on {} probes nobody answered", stuck.len());
+		}
+		thread::sleep(Duration::from_millis(100));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(args: &[&str]) -> Result<(Option<Job>, Vec<String>), String> {
+		parse_job(args.iter().map(|arg| arg.to_string()).collect()).map_err(|e| e.to_string())
+	}
+
+	#[test]
+	fn test_no_job_starts_the_prompt() {
+		let (job, node_args) = parse(&["--config", "node.toml"]).unwrap();
+		assert_eq!(job, None);
+		assert_eq!(node_args, vec!["--config", "node.toml"]);
+		assert_eq!(parse(&[]).unwrap(), (None, vec![]));
+	}
+
+	#[test]
+	fn test_probeall() {
+		let (job, node_args) = parse(&[
+			"probeall",
+			"--config",
+			"node.toml",
+			"--candidates",
+			"data/transactions",
+			"--targets",
+			"all",
+			"--type",
+			"assumptions",
+			"--guess-strategy",
+			"fixed:02aa",
+		])
+		.unwrap();
+		assert_eq!(
+			job,
+			Some(Job::ProbeAll {
+				probetype: "assumptions".to_string(),
+				targets: "all".to_string(),
+				candidates: "data/transactions".to_string(),
+			})
+		);
+		assert_eq!(node_args, vec!["--config", "node.toml", "--guess-strategy", "fixed:02aa"]);
+
+		let (job, _) =
+			parse(&["probeall", "--targets", "nodes.json", "--candidates", "txs"]).unwrap();
+		assert_eq!(
+			job,
+			Some(Job::ProbeAll {
+				probetype: "all".to_string(),
+				targets: "nodes.json".to_string(),
+				candidates: "txs".to_string(),
+			})
+		);
+	}
+
+	#[test]
+	fn test_dump_results() {
+		let (job, node_args) =
+			parse(&["dump-results", "--out", "results", "--candidates", "txs"]).unwrap();
+		assert_eq!(
+			job,
+			Some(Job::DumpResults {
+				result_dir: "results".to_string(),
+				candidates: "txs".to_string(),
+				aggregate: None,
+				sign: false,
+			})
+		);
+		assert!(node_args.is_empty());
+
+		let (job, _) = parse(&[
+			"dump-results",
+			"--sign",
+			"--out",
+			"results",
+			"--candidates",
+			"txs",
+			"--aggregate",
+			"5",
+		])
+		.unwrap();
+		assert_eq!(
+			job,
+			Some(Job::DumpResults {
+				result_dir: "results".to_string(),
+				candidates: "txs".to_string(),
+				aggregate: Some(5),
+				sign: true,
+			})
+		);
+	}
+
+	#[test]
+	fn test_serve() {
+		let (job, node_args) = parse(&["serve", "--rpc-socket", "rpc.sock"]).unwrap();
+		assert_eq!(job, Some(Job::Serve));
+		assert_eq!(node_args, vec!["--rpc-socket", "rpc.sock"]);
+		assert_eq!(
+			parse(&["serve", "--targets", "all"]).unwrap_err(),
+			"serve doesn't take --targets"
+		);
+	}
+
+	#[test]
+	fn test_bad_jobs() {
+		assert_eq!(parse(&["probe", "--targets", "all"]).unwrap_err(), "unknown job probe");
+		assert_eq!(
+			parse(&["probeall", "--targets", "all"]).unwrap_err(),
+			"probeall requires --candidates"
+		);
+		assert_eq!(parse(&["probeall", "--targets"]).unwrap_err(), "--targets needs a value");
+		assert_eq!(
+			parse(&["probeall", "--targets", "all", "--candidates", "txs", "--sign"]).unwrap_err(),
+			"probeall doesn't take --sign"
+		);
+		assert_eq!(
+			parse(&["dump-results", "--out", "r", "--candidates", "txs", "--aggregate", "0"])
+				.unwrap_err(),
+			"--aggregate must be a positive number, got 0"
+		);
+	}
+}
@@ -0,0 +1,193 @@
+use crate::cli::Attempt;
+use crate::graph_import::parse_scid;
+use crate::hidden_graph::is_counterparty_confirmed;
+use bitcoin::secp256k1::PublicKey;
+use lightning::util::message_signing;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+/// Node operators that asked us not to probe them or publish their private channels.
+///
+/// Every line of the file is `<pubkey> [<scid>...] [sig:<signature>]`, `#` starts a comment.
+/// A line without short channel ids covers the whole node, otherwise only the listed channels.
+/// Short channel ids are either numbers or `<block>x<tx>x<output>`. The signature is what
+/// `signmessage` on the listed node gives for the rest of the line. Requests that come with
+/// one are checked, lines with a bad signature are rejected.
+#[derive(Default)]
+pub(crate) struct Denylist {
+	nodes: HashSet<String>,
+	channels: HashSet<u64>,
+	signed: usize,
+}
+
+impl Denylist {
+	/// Loads the denylist at `path`, no file means an empty list.
+	pub(crate) fn load(path: &str) -> Result<Denylist, Box<dyn std::error::Error>> {
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Denylist::default()),
+			Err(e) => return Err(e.into()),
+		};
+		let mut denylist = Denylist::default();
+		for (i, line) in contents.lines().enumerate() {
+			let line = line.split('#').next().unwrap().trim();
+			if line.is_empty() {
+				continue;
+			}
+			denylist.add_line(line).map_err(|e| format!("{} line {}: {}", path, i + 1, e))?;
+		}
+		Ok(denylist)
+	}
+
+	fn add_line(&mut self, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+		let (request, signature) = match line.split_once(" sig:") {
+			Some((request, signature)) => (request.trim(), Some(signature.trim())),
+			None => (line, None),
+		};
+		let mut words = request.split_whitespace();
+		let pubkey_str = words.next().ok_or("missing pubkey")?;
+		let pubkey = PublicKey::from_str(pubkey_str)?;
+		if let Some(signature) = signature {
+			if !message_signing::verify(request.as_bytes(), signature, &pubkey) {
+				return Err("signature doesn't match the pubkey".into());
+			}
+			self.signed += 1;
+		}
+
+		let mut scids = Vec::new();
+		for scid in words {
+			scids.push(parse_scid(scid).ok_or(format!("invalid short channel id {}", scid))?);
+		}
+		if scids.is_empty() {
+			self.nodes.insert(pubkey.to_string());
+		}
+		self.channels.extend(scids);
+		Ok(())
+	}
+
+	pub(crate) fn is_empty(&self) -> bool {
+		self.nodes.is_empty() && self.channels.is_empty()
+	}
+
+	/// How many nodes and channels are listed, and how many requests were signed.
+	pub(crate) fn counts(&self) -> (usize, usize, usize) {
+		(self.nodes.len(), self.channels.len(), self.signed)
+	}
+
+	pub(crate) fn is_denied_node(&self, pubkey: &str) -> bool {
+		self.nodes.contains(pubkey)
+	}
+
+	pub(crate) fn is_denied_channel(&self, scid: u64) -> bool {
+		self.channels.contains(&scid)
+	}
+
+	/// Whether a channel between these nodes may not be probed or published. The counterparty
+	/// isn't always known.
+	pub(crate) fn is_denied(&self, target: &str, scid: u64, counterparty: Option<&str>) -> bool {
+		self.is_denied_node(target)
+			|| self.is_denied_channel(scid)
+			|| matches!(counterparty, Some(counterparty) if self.is_denied_node(counterparty))
+	}
+
+	/// Whether the channel a probe attempt was about may not be published. The guess only
+	/// counts as its counterparty if the target confirmed it.
+	pub(crate) fn is_attempt_denied(&self, attempt: &Attempt) -> bool {
+		let counterparty = if is_counterparty_confirmed(&attempt.result) {
+			Some(attempt.guess_pubkey.as_str())
+		} else {
+			None
+		};
+		let scid = attempt.channel_id.parse::<u64>().unwrap_or_default();
+		self.is_denied(&attempt.target_pubkey, scid, counterparty)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::probe::scid_from_parts;
+	use crate::test_utils::{pubkey, TempDir};
+	use bitcoin::secp256k1::SecretKey;
+
+	fn load(contents: &str) -> Result<Denylist, Box<dyn std::error::Error>> {
+		let dir = TempDir::new("denylist");
+		let path = format!("{}/denylist", dir.path());
+		fs::write(&path, contents).unwrap();
+		Denylist::load(&path)
+	}
+
+	#[test]
+	fn test_parse() {
+		let (node, channels, other) = (pubkey(1), pubkey(2), pubkey(3));
+		let denylist = load(&format!(
+			"# opted out\n\n{}\n{} 700000x1x0 {}  # just these\n",
+			node,
+			channels,
+			scid_from_parts(700_001, 2, 1)
+		))
+		.unwrap();
+		assert_eq!(denylist.counts(), (1, 2, 0));
+		assert!(denylist.is_denied_node(&node.to_string()));
+		assert!(!denylist.is_denied_node(&channels.to_string()));
+		assert!(denylist.is_denied_channel(scid_from_parts(700_000, 1, 0)));
+		assert!(denylist.is_denied_channel(scid_from_parts(700_001, 2, 1)));
+
+		let other = other.to_string();
+		assert!(denylist.is_denied(&node.to_string(), 1, None));
+		assert!(denylist.is_denied(&other, 1, Some(&node.to_string())));
+		assert!(denylist.is_denied(&other, scid_from_parts(700_000, 1, 0), None));
+		assert!(!denylist.is_denied(&other, 1, Some(&channels.to_string())));
+	}
+
+	#[test]
+	fn test_attempts() {
+		let denylist = load(&format!("{}\n", pubkey(1))).unwrap();
+		let attempt = |target: u8, guess: u8, result: &str| Attempt {
+			target_pubkey: pubkey(target).to_string(),
+			guess_pubkey: pubkey(guess).to_string(),
+			channel_id: scid_from_parts(700_000, 1, 0).to_string(),
+			result: result.to_string(),
+			date_found: chrono::Utc::now().naive_utc(),
+			hidden_path: None,
+			guess_strategy: None,
+		};
+		assert!(denylist.is_attempt_denied(&attempt(1, 2, "fee_insufficient")));
+		assert!(denylist.is_attempt_denied(&attempt(2, 1, "incorrect_or_unknown_payment_details")));
+		// an unconfirmed guess says nothing about the node guessed
+		assert!(!denylist.is_attempt_denied(&attempt(2, 1, "fee_insufficient")));
+		assert!(!denylist.is_attempt_denied(&attempt(
+			2,
+			3,
+			"incorrect_or_unknown_payment_details"
+		)));
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		assert!(Denylist::load("/nonexistent/denylist").unwrap().is_empty());
+		assert!(load("# nothing yet\n").unwrap().is_empty());
+
+		let e = load(&format!("{}\n{} 700000x1\n", pubkey(1), pubkey(2))).err().unwrap();
+		assert!(e.to_string().ends_with("line 2: invalid short channel id 700000x1"));
+		assert!(load("not-a-pubkey\n").is_err());
+	}
+
+	#[test]
+	fn test_signed_requests() {
+		let secret = SecretKey::from_slice(&[1; 32]).unwrap();
+		let request = format!("{} 700000x1x0", pubkey(1));
+		let signature = message_signing::sign(request.as_bytes(), &secret).unwrap();
+
+		let denylist = load(&format!("{} sig:{}\n{}\n", request, signature, pubkey(2))).unwrap();
+		assert_eq!(denylist.counts(), (1, 1, 1));
+
+		// signed by the node, but not for this request
+		let e = load(&format!("{} 700000x1x1 sig:{}\n", pubkey(1), signature)).err().unwrap();
+		assert!(e.to_string().ends_with("line 1: signature doesn't match the pubkey"));
+		// someone else's request, signed by the first node
+		assert!(load(&format!("{} 700000x1x0 sig:{}\n", pubkey(2), signature)).is_err());
+	}
+}
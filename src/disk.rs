@@ -16,7 +16,7 @@ use lightning::chain::keysinterface::Sign;
 use lightning::chain::transaction::OutPoint;
 // use lightning::routing::network_graph::NetworkGraph;
 use lightning::routing::scoring::{ProbabilisticScorer, ProbabilisticScoringParameters};
-use lightning::util::logger::{Logger, Record};
+use lightning::util::logger::{Level, Logger, Record};
 use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
 use std::collections::HashMap;
 use std::fs;
@@ -248,17 +248,19 @@ pub(crate) fn get_full_filepath(mut filepath: PathBuf, filename: String) -> Stri
 
 pub(crate) struct FilesystemLogger {
 	data_dir: String,
+	/// Records below this level are dropped.
+	level: Level,
 }
 impl FilesystemLogger {
-	pub(crate) fn new(data_dir: String) -> Self {
+	pub(crate) fn new(data_dir: String, level: Level) -> Self {
 		let logs_path = format!("{}/logs", data_dir);
 		fs::create_dir_all(logs_path.clone()).unwrap();
-		Self { data_dir: logs_path }
+		Self { data_dir: logs_path, level }
 	}
 }
 impl Logger for FilesystemLogger {
 	fn log(&self, record: &Record) {
-		if record.level.to_string() != "INFO" && record.level.to_string() != "WARN" {
+		if record.level < self.level {
 			return;
 		}
 		let raw_log = record.args.to_string();
@@ -0,0 +1,341 @@
+use crate::control::Control;
+use crate::hidden_graph::is_channel_found;
+use crate::probe::{matches_assumptions, vout_from_scid};
+use crate::NetworkGraph;
+use bitcoin::secp256k1::PublicKey;
+use chrono::{NaiveDateTime, Utc};
+use lightning::routing::gossip::NodeId;
+use rand::Rng;
+use rusqlite::{params, Connection};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+/// A probe we know the answer to: one of the target's public channels, probed as if it was
+/// private, or someone else's public channel the target has nothing to do with.
+pub(crate) struct EvaluationProbe {
+	pub(crate) target: PublicKey,
+	pub(crate) short_channel_id: u64,
+	pub(crate) expected: Control,
+}
+
+/// Up to `max_channels` public channels of `targets`, each paired with a channel of another
+/// node to catch false positives. Also returns how many of the picked channels have a known
+/// capacity, and how many of those the `assumptions` filter of `probeall` would have kept.
+pub(crate) fn pick_probes(
+	targets: &[PublicKey], network_graph: &NetworkGraph, max_channels: usize,
+) -> (Vec<EvaluationProbe>, usize, usize) {
+	let graph = network_graph.read_only();
+	let all_channels: Vec<u64> = graph.channels().keys().copied().collect();
+	let mut rng = rand::thread_rng();
+
+	let mut probes = Vec::new();
+	// not every positive gets a negative, so they're counted on their own
+	let mut positives = 0;
+	let mut assumption_channels = 0;
+	let mut assumption_matches = 0;
+	for target in targets {
+		let node = match graph.nodes().get(&NodeId::from_pubkey(target)) {
+			Some(node) => node,
+			None => continue,
+		};
+		let own_channels: HashSet<u64> = node.channels.iter().copied().collect();
+		let other_channels: Vec<u64> =
+			all_channels.iter().copied().filter(|scid| !own_channels.contains(scid)).collect();
+		for scid in node.channels.iter() {
+			if positives >= max_channels {
+				return (probes, assumption_channels, assumption_matches);
+			}
+			if let Some(capacity) = graph.channels().get(scid).and_then(|c| c.capacity_sats) {
+				assumption_channels += 1;
+				if matches_assumptions(capacity, vout_from_scid(scid) as u64) {
+					assumption_matches += 1;
+				}
+			}
+			probes.push(EvaluationProbe {
+				target: *target,
+				short_channel_id: *scid,
+				expected: Control::Present,
+			});
+			positives += 1;
+
+			// a target with every channel of the graph gets no negative
+			if !other_channels.is_empty() {
+				probes.push(EvaluationProbe {
+					target: *target,
+					short_channel_id: other_channels[rng.gen_range(0, other_channels.len())],
+					expected: Control::Absent,
+				});
+			}
+		}
+	}
+	(probes, assumption_channels, assumption_matches)
+}
+
+/// How well the probes picked out a target's public channels in one `evaluate` run.
+pub(crate) struct EvaluationReport {
+	pub(crate) run_id: i64,
+	pub(crate) date_run: NaiveDateTime,
+	pub(crate) guess_strategy: String,
+	pub(crate) targets: usize,
+	pub(crate) positives: usize,
+	pub(crate) positives_answered: usize,
+	pub(crate) detected: usize,
+	pub(crate) negatives: usize,
+	pub(crate) negatives_answered: usize,
+	pub(crate) false_positives: usize,
+	pub(crate) assumption_channels: usize,
+	pub(crate) assumption_matches: usize,
+	/// How often each result came back, for the target's own channels and for the others.
+	pub(crate) positive_results: BTreeMap<String, usize>,
+	pub(crate) negative_results: BTreeMap<String, usize>,
+}
+
+impl EvaluationReport {
+	pub(crate) fn detection_rate(&self) -> Option<f64> {
+		ratio(self.detected, self.positives_answered)
+	}
+
+	pub(crate) fn false_positive_rate(&self) -> Option<f64> {
+		ratio(self.false_positives, self.negatives_answered)
+	}
+
+	/// The share of public channels the `assumptions` filter would have let through.
+	pub(crate) fn assumption_recall(&self) -> Option<f64> {
+		ratio(self.assumption_matches, self.assumption_channels)
+	}
+}
+
+fn ratio(part: usize, whole: usize) -> Option<f64> {
+	if whole == 0 {
+		None
+	} else {
+		Some(part as f64 / whole as f64)
+	}
+}
+
+struct EvaluationAnswer {
+	target_pubkey: String,
+	short_channel_id: u64,
+	expected: Control,
+	result: &'static str,
+}
+
+/// Keeps the probes of an `evaluate` run apart from the rest, their answers only go into the
+/// run's report.
+pub(crate) struct Evaluations {
+	pending: Mutex<HashMap<(String, u64), Control>>,
+	answers: Mutex<Vec<EvaluationAnswer>>,
+}
+
+impl Evaluations {
+	pub(crate) fn new() -> Evaluations {
+		Evaluations { pending: Mutex::new(HashMap::new()), answers: Mutex::new(Vec::new()) }
+	}
+
+	pub(crate) fn record_sent(&self, probe: &EvaluationProbe) {
+		let key = (probe.target.to_string(), probe.short_channel_id);
+		self.pending.lock().unwrap().insert(key, probe.expected);
+	}
+
+	/// Probes of the run still waiting for an answer.
+	pub(crate) fn pending(&self) -> usize {
+		self.pending.lock().unwrap().len()
+	}
+
+	/// Forgets a probe that didn't go out after all.
+	pub(crate) fn forget(&self, probe: &EvaluationProbe) {
+		let key = (probe.target.to_string(), probe.short_channel_id);
+		self.pending.lock().unwrap().remove(&key);
+	}
+
+	/// Whether the probe of `short_channel_id` belongs to a run, and what it should find.
+	pub(crate) fn take_probe(&self, target: &str, short_channel_id: u64) -> Option<Control> {
+		self.pending.lock().unwrap().remove(&(target.to_string(), short_channel_id))
+	}
+
+	pub(crate) fn record_answer(
+		&self, target: &str, short_channel_id: u64, expected: Control, result: &'static str,
+	) {
+		self.answers.lock().unwrap().push(EvaluationAnswer {
+			target_pubkey: target.to_string(),
+			short_channel_id,
+			expected,
+			result,
+		});
+	}
+
+	/// Sums up the answers of the run and stores them. Probes still without an answer are
+	/// given up on.
+	pub(crate) fn finish(
+		&self, db: &Connection, probes: &[EvaluationProbe], guess_strategy: &str,
+		assumption_channels: usize, assumption_matches: usize,
+	) -> Result<EvaluationReport, rusqlite::Error> {
+		self.pending.lock().unwrap().clear();
+		let answers: Vec<EvaluationAnswer> = self.answers.lock().unwrap().drain(..).collect();
+
+		let mut report = EvaluationReport {
+			run_id: 0,
+			date_run: Utc::now().naive_utc(),
+			guess_strategy: guess_strategy.to_string(),
+			targets: probes.iter().map(|probe| probe.target).collect::<HashSet<_>>().len(),
+			positives: probes.iter().filter(|probe| probe.expected == Control::Present).count(),
+			positives_answered: 0,
+			detected: 0,
+			negatives: probes.iter().filter(|probe| probe.expected == Control::Absent).count(),
+			negatives_answered: 0,
+			false_positives: 0,
+			assumption_channels,
+			assumption_matches,
+			positive_results: BTreeMap::new(),
+			negative_results: BTreeMap::new(),
+		};
+		for answer in answers.iter() {
+			let found = is_channel_found(answer.result);
+			let results = match answer.expected {
+				Control::Present => {
+					report.positives_answered += 1;
+					report.detected += found as usize;
+					&mut report.positive_results
+				}
+				Control::Absent => {
+					report.negatives_answered += 1;
+					report.false_positives += found as usize;
+					&mut report.negative_results
+				}
+			};
+			*results.entry(answer.result.to_string()).or_default() += 1;
+		}
+
+		db.execute(
+			"INSERT INTO evaluation_run (
+                date_run, guess_strategy, targets, positives, positives_answered, detected,
+                negatives, negatives_answered, false_positives, assumption_channels,
+                assumption_matches, positive_results, negative_results)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+			params![
+				report.date_run,
+				&report.guess_strategy,
+				report.targets as i64,
+				report.positives as i64,
+				report.positives_answered as i64,
+				report.detected as i64,
+				report.negatives as i64,
+				report.negatives_answered as i64,
+				report.false_positives as i64,
+				report.assumption_channels as i64,
+				report.assumption_matches as i64,
+				serde_json::to_string(&report.positive_results).unwrap(),
+				serde_json::to_string(&report.negative_results).unwrap(),
+			],
+		)?;
+		report.run_id = db.last_insert_rowid();
+		for answer in answers.iter() {
+			db.execute(
+				"INSERT INTO evaluation_probe (run_id, target_pubkey, channel_id, expected, result)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+				params![
+					report.run_id,
+					&answer.target_pubkey,
+					answer.short_channel_id.to_string(),
+					answer.expected.as_str(),
+					answer.result,
+				],
+			)?;
+		}
+		Ok(report)
+	}
+}
+
+/// Every stored run, oldest first, to see how changes to the heuristics played out.
+pub(crate) fn read_runs(db: &Connection) -> Result<Vec<EvaluationReport>, rusqlite::Error> {
+	let mut stmt = db.prepare(
+		"SELECT run_id, date_run, guess_strategy, targets, positives, positives_answered,
+            detected, negatives, negatives_answered, false_positives, assumption_channels,
+            assumption_matches, positive_results, negative_results
+        FROM evaluation_run ORDER BY run_id",
+	)?;
+	let mut rows = stmt.query([])?;
+	let mut runs = Vec::new();
+	while let Some(row) = rows.next()? {
+		let count =
+			|i: usize| -> Result<usize, rusqlite::Error> { Ok(row.get::<_, i64>(i)? as usize) };
+		let results = |i: usize| -> Result<BTreeMap<String, usize>, rusqlite::Error> {
+			let json: String = row.get(i)?;
+			Ok(serde_json::from_str(&json).unwrap_or_default())
+		};
+		runs.push(EvaluationReport {
+			run_id: row.get(0)?,
+			date_run: row.get(1)?,
+			guess_strategy: row.get(2)?,
+			targets: count(3)?,
+			positives: count(4)?,
+			positives_answered: count(5)?,
+			detected: count(6)?,
+			negatives: count(7)?,
+			negatives_answered: count(8)?,
+			false_positives: count(9)?,
+			assumption_channels: count(10)?,
+			assumption_matches: count(11)?,
+			positive_results: results(12)?,
+			negative_results: results(13)?,
+		});
+	}
+	Ok(runs)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::probe::scid_from_parts;
+	use crate::test_utils::{self, add_public_channel, pubkey, TempDir};
+
+	#[test]
+	fn test_pick_probes_caps_positives_without_negatives() {
+		let data_dir = TempDir::new("evaluate");
+		let network_graph = test_utils::network_graph(&data_dir);
+		let target = pubkey(1);
+		// every channel in the graph is the target's, so no negative can be found
+		for i in 0..5 {
+			add_public_channel(&network_graph, scid_from_parts(700_000, i, 0), target, pubkey(2));
+		}
+
+		let (probes, _, _) = pick_probes(&[target], &network_graph, 3);
+		assert_eq!(probes.len(), 3);
+		assert!(probes.iter().all(|probe| probe.expected == Control::Present));
+	}
+
+	#[test]
+	fn test_pick_probes_pairs_negatives() {
+		let data_dir = TempDir::new("evaluate");
+		let network_graph = test_utils::network_graph(&data_dir);
+		let target = pubkey(1);
+		add_public_channel(&network_graph, scid_from_parts(700_000, 1, 0), target, pubkey(2));
+		add_public_channel(&network_graph, scid_from_parts(700_000, 2, 0), target, pubkey(3));
+		add_public_channel(&network_graph, scid_from_parts(700_000, 3, 0), pubkey(2), pubkey(3));
+
+		let (probes, _, _) = pick_probes(&[target], &network_graph, 10);
+		let expected: Vec<Control> = probes.iter().map(|probe| probe.expected).collect();
+		assert_eq!(
+			expected,
+			[Control::Present, Control::Absent, Control::Present, Control::Absent]
+		);
+		for negative in probes.iter().filter(|probe| probe.expected == Control::Absent) {
+			assert_eq!(negative.short_channel_id, scid_from_parts(700_000, 3, 0));
+		}
+	}
+
+	#[test]
+	fn test_pending_only_counts_the_run() {
+		let evaluations = Evaluations::new();
+		let probe =
+			EvaluationProbe { target: pubkey(1), short_channel_id: 42, expected: Control::Present };
+		evaluations.record_sent(&probe);
+		assert_eq!(evaluations.pending(), 1);
+
+		// answers to anything else don't count
+		assert_eq!(evaluations.take_probe(&pubkey(2).to_string(), 42), None);
+		assert_eq!(evaluations.pending(), 1);
+		assert_eq!(evaluations.take_probe(&pubkey(1).to_string(), 42), Some(Control::Present));
+		assert_eq!(evaluations.pending(), 0);
+	}
+}
@@ -0,0 +1,335 @@
+use crate::cli::Transaction;
+use crate::hidden_graph::DiscoveredChannel;
+use crate::NetworkGraph;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ExportNode {
+	pub(crate) id: String,
+	pub(crate) alias: String,
+	pub(crate) public: bool,
+}
+
+/// One channel of the combined graph. `policy_1` is the policy of `node_1` for forwarding
+/// towards `node_2`, `policy_2` the reverse.
+#[derive(Serialize, Debug)]
+pub(crate) struct ExportEdge {
+	pub(crate) short_channel_id: u64,
+	pub(crate) node_1: String,
+	pub(crate) node_2: String,
+	pub(crate) capacity_sats: Option<u64>,
+	pub(crate) public: bool,
+	pub(crate) policy_1_fee_base_msat: Option<u32>,
+	pub(crate) policy_1_fee_rate_ppm: Option<u32>,
+	pub(crate) policy_1_cltv_expiry_delta: Option<u16>,
+	pub(crate) policy_2_fee_base_msat: Option<u32>,
+	pub(crate) policy_2_fee_rate_ppm: Option<u32>,
+	pub(crate) policy_2_cltv_expiry_delta: Option<u16>,
+	pub(crate) date_found: Option<NaiveDateTime>,
+	pub(crate) closed: bool,
+}
+
+impl ExportEdge {
+	fn attributes(&self) -> Vec<(&'static str, Option<String>)> {
+		vec![
+			("short_channel_id", Some(self.short_channel_id.to_string())),
+			("capacity_sats", self.capacity_sats.map(|c| c.to_string())),
+			("public", Some(self.public.to_string())),
+			("policy_1_fee_base_msat", self.policy_1_fee_base_msat.map(|f| f.to_string())),
+			("policy_1_fee_rate_ppm", self.policy_1_fee_rate_ppm.map(|f| f.to_string())),
+			("policy_1_cltv_expiry_delta", self.policy_1_cltv_expiry_delta.map(|c| c.to_string())),
+			("policy_2_fee_base_msat", self.policy_2_fee_base_msat.map(|f| f.to_string())),
+			("policy_2_fee_rate_ppm", self.policy_2_fee_rate_ppm.map(|f| f.to_string())),
+			("policy_2_cltv_expiry_delta", self.policy_2_cltv_expiry_delta.map(|c| c.to_string())),
+			("date_found", self.date_found.map(|d| d.to_string())),
+			("closed", Some(self.closed.to_string())),
+		]
+	}
+}
+
+/// The public `NetworkGraph` merged with the private channels our probes discovered.
+#[derive(Default, Debug)]
+pub(crate) struct ExportGraph {
+	pub(crate) nodes: Vec<ExportNode>,
+	pub(crate) edges: Vec<ExportEdge>,
+}
+
+impl ExportGraph {
+	pub(crate) fn build(
+		network_graph: &NetworkGraph, discovered: &[DiscoveredChannel],
+		txs: &HashMap<u64, Transaction>,
+	) -> ExportGraph {
+		let mut nodes: BTreeMap<String, ExportNode> = BTreeMap::new();
+		let mut edges = Vec::new();
+
+		let graph = network_graph.read_only();
+		for (node_id, node_info) in graph.nodes() {
+			let id = hex::encode(node_id.as_slice());
+			let alias = match &node_info.announcement_info {
+				Some(announcement) => announcement.alias.to_string(),
+				None => String::new(),
+			};
+			nodes.insert(id.clone(), ExportNode { id, alias, public: true });
+		}
+		for (scid, channel) in graph.channels() {
+			let policy_1 = channel.one_to_two.as_ref();
+			let policy_2 = channel.two_to_one.as_ref();
+			edges.push(ExportEdge {
+				short_channel_id: *scid,
+				node_1: hex::encode(channel.node_one.as_slice()),
+				node_2: hex::encode(channel.node_two.as_slice()),
+				capacity_sats: channel.capacity_sats.or(txs.get(scid).map(|tx| tx.amount)),
+				public: true,
+				policy_1_fee_base_msat: policy_1.map(|p| p.fees.base_msat),
+				policy_1_fee_rate_ppm: policy_1.map(|p| p.fees.proportional_millionths),
+				policy_1_cltv_expiry_delta: policy_1.map(|p| p.cltv_expiry_delta),
+				policy_2_fee_base_msat: policy_2.map(|p| p.fees.base_msat),
+				policy_2_fee_rate_ppm: policy_2.map(|p| p.fees.proportional_millionths),
+				policy_2_cltv_expiry_delta: policy_2.map(|p| p.cltv_expiry_delta),
+				date_found: None,
+				closed: false,
+			});
+		}
+
+		for channel in discovered {
+			// Without a confirmed counterparty the far end gets a placeholder node per channel.
+			let counterparty = match &channel.counterparty {
+				Some(counterparty) => counterparty.clone(),
+				None => format!("unknown-{}", channel.short_channel_id),
+			};
+			for id in [&channel.target_pubkey, &counterparty] {
+				nodes.entry(id.clone()).or_insert_with(|| ExportNode {
+					id: id.clone(),
+					alias: String::new(),
+					public: false,
+				});
+			}
+			// we only ever learn the target's side of a private channel
+			let policy = channel.policy.as_ref();
+			edges.push(ExportEdge {
+				short_channel_id: channel.short_channel_id,
+				node_1: channel.target_pubkey.clone(),
+				node_2: counterparty,
+				capacity_sats: txs.get(&channel.short_channel_id).map(|tx| tx.amount),
+				public: false,
+				policy_1_fee_base_msat: policy.map(|p| p.fees.base_msat),
+				policy_1_fee_rate_ppm: policy.map(|p| p.fees.proportional_millionths),
+				policy_1_cltv_expiry_delta: policy.map(|p| p.cltv_expiry_delta),
+				policy_2_fee_base_msat: None,
+				policy_2_fee_rate_ppm: None,
+				policy_2_cltv_expiry_delta: None,
+				date_found: Some(channel.date_found),
+				closed: channel.closed,
+			});
+		}
+
+		ExportGraph { nodes: nodes.into_values().collect(), edges }
+	}
+
+	/// Writes the graph as GraphML, DOT, or as `nodes.csv` and `edges.csv` inside `path`.
+	pub(crate) fn write(&self, format: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+		match format {
+			"graphml" => self.write_graphml(&mut BufWriter::new(File::create(path)?))?,
+			"dot" => self.write_dot(&mut BufWriter::new(File::create(path)?))?,
+			"csv" => self.write_csv(Path::new(path))?,
+			_ => {
+				return Err(format!(
+					"unknown export format `{}`, expected graphml, dot or csv",
+					format
+				)
+				.into())
+			}
+		}
+		Ok(())
+	}
+
+	fn write_graphml<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+		writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+		writeln!(w, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+		writeln!(w, r#"  <key id="alias" for="node" attr.name="alias" attr.type="string"/>"#)?;
+		writeln!(w, r#"  <key id="public" for="all" attr.name="public" attr.type="boolean"/>"#)?;
+		if let Some(edge) = self.edges.first() {
+			for (name, _) in edge.attributes().iter().filter(|(name, _)| *name != "public") {
+				let attr_type = match *name {
+					"date_found" => "string",
+					"closed" => "boolean",
+					_ => "long",
+				};
+				writeln!(
+					w,
+					r#"  <key id="{0}" for="edge" attr.name="{0}" attr.type="{1}"/>"#,
+					name, attr_type
+				)?;
+			}
+		}
+		writeln!(w, r#"  <graph id="hidden-lightning-network" edgedefault="undirected">"#)?;
+		for node in self.nodes.iter() {
+			writeln!(w, r#"    <node id="{}">"#, xml_escape(&node.id))?;
+			writeln!(w, r#"      <data key="alias">{}</data>"#, xml_escape(&node.alias))?;
+			writeln!(w, r#"      <data key="public">{}</data>"#, node.public)?;
+			writeln!(w, "    </node>")?;
+		}
+		for edge in self.edges.iter() {
+			writeln!(
+				w,
+				r#"    <edge source="{}" target="{}">"#,
+				xml_escape(&edge.node_1),
+				xml_escape(&edge.node_2)
+			)?;
+			for (name, value) in edge.attributes() {
+				if let Some(value) = value {
+					writeln!(w, r#"      <data key="{}">{}</data>"#, name, xml_escape(&value))?;
+				}
+			}
+			writeln!(w, "    </edge>")?;
+		}
+		writeln!(w, "  </graph>")?;
+		writeln!(w, "</graphml>")?;
+		w.flush()
+	}
+
+	fn write_dot<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+		writeln!(w, "graph hidden_lightning_network {{")?;
+		for node in self.nodes.iter() {
+			writeln!(
+				w,
+				"  \"{}\" [alias=\"{}\", public={}];",
+				dot_escape(&node.id),
+				dot_escape(&node.alias),
+				node.public
+			)?;
+		}
+		for edge in self.edges.iter() {
+			let attributes: Vec<String> = edge
+				.attributes()
+				.into_iter()
+				.filter_map(|(name, value)| {
+					value.map(|v| format!("{}=\"{}\"", name, dot_escape(&v)))
+				})
+				.collect();
+			writeln!(
+				w,
+				"  \"{}\" -- \"{}\" [{}];",
+				dot_escape(&edge.node_1),
+				dot_escape(&edge.node_2),
+				attributes.join(", ")
+			)?;
+		}
+		writeln!(w, "}}")?;
+		w.flush()
+	}
+
+	fn write_csv(&self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+		fs::create_dir_all(dir)?;
+		let mut nodes = csv::Writer::from_path(dir.join("nodes.csv"))?;
+		for node in self.nodes.iter() {
+			nodes.serialize(node)?;
+		}
+		nodes.flush()?;
+		let mut edges = csv::Writer::from_path(dir.join("edges.csv"))?;
+		for edge in self.edges.iter() {
+			edges.serialize(edge)?;
+		}
+		edges.flush()?;
+		Ok(())
+	}
+}
+
+fn xml_escape(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&apos;")
+}
+
+fn dot_escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+
+	fn graph() -> ExportGraph {
+		let node = |id: &str, alias: &str, public| ExportNode {
+			id: id.to_string(),
+			alias: alias.to_string(),
+			public,
+		};
+		ExportGraph {
+			nodes: vec![
+				node("02aa", r#"<b>Tom & "Jerry's"</b>"#, true),
+				node("03bb", r"back\slash", false),
+			],
+			edges: vec![ExportEdge {
+				short_channel_id: 42,
+				node_1: "02aa".to_string(),
+				node_2: "03bb".to_string(),
+				capacity_sats: Some(100_000),
+				public: false,
+				policy_1_fee_base_msat: Some(1000),
+				policy_1_fee_rate_ppm: Some(1),
+				policy_1_cltv_expiry_delta: Some(40),
+				policy_2_fee_base_msat: None,
+				policy_2_fee_rate_ppm: None,
+				policy_2_cltv_expiry_delta: None,
+				date_found: None,
+				closed: false,
+			}],
+		}
+	}
+
+	#[test]
+	fn test_graphml() {
+		let dir = TempDir::new("export");
+		let path = format!("{}/graph.graphml", dir.path());
+		graph().write("graphml", &path).unwrap();
+		let graphml = fs::read_to_string(&path).unwrap();
+		assert!(graphml.contains(
+			r#"<data key="alias">&lt;b&gt;Tom &amp; &quot;Jerry&apos;s&quot;&lt;/b&gt;</data>"#
+		));
+		assert!(graphml.contains(r#"<edge source="02aa" target="03bb">"#));
+		assert!(graphml.contains(r#"<data key="policy_1_fee_base_msat">1000</data>"#));
+		// attributes we don't know are left out rather than written empty
+		assert!(!graphml.contains(r#"key="policy_2_fee_base_msat">"#));
+		assert!(graphml.contains(
+			r#"<key id="date_found" for="edge" attr.name="date_found" attr.type="string"/>"#
+		));
+	}
+
+	#[test]
+	fn test_dot() {
+		let dir = TempDir::new("export");
+		let path = format!("{}/graph.dot", dir.path());
+		graph().write("dot", &path).unwrap();
+		let dot = fs::read_to_string(&path).unwrap();
+		assert!(dot.starts_with("graph hidden_lightning_network {"));
+		assert!(dot.contains(r#""02aa" [alias="<b>Tom & \"Jerry's\"</b>", public=true];"#));
+		assert!(dot.contains(r#""03bb" [alias="back\\slash", public=false];"#));
+		assert!(dot.contains(r#""02aa" -- "03bb" [short_channel_id="42", capacity_sats="100000""#));
+	}
+
+	#[test]
+	fn test_csv() {
+		let dir = TempDir::new("export");
+		let out = format!("{}/graph", dir.path());
+		graph().write("csv", &out).unwrap();
+		let mut nodes = csv::Reader::from_path(format!("{}/nodes.csv", out)).unwrap();
+		let aliases: Vec<String> =
+			nodes.records().map(|record| record.unwrap()[1].to_string()).collect();
+		assert_eq!(aliases, vec![r#"<b>Tom & "Jerry's"</b>"#, r"back\slash"]);
+		let edges = fs::read_to_string(format!("{}/edges.csv", out)).unwrap();
+		assert!(edges.starts_with("short_channel_id,node_1,node_2,capacity_sats,public,"));
+		assert!(edges.contains("42,02aa,03bb,100000,false,1000,1,40,,,,,false"));
+
+		assert!(graph().write("json", &out).is_err());
+	}
+}
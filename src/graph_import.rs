@@ -0,0 +1,679 @@
+use crate::probe::{scid_from_parts, MAX_SCID_BLOCK, MAX_SCID_TX_INDEX, MAX_SCID_VOUT_INDEX};
+use crate::NetworkGraph;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::BlockHash;
+use chrono::Utc;
+use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+use lightning::ln::msgs::{
+	NetAddress, OptionalField, UnsignedChannelUpdate, UnsignedNodeAnnouncement,
+};
+use lightning::util::ser::{Readable, Writeable};
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// LDK rejects unsigned channel updates that are more than two weeks old, so older policies from
+/// a dump are re-stamped just inside that window. Live gossip will always be newer and replace
+/// them.
+const MAX_IMPORT_AGE_SECS: u64 = 60 * 60 * 24 * 13;
+
+#[derive(Deserialize, Debug)]
+struct LndGraph {
+	#[serde(default)]
+	nodes: Vec<LndNode>,
+	#[serde(default)]
+	edges: Vec<LndEdge>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LndNode {
+	pub_key: String,
+	#[serde(default)]
+	last_update: u32,
+	#[serde(default)]
+	alias: String,
+	#[serde(default)]
+	color: String,
+	#[serde(default)]
+	addresses: Vec<LndAddress>,
+	#[serde(default)]
+	features: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LndAddress {
+	addr: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct LndEdge {
+	channel_id: String,
+	node1_pub: String,
+	node2_pub: String,
+	node1_policy: Option<LndPolicy>,
+	node2_policy: Option<LndPolicy>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LndPolicy {
+	#[serde(default)]
+	time_lock_delta: u16,
+	#[serde(default)]
+	min_htlc: String,
+	#[serde(default)]
+	max_htlc_msat: String,
+	#[serde(default)]
+	fee_base_msat: String,
+	#[serde(default)]
+	fee_rate_milli_msat: String,
+	#[serde(default)]
+	disabled: bool,
+	#[serde(default)]
+	last_update: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClnChannels {
+	channels: Vec<ClnChannel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClnChannel {
+	source: String,
+	destination: String,
+	short_channel_id: String,
+	#[serde(default)]
+	active: bool,
+	#[serde(default)]
+	last_update: u32,
+	#[serde(default)]
+	base_fee_millisatoshi: u32,
+	#[serde(default)]
+	fee_per_millionth: u32,
+	#[serde(default)]
+	delay: u16,
+	#[serde(default)]
+	htlc_minimum_msat: serde_json::Value,
+	#[serde(default)]
+	htlc_maximum_msat: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClnNodes {
+	nodes: Vec<ClnNode>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClnNode {
+	nodeid: String,
+	#[serde(default)]
+	last_timestamp: u32,
+	#[serde(default)]
+	alias: String,
+	#[serde(default)]
+	color: String,
+	#[serde(default)]
+	features: String,
+	#[serde(default)]
+	addresses: Vec<ClnAddress>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClnAddress {
+	#[serde(rename = "type")]
+	addr_type: String,
+	#[serde(default)]
+	address: String,
+	#[serde(default)]
+	port: u16,
+}
+
+/// Counts of what an `importgraph` run added to the `NetworkGraph`.
+#[derive(Default, Debug)]
+pub(crate) struct ImportSummary {
+	pub(crate) channels: usize,
+	pub(crate) channel_updates: usize,
+	pub(crate) nodes: usize,
+	pub(crate) skipped: usize,
+}
+
+/// Imports an LND `describegraph` dump, or a CLN `listchannels` or `listnodes` dump, into the
+/// network graph. Every channel and node that was added is recorded in the
+/// `unverified_graph_entry` table, since none of it came with signatures.
+pub(crate) fn import_graph(
+	format: &str, path: &str, network: Network, network_graph: &NetworkGraph, db: &Connection,
+) -> Result<ImportSummary, Box<dyn std::error::Error>> {
+	let data = fs::read_to_string(path)?;
+	let chain_hash = genesis_block(network).header.block_hash();
+	let source = format!("{}:{}", format, path);
+	let mut summary = ImportSummary::default();
+	match format {
+		"lnd" => {
+			let graph: LndGraph = serde_json::from_str(&data)?;
+			for edge in graph.edges.iter() {
+				import_lnd_edge(edge, chain_hash, network_graph, db, &source, &mut summary)?;
+			}
+			for node in graph.nodes.iter() {
+				let features = lnd_features(&node.features);
+				let addresses =
+					node.addresses.iter().filter_map(|a| net_address(&a.addr)).collect();
+				let node = ImportedNode {
+					pubkey: &node.pub_key,
+					timestamp: node.last_update,
+					alias: &node.alias,
+					color: &node.color,
+					features,
+					addresses,
+				};
+				import_node(node, network_graph, db, &source, &mut summary)?;
+			}
+		}
+		"cln" => {
+			let value: serde_json::Value = serde_json::from_str(&data)?;
+			if value.get("channels").is_some() {
+				let dump: ClnChannels = serde_json::from_value(value)?;
+				for channel in dump.channels.iter() {
+					import_cln_channel(
+						channel,
+						chain_hash,
+						network_graph,
+						db,
+						&source,
+						&mut summary,
+					)?;
+				}
+			} else if value.get("nodes").is_some() {
+				let dump: ClnNodes = serde_json::from_value(value)?;
+				for node in dump.nodes.iter() {
+					let features = cln_features(&node.features);
+					let addresses = node
+						.addresses
+						.iter()
+						.filter(|a| a.addr_type == "ipv4" || a.addr_type == "ipv6")
+						.filter_map(|a| net_address(&format_host_port(&a.address, a.port)))
+						.collect();
+					let node = ImportedNode {
+						pubkey: &node.nodeid,
+						timestamp: node.last_timestamp,
+						alias: &node.alias,
+						color: &node.color,
+						features,
+						addresses,
+					};
+					import_node(node, network_graph, db, &source, &mut summary)?;
+				}
+			} else {
+				return Err("expected a `listchannels` or `listnodes` dump".into());
+			}
+		}
+		_ => {
+			return Err(format!("unknown graph format `{}`, expected `lnd` or `cln`", format).into())
+		}
+	}
+	Ok(summary)
+}
+
+fn import_lnd_edge(
+	edge: &LndEdge, chain_hash: BlockHash, network_graph: &NetworkGraph, db: &Connection,
+	source: &str, summary: &mut ImportSummary,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let scid = match edge.channel_id.parse::<u64>() {
+		Ok(scid) => scid,
+		Err(_) => {
+			summary.skipped += 1;
+			return Ok(());
+		}
+	};
+	let (node_one, node_two) =
+		match (PublicKey::from_str(&edge.node1_pub), PublicKey::from_str(&edge.node2_pub)) {
+			(Ok(one), Ok(two)) => (one, two),
+			_ => {
+				summary.skipped += 1;
+				return Ok(());
+			}
+		};
+	add_channel(scid, node_one, node_two, network_graph, db, source, summary)?;
+
+	for (direction, policy) in [(0u8, &edge.node1_policy), (1u8, &edge.node2_policy)] {
+		if let Some(policy) = policy {
+			let max_htlc = policy.max_htlc_msat.parse::<u64>().ok().filter(|max| *max > 0);
+			let update = UnsignedChannelUpdate {
+				chain_hash,
+				short_channel_id: scid,
+				timestamp: import_timestamp(policy.last_update),
+				flags: direction | if policy.disabled { 1 << 1 } else { 0 },
+				cltv_expiry_delta: policy.time_lock_delta,
+				htlc_minimum_msat: policy.min_htlc.parse().unwrap_or(0),
+				htlc_maximum_msat: match max_htlc {
+					Some(max) => OptionalField::Present(max),
+					None => OptionalField::Absent,
+				},
+				fee_base_msat: policy.fee_base_msat.parse().unwrap_or(0),
+				fee_proportional_millionths: policy.fee_rate_milli_msat.parse().unwrap_or(0),
+				excess_data: Vec::new(),
+			};
+			match network_graph.update_channel_unsigned(&update) {
+				Ok(_) => summary.channel_updates += 1,
+				Err(_) => summary.skipped += 1,
+			}
+		}
+	}
+	Ok(())
+}
+
+fn import_cln_channel(
+	channel: &ClnChannel, chain_hash: BlockHash, network_graph: &NetworkGraph, db: &Connection,
+	source: &str, summary: &mut ImportSummary,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let scid = parse_cln_scid(&channel.short_channel_id);
+	let keys = (PublicKey::from_str(&channel.source), PublicKey::from_str(&channel.destination));
+	let (scid, source_key, destination_key) = match (scid, keys) {
+		(Some(scid), (Ok(src), Ok(dst))) => (scid, src, dst),
+		_ => {
+			summary.skipped += 1;
+			return Ok(());
+		}
+	};
+	// listchannels reports each direction separately, so order the keys the way a
+	// channel_announcement would and work out which side this entry describes.
+	let (node_one, node_two) = if source_key.serialize() < destination_key.serialize() {
+		(source_key, destination_key)
+	} else {
+		(destination_key, source_key)
+	};
+	if !network_graph.read_only().channels().contains_key(&scid) {
+		add_channel(scid, node_one, node_two, network_graph, db, source, summary)?;
+	}
+
+	let direction = if source_key == node_one { 0 } else { 1 };
+	let update = UnsignedChannelUpdate {
+		chain_hash,
+		short_channel_id: scid,
+		timestamp: import_timestamp(channel.last_update),
+		flags: direction | if channel.active { 0 } else { 1 << 1 },
+		cltv_expiry_delta: channel.delay,
+		htlc_minimum_msat: cln_msat(&channel.htlc_minimum_msat).unwrap_or(0),
+		htlc_maximum_msat: match cln_msat(&channel.htlc_maximum_msat) {
+			Some(max) => OptionalField::Present(max),
+			None => OptionalField::Absent,
+		},
+		fee_base_msat: channel.base_fee_millisatoshi,
+		fee_proportional_millionths: channel.fee_per_millionth,
+		excess_data: Vec::new(),
+	};
+	match network_graph.update_channel_unsigned(&update) {
+		Ok(_) => summary.channel_updates += 1,
+		Err(_) => summary.skipped += 1,
+	}
+	Ok(())
+}
+
+fn add_channel(
+	scid: u64, node_one: PublicKey, node_two: PublicKey, network_graph: &NetworkGraph,
+	db: &Connection, source: &str, summary: &mut ImportSummary,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+	match network_graph.add_channel_from_partial_announcement(
+		scid,
+		now,
+		ChannelFeatures::empty(),
+		node_one,
+		node_two,
+	) {
+		Ok(_) => {
+			summary.channels += 1;
+			mark_unverified(db, "channel", &scid.to_string(), source)?;
+		}
+		// Already known, most likely from signed gossip which we'd rather keep.
+		Err(_) => summary.skipped += 1,
+	}
+	Ok(())
+}
+
+/// The parts of a node announcement that LND and CLN dumps have in common.
+struct ImportedNode<'a> {
+	pubkey: &'a str,
+	timestamp: u32,
+	alias: &'a str,
+	color: &'a str,
+	features: NodeFeatures,
+	addresses: Vec<NetAddress>,
+}
+
+fn import_node(
+	node: ImportedNode, network_graph: &NetworkGraph, db: &Connection, source: &str,
+	summary: &mut ImportSummary,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let node_id = match PublicKey::from_str(node.pubkey) {
+		Ok(node_id) => node_id,
+		Err(_) => {
+			summary.skipped += 1;
+			return Ok(());
+		}
+	};
+	let mut alias_bytes = [0; 32];
+	let alias_len = node.alias.len().min(32);
+	alias_bytes[..alias_len].copy_from_slice(&node.alias.as_bytes()[..alias_len]);
+	let mut rgb = [0; 3];
+	if let Ok(bytes) = hex::decode(node.color.trim_start_matches('#')) {
+		if bytes.len() == 3 {
+			rgb.copy_from_slice(&bytes);
+		}
+	}
+	let announcement = unsigned_node_announcement(
+		node.features,
+		node.timestamp,
+		node_id,
+		rgb,
+		alias_bytes,
+		&node.addresses,
+	)?;
+	match network_graph.update_node_from_unsigned_announcement(&announcement) {
+		Ok(_) => {
+			summary.nodes += 1;
+			mark_unverified(db, "node", node.pubkey, source)?;
+		}
+		Err(_) => summary.skipped += 1,
+	}
+	Ok(())
+}
+
+/// `UnsignedNodeAnnouncement` keeps its excess data fields private, so build it by reading back
+/// the wire encoding rather than as a struct literal.
+pub(crate) fn unsigned_node_announcement(
+	features: NodeFeatures, timestamp: u32, node_id: PublicKey, rgb: [u8; 3], alias: [u8; 32],
+	addresses: &[NetAddress],
+) -> Result<UnsignedNodeAnnouncement, Box<dyn std::error::Error>> {
+	let mut encoded_addresses = Vec::new();
+	for address in addresses {
+		encoded_addresses.extend_from_slice(&address.encode());
+	}
+	let mut bytes = features.encode();
+	bytes.extend_from_slice(&timestamp.encode());
+	bytes.extend_from_slice(&node_id.serialize());
+	bytes.extend_from_slice(&rgb);
+	bytes.extend_from_slice(&alias);
+	bytes.extend_from_slice(&(encoded_addresses.len() as u16).encode());
+	bytes.extend_from_slice(&encoded_addresses);
+	UnsignedNodeAnnouncement::read(&mut Cursor::new(bytes))
+		.map_err(|e| format!("couldn't build node announcement: {:?}", e).into())
+}
+
+fn mark_unverified(
+	db: &Connection, kind: &str, id: &str, source: &str,
+) -> Result<(), rusqlite::Error> {
+	db.execute(
+		"INSERT INTO unverified_graph_entry (kind, id, source, date_imported)
+            VALUES (?1, ?2, ?3, ?4)",
+		params![kind, id, source, Utc::now().naive_utc()],
+	)?;
+	Ok(())
+}
+
+fn import_timestamp(timestamp: u32) -> u32 {
+	let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+	(timestamp as u64).max(now - MAX_IMPORT_AGE_SECS) as u32
+}
+
+/// LND lists features as a map keyed by bit number.
+fn lnd_features(features: &HashMap<String, serde_json::Value>) -> NodeFeatures {
+	let mut flags: Vec<u8> = Vec::new();
+	for bit in features.keys().filter_map(|bit| bit.parse::<usize>().ok()) {
+		if flags.len() <= bit / 8 {
+			flags.resize(bit / 8 + 1, 0);
+		}
+		flags[bit / 8] |= 1 << (bit % 8);
+	}
+	NodeFeatures::from_le_bytes(flags)
+}
+
+/// CLN lists features as big-endian hex.
+fn cln_features(features: &str) -> NodeFeatures {
+	let mut flags = hex::decode(features).unwrap_or_default();
+	flags.reverse();
+	NodeFeatures::from_le_bytes(flags)
+}
+
+/// CLN reports amounts either as plain numbers or as strings like `"1000msat"`.
+fn cln_msat(value: &serde_json::Value) -> Option<u64> {
+	match value {
+		serde_json::Value::Number(n) => n.as_u64(),
+		serde_json::Value::String(s) => s.trim_end_matches("msat").parse().ok(),
+		_ => None,
+	}
+}
+
+/// Parses a short channel id that is either a number or in CLN's format.
+pub(crate) fn parse_scid(scid: &str) -> Option<u64> {
+	match scid.parse::<u64>() {
+		Ok(scid) => Some(scid),
+		Err(_) => parse_cln_scid(scid),
+	}
+}
+
+/// Parses CLN's `<block>x<tx_index>x<vout>` short channel id format.
+fn parse_cln_scid(scid: &str) -> Option<u64> {
+	let mut parts = scid.split('x').map(|part| part.parse::<u64>().ok());
+	match (parts.next(), parts.next(), parts.next(), parts.next()) {
+		(Some(Some(block)), Some(Some(tx_index)), Some(Some(vout)), None)
+			if block <= MAX_SCID_BLOCK
+				&& tx_index <= MAX_SCID_TX_INDEX
+				&& vout <= MAX_SCID_VOUT_INDEX =>
+		{
+			Some(scid_from_parts(block, tx_index, vout))
+		}
+		_ => None,
+	}
+}
+
+fn format_host_port(address: &str, port: u16) -> String {
+	if address.contains(':') {
+		format!("[{}]:{}", address, port)
+	} else {
+		format!("{}:{}", address, port)
+	}
+}
+
+fn net_address(addr: &str) -> Option<NetAddress> {
+	match SocketAddr::from_str(addr) {
+		Ok(SocketAddr::V4(a)) => Some(NetAddress::IPv4 { addr: a.ip().octets(), port: a.port() }),
+		Ok(SocketAddr::V6(a)) => Some(NetAddress::IPv6 { addr: a.ip().octets(), port: a.port() }),
+		// Tor and DNS addresses aren't needed for probing.
+		Err(_) => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::create_tables;
+	use crate::test_utils::{network_graph, pubkey, TempDir};
+	use lightning::routing::gossip::NodeId;
+
+	/// `a` and `b` in the order of a channel announcement.
+	fn ordered(a: PublicKey, b: PublicKey) -> (PublicKey, PublicKey) {
+		if a.serialize() < b.serialize() {
+			(a, b)
+		} else {
+			(b, a)
+		}
+	}
+
+	fn import(
+		format: &str, dump: &str, network_graph: &NetworkGraph, db: &Connection,
+	) -> Result<ImportSummary, Box<dyn std::error::Error>> {
+		let dir = TempDir::new("graph-import");
+		let path = format!("{}/dump.json", dir.path());
+		fs::write(&path, dump).unwrap();
+		import_graph(format, &path, Network::Regtest, network_graph, db)
+	}
+
+	fn unverified(db: &Connection, kind: &str) -> usize {
+		db.query_row(
+			"SELECT COUNT(*) FROM unverified_graph_entry WHERE kind = ?1",
+			params![kind],
+			|row| row.get(0),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn test_parse_scid() {
+		let scid = scid_from_parts(700_000, 1234, 1);
+		assert_eq!(parse_scid("700000x1234x1"), Some(scid));
+		assert_eq!(parse_scid(&scid.to_string()), Some(scid));
+		for invalid in ["", "700000x1234", "700000x1234x1x", "x700000x1234x1", "700000xax1234x1"] {
+			assert_eq!(parse_scid(invalid), None, "{}", invalid);
+		}
+		assert_eq!(parse_scid("16777216x0x0"), None);
+		assert_eq!(parse_scid("0x16777216x0"), None);
+		assert_eq!(parse_scid("0x0x65536"), None);
+	}
+
+	#[test]
+	fn test_import_lnd() {
+		let data_dir = TempDir::new("graph-import");
+		let network_graph = network_graph(&data_dir);
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+
+		let (one, two) = ordered(pubkey(1), pubkey(2));
+		let scid = scid_from_parts(700_000, 1, 0);
+		let dump = format!(
+			r##"{{
+				"nodes": [{{
+					"pub_key": "{one}", "alias": "one", "color": "#3399ff",
+					"addresses": [{{"addr": "1.2.3.4:9735"}}, {{"addr": "abc.onion:9735"}}],
+					"features": {{"9": {{"name": "tlv-onion"}}}}
+				}}, {{"pub_key": "not-a-node"}}],
+				"edges": [{{
+					"channel_id": "{scid}", "node1_pub": "{one}", "node2_pub": "{two}",
+					"node1_policy": {{
+						"time_lock_delta": 40, "min_htlc": "1000", "max_htlc_msat": "990000000",
+						"fee_base_msat": "1000", "fee_rate_milli_msat": "1"
+					}},
+					"node2_policy": {{
+						"time_lock_delta": 144, "min_htlc": "1", "max_htlc_msat": "0",
+						"fee_base_msat": "0", "fee_rate_milli_msat": "100", "disabled": true
+					}}
+				}}, {{
+					"channel_id": "700000x1x1", "node1_pub": "{one}", "node2_pub": "{two}"
+				}}]
+			}}"##,
+			one = one,
+			two = two,
+			scid = scid
+		);
+		let summary = import("lnd", &dump, &network_graph, &db).unwrap();
+		assert_eq!(
+			(summary.channels, summary.channel_updates, summary.nodes, summary.skipped),
+			(1, 2, 1, 2)
+		);
+		assert_eq!((unverified(&db, "channel"), unverified(&db, "node")), (1, 1));
+
+		let graph = network_graph.read_only();
+		let channel = &graph.channels()[&scid];
+		assert_eq!(channel.node_one, NodeId::from_pubkey(&one));
+		let one_to_two = channel.one_to_two.as_ref().unwrap();
+		assert!(one_to_two.enabled);
+		assert_eq!(one_to_two.cltv_expiry_delta, 40);
+		assert_eq!(one_to_two.htlc_minimum_msat, 1000);
+		assert_eq!(one_to_two.htlc_maximum_msat, Some(990_000_000));
+		assert_eq!((one_to_two.fees.base_msat, one_to_two.fees.proportional_millionths), (1000, 1));
+		let two_to_one = channel.two_to_one.as_ref().unwrap();
+		assert!(!two_to_one.enabled);
+		assert_eq!(two_to_one.cltv_expiry_delta, 144);
+		assert_eq!(two_to_one.htlc_maximum_msat, None);
+		assert_eq!(two_to_one.fees.proportional_millionths, 100);
+
+		let node = &graph.nodes()[&NodeId::from_pubkey(&one)];
+		let announcement = node.announcement_info.as_ref().unwrap();
+		assert_eq!(&announcement.alias.0[..4], b"one\0");
+		assert_eq!(announcement.rgb, [0x33, 0x99, 0xff]);
+		assert_eq!(
+			announcement.addresses,
+			vec![NetAddress::IPv4 { addr: [1, 2, 3, 4], port: 9735 }]
+		);
+		assert!(announcement.features.supports_variable_length_onion());
+	}
+
+	#[test]
+	fn test_import_cln() {
+		let data_dir = TempDir::new("graph-import");
+		let network_graph = network_graph(&data_dir);
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+
+		let (one, two) = ordered(pubkey(1), pubkey(2));
+		let scid = scid_from_parts(700_000, 1, 0);
+		// listchannels has an entry per direction, the second one naming node two as source
+		let channels = format!(
+			r#"{{"channels": [{{
+				"source": "{one}", "destination": "{two}", "short_channel_id": "700000x1x0",
+				"active": true, "base_fee_millisatoshi": 1000, "fee_per_millionth": 1,
+				"delay": 40, "htlc_minimum_msat": "1000msat", "htlc_maximum_msat": 990000000
+			}}, {{
+				"source": "{two}", "destination": "{one}", "short_channel_id": "700000x1x0",
+				"active": false, "base_fee_millisatoshi": 0, "fee_per_millionth": 100,
+				"delay": 144
+			}}, {{
+				"source": "{one}", "destination": "{two}", "short_channel_id": "700000x1x0x"
+			}}]}}"#,
+			one = one,
+			two = two
+		);
+		let summary = import("cln", &channels, &network_graph, &db).unwrap();
+		assert_eq!((summary.channels, summary.channel_updates, summary.skipped), (1, 2, 1));
+
+		let nodes = format!(
+			r#"{{"nodes": [{{
+				"nodeid": "{two}", "alias": "two", "color": "ff0000", "features": "0200",
+				"addresses": [
+					{{"type": "ipv4", "address": "1.2.3.4", "port": 9735}},
+					{{"type": "ipv6", "address": "::1", "port": 9736}},
+					{{"type": "torv3", "address": "abc.onion", "port": 9735}}
+				]
+			}}]}}"#,
+			two = two
+		);
+		let summary = import("cln", &nodes, &network_graph, &db).unwrap();
+		assert_eq!((summary.nodes, summary.skipped), (1, 0));
+		assert_eq!((unverified(&db, "channel"), unverified(&db, "node")), (1, 1));
+
+		let graph = network_graph.read_only();
+		let channel = &graph.channels()[&scid];
+		let one_to_two = channel.one_to_two.as_ref().unwrap();
+		assert!(one_to_two.enabled);
+		assert_eq!(one_to_two.htlc_minimum_msat, 1000);
+		assert_eq!(one_to_two.htlc_maximum_msat, Some(990_000_000));
+		let two_to_one = channel.two_to_one.as_ref().unwrap();
+		assert!(!two_to_one.enabled);
+		assert_eq!(two_to_one.cltv_expiry_delta, 144);
+		assert_eq!(two_to_one.fees.proportional_millionths, 100);
+
+		let node = &graph.nodes()[&NodeId::from_pubkey(&two)];
+		let announcement = node.announcement_info.as_ref().unwrap();
+		assert_eq!(announcement.rgb, [0xff, 0, 0]);
+		assert_eq!(announcement.addresses.len(), 2);
+		assert!(announcement.features.supports_variable_length_onion());
+	}
+
+	#[test]
+	fn test_import_errors() {
+		let data_dir = TempDir::new("graph-import");
+		let network_graph = network_graph(&data_dir);
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+
+		assert!(import("eclair", "{}", &network_graph, &db).is_err());
+		assert!(import("cln", r#"{"peers": []}"#, &network_graph, &db).is_err());
+		assert!(import("lnd", "not json", &network_graph, &db).is_err());
+	}
+}
@@ -0,0 +1,84 @@
+use crate::cli::Node;
+use crate::denylist::Denylist;
+use crate::disk::FilesystemLogger;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, SignOnly};
+use lightning::util::logger::Logger;
+use lightning::{log_given_level, log_info, log_internal};
+use rand::Rng;
+use std::fs;
+use std::str::FromStr;
+
+/// How the node at the far end of a probed channel is picked. The guessed node is the payee
+/// every forwarding node on the way sees, so always using the same one makes our probes easy to
+/// spot.
+pub(crate) enum GuessStrategy {
+	/// A fresh random key for every probe.
+	Random(Secp256k1<SignOnly>),
+	Fixed(PublicKey),
+	/// A random pick from likely counterparties, a probe that guesses right confirms the
+	/// counterparty along with the channel.
+	Candidates(Vec<PublicKey>),
+}
+
+impl GuessStrategy {
+	/// Parses `random`, `fixed:<pubkey>` or `list:<nodefile>`, where the node file has the same
+	/// format as the one `probeall` takes. Nodes on the denylist are never guessed.
+	pub(crate) fn parse(
+		arg: &str, denylist: &Denylist, logger: &FilesystemLogger,
+	) -> Result<GuessStrategy, Box<dyn std::error::Error>> {
+		if arg == "random" {
+			return Ok(GuessStrategy::Random(Secp256k1::signing_only()));
+		}
+		if let Some(pubkey) = arg.strip_prefix("fixed:") {
+			if denylist.is_denied_node(pubkey) {
+				return Err(format!("{} is on the denylist", pubkey).into());
+			}
+			return Ok(GuessStrategy::Fixed(PublicKey::from_str(pubkey)?));
+		}
+		if let Some(path) = arg.strip_prefix("list:") {
+			let nodes: Vec<Node> = serde_json::from_str(&fs::read_to_string(path)?)?;
+			let mut candidates = Vec::new();
+			for node in nodes {
+				if denylist.is_denied_node(&node.pubkey) {
+					log_info!(logger, "Not guessing {}, it is on the denylist", node.pubkey);
+					continue;
+				}
+				candidates.push(PublicKey::from_str(&node.pubkey)?);
+			}
+			if candidates.is_empty() {
+				return Err(format!("no candidates in {}", path).into());
+			}
+			return Ok(GuessStrategy::Candidates(candidates));
+		}
+		Err(format!(
+			"unknown guess strategy `{}`, expected random, fixed:<pubkey> or list:<file>",
+			arg
+		)
+		.into())
+	}
+
+	pub(crate) fn name(&self) -> &'static str {
+		match self {
+			GuessStrategy::Random(_) => "random",
+			GuessStrategy::Fixed(_) => "fixed",
+			GuessStrategy::Candidates(_) => "candidate",
+		}
+	}
+
+	pub(crate) fn next_guess(&self) -> PublicKey {
+		self.next_guess_from(&mut rand::thread_rng())
+	}
+
+	/// The next guess, with any randomness drawn from `rng`.
+	pub(crate) fn next_guess_from<R: Rng>(&self, rng: &mut R) -> PublicKey {
+		match self {
+			GuessStrategy::Random(secp_ctx) => loop {
+				if let Ok(secret) = SecretKey::from_slice(&rng.gen::<[u8; 32]>()) {
+					return PublicKey::from_secret_key(secp_ctx, &secret);
+				}
+			},
+			GuessStrategy::Fixed(pubkey) => *pubkey,
+			GuessStrategy::Candidates(candidates) => candidates[rng.gen_range(0, candidates.len())],
+		}
+	}
+}
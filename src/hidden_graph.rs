@@ -0,0 +1,497 @@
+use crate::cli::{get_attempts, Attempt};
+use crate::disk::FilesystemLogger;
+use crate::probe::{GUESSED_CLTV_EXPIRY_DELTA, GUESSED_FEES};
+use crate::NetworkGraph;
+use bitcoin::secp256k1::PublicKey;
+use chrono::{NaiveDateTime, Utc};
+use lightning::ln::channelmanager::ChannelDetails;
+use lightning::ln::msgs::{LightningError, OptionalField, UnsignedChannelUpdate};
+use lightning::ln::PaymentHash;
+use lightning::routing::gossip::{NodeId, RoutingFees};
+use lightning::routing::router::{Route, RouteHint, RouteHintHop, RouteHop, RouteParameters};
+use lightning::routing::scoring::Score;
+use lightning_invoice::payment::Router;
+use lightning_invoice::utils::DefaultRouter;
+use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// How many private hops a route hint may walk back from the payee before it has to reach a
+/// node in the public graph.
+const MAX_HIDDEN_HOPS: usize = 2;
+
+/// A forwarding policy learned from a `channel_update` returned in a probe failure.
+#[derive(Clone, Debug)]
+pub(crate) struct HiddenPolicy {
+	pub(crate) fees: RoutingFees,
+	pub(crate) cltv_expiry_delta: u16,
+	pub(crate) htlc_minimum_msat: u64,
+	pub(crate) htlc_maximum_msat: Option<u64>,
+	pub(crate) last_update: u32,
+}
+
+/// A private channel that one of our probes found behind a target node.
+#[derive(Clone, Debug)]
+pub(crate) struct DiscoveredChannel {
+	pub(crate) target_pubkey: String,
+	pub(crate) short_channel_id: u64,
+	/// The node on the other side, if a probe with the right guessed pubkey reached it.
+	pub(crate) counterparty: Option<String>,
+	pub(crate) date_found: NaiveDateTime,
+	/// Set when a later probe of the same channel came back `unknown_next_peer`.
+	pub(crate) closed: bool,
+	/// The target's policy for forwarding over this channel, if it ever told us.
+	pub(crate) policy: Option<HiddenPolicy>,
+}
+
+/// Whether a probe result means the target has a channel with the probed short channel id.
+pub(crate) fn is_channel_found(result: &str) -> bool {
+	result != "unknown" && result != "unknown_next_peer"
+}
+
+/// Whether a probe result came from the guessed node itself, which makes it the target's
+/// counterparty on the channel.
+pub(crate) fn is_counterparty_confirmed(result: &str) -> bool {
+	result == "incorrect_or_unknown_payment_details" || result == "required_node_feature_missing"
+}
+
+/// Collapses every probe attempt into one entry per discovered target/channel pair.
+pub(crate) fn discovered_channels(attempts: &[Attempt]) -> Vec<DiscoveredChannel> {
+	let mut sorted: Vec<&Attempt> = attempts.iter().collect();
+	sorted.sort_by_key(|attempt| attempt.date_found);
+
+	let mut channels = BTreeMap::new();
+	for attempt in sorted {
+		apply_attempt(&mut channels, attempt);
+	}
+	channels.into_values().collect()
+}
+
+fn apply_attempt(channels: &mut BTreeMap<(String, u64), DiscoveredChannel>, attempt: &Attempt) {
+	let scid = match attempt.channel_id.parse::<u64>() {
+		Ok(scid) => scid,
+		Err(_) => return,
+	};
+	let key = (attempt.target_pubkey.clone(), scid);
+	if is_channel_found(&attempt.result) {
+		let channel = channels.entry(key).or_insert_with(|| DiscoveredChannel {
+			target_pubkey: attempt.target_pubkey.clone(),
+			short_channel_id: scid,
+			counterparty: None,
+			date_found: attempt.date_found,
+			closed: false,
+			policy: None,
+		});
+		if is_counterparty_confirmed(&attempt.result) {
+			channel.counterparty = Some(attempt.guess_pubkey.clone());
+		}
+		channel.closed = false;
+	} else if attempt.result == "unknown_next_peer" {
+		if let Some(channel) = channels.get_mut(&key) {
+			channel.closed = true;
+		}
+	}
+}
+
+/// An overlay of the private channels we've discovered, kept next to the public
+/// `NetworkGraph` so that the router can reach nodes behind them.
+pub(crate) struct HiddenGraph {
+	channels: RwLock<BTreeMap<(String, u64), DiscoveredChannel>>,
+}
+
+impl HiddenGraph {
+	/// Rebuilds the overlay from the recorded attempts and learned policies.
+	pub(crate) fn read(db: &Connection) -> Result<HiddenGraph, Box<dyn std::error::Error>> {
+		let mut channels = BTreeMap::new();
+		for channel in discovered_channels(&get_attempts(db)?) {
+			channels.insert((channel.target_pubkey.clone(), channel.short_channel_id), channel);
+		}
+
+		let mut stmt = db.prepare(
+			"SELECT short_channel_id, node_pubkey, fee_base_msat, fee_proportional_millionths,
+                cltv_expiry_delta, htlc_minimum_msat, htlc_maximum_msat, last_update
+            FROM hidden_channel_policy ORDER BY last_update",
+		)?;
+		let mut rows = stmt.query([])?;
+		while let Some(row) = rows.next()? {
+			let scid: String = row.get(0)?;
+			let node_pubkey: String = row.get(1)?;
+			let scid = match scid.parse::<u64>() {
+				Ok(scid) => scid,
+				Err(_) => continue,
+			};
+			if let Some(channel) = channels.get_mut(&(node_pubkey, scid)) {
+				channel.policy = Some(HiddenPolicy {
+					fees: RoutingFees {
+						base_msat: row.get(2)?,
+						proportional_millionths: row.get(3)?,
+					},
+					cltv_expiry_delta: row.get(4)?,
+					htlc_minimum_msat: row.get::<_, i64>(5)? as u64,
+					htlc_maximum_msat: row.get::<_, Option<i64>>(6)?.map(|max| max as u64),
+					last_update: row.get(7)?,
+				});
+			}
+		}
+		Ok(HiddenGraph { channels: RwLock::new(channels) })
+	}
+
+	pub(crate) fn channels(&self) -> Vec<DiscoveredChannel> {
+		self.channels.read().unwrap().values().cloned().collect()
+	}
+
+	/// Folds a freshly recorded attempt into the overlay.
+	pub(crate) fn record_attempt(&self, attempt: &Attempt) {
+		apply_attempt(&mut self.channels.write().unwrap(), attempt);
+	}
+
+	/// Stores the policy from a `channel_update` that `forwarding_node` sent back about one of its
+	/// private channels. Returns whether it was new to us, updates for channels we haven't
+	/// discovered or that we've already seen are ignored.
+	pub(crate) fn learn_policy(
+		&self, db: &Connection, forwarding_node: &PublicKey, update: &UnsignedChannelUpdate,
+	) -> Result<bool, rusqlite::Error> {
+		let key = (forwarding_node.to_string(), update.short_channel_id);
+		let mut channels = self.channels.write().unwrap();
+		let channel = match channels.get_mut(&key) {
+			Some(channel) => channel,
+			None => return Ok(false),
+		};
+		if let Some(policy) = &channel.policy {
+			if policy.last_update >= update.timestamp {
+				return Ok(false);
+			}
+		}
+		let policy = HiddenPolicy {
+			fees: RoutingFees {
+				base_msat: update.fee_base_msat,
+				proportional_millionths: update.fee_proportional_millionths,
+			},
+			cltv_expiry_delta: update.cltv_expiry_delta,
+			htlc_minimum_msat: update.htlc_minimum_msat,
+			htlc_maximum_msat: match update.htlc_maximum_msat {
+				OptionalField::Present(max) => Some(max),
+				OptionalField::Absent => None,
+			},
+			last_update: update.timestamp,
+		};
+		db.execute(
+			"INSERT INTO hidden_channel_policy (
+                short_channel_id, node_pubkey, fee_base_msat, fee_proportional_millionths,
+                cltv_expiry_delta, htlc_minimum_msat, htlc_maximum_msat, last_update, date_learned)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+			params![
+				update.short_channel_id.to_string(),
+				forwarding_node.to_string(),
+				policy.fees.base_msat,
+				policy.fees.proportional_millionths,
+				policy.cltv_expiry_delta,
+				policy.htlc_minimum_msat as i64,
+				policy.htlc_maximum_msat.map(|max| max as i64),
+				policy.last_update,
+				Utc::now().naive_utc(),
+			],
+		)?;
+		channel.policy = Some(policy);
+		Ok(true)
+	}
+
+	/// The most recent policy `target` sent back for any of its private channels.
+	pub(crate) fn learned_policy(&self, target: &str) -> Option<HiddenPolicy> {
+		self.channels
+			.read()
+			.unwrap()
+			.values()
+			.filter(|channel| channel.target_pubkey == target)
+			.filter_map(|channel| channel.policy.clone())
+			.max_by_key(|policy| policy.last_update)
+	}
+
+	/// Route hints reaching `payee` over discovered channels. Hints walk back through private
+	/// nodes until they reach one in the public graph, at most `MAX_HIDDEN_HOPS` deep.
+	pub(crate) fn route_hints_to(
+		&self, payee: &PublicKey, network_graph: &NetworkGraph,
+	) -> Vec<RouteHint> {
+		let channels = self.channels.read().unwrap();
+		let mut hints = Vec::new();
+		let mut partial: Vec<Vec<RouteHintHop>> = vec![vec![]];
+		for _ in 0..MAX_HIDDEN_HOPS {
+			let mut next = Vec::new();
+			for hops in partial {
+				let towards = match hops.first() {
+					Some(hop) => hop.src_node_id,
+					None => *payee,
+				};
+				for channel in open_channels_to(&channels, &towards) {
+					let hop = match route_hint_hop(channel) {
+						Some(hop) => hop,
+						None => continue,
+					};
+					// Don't walk in circles.
+					if hop.src_node_id == *payee
+						|| hops.iter().any(|h| h.src_node_id == hop.src_node_id)
+					{
+						continue;
+					}
+					let mut extended = vec![hop];
+					extended.extend(hops.iter().cloned());
+					if is_public(network_graph, &extended[0].src_node_id) {
+						hints.push(RouteHint(extended));
+					} else {
+						next.push(extended);
+					}
+				}
+			}
+			partial = next;
+		}
+		hints
+	}
+
+	/// Index of the first hop in a probe's `path` that went over a discovered private channel.
+	/// Equals the index of the final, guessed hop when the probe didn't go through any.
+	pub(crate) fn hidden_path_start(&self, path: &[RouteHop]) -> usize {
+		let channels = self.channels.read().unwrap();
+		let mut start = path.len().saturating_sub(1);
+		while start >= 2 {
+			let key = (path[start - 2].pubkey.to_string(), path[start - 1].short_channel_id);
+			if !channels.contains_key(&key) {
+				break;
+			}
+			start -= 1;
+		}
+		start
+	}
+
+	/// Private nodes we know a route hint to, i.e. the counterparties of discovered channels
+	/// that are not in the public graph. Probing these maps the clusters behind them.
+	pub(crate) fn private_nodes(&self, network_graph: &NetworkGraph) -> Vec<PublicKey> {
+		let mut nodes: Vec<PublicKey> = self
+			.channels
+			.read()
+			.unwrap()
+			.values()
+			.filter(|channel| !channel.closed)
+			.filter_map(|channel| channel.counterparty.as_ref())
+			.filter_map(|counterparty| PublicKey::from_str(counterparty).ok())
+			.filter(|node| !is_public(network_graph, node))
+			.collect();
+		nodes.sort();
+		nodes.dedup();
+		nodes.retain(|node| self.hops_to(node, network_graph).is_some());
+		nodes
+	}
+
+	/// The discovered hops leading from a public node to the private `node`, if we know any.
+	pub(crate) fn hops_to(
+		&self, node: &PublicKey, network_graph: &NetworkGraph,
+	) -> Option<Vec<RouteHintHop>> {
+		if is_public(network_graph, node) {
+			return Some(vec![]);
+		}
+		self.route_hints_to(node, network_graph)
+			.into_iter()
+			.map(|hint| hint.0)
+			.min_by_key(|h| h.len())
+	}
+}
+
+/// Writes `hops` as `<entry> -[scid]-> <node> ... -[scid]-> <guess>`, where the first hop is
+/// only there for the node the hidden part was entered from.
+pub(crate) fn format_hidden_path(hops: &[RouteHop]) -> String {
+	let mut path = match hops.first() {
+		Some(entry) => entry.pubkey.to_string(),
+		None => return String::new(),
+	};
+	for hop in &hops[1..] {
+		path.push_str(&format!(" -[{}]-> {}", hop.short_channel_id, hop.pubkey));
+	}
+	path
+}
+
+fn open_channels_to<'a>(
+	channels: &'a BTreeMap<(String, u64), DiscoveredChannel>, node: &PublicKey,
+) -> impl Iterator<Item = &'a DiscoveredChannel> {
+	let node = node.to_string();
+	channels
+		.values()
+		.filter(move |channel| !channel.closed && channel.counterparty.as_ref() == Some(&node))
+}
+
+fn route_hint_hop(channel: &DiscoveredChannel) -> Option<RouteHintHop> {
+	let src_node_id = PublicKey::from_str(&channel.target_pubkey).ok()?;
+	Some(match &channel.policy {
+		Some(policy) => RouteHintHop {
+			src_node_id,
+			short_channel_id: channel.short_channel_id,
+			fees: policy.fees,
+			cltv_expiry_delta: policy.cltv_expiry_delta,
+			htlc_minimum_msat: Some(policy.htlc_minimum_msat),
+			htlc_maximum_msat: policy.htlc_maximum_msat,
+		},
+		None => RouteHintHop {
+			src_node_id,
+			short_channel_id: channel.short_channel_id,
+			fees: GUESSED_FEES,
+			cltv_expiry_delta: GUESSED_CLTV_EXPIRY_DELTA,
+			htlc_minimum_msat: None,
+			htlc_maximum_msat: None,
+		},
+	})
+}
+
+fn is_public(network_graph: &NetworkGraph, node: &PublicKey) -> bool {
+	network_graph.read_only().nodes().contains_key(&NodeId::from_pubkey(node))
+}
+
+/// Wraps the `DefaultRouter` so that payments made through the `InvoicePayer` can use the
+/// hidden graph to reach private nodes.
+pub(crate) struct HiddenRouter {
+	router: DefaultRouter<Arc<NetworkGraph>, Arc<FilesystemLogger>>,
+	network_graph: Arc<NetworkGraph>,
+	hidden_graph: Arc<HiddenGraph>,
+}
+
+impl HiddenRouter {
+	pub(crate) fn new(
+		network_graph: Arc<NetworkGraph>, hidden_graph: Arc<HiddenGraph>,
+		logger: Arc<FilesystemLogger>, random_seed_bytes: [u8; 32],
+	) -> Self {
+		let router = DefaultRouter::new(network_graph.clone(), logger, random_seed_bytes);
+		HiddenRouter { router, network_graph, hidden_graph }
+	}
+}
+
+impl<S: Score> Router<S> for HiddenRouter {
+	fn find_route(
+		&self, payer: &PublicKey, route_params: &RouteParameters, payment_hash: &PaymentHash,
+		first_hops: Option<&[&ChannelDetails]>, scorer: &S,
+	) -> Result<Route, LightningError> {
+		let mut route_params = route_params.clone();
+		let payee = route_params.payment_params.payee_pubkey;
+		route_params
+			.payment_params
+			.route_hints
+			.extend(self.hidden_graph.route_hints_to(&payee, &self.network_graph));
+		self.router.find_route(payer, &route_params, payment_hash, first_hops, scorer)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::create_tables;
+	use crate::probe::{probe_result, scid_from_parts};
+	use crate::test_utils::{add_public_channel, network_graph, pubkey, TempDir};
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+
+	fn attempt(guess: &PublicKey, scid: u64, result: &str) -> Attempt {
+		attempt_at(&pubkey(1), guess, scid, result)
+	}
+
+	fn attempt_at(target: &PublicKey, guess: &PublicKey, scid: u64, result: &str) -> Attempt {
+		Attempt {
+			target_pubkey: target.to_string(),
+			guess_pubkey: guess.to_string(),
+			channel_id: scid.to_string(),
+			result: result.to_string(),
+			date_found: Utc::now().naive_utc(),
+			hidden_path: None,
+			guess_strategy: None,
+		}
+	}
+
+	#[test]
+	fn test_required_node_feature_missing_only_counts_from_the_guess() {
+		let from_guess = probe_result(0x6003, true);
+		let from_target = probe_result(0x6003, false);
+		assert_eq!(from_guess, "required_node_feature_missing");
+		assert_eq!(from_target, "unknown");
+
+		let found = scid_from_parts(700_000, 1, 0);
+		let not_found = scid_from_parts(700_000, 2, 0);
+		let channels = discovered_channels(&[
+			attempt(&pubkey(2), found, from_guess),
+			attempt(&pubkey(3), not_found, from_target),
+		]);
+		assert_eq!(channels.len(), 1);
+		assert_eq!(channels[0].short_channel_id, found);
+		assert_eq!(channels[0].counterparty, Some(pubkey(2).to_string()));
+	}
+
+	#[test]
+	fn test_route_hints_to() {
+		let data_dir = TempDir::new("hidden-graph");
+		let network_graph = network_graph(&data_dir);
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+		let hidden_graph = HiddenGraph::read(&db).unwrap();
+
+		// 1 is public, 2, 3 and 4 are private and hang off it one behind the other
+		let (public, two, three, four) = (pubkey(1), pubkey(2), pubkey(3), pubkey(4));
+		add_public_channel(&network_graph, scid_from_parts(600_000, 1, 0), public, pubkey(9));
+		let scid = |tx_index| scid_from_parts(700_000, tx_index, 0);
+		let confirmed = "incorrect_or_unknown_payment_details";
+		for attempt in [
+			attempt_at(&public, &two, scid(1), confirmed),
+			attempt_at(&two, &three, scid(2), confirmed),
+			attempt_at(&three, &four, scid(3), confirmed),
+			// back from 3 to 2, which mustn't send hints to 2 in circles
+			attempt_at(&three, &two, scid(4), confirmed),
+			// found, but without knowing where it leads
+			attempt_at(&public, &pubkey(5), scid(5), "invalid_onion_hmac"),
+			// found and closed since
+			attempt_at(&public, &three, scid(6), confirmed),
+			attempt_at(&public, &three, scid(6), "unknown_next_peer"),
+		] {
+			hidden_graph.record_attempt(&attempt);
+		}
+
+		let hints = |payee: &PublicKey| -> Vec<Vec<(PublicKey, u64)>> {
+			hidden_graph
+				.route_hints_to(payee, &network_graph)
+				.into_iter()
+				.map(|hint| {
+					hint.0.iter().map(|hop| (hop.src_node_id, hop.short_channel_id)).collect()
+				})
+				.collect()
+		};
+		assert_eq!(hints(&two), vec![vec![(public, scid(1))]]);
+		assert_eq!(hints(&three), vec![vec![(public, scid(1)), (two, scid(2))]]);
+		// more than `MAX_HIDDEN_HOPS` away from the public graph
+		assert!(hints(&four).is_empty());
+		assert!(hints(&pubkey(5)).is_empty());
+
+		assert_eq!(hidden_graph.hops_to(&public, &network_graph).unwrap().len(), 0);
+		assert_eq!(hidden_graph.hops_to(&three, &network_graph).unwrap().len(), 2);
+		assert!(hidden_graph.hops_to(&four, &network_graph).is_none());
+		let mut private_nodes = vec![two, three];
+		private_nodes.sort();
+		assert_eq!(hidden_graph.private_nodes(&network_graph), private_nodes);
+
+		// hops use the guessed policy until the target tells us its own
+		let hop = &hidden_graph.route_hints_to(&two, &network_graph)[0].0[0];
+		assert_eq!(hop.fees, GUESSED_FEES);
+		assert_eq!(hop.cltv_expiry_delta, GUESSED_CLTV_EXPIRY_DELTA);
+		let update = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Regtest).header.block_hash(),
+			short_channel_id: scid(1),
+			timestamp: 1,
+			flags: 0,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000,
+			htlc_maximum_msat: OptionalField::Present(500_000_000),
+			fee_base_msat: 0,
+			fee_proportional_millionths: 100,
+			excess_data: Vec::new(),
+		};
+		assert!(hidden_graph.learn_policy(&db, &public, &update).unwrap());
+		assert!(!hidden_graph.learn_policy(&db, &public, &update).unwrap());
+		let hop = &hidden_graph.route_hints_to(&two, &network_graph)[0].0[0];
+		assert_eq!(hop.fees, RoutingFees { base_msat: 0, proportional_millionths: 100 });
+		assert_eq!(hop.cltv_expiry_delta, 144);
+		assert_eq!(hop.htlc_minimum_msat, Some(1000));
+		assert_eq!(hop.htlc_maximum_msat, Some(500_000_000));
+	}
+}
@@ -0,0 +1,255 @@
+use crate::hidden_graph::HiddenGraph;
+use crate::probe::{GUESSED_CLTV_EXPIRY_DELTA, GUESSED_FEES};
+use crate::NetworkGraph;
+use bitcoin::secp256k1::PublicKey;
+use lightning::ln::features::NodeFeatures;
+use lightning::routing::gossip::{NodeId, RoutingFees};
+use lightning::util::ser::Writeable;
+use std::collections::HashMap;
+
+/// The policy we put on the guessed hop of a probe. The target checks it against its real
+/// policy for the channel, so the closer it is the more probes make it to the guessed node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct HintPolicy {
+	pub(crate) fees: RoutingFees,
+	pub(crate) cltv_expiry_delta: u16,
+	pub(crate) htlc_minimum_msat: u64,
+}
+
+impl Default for HintPolicy {
+	fn default() -> Self {
+		HintPolicy {
+			fees: GUESSED_FEES,
+			cltv_expiry_delta: GUESSED_CLTV_EXPIRY_DELTA,
+			htlc_minimum_msat: 0,
+		}
+	}
+}
+
+/// Our best guess at `target`'s policy for a channel we don't know yet. In order of preference
+/// that is a policy it sent back for one of its private channels, the most common policy on
+/// its public channels, and the defaults of the implementation it appears to run.
+pub(crate) fn hint_policy(
+	target: &PublicKey, network_graph: &NetworkGraph, hidden_graph: &HiddenGraph,
+) -> HintPolicy {
+	if let Some(policy) = hidden_graph.learned_policy(&target.to_string()) {
+		return HintPolicy {
+			fees: policy.fees,
+			cltv_expiry_delta: policy.cltv_expiry_delta,
+			htlc_minimum_msat: policy.htlc_minimum_msat,
+		};
+	}
+
+	let graph = network_graph.read_only();
+	let node_id = NodeId::from_pubkey(target);
+	let node = match graph.nodes().get(&node_id) {
+		Some(node) => node,
+		None => return HintPolicy::default(),
+	};
+
+	let mut counts: HashMap<(u32, u32, u16, u64), usize> = HashMap::new();
+	for scid in node.channels.iter() {
+		let channel = match graph.channels().get(scid) {
+			Some(channel) => channel,
+			None => continue,
+		};
+		let update = if channel.node_one == node_id {
+			channel.one_to_two.as_ref()
+		} else {
+			channel.two_to_one.as_ref()
+		};
+		if let Some(update) = update.filter(|update| update.enabled) {
+			let key = (
+				update.fees.base_msat,
+				update.fees.proportional_millionths,
+				update.cltv_expiry_delta,
+				update.htlc_minimum_msat,
+			);
+			*counts.entry(key).or_insert(0) += 1;
+		}
+	}
+	// ties go to the lower fee, which is the more common default
+	let most_common = counts
+		.into_iter()
+		.max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then_with(|| b.cmp(a)));
+	if let Some(((base_msat, proportional_millionths, cltv_expiry_delta, htlc_minimum_msat), _)) =
+		most_common
+	{
+		return HintPolicy {
+			fees: RoutingFees { base_msat, proportional_millionths },
+			cltv_expiry_delta,
+			htlc_minimum_msat,
+		};
+	}
+
+	match &node.announcement_info {
+		Some(announcement) => implementation_defaults(&announcement.features),
+		None => HintPolicy::default(),
+	}
+}
+
+/// The default forwarding policy of the implementation that sets these node features.
+fn implementation_defaults(features: &NodeFeatures) -> HintPolicy {
+	let policy = |base_msat, proportional_millionths, cltv_expiry_delta| HintPolicy {
+		fees: RoutingFees { base_msat, proportional_millionths },
+		cltv_expiry_delta,
+		htlc_minimum_msat: 0,
+	};
+	match implementation(features) {
+		Some("lnd") => policy(1000, 1, 40),
+		Some("eclair") => policy(1000, 200, 144),
+		_ => HintPolicy::default(),
+	}
+}
+
+/// The implementation a node appears to run, going by the feature bits only one implementation
+/// is known to set. Most nodes set none of them and come out as `None`, the bits every
+/// implementation shares, like onion messages or scid privacy, don't tell them apart.
+pub(crate) fn implementation(features: &NodeFeatures) -> Option<&'static str> {
+	// the wire encoding is a length followed by the flags, big endian
+	let mut flags = features.encode().split_off(2);
+	flags.reverse();
+	let has_bit =
+		|bit: usize| matches!(flags.get(bit / 8), Some(byte) if byte & (1 << (bit % 8)) != 0);
+
+	// lnd: amp, script enforced leases
+	if has_bit(30) || has_bit(31) || has_bit(2022) || has_bit(2023) {
+		return Some("lnd");
+	}
+	// eclair: trampoline prototype
+	if has_bit(148) || has_bit(149) {
+		return Some("eclair");
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::cli::Attempt;
+	use crate::create_tables;
+	use crate::probe::scid_from_parts;
+	use crate::test_utils::{add_public_channel, announce_node, network_graph, pubkey, TempDir};
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::network::constants::Network;
+	use chrono::Utc;
+	use lightning::ln::features::ChannelFeatures;
+	use lightning::ln::msgs::{OptionalField, UnsignedChannelUpdate};
+	use rusqlite::Connection;
+
+	fn features(bit: usize) -> NodeFeatures {
+		let mut flags = vec![0; bit / 8 + 1];
+		flags[bit / 8] |= 1 << (bit % 8);
+		NodeFeatures::from_le_bytes(flags)
+	}
+
+	fn policy(base_msat: u32, proportional_millionths: u32, cltv_expiry_delta: u16) -> HintPolicy {
+		HintPolicy {
+			fees: RoutingFees { base_msat, proportional_millionths },
+			cltv_expiry_delta,
+			htlc_minimum_msat: 1,
+		}
+	}
+
+	/// Sets both directions of `scid` to `policy`.
+	fn set_policy(network_graph: &NetworkGraph, scid: u64, policy: HintPolicy) {
+		for direction in 0..2 {
+			let update = UnsignedChannelUpdate {
+				chain_hash: genesis_block(Network::Regtest).header.block_hash(),
+				short_channel_id: scid,
+				timestamp: Utc::now().timestamp() as u32 + 1,
+				flags: direction,
+				cltv_expiry_delta: policy.cltv_expiry_delta,
+				htlc_minimum_msat: policy.htlc_minimum_msat,
+				htlc_maximum_msat: OptionalField::Absent,
+				fee_base_msat: policy.fees.base_msat,
+				fee_proportional_millionths: policy.fees.proportional_millionths,
+				excess_data: Vec::new(),
+			};
+			network_graph.update_channel_unsigned(&update).unwrap();
+		}
+	}
+
+	#[test]
+	fn test_hint_policy_fallback_order() {
+		let data_dir = TempDir::new("hint-policy");
+		let network_graph = network_graph(&data_dir);
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+		let hidden_graph = HiddenGraph::read(&db).unwrap();
+		let (target, peer) = (pubkey(1), pubkey(2));
+		let scid = |tx_index| scid_from_parts(700_000, tx_index, 0);
+
+		// nothing known about the target
+		assert_eq!(hint_policy(&target, &network_graph, &hidden_graph), HintPolicy::default());
+
+		// a channel without policies, the implementation's defaults are all there is
+		let now = Utc::now().timestamp() as u64;
+		network_graph
+			.add_channel_from_partial_announcement(
+				scid(1),
+				now,
+				ChannelFeatures::empty(),
+				target,
+				peer,
+			)
+			.unwrap();
+		announce_node(&network_graph, target, NodeFeatures::empty(), 1);
+		assert_eq!(hint_policy(&target, &network_graph, &hidden_graph), HintPolicy::default());
+		announce_node(&network_graph, target, features(148), 2);
+		let eclair = HintPolicy { htlc_minimum_msat: 0, ..policy(1000, 200, 144) };
+		assert_eq!(hint_policy(&target, &network_graph, &hidden_graph), eclair);
+
+		// the most common public policy, and the lower fee on a tie
+		add_public_channel(&network_graph, scid(2), target, peer);
+		set_policy(&network_graph, scid(2), policy(0, 100, 144));
+		add_public_channel(&network_graph, scid(3), target, peer);
+		set_policy(&network_graph, scid(3), policy(1000, 1, 40));
+		assert_eq!(hint_policy(&target, &network_graph, &hidden_graph), policy(0, 100, 144));
+		add_public_channel(&network_graph, scid(4), target, peer);
+		set_policy(&network_graph, scid(4), policy(1000, 1, 40));
+		assert_eq!(hint_policy(&target, &network_graph, &hidden_graph), policy(1000, 1, 40));
+
+		// what the target told us about one of its private channels beats all of it
+		hidden_graph.record_attempt(&Attempt {
+			target_pubkey: target.to_string(),
+			guess_pubkey: pubkey(3).to_string(),
+			channel_id: scid(5).to_string(),
+			result: "incorrect_or_unknown_payment_details".to_string(),
+			date_found: Utc::now().naive_utc(),
+			hidden_path: None,
+			guess_strategy: None,
+		});
+		let update = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Regtest).header.block_hash(),
+			short_channel_id: scid(5),
+			timestamp: 1,
+			flags: 0,
+			cltv_expiry_delta: 18,
+			htlc_minimum_msat: 1,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat: 10,
+			fee_proportional_millionths: 10,
+			excess_data: Vec::new(),
+		};
+		hidden_graph.learn_policy(&db, &target, &update).unwrap();
+		assert_eq!(hint_policy(&target, &network_graph, &hidden_graph), policy(10, 10, 18));
+	}
+
+	#[test]
+	fn test_implementation() {
+		assert_eq!(implementation(&NodeFeatures::empty()), None);
+		assert_eq!(implementation(&features(31)), Some("lnd"));
+		assert_eq!(implementation(&features(2023)), Some("lnd"));
+		assert_eq!(implementation(&features(149)), Some("eclair"));
+		// onion messages and scid privacy are set by more than one
+		assert_eq!(implementation(&features(39)), None);
+		assert_eq!(implementation(&features(47)), None);
+		let mut flags = vec![0; 6];
+		flags[4] |= 1 << 7;
+		flags[5] |= 1 << 7;
+		assert_eq!(implementation(&NodeFeatures::from_le_bytes(flags.clone())), None);
+		flags[3] |= 1 << 7;
+		assert_eq!(implementation(&NodeFeatures::from_le_bytes(flags)), Some("lnd"));
+	}
+}
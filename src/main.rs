@@ -1,14 +1,51 @@
+mod aggregate;
+mod audit;
 pub(crate) mod bitcoind_client;
 mod cli;
+mod config;
+mod control;
 mod convert;
+mod daemon;
+mod denylist;
 mod disk;
+mod evaluate;
+mod export;
+mod graph_import;
+mod guess;
 mod hex_utils;
+mod hidden_graph;
+mod hint_policy;
+mod manifest;
+mod metrics;
+#[cfg(test)]
+mod mock_chain;
+#[cfg(test)]
+mod node_tests;
+mod politeness;
 mod probe;
-
-use crate::bitcoind_client::BitcoindClient;
+mod probe_monitor;
+#[cfg(test)]
+mod probe_tests;
+mod route_cache;
+mod rpc;
+mod simulator;
+mod stats;
+#[cfg(test)]
+mod test_utils;
+
+use crate::bitcoind_client::{BitcoindClient, ChainClient};
 use crate::cli::Attempt;
+use crate::control::ControlProbes;
+use crate::denylist::Denylist;
 use crate::disk::FilesystemLogger;
 use crate::disk::YourPersister;
+use crate::evaluate::Evaluations;
+use crate::hidden_graph::{format_hidden_path, is_channel_found, HiddenGraph, HiddenRouter};
+use crate::metrics::Metrics;
+use crate::politeness::Politeness;
+use crate::probe_monitor::ProbeMonitor;
+use crate::route_cache::ProbeRouteCache;
+use crate::rpc::RpcServer;
 use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode;
@@ -18,7 +55,7 @@ use bitcoin::secp256k1::Secp256k1;
 use bitcoin_bech32::WitnessProgram;
 use chrono::Utc;
 use lightning::chain;
-use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning::chain::chaininterface::ConfirmationTarget;
 use lightning::chain::chainmonitor;
 use lightning::chain::keysinterface::{InMemorySigner, KeysInterface, KeysManager, Recipient};
 use lightning::chain::{BestBlock, Filter, Watch};
@@ -26,10 +63,11 @@ use lightning::ln::channelmanager::{self, PaymentId};
 use lightning::ln::channelmanager::{
 	ChainParameters, ChannelManagerReadArgs, SimpleArcChannelManager,
 };
+use lightning::ln::msgs::UnsignedChannelUpdate;
 use lightning::ln::peer_handler::{IgnoringMessageHandler, MessageHandler, SimpleArcPeerManager};
 use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
 use lightning::routing::gossip;
-use lightning::routing::gossip::{NodeId, P2PGossipSync};
+use lightning::routing::gossip::{NetworkUpdate, NodeId, P2PGossipSync};
 use lightning::routing::router::{Route, RouteHop};
 use lightning::routing::scoring::ProbabilisticScorer;
 use lightning::util::config::UserConfig;
@@ -43,7 +81,6 @@ use lightning_block_sync::poll;
 use lightning_block_sync::SpvClient;
 use lightning_block_sync::UnboundedCache;
 use lightning_invoice::payment;
-use lightning_invoice::utils::DefaultRouter;
 use lightning_net_tokio::SocketDescriptor;
 use lightning_persister::FilesystemPersister;
 use lightning_rapid_gossip_sync::RapidGossipSync;
@@ -57,10 +94,9 @@ use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Write;
-use std::ops::Deref;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 pub(crate) enum HTLCStatus {
 	Pending,
@@ -92,6 +128,15 @@ pub(crate) struct PaymentInfo {
 	secret: Option<PaymentSecret>,
 	status: HTLCStatus,
 	amt_msat: MillisatAmount,
+	probe: Option<ProbeInfo>,
+}
+
+/// What we sent a probe with, for recording along with its result.
+pub(crate) struct ProbeInfo {
+	guess_strategy: &'static str,
+	payment_id: PaymentId,
+	sent_at: Instant,
+	path: Vec<RouteHop>,
 }
 
 pub(crate) type PaymentInfoStorage = Arc<Mutex<HashMap<PaymentHash, PaymentInfo>>>;
@@ -99,8 +144,8 @@ pub(crate) type PaymentInfoStorage = Arc<Mutex<HashMap<PaymentHash, PaymentInfo>
 type ChainMonitor = chainmonitor::ChainMonitor<
 	InMemorySigner,
 	Arc<dyn Filter + Send + Sync>,
-	Arc<BitcoindClient>,
-	Arc<BitcoindClient>,
+	Arc<dyn ChainClient>,
+	Arc<dyn ChainClient>,
 	Arc<FilesystemLogger>,
 	Arc<YourPersister>,
 	//Arc<FilesystemPersister>,
@@ -109,14 +154,14 @@ type ChainMonitor = chainmonitor::ChainMonitor<
 pub(crate) type PeerManager = SimpleArcPeerManager<
 	SocketDescriptor,
 	ChainMonitor,
-	BitcoindClient,
-	BitcoindClient,
+	dyn ChainClient,
+	dyn ChainClient,
 	dyn chain::Access + Send + Sync,
 	FilesystemLogger,
 >;
 
 pub(crate) type ChannelManager =
-	SimpleArcChannelManager<ChainMonitor, BitcoindClient, BitcoindClient, FilesystemLogger>;
+	SimpleArcChannelManager<ChainMonitor, dyn ChainClient, dyn ChainClient, FilesystemLogger>;
 
 pub(crate) type InvoicePayer<E> = payment::InvoicePayer<
 	Arc<ChannelManager>,
@@ -126,7 +171,7 @@ pub(crate) type InvoicePayer<E> = payment::InvoicePayer<
 	E,
 >;
 
-type Router = DefaultRouter<Arc<NetworkGraph>, Arc<FilesystemLogger>>;
+type Router = HiddenRouter;
 
 /*
 type GossipSync<P, G, A, L> =
@@ -147,10 +192,14 @@ pub type PaymentState = Arc<Mutex<HashMap<PaymentId, Route>>>;
 
 async fn handle_ldk_events(
 	_pending_payment_state: PaymentState, channel_manager: Arc<ChannelManager>,
-	bitcoind_client: Arc<BitcoindClient>, keys_manager: Arc<KeysManager>,
+	bitcoind_client: Arc<dyn ChainClient>, keys_manager: Arc<KeysManager>,
 	inbound_payments: PaymentInfoStorage, outbound_payments: PaymentInfoStorage,
 	pending_payments: PaymentInfoStorage, network: Network, event: &Event,
 	db: Arc<Mutex<rusqlite::Connection>>, logger: Arc<FilesystemLogger>,
+	network_graph: Arc<NetworkGraph>, hidden_graph: Arc<HiddenGraph>,
+	route_cache: Arc<ProbeRouteCache>, probe_monitor: Arc<ProbeMonitor>,
+	politeness: Arc<Politeness>, controls: Arc<ControlProbes>, evaluations: Arc<Evaluations>,
+	metrics: Arc<Metrics>,
 ) {
 	match event {
 		Event::FundingGenerationReady {
@@ -243,6 +292,7 @@ async fn handle_ldk_events(
 						secret: payment_secret,
 						status: HTLCStatus::Succeeded,
 						amt_msat: MillisatAmount(Some(*amount_msat)),
+						probe: None,
 					});
 				}
 			}
@@ -277,28 +327,142 @@ async fn handle_ldk_events(
 			println!("PaymentPathSuccessful");
 			print!("> ")
 		}
-		Event::PaymentPathFailed { path, error_code, .. } => {
+		Event::PaymentPathFailed {
+			payment_hash,
+			path,
+			rejected_by_dest,
+			error_code,
+			network_update,
+			short_channel_id: failed_scid,
+			..
+		} => {
+			// the private channels we went through to reach a target that is itself private
+			let hidden_start = hidden_graph.hidden_path_start(path);
+			let hidden_path = if hidden_start + 1 < path.len() {
+				Some(format_hidden_path(&path[hidden_start - 1..]))
+			} else {
+				None
+			};
+			// a failure on one of those channels tells us nothing about the guessed one
+			let failed_before_guess = match failed_scid {
+				Some(scid) => path[hidden_start..path.len() - 1]
+					.iter()
+					.any(|hop| hop.short_channel_id == *scid),
+				None => false,
+			};
+			// cached probe routes over a channel that failed before the guessed one need
+			// to be found again
+			if let Some(scid) = failed_scid {
+				if *scid != path.last().unwrap().short_channel_id {
+					route_cache.invalidate_channel(*scid);
+				}
+			}
+			let full_path = path;
+
 			// get last hop for channel/node details
 			let (last_hop, path) = path.split_last().unwrap();
 			let chan_id = last_hop.short_channel_id;
 			let guessed_node_pubkey = last_hop.pubkey;
-			if path.len() < 1 {
-				return;
-			}
-			let node_pubkey = path.last().unwrap().pubkey;
+			let node_pubkey = match probe::probe_origin(path, chan_id, &channel_manager) {
+				Some(node_pubkey) => node_pubkey,
+				None => {
+					log_debug!(logger, "Could not tell where the probe over {} came from", chan_id);
+					return;
+				}
+			};
+
+			let guess_strategy = match pending_payments.lock().unwrap().get(payment_hash) {
+				Some(PaymentInfo { probe: Some(probe), .. }) => {
+					probe_monitor.record_answer(&probe.path, probe.sent_at.elapsed());
+					Some(probe.guess_strategy)
+				}
+				_ => None,
+			};
 
 			if let Some(error_code) = error_code {
-				let result = match error_code {
-					0x400f => "incorrect_or_unknown_payment_details", // bingo
-					0x100c => "fee_insufficient",                     // channel found, wrong fee
-					0xc005 => "invalid_onion_hmac",                   // channel found, wrong node
-					0x100d => "incorrect_cltv_expiry",                // channel found, wrong cltv
-					0x100b => "amount_below_minimum",                 // channel found, amount below minimum
-					0x400a => "unknown_next_peer",                    // no channel found
-					_ => "unknown",
-				};
+				let result = probe::probe_result(*error_code, *rejected_by_dest);
+
+				if failed_before_guess {
+					metrics.record_result("hidden_path", result);
+					log_info!(
+						logger,
+						"Probe of {} failed on the hidden path {}: {}",
+						node_pubkey,
+						hidden_path.unwrap_or_default(),
+						result
+					);
+					if let Some(NetworkUpdate::ChannelUpdateMessage { msg }) = network_update {
+						learn_hidden_policy(
+							&hidden_graph,
+							&db.lock().unwrap(),
+							full_path,
+							&msg.contents,
+						);
+					}
+					return;
+				}
 
 				log_info!(logger, "Result: {}", result);
+				// a hop on the way or the guessed node failing says nothing about how the
+				// target treats our probes
+				let failed_at_target =
+					probe::failed_at_target(&node_pubkey, chan_id, network_update, *failed_scid);
+				if failed_at_target {
+					politeness.record_answer(&node_pubkey.to_string(), *error_code);
+				}
+				// whether the answer is about the guessed channel at all, a hop on the way
+				// failing the probe never got it there
+				let answered = failed_at_target || *rejected_by_dest;
+
+				// answers to controls only tell us whether the target can be trusted
+				if let Some(control) = controls.take_control(&node_pubkey.to_string(), chan_id) {
+					if !answered {
+						log_info!(
+							logger,
+							"Control ({}) of {} failed on the way: {}",
+							control.as_str(),
+							node_pubkey,
+							result
+						);
+						return;
+					}
+					metrics.record_result("control", result);
+					let write_start = Instant::now();
+					let verdict = controls.record_answer(
+						&db.lock().unwrap(),
+						&node_pubkey.to_string(),
+						chan_id,
+						control,
+						result,
+					);
+					metrics.record_db_write(write_start.elapsed());
+					if let Some(verdict) = verdict {
+						log_info!(
+							logger,
+							"Control ({}) of {}: {}, target is {} with confidence {:.2}",
+							control.as_str(),
+							node_pubkey,
+							result,
+							verdict.status.as_str(),
+							verdict.confidence
+						);
+					}
+					return;
+				}
+
+				// probes of an `evaluate` run only count towards its report
+				if let Some(expected) = evaluations.take_probe(&node_pubkey.to_string(), chan_id) {
+					if answered {
+						metrics.record_result("evaluation", result);
+						evaluations.record_answer(
+							&node_pubkey.to_string(),
+							chan_id,
+							expected,
+							result,
+						);
+					}
+					return;
+				}
 
 				let attempt = Attempt {
 					target_pubkey: node_pubkey.to_string(),
@@ -306,24 +470,55 @@ async fn handle_ldk_events(
 					channel_id: chan_id.to_string(),
 					result: result.to_string(),
 					date_found: Utc::now().naive_utc(),
+					hidden_path,
+					guess_strategy: guess_strategy.map(str::to_string),
 				};
 
-				db.clone()
-					.lock()
-					.unwrap()
-					.execute(
-						"INSERT INTO attempt (
-                                target_pubkey, guess_pubkey, channel_id, result, date_found)
-                            VALUES (?1, ?2, ?3, ?4, ?5)",
-						params![
-							&attempt.target_pubkey,
-							&attempt.guess_pubkey,
-							&attempt.channel_id,
-							&attempt.result,
-							attempt.date_found,
-						],
-					)
-					.unwrap();
+				metrics.record_result("probe", result);
+				if is_channel_found(result) {
+					metrics.record_discovery();
+				}
+				let write_start = Instant::now();
+				record_attempt(&db.lock().unwrap(), &hidden_graph, &attempt);
+				metrics.record_db_write(write_start.elapsed());
+
+				if let Some(NetworkUpdate::ChannelUpdateMessage { msg }) = network_update {
+					let learned = learn_hidden_policy(
+						&hidden_graph,
+						&db.lock().unwrap(),
+						full_path,
+						&msg.contents,
+					);
+					// now that we know what the target wants for the guessed hop, the probe can
+					// make it to the guessed node
+					let wrong_policy = matches!(
+						result,
+						"fee_insufficient" | "incorrect_cltv_expiry" | "amount_below_minimum"
+					);
+					if learned && wrong_policy && msg.contents.short_channel_id == chan_id {
+						if let Err(e) = probe::resend_probe(
+							&node_pubkey,
+							chan_id,
+							&guessed_node_pubkey,
+							&channel_manager,
+							&network_graph,
+							&logger,
+							pending_payments.clone(),
+							&hidden_graph,
+							&route_cache,
+							guess_strategy.unwrap_or("fixed"),
+							&metrics,
+						) {
+							log_info!(
+								logger,
+								"Could not probe {}:{} again: {}",
+								node_pubkey,
+								chan_id,
+								e
+							);
+						}
+					}
+				}
 			}
 		}
 		Event::PaymentFailed { payment_hash, .. } => {
@@ -409,10 +604,203 @@ async fn handle_ldk_events(
 	}
 }
 
-async fn start_ldk() {
-	let args = match cli::parse_startup_args() {
-		Ok(user_args) => user_args,
-		Err(()) => return,
+/// Stores the answer to a probe and folds it into the hidden graph.
+fn record_attempt(db: &Connection, hidden_graph: &HiddenGraph, attempt: &Attempt) {
+	db.execute(
+		"INSERT INTO attempt (
+            target_pubkey, guess_pubkey, channel_id, result, date_found, hidden_path,
+            guess_strategy)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+		params![
+			&attempt.target_pubkey,
+			&attempt.guess_pubkey,
+			&attempt.channel_id,
+			&attempt.result,
+			attempt.date_found,
+			&attempt.hidden_path,
+			&attempt.guess_strategy,
+		],
+	)
+	.unwrap();
+	hidden_graph.record_attempt(attempt);
+}
+
+/// A policy sent back by a node on the path about one of its private channels makes that
+/// channel usable for routing.
+fn learn_hidden_policy(
+	hidden_graph: &HiddenGraph, db: &Connection, path: &[RouteHop], update: &UnsignedChannelUpdate,
+) -> bool {
+	let mut learned = false;
+	for (i, hop) in path.iter().enumerate().skip(1) {
+		if hop.short_channel_id == update.short_channel_id {
+			learned |= hidden_graph.learn_policy(db, &path[i - 1].pubkey, update).unwrap();
+		}
+	}
+	learned
+}
+
+/// Creates the tables probes and their answers are stored in, and brings older ones up to date.
+fn create_tables(db: &Connection) -> rusqlite::Result<()> {
+	db.execute(
+		"CREATE TABLE if not exists attempt ( 
+            target_pubkey TEXT,
+            guess_pubkey TEXT,
+            channel_id TEXT,
+            result TEXT,
+            date_found DATETIME,
+            hidden_path TEXT,
+            guess_strategy TEXT
+            )",
+		[], // empty list of parameters.
+	)?;
+	// columns added since the table was first created
+	add_column_if_missing(db, "attempt", "hidden_path", "TEXT")?;
+	add_column_if_missing(db, "attempt", "guess_strategy", "TEXT")?;
+
+	// Channels and nodes added by `importgraph` came without signatures, so keep track of them
+	db.execute(
+		"CREATE TABLE if not exists unverified_graph_entry (
+            kind TEXT,
+            id TEXT,
+            source TEXT,
+            date_imported DATETIME
+            )",
+		[],
+	)?;
+
+	// Policies learned from channel updates that targets sent back about their private channels
+	db.execute(
+		"CREATE TABLE if not exists hidden_channel_policy (
+            short_channel_id TEXT,
+            node_pubkey TEXT,
+            fee_base_msat INTEGER,
+            fee_proportional_millionths INTEGER,
+            cltv_expiry_delta INTEGER,
+            htlc_minimum_msat INTEGER,
+            htlc_maximum_msat INTEGER,
+            last_update INTEGER,
+            date_learned DATETIME
+            )",
+		[],
+	)?;
+
+	// Answers to probes we knew the answer to, and what they say about each target
+	db.execute(
+		"CREATE TABLE if not exists control_attempt (
+            target_pubkey TEXT,
+            channel_id TEXT,
+            control TEXT,
+            result TEXT,
+            date_found DATETIME
+            )",
+		[],
+	)?;
+	db.execute(
+		"CREATE TABLE if not exists target_status (
+            target_pubkey TEXT PRIMARY KEY,
+            status TEXT,
+            confidence REAL,
+            controls_answered INTEGER,
+            deviations INTEGER,
+            date_updated DATETIME
+            )",
+		[],
+	)?;
+
+	// Runs of `evaluate`, which probes public channels as if they were private
+	db.execute(
+		"CREATE TABLE if not exists evaluation_run (
+            run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            date_run DATETIME,
+            guess_strategy TEXT,
+            targets INTEGER,
+            positives INTEGER,
+            positives_answered INTEGER,
+            detected INTEGER,
+            negatives INTEGER,
+            negatives_answered INTEGER,
+            false_positives INTEGER,
+            assumption_channels INTEGER,
+            assumption_matches INTEGER,
+            positive_results TEXT,
+            negative_results TEXT
+            )",
+		[],
+	)?;
+	db.execute(
+		"CREATE TABLE if not exists evaluation_probe (
+            run_id INTEGER,
+            target_pubkey TEXT,
+            channel_id TEXT,
+            expected TEXT,
+            result TEXT
+            )",
+		[],
+	)?;
+	Ok(())
+}
+
+/// Adds `column` to an existing `table` that was created by an older version.
+fn add_column_if_missing(
+	db: &Connection, table: &str, column: &str, decl: &str,
+) -> rusqlite::Result<()> {
+	let mut stmt = db.prepare(&format!("PRAGMA table_info({})", table))?;
+	let columns =
+		stmt.query_map([], |row| row.get::<_, String>(1))?.collect::<Result<Vec<_>, _>>()?;
+	if !columns.iter().any(|c| c == column) {
+		db.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), [])?;
+	}
+	Ok(())
+}
+
+/// Checks that the chain we've connected to is running the network we expect.
+async fn check_chain(chain: &dyn ChainClient, network: Network) -> Result<(), String> {
+	let chain_name = chain.get_blockchain_info().await.chain;
+	if chain_name
+		!= match network {
+			bitcoin::Network::Bitcoin => "main",
+			bitcoin::Network::Testnet => "test",
+			bitcoin::Network::Regtest => "regtest",
+			bitcoin::Network::Signet => "signet",
+		} {
+		return Err(format!(
+			"Chain argument ({}) didn't match bitcoind chain ({})",
+			network, chain_name
+		));
+	}
+	Ok(())
+}
+
+/// A ChannelManager for a node that has never run before, starting at the chain's tip.
+async fn fresh_channel_manager(
+	chain: &Arc<dyn ChainClient>, network: Network, chain_monitor: &Arc<ChainMonitor>,
+	keys_manager: &Arc<KeysManager>, logger: &Arc<FilesystemLogger>, user_config: UserConfig,
+) -> (BlockHash, ChannelManager) {
+	let getinfo_resp = chain.get_blockchain_info().await;
+
+	let chain_params = ChainParameters {
+		network,
+		best_block: BestBlock::new(
+			getinfo_resp.latest_blockhash,
+			getinfo_resp.latest_height as u32,
+		),
+	};
+	let fresh_channel_manager = channelmanager::ChannelManager::new(
+		chain.clone(),
+		chain_monitor.clone(),
+		chain.clone(),
+		logger.clone(),
+		keys_manager.clone(),
+		user_config,
+		chain_params,
+	);
+	(getinfo_resp.latest_blockhash, fresh_channel_manager)
+}
+
+async fn start_ldk() -> i32 {
+	let (args, job) = match cli::parse_startup_args() {
+		Ok(parsed) => parsed,
+		Err(()) => return daemon::EXIT_USAGE,
 	};
 
 	// Initialize the LDK data directory if necessary.
@@ -420,7 +808,7 @@ async fn start_ldk() {
 	fs::create_dir_all(ldk_data_dir.clone()).unwrap();
 
 	// Initialize our bitcoind client.
-	let bitcoind_client = match BitcoindClient::new(
+	let bitcoind_client: Arc<dyn ChainClient> = match BitcoindClient::new(
 		args.bitcoind_rpc_host.clone(),
 		args.bitcoind_rpc_port,
 		args.bitcoind_rpc_username.clone(),
@@ -432,24 +820,14 @@ async fn start_ldk() {
 		Ok(client) => Arc::new(client),
 		Err(e) => {
 			println!("Failed to connect to bitcoind client: {}", e);
-			return;
+			return daemon::EXIT_STARTUP;
 		}
 	};
 
 	// Check that the bitcoind we've connected to is running the network we expect
-	let bitcoind_chain = bitcoind_client.get_blockchain_info().await.chain;
-	if bitcoind_chain
-		!= match args.network {
-			bitcoin::Network::Bitcoin => "main",
-			bitcoin::Network::Testnet => "test",
-			bitcoin::Network::Regtest => "regtest",
-			bitcoin::Network::Signet => "signet",
-		} {
-		println!(
-			"Chain argument ({}) didn't match bitcoind chain ({})",
-			args.network, bitcoind_chain
-		);
-		return;
+	if let Err(e) = check_chain(&*bitcoind_client, args.network).await {
+		println!("{}", e);
+		return daemon::EXIT_STARTUP;
 	}
 
 	// ## Setup
@@ -459,7 +837,7 @@ async fn start_ldk() {
 	let fee_estimator = bitcoind_client.clone();
 
 	// Step 2: Initialize the Logger
-	let logger = Arc::new(FilesystemLogger::new(ldk_data_dir.clone()));
+	let logger = Arc::new(FilesystemLogger::new(ldk_data_dir.clone(), args.log_level));
 
 	// Step 3: Initialize the BroadcasterInterface
 
@@ -501,7 +879,7 @@ async fn start_ldk() {
 			}
 			Err(e) => {
 				println!("ERROR: Unable to create keys seed file {}: {}", keys_seed_path, e);
-				return;
+				return daemon::EXIT_STARTUP;
 			}
 		}
 		key
@@ -539,7 +917,7 @@ async fn start_ldk() {
 						Ok(_) => continue,
 						Err(e) => {
 							log_info!(logger, "{:?}", e);
-							return;
+							return daemon::EXIT_STARTUP;
 						}
 					}
 				}
@@ -571,25 +949,15 @@ async fn start_ldk() {
 		} else {
 			// We're starting a fresh node.
 			restarting_node = false;
-			let getinfo_resp = bitcoind_client.get_blockchain_info().await;
-
-			let chain_params = ChainParameters {
-				network: args.network,
-				best_block: BestBlock::new(
-					getinfo_resp.latest_blockhash,
-					getinfo_resp.latest_height as u32,
-				),
-			};
-			let fresh_channel_manager = channelmanager::ChannelManager::new(
-				fee_estimator.clone(),
-				chain_monitor.clone(),
-				broadcaster.clone(),
-				logger.clone(),
-				keys_manager.clone(),
+			fresh_channel_manager(
+				&bitcoind_client,
+				args.network,
+				&chain_monitor,
+				&keys_manager,
+				&logger,
 				user_config,
-				chain_params,
-			);
-			(getinfo_resp.latest_blockhash, fresh_channel_manager)
+			)
+			.await
 		}
 	};
 
@@ -616,7 +984,7 @@ async fn start_ldk() {
 		}
 		chain_tip = Some(
 			init::synchronize_listeners(
-				&mut bitcoind_client.deref(),
+				&*bitcoind_client,
 				args.network,
 				&mut cache,
 				chain_listeners,
@@ -700,16 +1068,14 @@ async fn start_ldk() {
 
 	// Step 14: Connect and Disconnect Blocks
 	if chain_tip.is_none() {
-		chain_tip =
-			Some(init::validate_best_block_header(&mut bitcoind_client.deref()).await.unwrap());
+		chain_tip = Some(init::validate_best_block_header(&*bitcoind_client).await.unwrap());
 	}
 	let channel_manager_listener = channel_manager.clone();
 	let chain_monitor_listener = chain_monitor.clone();
 	let bitcoind_block_source = bitcoind_client.clone();
 	let network = args.network;
 	tokio::spawn(async move {
-		let mut derefed = bitcoind_block_source.deref();
-		let chain_poller = poll::ChainPoller::new(&mut derefed, network);
+		let chain_poller = poll::ChainPoller::new(&*bitcoind_block_source, network);
 		let chain_listener = (chain_monitor_listener, channel_manager_listener);
 		let mut spv_client =
 			SpvClient::new(chain_tip.unwrap(), chain_poller, &mut cache, &chain_listener);
@@ -720,27 +1086,32 @@ async fn start_ldk() {
 	});
 
 	// Create DB
-	let path = "./my_db.db3";
 	let db_arc: Arc<Mutex<rusqlite::Connection>> =
-		Arc::new(Mutex::new(Connection::open(&path).unwrap()));
+		Arc::new(Mutex::new(Connection::open(&args.db_path).unwrap()));
 	// let db = Connection::open(&path).unwrap();
 	// println!("{}", db.is_autocommit());
 
-	db_arc
-		.clone()
-		.lock()
-		.unwrap()
-		.execute(
-			"CREATE TABLE if not exists attempt ( 
-            target_pubkey TEXT,
-            guess_pubkey TEXT,
-            channel_id TEXT,
-            result TEXT,
-            date_found DATETIME
-            )",
-			[], // empty list of parameters.
-		)
-		.unwrap();
+	create_tables(&db_arc.lock().unwrap()).unwrap();
+	let hidden_graph = Arc::new(HiddenGraph::read(&db_arc.lock().unwrap()).unwrap());
+
+	// Operators that asked not to be probed or published
+	let denylist = match Denylist::load(&format!("{}/denylist.txt", ldk_data_dir)) {
+		Ok(denylist) => Arc::new(denylist),
+		Err(e) => {
+			println!("ERROR: failed to load the denylist: {}", e);
+			return daemon::EXIT_STARTUP;
+		}
+	};
+	if !denylist.is_empty() {
+		let (nodes, channels, signed) = denylist.counts();
+		log_info!(
+			logger,
+			"Loaded denylist with {} nodes and {} channels ({} signed requests)",
+			nodes,
+			channels,
+			signed
+		);
+	}
 
 	// TODO: read attempts
 	// select target_pubkey, channel_id from attempt
@@ -763,6 +1134,20 @@ async fn start_ldk() {
 	let payment_state_for_events = payment_state.clone();
 	let event_logger = logger.clone();
 	let db_arc_copy = db_arc.clone();
+	let network_graph_for_events = network_graph.clone();
+	let hidden_graph_for_events = hidden_graph.clone();
+	let route_cache = Arc::new(ProbeRouteCache::with_amount_msat(args.probe.amount_msat));
+	let route_cache_for_events = route_cache.clone();
+	let probe_monitor = Arc::new(ProbeMonitor::new(probe_monitor::DEFAULT_PROBE_TIMEOUT));
+	let probe_monitor_for_events = probe_monitor.clone();
+	let politeness = Arc::new(Politeness::new(args.probe.limits));
+	let politeness_for_events = politeness.clone();
+	let controls = Arc::new(ControlProbes::read(&db_arc.lock().unwrap()).unwrap());
+	let controls_for_events = controls.clone();
+	let evaluations = Arc::new(Evaluations::new());
+	let evaluations_for_events = evaluations.clone();
+	let metrics = Arc::new(Metrics::new());
+	let metrics_for_events = metrics.clone();
 	let event_handler = move |event: &Event| {
 		handle.block_on(handle_ldk_events(
 			payment_state_for_events.clone(),
@@ -776,6 +1161,14 @@ async fn start_ldk() {
 			event,
 			db_arc.clone(),
 			event_logger.clone(),
+			network_graph_for_events.clone(),
+			hidden_graph_for_events.clone(),
+			route_cache_for_events.clone(),
+			probe_monitor_for_events.clone(),
+			politeness_for_events.clone(),
+			controls_for_events.clone(),
+			evaluations_for_events.clone(),
+			metrics_for_events.clone(),
 		));
 	};
 
@@ -802,8 +1195,9 @@ async fn start_ldk() {
 	});
 
 	// Step 17: Create InvoicePayer
-	let router = DefaultRouter::new(
+	let router = HiddenRouter::new(
 		network_graph.clone(),
+		hidden_graph.clone(),
 		logger.clone(),
 		keys_manager.get_secure_random_bytes(),
 	);
@@ -837,7 +1231,6 @@ async fn start_ldk() {
 	);
 
 	// Regularly reconnect to channel peers.
-	/*
 	let connect_cm = Arc::clone(&channel_manager);
 	let connect_pm = Arc::clone(&peer_manager);
 	let peer_data_path = format!("{}/channel_peer_data", ldk_data_dir.clone());
@@ -870,7 +1263,6 @@ async fn start_ldk() {
 			}
 		}
 	});
-		*/
 
 	// Regularly broadcast our node_announcement. This is only required (or possible) if we have
 	// some public channels, and is only useful if we have public listen address(es) to announce.
@@ -894,30 +1286,78 @@ async fn start_ldk() {
 	}
 		*/
 
-	// Start the CLI.
-	cli::poll_for_user_input(
-		payment_state.clone(),
-		invoice_payer.clone(),
-		peer_manager.clone(),
-		channel_manager.clone(),
-		keys_manager.clone(),
-		inbound_payments,
-		outbound_payments,
+	let running = cli::stop_on_signal();
+	let probe_context = cli::ProbeContext {
+		channel_manager: channel_manager.clone(),
+		keys_manager,
+		network_graph,
+		logger,
+		scorer,
+		db: db_arc_copy,
+		hidden_graph,
+		route_cache,
+		probe_monitor,
+		politeness,
+		controls,
+		denylist,
 		pending_payments,
-		ldk_data_dir.clone(),
-		network,
-		network_graph.clone(),
-		logger.clone(),
-		scorer.clone(),
-		db_arc_copy.clone(),
-	)
-	.await;
+		ldk_data_dir,
+		probe_config: args.probe.clone(),
+		metrics,
+	};
+	if let Some(bind) = args.metrics_bind {
+		if let Err(e) = metrics::serve(bind, probe_context.clone()).await {
+			println!("ERROR: {}", e);
+			return daemon::EXIT_STARTUP;
+		}
+	}
+	let rpc_server = if args.rpc.bind.is_some() || args.rpc.socket.is_some() {
+		match RpcServer::start(&args.rpc, probe_context.clone(), peer_manager.clone()).await {
+			Ok(server) => Some(server),
+			Err(e) => {
+				println!("ERROR: {}", e);
+				return daemon::EXIT_STARTUP;
+			}
+		}
+	} else {
+		None
+	};
+	let exit_code = match job {
+		// Run the job instead of the CLI.
+		Some(job) => daemon::run_job(&job, &probe_context, &running),
+		// Start the CLI.
+		None => {
+			let stdin_closed = cli::poll_for_user_input(
+				payment_state,
+				invoice_payer,
+				peer_manager,
+				inbound_payments,
+				outbound_payments,
+				network,
+				evaluations,
+				probe_context,
+				running.clone(),
+			)
+			.await;
+			// without a prompt, under systemd or with </dev/null, the node is there for RPC
+			if stdin_closed && rpc_server.is_some() {
+				println!("stdin is closed, serving RPC until a signal");
+				daemon::wait_for_signal(&running);
+			}
+			daemon::EXIT_OK
+		}
+	};
+
+	if let Some(rpc_server) = rpc_server {
+		rpc_server.shutdown();
+	}
 
 	// Stop the background processor.
 	background_processor.stop().unwrap();
+	exit_code
 }
 
 #[tokio::main]
 pub async fn main() {
-	start_ldk().await;
+	std::process::exit(start_ldk().await);
 }
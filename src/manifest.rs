@@ -0,0 +1,284 @@
+use crate::NetworkGraph;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use chrono::{NaiveDateTime, Utc};
+use lightning::util::message_signing;
+use lightning::util::ser::Writeable;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// What a published results file was made from, so anyone can check it came from our node and
+/// wasn't edited since.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct ResultsManifest {
+	pub(crate) node_pubkey: String,
+	pub(crate) created: NaiveDateTime,
+	/// The results file, relative to the manifest.
+	pub(crate) file: String,
+	pub(crate) file_sha256: String,
+	/// One hash for every record of the results file, see `record_hashes`.
+	pub(crate) record_sha256s: Vec<String>,
+	/// The transaction files the results were matched against.
+	pub(crate) dataset_sha256: String,
+	pub(crate) dataset_files: usize,
+	/// The network graph as it was when the results were dumped.
+	pub(crate) graph_sha256: String,
+	pub(crate) graph_channels: usize,
+	pub(crate) graph_nodes: usize,
+}
+
+/// The manifest with a `signmessage` style signature over its canonical JSON encoding.
+#[derive(Serialize, Deserialize, Debug)]
+struct SignedManifest {
+	manifest: ResultsManifest,
+	signature: String,
+}
+
+/// How a results bundle held up against its manifest.
+pub(crate) struct Verification {
+	pub(crate) manifest: ResultsManifest,
+	pub(crate) signature_valid: bool,
+	pub(crate) file_matches: bool,
+	/// Records whose hash doesn't match the manifest, by index.
+	pub(crate) changed_records: Vec<usize>,
+	/// Records in the file less records in the manifest.
+	pub(crate) added_records: isize,
+	/// `None` if no dataset was given to check against.
+	pub(crate) dataset_matches: Option<bool>,
+}
+
+impl Verification {
+	pub(crate) fn is_valid(&self) -> bool {
+		self.signature_valid
+			&& self.file_matches
+			&& self.changed_records.is_empty()
+			&& self.added_records == 0
+			&& self.dataset_matches != Some(false)
+	}
+}
+
+/// Hashes `file` in `result_dir` along with the dataset and graph behind it, signs it all with
+/// the node key and writes the manifest next to it.
+pub(crate) fn write_manifest(
+	result_dir: &str, file: &str, tx_dir: &str, network_graph: &NetworkGraph,
+	node_secret: &SecretKey,
+) -> Result<ResultsManifest, Box<dyn std::error::Error>> {
+	let contents = fs::read(Path::new(result_dir).join(file))?;
+	let (dataset_sha256, dataset_files) = hash_dataset(tx_dir)?;
+	let graph_sha256 = sha256(&network_graph.encode());
+	let (graph_channels, graph_nodes) = {
+		let graph = network_graph.read_only();
+		(graph.channels().len(), graph.nodes().len())
+	};
+
+	let manifest = ResultsManifest {
+		node_pubkey: PublicKey::from_secret_key(&Secp256k1::signing_only(), node_secret)
+			.to_string(),
+		created: Utc::now().naive_utc(),
+		file: file.to_string(),
+		file_sha256: sha256(&contents),
+		record_sha256s: record_hashes(&contents)?,
+		dataset_sha256,
+		dataset_files,
+		graph_sha256,
+		graph_channels,
+		graph_nodes,
+	};
+	let signature =
+		message_signing::sign(serde_json::to_string(&manifest)?.as_bytes(), node_secret)?;
+	let signed = SignedManifest { manifest, signature };
+
+	let writer = BufWriter::new(File::create(Path::new(result_dir).join(MANIFEST_FILE))?);
+	serde_json::to_writer_pretty(writer, &signed)?;
+	Ok(signed.manifest)
+}
+
+/// Checks the manifest in `result_dir` was signed by `pubkey`, and that the results file, and
+/// the dataset if given, still hash to what the manifest says.
+pub(crate) fn verify_results(
+	result_dir: &str, pubkey: &PublicKey, tx_dir: Option<&str>,
+) -> Result<Verification, Box<dyn std::error::Error>> {
+	let signed: SignedManifest =
+		serde_json::from_str(&fs::read_to_string(Path::new(result_dir).join(MANIFEST_FILE))?)?;
+	let manifest = signed.manifest;
+	let signature_valid = manifest.node_pubkey == pubkey.to_string()
+		&& message_signing::verify(
+			serde_json::to_string(&manifest)?.as_bytes(),
+			&signed.signature,
+			pubkey,
+		);
+
+	let contents = fs::read(Path::new(result_dir).join(&manifest.file))?;
+	let records = record_hashes(&contents)?;
+	let changed_records = records
+		.iter()
+		.zip(manifest.record_sha256s.iter())
+		.enumerate()
+		.filter(|(_, (record, expected))| record != expected)
+		.map(|(i, _)| i)
+		.collect();
+	let dataset_matches = match tx_dir {
+		Some(tx_dir) => Some(hash_dataset(tx_dir)?.0 == manifest.dataset_sha256),
+		None => None,
+	};
+
+	Ok(Verification {
+		signature_valid,
+		file_matches: sha256(&contents) == manifest.file_sha256,
+		changed_records,
+		added_records: records.len() as isize - manifest.record_sha256s.len() as isize,
+		dataset_matches,
+		manifest,
+	})
+}
+
+/// Hashes every element of a JSON array, or every entry of a JSON object, in their canonical
+/// encoding with sorted keys, so a record can be checked no matter how the file is formatted.
+fn record_hashes(contents: &[u8]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+	let records = match serde_json::from_slice(contents)? {
+		serde_json::Value::Array(records) => records,
+		serde_json::Value::Object(entries) => {
+			entries.into_iter().map(|(key, value)| serde_json::json!({ key: value })).collect()
+		}
+		_ => return Err("results are neither a list nor an object".into()),
+	};
+	let mut hashes = Vec::new();
+	for record in records {
+		hashes.push(sha256(serde_json::to_string(&record)?.as_bytes()));
+	}
+	Ok(hashes)
+}
+
+/// Hashes the names and contents of every file in `tx_dir`, in order of their names.
+fn hash_dataset(tx_dir: &str) -> Result<(String, usize), Box<dyn std::error::Error>> {
+	let mut paths = Vec::new();
+	for entry in fs::read_dir(tx_dir)? {
+		paths.push(entry?.path());
+	}
+	paths.retain(|path| path.is_file());
+	paths.sort();
+
+	let mut engine = Sha256::engine();
+	for path in paths.iter() {
+		let name = path.file_name().unwrap().to_string_lossy();
+		engine.input(name.as_bytes());
+		engine.input(&[0]);
+		engine.input(&Sha256::hash(&fs::read(path)?));
+	}
+	Ok((hex::encode(Sha256::from_engine(engine).into_inner()), paths.len()))
+}
+
+fn sha256(data: &[u8]) -> String {
+	hex::encode(Sha256::hash(data).into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{network_graph, TempDir};
+
+	const RESULTS: &str = r#"[{"channel_id": "700000x1x0"}, {"channel_id": "700001x2x1"}]"#;
+
+	/// A results file and a dataset of one transaction file, with a manifest signed by the
+	/// key `[1; 32]`.
+	fn signed_bundle() -> (TempDir, TempDir, SecretKey) {
+		let result_dir = TempDir::new("manifest-results");
+		let tx_dir = TempDir::new("manifest-dataset");
+		fs::write(Path::new(result_dir.path()).join("results.json"), RESULTS).unwrap();
+		fs::write(Path::new(tx_dir.path()).join("700000.csv"), "txid,vout\n").unwrap();
+		let node_secret = SecretKey::from_slice(&[1; 32]).unwrap();
+		let graph_dir = TempDir::new("manifest-graph");
+		write_manifest(
+			result_dir.path(),
+			"results.json",
+			tx_dir.path(),
+			&network_graph(&graph_dir),
+			&node_secret,
+		)
+		.unwrap();
+		(result_dir, tx_dir, node_secret)
+	}
+
+	fn node_pubkey(node_secret: &SecretKey) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::signing_only(), node_secret)
+	}
+
+	#[test]
+	fn test_sign_and_verify() {
+		let (result_dir, tx_dir, node_secret) = signed_bundle();
+		let verification =
+			verify_results(result_dir.path(), &node_pubkey(&node_secret), Some(tx_dir.path()))
+				.unwrap();
+		assert!(verification.signature_valid);
+		assert!(verification.file_matches);
+		assert_eq!(verification.dataset_matches, Some(true));
+		assert_eq!(verification.manifest.record_sha256s.len(), 2);
+		assert_eq!(verification.manifest.dataset_files, 1);
+		assert!(verification.is_valid());
+
+		// formatting doesn't change the records, only the file
+		let reformatted = serde_json::to_string_pretty(
+			&serde_json::from_str::<serde_json::Value>(RESULTS).unwrap(),
+		)
+		.unwrap();
+		fs::write(Path::new(result_dir.path()).join("results.json"), reformatted).unwrap();
+		let verification =
+			verify_results(result_dir.path(), &node_pubkey(&node_secret), None).unwrap();
+		assert!(!verification.file_matches);
+		assert!(verification.changed_records.is_empty());
+		assert_eq!(verification.dataset_matches, None);
+	}
+
+	#[test]
+	fn test_tampered_file() {
+		let (result_dir, tx_dir, node_secret) = signed_bundle();
+		let results = Path::new(result_dir.path()).join("results.json");
+		fs::write(
+			&results,
+			r#"[{"channel_id": "700000x1x0"}, {"channel_id": "700001x2x0"}, {"channel_id": "1x2x3"}]"#,
+		)
+		.unwrap();
+		fs::write(Path::new(tx_dir.path()).join("700001.csv"), "txid,vout\n").unwrap();
+
+		let verification =
+			verify_results(result_dir.path(), &node_pubkey(&node_secret), Some(tx_dir.path()))
+				.unwrap();
+		assert!(verification.signature_valid);
+		assert!(!verification.file_matches);
+		assert_eq!(verification.changed_records, vec![1]);
+		assert_eq!(verification.added_records, 1);
+		assert_eq!(verification.dataset_matches, Some(false));
+		assert!(!verification.is_valid());
+	}
+
+	#[test]
+	fn test_tampered_manifest() {
+		let (result_dir, _tx_dir, node_secret) = signed_bundle();
+		let path = Path::new(result_dir.path()).join(MANIFEST_FILE);
+		let mut signed: SignedManifest =
+			serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+		signed.manifest.graph_channels += 1;
+		fs::write(&path, serde_json::to_string(&signed).unwrap()).unwrap();
+
+		let verification =
+			verify_results(result_dir.path(), &node_pubkey(&node_secret), None).unwrap();
+		assert!(!verification.signature_valid);
+		assert!(verification.file_matches);
+		assert!(!verification.is_valid());
+	}
+
+	#[test]
+	fn test_wrong_key() {
+		let (result_dir, _tx_dir, _) = signed_bundle();
+		let other = node_pubkey(&SecretKey::from_slice(&[2; 32]).unwrap());
+		let verification = verify_results(result_dir.path(), &other, None).unwrap();
+		assert!(!verification.signature_valid);
+		assert!(verification.file_matches);
+		assert!(!verification.is_valid());
+	}
+}
@@ -0,0 +1,275 @@
+//! Counters and gauges for a probing campaign, served in the Prometheus text format on
+//! `/metrics` when `metrics.bind` is set. Counters are bumped by the probe loop and the event
+//! handler as things happen, gauges are read off the node when Prometheus scrapes them.
+
+use crate::cli::ProbeContext;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use lightning::util::logger::Logger;
+use lightning::{log_given_level, log_info, log_internal};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Upper bounds of the DB write latency buckets, in seconds.
+const DB_WRITE_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1, 1.0];
+
+#[derive(Default)]
+struct Histogram {
+	/// One count per bucket in `DB_WRITE_BUCKETS`, each counting only what's above the one before.
+	buckets: [u64; DB_WRITE_BUCKETS.len()],
+	count: u64,
+	sum: f64,
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+	/// By what the probe went out for, `probe`, `control`, `evaluation`, `audit`, `resend` or
+	/// `probeprivate`.
+	probes_sent: Mutex<BTreeMap<&'static str, u64>>,
+	/// By what the answer was to, `probe`, `control`, `evaluation` or `hidden_path`, and by
+	/// `probe_result`.
+	results: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+	channels_discovered: AtomicU64,
+	probes_abandoned: AtomicU64,
+	db_writes: Mutex<Histogram>,
+}
+
+/// What's read off the node on every scrape.
+#[derive(Default)]
+struct Gauges {
+	/// Probes waiting for an answer, by the short channel id of their first hop.
+	in_flight: BTreeMap<u64, usize>,
+	/// Probes out for longer than the stuck probe timeout.
+	stuck: usize,
+	graph_nodes: usize,
+	graph_channels: usize,
+	hidden_channels: usize,
+}
+
+impl Metrics {
+	pub(crate) fn new() -> Self {
+		Metrics::default()
+	}
+
+	pub(crate) fn record_sent(&self, kind: &'static str) {
+		*self.probes_sent.lock().unwrap().entry(kind).or_insert(0) += 1;
+	}
+
+	#[cfg(test)]
+	pub(crate) fn probes_sent(&self, kind: &str) -> u64 {
+		self.probes_sent.lock().unwrap().get(kind).copied().unwrap_or(0)
+	}
+
+	pub(crate) fn record_result(&self, kind: &'static str, result: &'static str) {
+		*self.results.lock().unwrap().entry((kind, result)).or_insert(0) += 1;
+	}
+
+	pub(crate) fn record_discovery(&self) {
+		self.channels_discovered.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn record_abandoned(&self, probes: usize) {
+		self.probes_abandoned.fetch_add(probes as u64, Ordering::Relaxed);
+	}
+
+	pub(crate) fn record_db_write(&self, elapsed: Duration) {
+		let seconds = elapsed.as_secs_f64();
+		let mut db_writes = self.db_writes.lock().unwrap();
+		if let Some(i) = DB_WRITE_BUCKETS.iter().position(|le| seconds <= *le) {
+			db_writes.buckets[i] += 1;
+		}
+		db_writes.count += 1;
+		db_writes.sum += seconds;
+	}
+
+	fn gauges(ctx: &ProbeContext) -> Gauges {
+		let mut gauges = Gauges::default();
+		let timeout = ctx.probe_monitor.timeout();
+		for payment in ctx.pending_payments.lock().unwrap().values() {
+			if let Some(probe) = &payment.probe {
+				if let Some(first_hop) = probe.path.first() {
+					*gauges.in_flight.entry(first_hop.short_channel_id).or_insert(0) += 1;
+				}
+				if probe.sent_at.elapsed() > timeout {
+					gauges.stuck += 1;
+				}
+			}
+		}
+		let graph = ctx.network_graph.read_only();
+		gauges.graph_nodes = graph.nodes().len();
+		gauges.graph_channels = graph.channels().len();
+		gauges.hidden_channels = ctx.hidden_graph.channels().iter().filter(|c| !c.closed).count();
+		gauges
+	}
+
+	fn render(&self, gauges: &Gauges) -> String {
+		let mut out = String::new();
+
+		header(&mut out, "probes_sent_total", "counter", "Probes sent, by what for.");
+		for (kind, count) in self.probes_sent.lock().unwrap().iter() {
+			writeln!(out, "ldk_sample_probes_sent_total{{kind=\"{}\"}} {}", kind, count).unwrap();
+		}
+
+		header(&mut out, "probe_results_total", "counter", "Answers to probes by classification.");
+		for ((kind, result), count) in self.results.lock().unwrap().iter() {
+			writeln!(
+				out,
+				"ldk_sample_probe_results_total{{kind=\"{}\",result=\"{}\"}} {}",
+				kind, result, count
+			)
+			.unwrap();
+		}
+
+		header(&mut out, "channels_discovered_total", "counter", "Probes that found a channel.");
+		writeln!(
+			out,
+			"ldk_sample_channels_discovered_total {}",
+			self.channels_discovered.load(Ordering::Relaxed)
+		)
+		.unwrap();
+
+		header(&mut out, "probes_abandoned_total", "counter", "Stuck probes given up on.");
+		writeln!(
+			out,
+			"ldk_sample_probes_abandoned_total {}",
+			self.probes_abandoned.load(Ordering::Relaxed)
+		)
+		.unwrap();
+
+		header(&mut out, "probes_in_flight", "gauge", "Probes waiting for an answer by first hop.");
+		for (short_channel_id, count) in &gauges.in_flight {
+			writeln!(
+				out,
+				"ldk_sample_probes_in_flight{{short_channel_id=\"{}\"}} {}",
+				short_channel_id, count
+			)
+			.unwrap();
+		}
+
+		header(&mut out, "probes_stuck", "gauge", "Probes out for longer than the stuck timeout.");
+		writeln!(out, "ldk_sample_probes_stuck {}", gauges.stuck).unwrap();
+
+		header(&mut out, "graph_nodes", "gauge", "Nodes in the public network graph.");
+		writeln!(out, "ldk_sample_graph_nodes {}", gauges.graph_nodes).unwrap();
+		header(&mut out, "graph_channels", "gauge", "Channels in the public network graph.");
+		writeln!(out, "ldk_sample_graph_channels {}", gauges.graph_channels).unwrap();
+		header(&mut out, "hidden_channels", "gauge", "Private channels found and still open.");
+		writeln!(out, "ldk_sample_hidden_channels {}", gauges.hidden_channels).unwrap();
+
+		header(&mut out, "db_write_seconds", "histogram", "Time taken to store a probe answer.");
+		let db_writes = self.db_writes.lock().unwrap();
+		let mut cumulative = 0;
+		for (le, count) in DB_WRITE_BUCKETS.iter().zip(db_writes.buckets.iter()) {
+			cumulative += count;
+			writeln!(out, "ldk_sample_db_write_seconds_bucket{{le=\"{}\"}} {}", le, cumulative)
+				.unwrap();
+		}
+		writeln!(out, "ldk_sample_db_write_seconds_bucket{{le=\"+Inf\"}} {}", db_writes.count)
+			.unwrap();
+		writeln!(out, "ldk_sample_db_write_seconds_sum {}", db_writes.sum).unwrap();
+		writeln!(out, "ldk_sample_db_write_seconds_count {}", db_writes.count).unwrap();
+		out
+	}
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	writeln!(out, "# HELP ldk_sample_{} {}", name, help).unwrap();
+	writeln!(out, "# TYPE ldk_sample_{} {}", name, kind).unwrap();
+}
+
+/// Starts serving `/metrics` on `addr` and returns where it ended up, which only differs with
+/// port 0.
+pub(crate) async fn serve(
+	addr: SocketAddr, ctx: ProbeContext,
+) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+	let listener = TcpListener::bind(addr)
+		.await
+		.map_err(|e| format!("couldn't listen for metrics on {}: {}", addr, e))?;
+	let addr = listener.local_addr()?;
+	log_info!(ctx.logger, "Serving metrics on {}", addr);
+	let ctx = Arc::new(ctx);
+	tokio::spawn(async move {
+		loop {
+			let stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					log_info!(ctx.logger, "Metrics connection failed: {}", e);
+					continue;
+				}
+			};
+			let ctx = ctx.clone();
+			tokio::spawn(async move {
+				let service = service_fn(|request| handle(ctx.clone(), request));
+				let _ = Http::new().http1_only(true).serve_connection(stream, service).await;
+			});
+		}
+	});
+	Ok(addr)
+}
+
+async fn handle(
+	ctx: Arc<ProbeContext>, request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+	if request.method() != Method::GET || request.uri().path() != "/metrics" {
+		return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap());
+	}
+	let body = ctx.metrics.render(&Metrics::gauges(&ctx));
+	Ok(Response::builder()
+		.header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+		.body(Body::from(body))
+		.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let metrics = Metrics::new();
+		metrics.record_sent("probe");
+		metrics.record_sent("probe");
+		metrics.record_sent("control");
+		metrics.record_result("probe", "unknown_next_peer");
+		metrics.record_result("probe", "invalid_onion_hmac");
+		metrics.record_discovery();
+		metrics.record_abandoned(3);
+		metrics.record_db_write(Duration::from_micros(700));
+		metrics.record_db_write(Duration::from_millis(2));
+		metrics.record_db_write(Duration::from_secs(2));
+		let mut gauges = Gauges::default();
+		gauges.in_flight.insert(123, 4);
+		gauges.graph_nodes = 10;
+
+		let out = metrics.render(&gauges);
+		let lines: Vec<&str> = out.lines().collect();
+		for expected in [
+			"# TYPE ldk_sample_probes_sent_total counter",
+			"ldk_sample_probes_sent_total{kind=\"control\"} 1",
+			"ldk_sample_probes_sent_total{kind=\"probe\"} 2",
+			"ldk_sample_probe_results_total{kind=\"probe\",result=\"invalid_onion_hmac\"} 1",
+			"ldk_sample_probe_results_total{kind=\"probe\",result=\"unknown_next_peer\"} 1",
+			"ldk_sample_channels_discovered_total 1",
+			"ldk_sample_probes_abandoned_total 3",
+			"ldk_sample_probes_in_flight{short_channel_id=\"123\"} 4",
+			"ldk_sample_probes_stuck 0",
+			"ldk_sample_graph_nodes 10",
+			"# TYPE ldk_sample_db_write_seconds histogram",
+			"ldk_sample_db_write_seconds_bucket{le=\"0.0005\"} 0",
+			"ldk_sample_db_write_seconds_bucket{le=\"0.001\"} 1",
+			"ldk_sample_db_write_seconds_bucket{le=\"0.0025\"} 2",
+			"ldk_sample_db_write_seconds_bucket{le=\"1\"} 2",
+			"ldk_sample_db_write_seconds_bucket{le=\"+Inf\"} 3",
+			"ldk_sample_db_write_seconds_count 3",
+		] {
+			assert!(lines.contains(&expected), "missing {}", expected);
+		}
+	}
+}
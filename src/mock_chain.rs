@@ -0,0 +1,366 @@
+//! A synthetic chain and wallet in memory, standing in for bitcoind in tests.
+
+use crate::bitcoind_client::{ChainClient, ChainFuture};
+use crate::convert::{BlockchainInfo, FundedTx, RawTx, SignedTx};
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+use bitcoin::util::address::Address;
+use bitcoin::util::sighash::SighashCache;
+use bitcoin::{EcdsaSighashType, Network, PrivateKey, Witness};
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning_block_sync::{
+	AsyncBlockSourceResult, BlockHeaderData, BlockSource, BlockSourceError,
+};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// What a block pays its miner, all of it goes to the wallet.
+const BLOCK_REWARD: u64 = 50 * 100_000_000;
+
+/// Change below this is left to the fee.
+const DUST_LIMIT: u64 = 546;
+
+/// The weight a signed P2WPKH input adds to a transaction.
+const P2WPKH_INPUT_WEIGHT: u64 = 272;
+
+struct Chain {
+	/// The blocks of the best chain, by height.
+	blocks: Vec<Block>,
+	/// Every header ever mined, including those a reorg took off the best chain.
+	headers: HashMap<BlockHash, BlockHeaderData>,
+	stale_blocks: HashMap<BlockHash, Block>,
+	mempool: Vec<Transaction>,
+	/// Bumped for every block, so that blocks mined after a reorg differ from the ones they
+	/// replace.
+	blocks_mined: u32,
+}
+
+struct Wallet {
+	keys: HashMap<Script, SecretKey>,
+	utxos: HashMap<OutPoint, TxOut>,
+}
+
+/// A regtest chain that only moves when a test mines or disconnects blocks, and a bitcoind
+/// wallet that gets every block reward. Everything broadcast stays in the mempool until the next
+/// block is mined.
+pub(crate) struct MockChain {
+	chain: Mutex<Chain>,
+	wallet: Mutex<Wallet>,
+	broadcasts: Mutex<Vec<Transaction>>,
+	secp_ctx: Secp256k1<bitcoin::secp256k1::All>,
+}
+
+impl MockChain {
+	pub(crate) fn new() -> MockChain {
+		let genesis = genesis_block(Network::Regtest);
+		let mut headers = HashMap::new();
+		headers.insert(
+			genesis.block_hash(),
+			BlockHeaderData { header: genesis.header, height: 0, chainwork: genesis.header.work() },
+		);
+		MockChain {
+			chain: Mutex::new(Chain {
+				blocks: vec![genesis],
+				headers,
+				stale_blocks: HashMap::new(),
+				mempool: Vec::new(),
+				blocks_mined: 0,
+			}),
+			wallet: Mutex::new(Wallet { keys: HashMap::new(), utxos: HashMap::new() }),
+			broadcasts: Mutex::new(Vec::new()),
+			secp_ctx: Secp256k1::new(),
+		}
+	}
+
+	pub(crate) fn tip(&self) -> (BlockHash, u32) {
+		let chain = self.chain.lock().unwrap();
+		(chain.blocks.last().unwrap().block_hash(), chain.blocks.len() as u32 - 1)
+	}
+
+	/// Mines `count` blocks on top of the tip, the first one with everything in the mempool.
+	pub(crate) fn mine_blocks(&self, count: usize) {
+		for _ in 0..count {
+			let address = self.new_address();
+			let mut chain = self.chain.lock().unwrap();
+			chain.blocks_mined += 1;
+			let height = chain.blocks.len() as u32;
+			let prev = chain.blocks.last().unwrap().header;
+			let coinbase = Transaction {
+				version: 2,
+				lock_time: 0,
+				input: vec![TxIn {
+					previous_output: OutPoint::null(),
+					script_sig: Builder::new()
+						.push_int(height as i64)
+						.push_int(chain.blocks_mined as i64)
+						.into_script(),
+					sequence: 0xffffffff,
+					witness: Witness::new(),
+				}],
+				output: vec![TxOut { value: BLOCK_REWARD, script_pubkey: address.script_pubkey() }],
+			};
+			let mut block = Block {
+				header: BlockHeader {
+					version: 0x20000000,
+					prev_blockhash: prev.block_hash(),
+					merkle_root: Default::default(),
+					time: prev.time + 600,
+					bits: prev.bits,
+					nonce: 0,
+				},
+				txdata: std::iter::once(coinbase).chain(chain.mempool.drain(..)).collect(),
+			};
+			block.header.merkle_root = block.compute_merkle_root().unwrap();
+			// regtest difficulty, about every other nonce does it
+			while block.header.validate_pow(&block.header.target()).is_err() {
+				block.header.nonce += 1;
+			}
+
+			let chainwork = chain.headers[&prev.block_hash()].chainwork + block.header.work();
+			chain.headers.insert(
+				block.block_hash(),
+				BlockHeaderData { header: block.header, height, chainwork },
+			);
+			self.wallet.lock().unwrap().add_outputs(&block.txdata[0]);
+			chain.blocks.push(block);
+		}
+	}
+
+	/// Takes the last `count` blocks off the best chain. Their transactions don't go back to the
+	/// mempool, a reorg in a test is about blocks, not about what's in them.
+	pub(crate) fn disconnect_blocks(&self, count: usize) {
+		let mut chain = self.chain.lock().unwrap();
+		for _ in 0..count {
+			let block = chain.blocks.pop().unwrap();
+			chain.stale_blocks.insert(block.block_hash(), block);
+		}
+	}
+
+	/// Everything that was broadcast, in order, including transactions already mined.
+	pub(crate) fn broadcasts(&self) -> Vec<Transaction> {
+		self.broadcasts.lock().unwrap().clone()
+	}
+
+	pub(crate) fn is_wallet_script(&self, script: &Script) -> bool {
+		self.wallet.lock().unwrap().keys.contains_key(script)
+	}
+
+	/// The output `outpoint` spends, if it's one the chain or mempool knows about.
+	pub(crate) fn output(&self, outpoint: &OutPoint) -> Option<TxOut> {
+		let chain = self.chain.lock().unwrap();
+		chain
+			.blocks
+			.iter()
+			.flat_map(|block| block.txdata.iter())
+			.chain(chain.mempool.iter())
+			.find(|tx| tx.txid() == outpoint.txid)
+			.and_then(|tx| tx.output.get(outpoint.vout as usize).cloned())
+	}
+
+	fn new_address(&self) -> Address {
+		let mut wallet = self.wallet.lock().unwrap();
+		let mut key_bytes = [0u8; 32];
+		key_bytes[..8].copy_from_slice(&(wallet.keys.len() as u64 + 1).to_be_bytes());
+		let secret_key = SecretKey::from_slice(&key_bytes).unwrap();
+		let private_key = PrivateKey::new(secret_key, Network::Regtest);
+		let address =
+			Address::p2wpkh(&private_key.public_key(&self.secp_ctx), Network::Regtest).unwrap();
+		wallet.keys.insert(address.script_pubkey(), secret_key);
+		address
+	}
+
+	fn block(&self, hash: &BlockHash) -> Option<Block> {
+		let chain = self.chain.lock().unwrap();
+		chain
+			.blocks
+			.iter()
+			.find(|block| block.block_hash() == *hash)
+			.or_else(|| chain.stale_blocks.get(hash))
+			.cloned()
+	}
+}
+
+impl Wallet {
+	/// Keeps track of the coins `tx` pays to us and the ones it spends.
+	fn add_outputs(&mut self, tx: &Transaction) {
+		for input in tx.input.iter() {
+			self.utxos.remove(&input.previous_output);
+		}
+		for (vout, output) in tx.output.iter().enumerate() {
+			if self.keys.contains_key(&output.script_pubkey) {
+				self.utxos.insert(OutPoint { txid: tx.txid(), vout: vout as u32 }, output.clone());
+			}
+		}
+	}
+}
+
+impl BlockSource for MockChain {
+	fn get_header<'a>(
+		&'a self, header_hash: &'a BlockHash, _height_hint: Option<u32>,
+	) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
+		Box::pin(async move {
+			match self.chain.lock().unwrap().headers.get(header_hash) {
+				Some(header) => Ok(*header),
+				None => Err(BlockSourceError::transient("unknown block")),
+			}
+		})
+	}
+
+	fn get_block<'a>(&'a self, header_hash: &'a BlockHash) -> AsyncBlockSourceResult<'a, Block> {
+		Box::pin(async move {
+			self.block(header_hash).ok_or_else(|| BlockSourceError::transient("unknown block"))
+		})
+	}
+
+	fn get_best_block(&self) -> AsyncBlockSourceResult<'_, (BlockHash, Option<u32>)> {
+		Box::pin(async move {
+			let (hash, height) = self.tip();
+			Ok((hash, Some(height)))
+		})
+	}
+}
+
+impl FeeEstimator for MockChain {
+	fn get_est_sat_per_1000_weight(&self, confirmation_target: ConfirmationTarget) -> u32 {
+		match confirmation_target {
+			ConfirmationTarget::Background => 253,
+			ConfirmationTarget::Normal => 2000,
+			ConfirmationTarget::HighPriority => 5000,
+		}
+	}
+}
+
+impl BroadcasterInterface for MockChain {
+	fn broadcast_transaction(&self, tx: &Transaction) {
+		self.broadcasts.lock().unwrap().push(tx.clone());
+		let mut chain = self.chain.lock().unwrap();
+		if chain.mempool.iter().all(|pending| pending.txid() != tx.txid()) {
+			chain.mempool.push(tx.clone());
+			self.wallet.lock().unwrap().add_outputs(tx);
+		}
+	}
+}
+
+impl ChainClient for MockChain {
+	fn get_blockchain_info(&self) -> ChainFuture<'_, BlockchainInfo> {
+		Box::pin(async move {
+			let (latest_blockhash, latest_height) = self.tip();
+			BlockchainInfo {
+				latest_height: latest_height as usize,
+				latest_blockhash,
+				chain: "regtest".to_string(),
+			}
+		})
+	}
+
+	fn create_raw_transaction(&self, outputs: Vec<HashMap<String, f64>>) -> ChainFuture<'_, RawTx> {
+		Box::pin(async move {
+			let tx = Transaction {
+				version: 2,
+				lock_time: 0,
+				input: Vec::new(),
+				output: outputs
+					.iter()
+					.flat_map(|outputs| outputs.iter())
+					.map(|(address, amount)| TxOut {
+						value: (amount * 100_000_000.0).round() as u64,
+						script_pubkey: Address::from_str(address).unwrap().script_pubkey(),
+					})
+					.collect(),
+			};
+			RawTx(encode::serialize_hex(&tx))
+		})
+	}
+
+	fn fund_raw_transaction(&self, raw_tx: RawTx) -> ChainFuture<'_, FundedTx> {
+		Box::pin(async move {
+			let mut tx: Transaction =
+				encode::deserialize(&hex::decode(&raw_tx.0).unwrap()).unwrap();
+			let feerate = self.get_est_sat_per_1000_weight(ConfirmationTarget::Normal) as u64;
+			let target: u64 = tx.output.iter().map(|output| output.value).sum();
+			let change_address = self.new_address();
+			let mut utxos: Vec<(OutPoint, TxOut)> = self
+				.wallet
+				.lock()
+				.unwrap()
+				.utxos
+				.iter()
+				.map(|(outpoint, output)| (*outpoint, output.clone()))
+				.collect();
+			utxos.sort_by_key(|(outpoint, _)| *outpoint);
+
+			let mut funded = 0;
+			for (outpoint, output) in utxos {
+				if funded >= target + fee(&tx, feerate) {
+					break;
+				}
+				tx.input.push(TxIn {
+					previous_output: outpoint,
+					script_sig: Script::new(),
+					sequence: 0xffffffff,
+					witness: Witness::new(),
+				});
+				funded += output.value;
+			}
+			let change = TxOut { value: 0, script_pubkey: change_address.script_pubkey() };
+			tx.output.push(change);
+			let fee = fee(&tx, feerate);
+			assert!(funded >= target + fee, "Insufficient funds");
+			let changepos = if funded - target - fee > DUST_LIMIT {
+				tx.output.last_mut().unwrap().value = funded - target - fee;
+				tx.output.len() as i64 - 1
+			} else {
+				tx.output.pop();
+				-1
+			};
+			FundedTx { changepos, hex: encode::serialize_hex(&tx) }
+		})
+	}
+
+	fn sign_raw_transaction_with_wallet(&self, tx_hex: String) -> ChainFuture<'_, SignedTx> {
+		Box::pin(async move {
+			let mut tx: Transaction = encode::deserialize(&hex::decode(&tx_hex).unwrap()).unwrap();
+			let wallet = self.wallet.lock().unwrap();
+			let mut complete = true;
+			for i in 0..tx.input.len() {
+				let spent = match wallet.utxos.get(&tx.input[i].previous_output) {
+					Some(spent) => spent,
+					None => {
+						complete = false;
+						continue;
+					}
+				};
+				let secret_key = wallet.keys[&spent.script_pubkey];
+				let public_key =
+					PrivateKey::new(secret_key, Network::Regtest).public_key(&self.secp_ctx);
+				let script_code = Script::new_p2pkh(&public_key.pubkey_hash());
+				let sighash = SighashCache::new(&tx)
+					.segwit_signature_hash(i, &script_code, spent.value, EcdsaSighashType::All)
+					.unwrap();
+				let signature = self
+					.secp_ctx
+					.sign_ecdsa(&Message::from_slice(&sighash[..]).unwrap(), &secret_key);
+				let mut signature = signature.serialize_der().to_vec();
+				signature.push(EcdsaSighashType::All as u8);
+				tx.input[i].witness = Witness::from_vec(vec![signature, public_key.to_bytes()]);
+			}
+			SignedTx { complete, hex: encode::serialize_hex(&tx) }
+		})
+	}
+
+	fn get_new_address(&self) -> ChainFuture<'_, Address> {
+		Box::pin(async move { self.new_address() })
+	}
+}
+
+/// What `tx` pays at `feerate` once its inputs are signed.
+fn fee(tx: &Transaction, feerate: u64) -> u64 {
+	let weight = tx.weight() as u64 + tx.input.len() as u64 * P2WPKH_INPUT_WEIGHT;
+	weight * feerate / 1000
+}
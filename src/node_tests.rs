@@ -0,0 +1,554 @@
+//! Starts nodes the way `start_ldk` does, on top of a synthetic chain instead of bitcoind, and
+//! runs them through startup, block sync, the events that touch the chain and probe answers, and
+//! drives one through the RPC server.
+
+use crate::bitcoind_client::ChainClient;
+use crate::cli::ProbeContext;
+use crate::config::{ProbeConfig, RpcConfig};
+use crate::control::{Control, ControlProbe, ControlProbes};
+use crate::daemon::{self, Job};
+use crate::denylist::Denylist;
+use crate::disk::{self, FilesystemLogger, YourPersister};
+use crate::evaluate::{EvaluationProbe, Evaluations};
+use crate::hidden_graph::HiddenGraph;
+use crate::metrics::{self, Metrics};
+use crate::mock_chain::MockChain;
+use crate::politeness::{Politeness, ProbeLimits};
+use crate::probe_monitor::{self, ProbeMonitor};
+use crate::route_cache::ProbeRouteCache;
+use crate::rpc::RpcServer;
+use crate::test_utils::{pubkey, TempDir};
+use crate::{
+	check_chain, create_tables, fresh_channel_manager, handle_ldk_events, ChainMonitor,
+	ChannelManager, NetworkGraph, PaymentInfoStorage, PaymentState, PeerManager,
+};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::util::address::Address;
+use hyper::{header, Body, Request, StatusCode};
+use lightning::chain::chainmonitor;
+use lightning::chain::keysinterface::{
+	KeysInterface, KeysManager, Recipient, SpendableOutputDescriptor,
+};
+use lightning::ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
+use lightning::ln::msgs::{ChannelMessageHandler, Init};
+use lightning::ln::peer_handler::{IgnoringMessageHandler, MessageHandler};
+use lightning::ln::PaymentHash;
+use lightning::routing::gossip::{NetworkUpdate, P2PGossipSync};
+use lightning::routing::router::RouteHop;
+use lightning::util::config::UserConfig;
+use lightning::util::events::{Event, MessageSendEvent, MessageSendEventsProvider};
+use lightning::util::logger::Level;
+use lightning_block_sync::{init, poll, SpvClient, UnboundedCache};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UnixStream};
+
+/// Everything `start_ldk` sets up that a test needs to get at, short of networking. The probing
+/// state is shared between the event handler and `probe_context`, as it is in `start_ldk`.
+pub(crate) struct TestNode {
+	pub(crate) client: Arc<dyn ChainClient>,
+	pub(crate) chain_monitor: Arc<ChainMonitor>,
+	pub(crate) keys_manager: Arc<KeysManager>,
+	pub(crate) channel_manager: Arc<ChannelManager>,
+	pub(crate) logger: Arc<FilesystemLogger>,
+	pub(crate) network_graph: Arc<NetworkGraph>,
+	pub(crate) db: Arc<Mutex<Connection>>,
+	pub(crate) hidden_graph: Arc<HiddenGraph>,
+	pub(crate) payments: PaymentInfoStorage,
+	pub(crate) route_cache: Arc<ProbeRouteCache>,
+	pub(crate) probe_monitor: Arc<ProbeMonitor>,
+	pub(crate) politeness: Arc<Politeness>,
+	pub(crate) controls: Arc<ControlProbes>,
+	pub(crate) evaluations: Arc<Evaluations>,
+	pub(crate) metrics: Arc<Metrics>,
+	pub(crate) data_dir: TempDir,
+}
+
+impl TestNode {
+	pub(crate) async fn new(name: &str, chain: &Arc<MockChain>, seed: u8) -> TestNode {
+		let data_dir = TempDir::new(&format!("node-tests-{}", name));
+
+		let client: Arc<dyn ChainClient> = chain.clone();
+		let logger = Arc::new(FilesystemLogger::new(data_dir.path().to_string(), Level::Info));
+		let persister = Arc::new(YourPersister::new(data_dir.path().to_string()));
+		let chain_monitor: Arc<ChainMonitor> = Arc::new(chainmonitor::ChainMonitor::new(
+			None,
+			client.clone(),
+			logger.clone(),
+			client.clone(),
+			persister,
+		));
+		let keys_manager = Arc::new(KeysManager::new(&[seed; 32], 42, 42));
+		let mut user_config = UserConfig::default();
+		user_config.channel_handshake_limits.force_announced_channel_preference = false;
+		let (_, channel_manager) = fresh_channel_manager(
+			&client,
+			Network::Regtest,
+			&chain_monitor,
+			&keys_manager,
+			&logger,
+			user_config,
+		)
+		.await;
+
+		let genesis = genesis_block(Network::Regtest).header.block_hash();
+		let db = Connection::open_in_memory().unwrap();
+		create_tables(&db).unwrap();
+		TestNode {
+			client,
+			chain_monitor,
+			keys_manager,
+			channel_manager: Arc::new(channel_manager),
+			logger: logger.clone(),
+			network_graph: Arc::new(NetworkGraph::new(genesis, logger)),
+			hidden_graph: Arc::new(HiddenGraph::read(&db).unwrap()),
+			route_cache: Arc::new(ProbeRouteCache::new()),
+			probe_monitor: Arc::new(ProbeMonitor::new(probe_monitor::DEFAULT_PROBE_TIMEOUT)),
+			politeness: Arc::new(Politeness::new(ProbeLimits::default())),
+			controls: Arc::new(ControlProbes::read(&db).unwrap()),
+			evaluations: Arc::new(Evaluations::new()),
+			metrics: Arc::new(Metrics::new()),
+			db: Arc::new(Mutex::new(db)),
+			payments: Arc::new(Mutex::new(HashMap::new())),
+			data_dir,
+		}
+	}
+
+	pub(crate) fn node_id(&self) -> PublicKey {
+		self.channel_manager.get_our_node_id()
+	}
+
+	/// What the prompt and the RPC server probe with, with the default settings.
+	fn probe_context(&self) -> ProbeContext {
+		ProbeContext {
+			channel_manager: self.channel_manager.clone(),
+			keys_manager: self.keys_manager.clone(),
+			network_graph: self.network_graph.clone(),
+			logger: self.logger.clone(),
+			scorer: Arc::new(Mutex::new(disk::read_scorer(
+				Path::new("no scorer yet"),
+				self.network_graph.clone(),
+				self.logger.clone(),
+			))),
+			db: self.db.clone(),
+			hidden_graph: self.hidden_graph.clone(),
+			route_cache: self.route_cache.clone(),
+			probe_monitor: self.probe_monitor.clone(),
+			politeness: self.politeness.clone(),
+			controls: self.controls.clone(),
+			denylist: Arc::new(Denylist::default()),
+			pending_payments: self.payments.clone(),
+			ldk_data_dir: self.data_dir.path().to_string(),
+			probe_config: ProbeConfig::default(),
+			metrics: self.metrics.clone(),
+		}
+	}
+
+	fn peer_manager(&self) -> Arc<PeerManager> {
+		let gossip_sync = Arc::new(P2PGossipSync::new(
+			self.network_graph.clone(),
+			None::<Arc<dyn lightning::chain::Access + Send + Sync>>,
+			self.logger.clone(),
+		));
+		Arc::new(PeerManager::new(
+			MessageHandler {
+				chan_handler: self.channel_manager.clone(),
+				route_handler: gossip_sync,
+			},
+			self.keys_manager.get_node_secret(Recipient::Node).unwrap(),
+			&[7; 32],
+			self.logger.clone(),
+			Arc::new(IgnoringMessageHandler {}),
+		))
+	}
+
+	/// Hands `event` to the same handler the background processor would.
+	pub(crate) async fn handle_event(&self, event: &Event) {
+		let payment_state: PaymentState = Arc::new(Mutex::new(HashMap::new()));
+		handle_ldk_events(
+			payment_state,
+			self.channel_manager.clone(),
+			self.client.clone(),
+			self.keys_manager.clone(),
+			self.payments.clone(),
+			self.payments.clone(),
+			self.payments.clone(),
+			Network::Regtest,
+			event,
+			self.db.clone(),
+			self.logger.clone(),
+			self.network_graph.clone(),
+			self.hidden_graph.clone(),
+			self.route_cache.clone(),
+			self.probe_monitor.clone(),
+			self.politeness.clone(),
+			self.controls.clone(),
+			self.evaluations.clone(),
+			self.metrics.clone(),
+		)
+		.await;
+	}
+}
+
+/// Checks `tx` against the outputs it spends, signatures and all.
+fn verify(chain: &MockChain, tx: &Transaction) {
+	tx.verify(|outpoint| chain.output(outpoint)).unwrap();
+}
+
+#[tokio::test]
+async fn test_check_chain() {
+	let chain = MockChain::new();
+	assert_eq!(check_chain(&chain, Network::Regtest).await, Ok(()));
+	assert_eq!(
+		check_chain(&chain, Network::Testnet).await,
+		Err("Chain argument (testnet) didn't match bitcoind chain (regtest)".to_string())
+	);
+}
+
+#[tokio::test]
+async fn test_fresh_node_starts_at_tip() {
+	let chain = Arc::new(MockChain::new());
+	chain.mine_blocks(3);
+	let node = TestNode::new("fresh", &chain, 1).await;
+
+	let best_block = node.channel_manager.current_best_block();
+	assert_eq!((best_block.block_hash(), best_block.height()), chain.tip());
+}
+
+#[tokio::test]
+async fn test_block_sync_follows_reorg() {
+	let chain = Arc::new(MockChain::new());
+	chain.mine_blocks(3);
+	let node = TestNode::new("sync", &chain, 1).await;
+
+	let chain_tip = init::validate_best_block_header(&*node.client).await.unwrap();
+	let mut cache = UnboundedCache::new();
+	let chain_poller = poll::ChainPoller::new(&*node.client, Network::Regtest);
+	let chain_listener = (node.chain_monitor.clone(), node.channel_manager.clone());
+	let mut spv_client = SpvClient::new(chain_tip, chain_poller, &mut cache, &chain_listener);
+
+	chain.mine_blocks(5);
+	spv_client.poll_best_tip().await.unwrap();
+	let best_block = node.channel_manager.current_best_block();
+	assert_eq!((best_block.block_hash(), best_block.height()), chain.tip());
+	assert_eq!(best_block.height(), 8);
+
+	// a longer fork from two blocks back replaces the tip
+	let (stale_tip, _) = chain.tip();
+	chain.disconnect_blocks(2);
+	chain.mine_blocks(3);
+	spv_client.poll_best_tip().await.unwrap();
+	let best_block = node.channel_manager.current_best_block();
+	assert_ne!(best_block.block_hash(), stale_tip);
+	assert_eq!((best_block.block_hash(), best_block.height()), chain.tip());
+	assert_eq!(best_block.height(), 9);
+}
+
+#[tokio::test]
+async fn test_funding_generation_ready() {
+	let chain = Arc::new(MockChain::new());
+	chain.mine_blocks(101);
+	let alice = TestNode::new("funding-alice", &chain, 1).await;
+	let bob = TestNode::new("funding-bob", &chain, 2).await;
+
+	let init = Init { features: InitFeatures::known(), remote_network_address: None };
+	alice.channel_manager.peer_connected(&bob.node_id(), &init);
+	bob.channel_manager.peer_connected(&alice.node_id(), &init);
+
+	alice.channel_manager.create_channel(bob.node_id(), 1_000_000, 0, 0, None).unwrap();
+	let open_channel = match alice.channel_manager.get_and_clear_pending_msg_events().remove(0) {
+		MessageSendEvent::SendOpenChannel { msg, .. } => msg,
+		_ => panic!("Unexpected event"),
+	};
+	bob.channel_manager.handle_open_channel(&alice.node_id(), InitFeatures::known(), &open_channel);
+	let accept_channel = match bob.channel_manager.get_and_clear_pending_msg_events().remove(0) {
+		MessageSendEvent::SendAcceptChannel { msg, .. } => msg,
+		_ => panic!("Unexpected event"),
+	};
+	alice.channel_manager.handle_accept_channel(
+		&bob.node_id(),
+		InitFeatures::known(),
+		&accept_channel,
+	);
+
+	let mut events = alice.channel_manager.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	let funding_event = events.remove(0);
+	let output_script = match &funding_event {
+		Event::FundingGenerationReady { output_script, .. } => output_script.clone(),
+		_ => panic!("Unexpected event"),
+	};
+	alice.handle_event(&funding_event).await;
+
+	let funding_created = match alice.channel_manager.get_and_clear_pending_msg_events().remove(0) {
+		MessageSendEvent::SendFundingCreated { msg, .. } => msg,
+		_ => panic!("Unexpected event"),
+	};
+	bob.channel_manager.handle_funding_created(&alice.node_id(), &funding_created);
+	let funding_signed = match bob.channel_manager.get_and_clear_pending_msg_events().remove(0) {
+		MessageSendEvent::SendFundingSigned { msg, .. } => msg,
+		_ => panic!("Unexpected event"),
+	};
+	alice.channel_manager.handle_funding_signed(&bob.node_id(), &funding_signed);
+
+	// the funding transaction pays the channel and hands the change back to the wallet
+	let broadcasts = chain.broadcasts();
+	assert_eq!(broadcasts.len(), 1);
+	let funding_tx = &broadcasts[0];
+	assert_eq!(funding_tx.txid(), funding_created.funding_txid);
+	let funding_output = &funding_tx.output[funding_created.funding_output_index as usize];
+	assert_eq!(*funding_output, TxOut { value: 1_000_000, script_pubkey: output_script });
+	assert_eq!(funding_tx.output.len(), 2);
+	assert!(funding_tx.output.iter().any(|output| chain.is_wallet_script(&output.script_pubkey)));
+	verify(&chain, funding_tx);
+}
+
+#[tokio::test]
+async fn test_spendable_outputs() {
+	let chain = Arc::new(MockChain::new());
+	chain.mine_blocks(101);
+	let node = TestNode::new("spendable", &chain, 1).await;
+
+	// pay the node's destination script from the wallet, like a channel close would
+	let destination_script = node.keys_manager.get_destination_script();
+	let destination = Address::from_script(&destination_script, Network::Regtest).unwrap();
+	let mut outputs = vec![HashMap::with_capacity(1)];
+	outputs[0].insert(destination.to_string(), 0.01);
+	let raw_tx = node.client.create_raw_transaction(outputs).await;
+	let funded_tx = node.client.fund_raw_transaction(raw_tx).await;
+	let signed_tx = node.client.sign_raw_transaction_with_wallet(funded_tx.hex).await;
+	assert!(signed_tx.complete);
+	let close_tx: Transaction =
+		bitcoin::consensus::encode::deserialize(&hex::decode(&signed_tx.hex).unwrap()).unwrap();
+	node.client.broadcast_transaction(&close_tx);
+	chain.mine_blocks(1);
+
+	let vout = close_tx.output.iter().position(|output| output.script_pubkey == destination_script);
+	let outpoint = OutPoint { txid: close_tx.txid(), vout: vout.unwrap() as u32 };
+	let descriptor = SpendableOutputDescriptor::StaticOutput {
+		outpoint: lightning::chain::transaction::OutPoint {
+			txid: outpoint.txid,
+			index: outpoint.vout as u16,
+		},
+		output: close_tx.output[outpoint.vout as usize].clone(),
+	};
+	node.handle_event(&Event::SpendableOutputs { outputs: vec![descriptor] }).await;
+
+	// the output is swept back into the wallet
+	let broadcasts = chain.broadcasts();
+	assert_eq!(broadcasts.len(), 2);
+	let sweep_tx = &broadcasts[1];
+	assert_eq!(sweep_tx.input.len(), 1);
+	assert_eq!(sweep_tx.input[0].previous_output, outpoint);
+	assert_eq!(sweep_tx.output.len(), 1);
+	assert!(chain.is_wallet_script(&sweep_tx.output[0].script_pubkey));
+	assert!(sweep_tx.output[0].value < 1_000_000);
+	verify(&chain, sweep_tx);
+}
+
+/// Posts a JSON-RPC request to the server on `socket`, with the cookie as basic auth if given.
+async fn rpc_call(socket: &str, cookie: Option<&str>, request: Value) -> (StatusCode, Value) {
+	let stream = UnixStream::connect(socket).await.unwrap();
+	let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+	tokio::spawn(connection);
+	let mut builder = Request::post("/").header(header::CONTENT_TYPE, "application/json");
+	if let Some(cookie) = cookie {
+		builder =
+			builder.header(header::AUTHORIZATION, format!("Basic {}", base64::encode(cookie)));
+	}
+	let response =
+		sender.send_request(builder.body(Body::from(request.to_string())).unwrap()).await.unwrap();
+	let status = response.status();
+	let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+	(status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn test_probeall_job_needs_a_usable_channel() {
+	let chain = Arc::new(MockChain::new());
+	let node = TestNode::new("no-channel", &chain, 7).await;
+	let ctx = node.probe_context();
+	let running = AtomicUsize::new(0);
+	let started = Instant::now();
+	assert!(!daemon::wait_for_usable_channel(&ctx, &running, Duration::from_millis(300)));
+	assert!(started.elapsed() >= Duration::from_millis(300));
+
+	// a signal doesn't wait out the timeout
+	running.store(1, Ordering::SeqCst);
+	let job = Job::ProbeAll {
+		probetype: "all".to_string(),
+		targets: "all".to_string(),
+		candidates: node.data_dir.path().to_string(),
+	};
+	assert_eq!(daemon::run_job(&job, &ctx, &running), daemon::EXIT_INTERRUPTED);
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+	let chain = Arc::new(MockChain::new());
+	let node = TestNode::new("metrics", &chain, 6).await;
+	let ctx = node.probe_context();
+	ctx.metrics.record_sent("probe");
+	let addr = metrics::serve(([127, 0, 0, 1], 0).into(), ctx).await.unwrap();
+
+	let get = |path: &'static str| async move {
+		let stream = TcpStream::connect(addr).await.unwrap();
+		let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+		tokio::spawn(connection);
+		let response =
+			sender.send_request(Request::get(path).body(Body::empty()).unwrap()).await.unwrap();
+		let status = response.status();
+		let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		(status, String::from_utf8(body.to_vec()).unwrap())
+	};
+	let (status, body) = get("/metrics").await;
+	assert_eq!(status, StatusCode::OK);
+	assert!(body.contains("ldk_sample_probes_sent_total{kind=\"probe\"} 1\n"));
+	assert!(body.contains("ldk_sample_probes_stuck 0\n"));
+	assert!(body.contains("ldk_sample_hidden_channels 0\n"));
+	assert_eq!(get("/").await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rpc_over_unix_socket() {
+	let chain = Arc::new(MockChain::new());
+	let node = TestNode::new("rpc", &chain, 6).await;
+	let socket = format!("{}/rpc.sock", node.data_dir.path());
+	let config = RpcConfig { bind: None, socket: Some(socket.clone()) };
+	let server =
+		RpcServer::start(&config, node.probe_context(), node.peer_manager()).await.unwrap();
+	let cookie_path = format!("{}/.cookie", node.data_dir.path());
+	let cookie = fs::read_to_string(&cookie_path).unwrap();
+	assert!(cookie.starts_with("__cookie__:"));
+
+	let nodeinfo = json!({"jsonrpc": "2.0", "id": 1, "method": "nodeinfo"});
+	let (status, _) = rpc_call(&socket, None, nodeinfo.clone()).await;
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+	let (status, _) = rpc_call(&socket, Some("__cookie__:00"), nodeinfo.clone()).await;
+	assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+	let (status, response) = rpc_call(&socket, Some(&cookie), nodeinfo).await;
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(response["id"], 1);
+	assert_eq!(response["result"]["node_pubkey"], node.node_id().to_string());
+	assert_eq!(response["result"]["num_channels"], 0);
+
+	let call = |method: &str, params: Value| json!({"jsonrpc": "2.0", "id": 2, "method": method, "params": params});
+	let (_, response) = rpc_call(&socket, Some(&cookie), call("listchannels", json!([]))).await;
+	assert_eq!(response["result"], json!([]));
+	let (_, response) = rpc_call(&socket, Some(&cookie), call("stopjob", json!([1]))).await;
+	assert_eq!(response["error"], json!({"code": -1, "message": "no job 1"}));
+
+	// a job that fails on its own thread shows up in listjobs
+	let missing = format!("{}/no-such-dir", node.data_dir.path());
+	let params = json!({"targets": "all", "candidates": missing});
+	let (_, response) = rpc_call(&socket, Some(&cookie), call("probeall", params)).await;
+	assert_eq!(response["result"]["id"], 1);
+	let mut job = Value::Null;
+	for _ in 0..100 {
+		let (_, response) = rpc_call(&socket, Some(&cookie), call("listjobs", json!([]))).await;
+		job = response["result"][0].clone();
+		if job["state"] == "failed" {
+			break;
+		}
+		tokio::time::sleep(Duration::from_millis(20)).await;
+	}
+	assert_eq!(job["state"], "failed");
+	assert!(job["error"].as_str().unwrap().starts_with("couldn't read transactions"));
+
+	server.shutdown();
+	assert!(!Path::new(&cookie_path).exists());
+	assert!(!Path::new(&socket).exists());
+}
+
+/// A probe from us over a public peer and the target to the guessed node, failed with
+/// `error_code` by the node at `failing_hop` in the path. Channel errors are about the channel
+/// out of that node, the way `process_onion_failure` reports them.
+fn failed_probe(failing_hop: usize, error_code: u16, rejected_by_dest: bool) -> Event {
+	let path: Vec<RouteHop> = (0..3)
+		.map(|i| RouteHop {
+			pubkey: pubkey(i as u8 + 2),
+			node_features: NodeFeatures::known(),
+			short_channel_id: i + 1,
+			channel_features: ChannelFeatures::known(),
+			fee_msat: 1000,
+			cltv_expiry_delta: 40,
+		})
+		.collect();
+	let (network_update, short_channel_id) = if rejected_by_dest {
+		(None, None)
+	} else {
+		let scid = path[failing_hop + 1].short_channel_id;
+		(
+			Some(NetworkUpdate::ChannelFailure { short_channel_id: scid, is_permanent: true }),
+			Some(scid),
+		)
+	};
+	Event::PaymentPathFailed {
+		payment_id: None,
+		payment_hash: PaymentHash([failing_hop as u8; 32]),
+		rejected_by_dest,
+		network_update,
+		all_paths_failed: true,
+		path,
+		short_channel_id,
+		retry: None,
+		error_code: Some(error_code),
+		error_data: None,
+	}
+}
+
+#[tokio::test]
+async fn test_control_answers_only_count_from_the_target() {
+	let chain = Arc::new(MockChain::new());
+	let node = TestNode::new("controls", &chain, 8).await;
+	// the target and the guessed channel of `failed_probe`
+	let (target, scid) = (pubkey(3).to_string(), 3);
+	let control =
+		ControlProbe { short_channel_id: scid, guess: pubkey(4), control: Control::Absent };
+
+	// the public peer before the target fails it, with a stale fee or a channel just closed
+	for error_code in [0x100c, 0x400a] {
+		node.controls.record_sent(&target, &control);
+		node.handle_event(&failed_probe(0, error_code, false)).await;
+		assert_eq!(node.controls.take_control(&target, scid), None);
+		assert!(node.controls.verdict(&target).is_none());
+	}
+
+	// the target answers the control
+	node.controls.record_sent(&target, &control);
+	node.handle_event(&failed_probe(1, 0x400a, false)).await;
+	let verdict = node.controls.verdict(&target).unwrap();
+	assert_eq!((verdict.answered, verdict.deviations), (1, 0));
+}
+
+#[tokio::test]
+async fn test_evaluation_answers_only_count_from_the_target() {
+	let chain = Arc::new(MockChain::new());
+	let node = TestNode::new("evaluations", &chain, 9).await;
+	let probe = |expected| EvaluationProbe { target: pubkey(3), short_channel_id: 3, expected };
+
+	// failed by the public peer before the target, neither detected nor a false positive
+	for expected in [Control::Present, Control::Absent] {
+		node.evaluations.record_sent(&probe(expected));
+		node.handle_event(&failed_probe(0, 0x100c, false)).await;
+		assert_eq!(node.evaluations.pending(), 0);
+	}
+	// the guessed node answers the positive
+	node.evaluations.record_sent(&probe(Control::Present));
+	node.handle_event(&failed_probe(2, 0x400f, true)).await;
+
+	let probes = [probe(Control::Present), probe(Control::Absent)];
+	let report = node.evaluations.finish(&node.db.lock().unwrap(), &probes, "fixed", 0, 0).unwrap();
+	assert_eq!((report.positives_answered, report.detected), (1, 1));
+	assert_eq!((report.negatives_answered, report.false_positives), (0, 0));
+}
@@ -0,0 +1,258 @@
+use chrono::{Timelike, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Back-off after the first `temporary_node_failure`, doubled for every one after that.
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// `temporary_node_failure`, the target is telling us it's overloaded.
+const TEMPORARY_NODE_FAILURE: u16 = 0x2002;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ProbeLimits {
+	pub(crate) per_minute: usize,
+	pub(crate) per_day: usize,
+	/// UTC hours `[start, end)` during which no probes go out at all.
+	pub(crate) quiet_hours: Option<(u32, u32)>,
+}
+
+impl Default for ProbeLimits {
+	fn default() -> Self {
+		ProbeLimits { per_minute: 30, per_day: 20_000, quiet_hours: None }
+	}
+}
+
+impl ProbeLimits {
+	pub(crate) fn in_quiet_hours(&self, hour: u32) -> bool {
+		match self.quiet_hours {
+			Some((start, end)) if start <= end => hour >= start && hour < end,
+			// the quiet hours run past midnight
+			Some((start, end)) => hour >= start || hour < end,
+			None => false,
+		}
+	}
+}
+
+/// Parses quiet hours written as `start-end`, in UTC hours.
+pub(crate) fn parse_quiet_hours(hours: &str) -> Result<(u32, u32), Box<dyn std::error::Error>> {
+	let (start, end) = hours.split_once('-').ok_or("quiet hours must be start-end")?;
+	let (start, end) = (start.parse::<u32>()?, end.parse::<u32>()?);
+	if start > 23 || end > 24 {
+		return Err("quiet hours must be between 0 and 24".into());
+	}
+	Ok((start, end))
+}
+
+/// Why a probe to a target has to wait.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Hold {
+	QuietHours,
+	PerMinute(Duration),
+	PerDay,
+	BackingOff(Duration),
+}
+
+#[derive(Default)]
+struct TargetState {
+	/// When we sent the probes of the last day, oldest first.
+	sent: VecDeque<Instant>,
+	backoff_level: u32,
+	backoff_until: Option<Instant>,
+}
+
+impl TargetState {
+	fn forget_older_than_a_day(&mut self, now: Instant) {
+		while matches!(self.sent.front(), Some(sent) if now.duration_since(*sent) >= DAY) {
+			self.sent.pop_front();
+		}
+	}
+
+	fn sent_in_last_minute(&self, now: Instant) -> usize {
+		self.sent.iter().rev().take_while(|sent| now.duration_since(**sent) < MINUTE).count()
+	}
+}
+
+/// Per target probe status, for `status`.
+pub(crate) struct TargetStatus {
+	pub(crate) target: String,
+	pub(crate) last_minute: usize,
+	pub(crate) last_day: usize,
+	pub(crate) backing_off: Option<Duration>,
+}
+
+/// Keeps the probe scheduler from hammering any single target, so a long campaign doesn't look
+/// like someone jamming their channels.
+pub(crate) struct Politeness {
+	limits: Mutex<ProbeLimits>,
+	targets: Mutex<HashMap<String, TargetState>>,
+}
+
+impl Politeness {
+	pub(crate) fn new(limits: ProbeLimits) -> Self {
+		Politeness { limits: Mutex::new(limits), targets: Mutex::new(HashMap::new()) }
+	}
+
+	pub(crate) fn limits(&self) -> ProbeLimits {
+		*self.limits.lock().unwrap()
+	}
+
+	pub(crate) fn set_limits(&self, limits: ProbeLimits) {
+		*self.limits.lock().unwrap() = limits;
+	}
+
+	/// Whether a probe may go out to `target` right now, and if not, why.
+	pub(crate) fn check(&self, target: &str) -> Option<Hold> {
+		let limits = self.limits();
+		if limits.in_quiet_hours(Utc::now().hour()) {
+			return Some(Hold::QuietHours);
+		}
+		let now = Instant::now();
+		let mut targets = self.targets.lock().unwrap();
+		let state = targets.get_mut(target)?;
+		if let Some(until) = state.backoff_until {
+			if until > now {
+				return Some(Hold::BackingOff(until - now));
+			}
+		}
+		state.forget_older_than_a_day(now);
+		if state.sent.len() >= limits.per_day {
+			return Some(Hold::PerDay);
+		}
+		if state.sent_in_last_minute(now) >= limits.per_minute {
+			let oldest_in_minute = state.sent[state.sent.len() - limits.per_minute];
+			return Some(Hold::PerMinute(MINUTE - now.duration_since(oldest_in_minute)));
+		}
+		None
+	}
+
+	pub(crate) fn record_sent(&self, target: &str) {
+		let mut targets = self.targets.lock().unwrap();
+		targets.entry(target.to_string()).or_default().sent.push_back(Instant::now());
+	}
+
+	/// Backs off exponentially while `target` keeps answering `temporary_node_failure`, any
+	/// other answer resets it.
+	pub(crate) fn record_answer(&self, target: &str, error_code: u16) {
+		let mut targets = self.targets.lock().unwrap();
+		let state = targets.entry(target.to_string()).or_default();
+		if error_code == TEMPORARY_NODE_FAILURE {
+			let backoff = BASE_BACKOFF
+				.checked_mul(1 << state.backoff_level.min(16))
+				.map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+			state.backoff_level += 1;
+			state.backoff_until = Some(Instant::now() + backoff);
+		} else {
+			state.backoff_level = 0;
+			state.backoff_until = None;
+		}
+	}
+
+	pub(crate) fn status(&self) -> Vec<TargetStatus> {
+		let now = Instant::now();
+		let mut targets = self.targets.lock().unwrap();
+		let mut status: Vec<TargetStatus> = targets
+			.iter_mut()
+			.map(|(target, state)| {
+				state.forget_older_than_a_day(now);
+				TargetStatus {
+					target: target.clone(),
+					last_minute: state.sent_in_last_minute(now),
+					last_day: state.sent.len(),
+					backing_off: state
+						.backoff_until
+						.filter(|until| *until > now)
+						.map(|until| until - now),
+				}
+			})
+			.collect();
+		status.sort_by_key(|target| std::cmp::Reverse(target.last_day));
+		status
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_quiet_hours() {
+		let limits = |quiet_hours| ProbeLimits { quiet_hours, ..Default::default() };
+		assert!(!limits(None).in_quiet_hours(3));
+
+		let night = limits(Some(parse_quiet_hours("1-5").unwrap()));
+		assert!(!night.in_quiet_hours(0));
+		assert!(night.in_quiet_hours(1));
+		assert!(night.in_quiet_hours(4));
+		assert!(!night.in_quiet_hours(5));
+
+		let past_midnight = limits(Some(parse_quiet_hours("22-6").unwrap()));
+		assert!(past_midnight.in_quiet_hours(23));
+		assert!(past_midnight.in_quiet_hours(0));
+		assert!(!past_midnight.in_quiet_hours(6));
+		assert!(!past_midnight.in_quiet_hours(12));
+
+		for invalid in ["", "1", "1-", "a-5", "24-1", "1-25"] {
+			assert!(parse_quiet_hours(invalid).is_err(), "{}", invalid);
+		}
+
+		// all day, no probe goes out whatever the hour is now
+		let politeness = Politeness::new(limits(Some((0, 24))));
+		assert_eq!(politeness.check("target"), Some(Hold::QuietHours));
+	}
+
+	#[test]
+	fn test_limits() {
+		let politeness =
+			Politeness::new(ProbeLimits { per_minute: 2, per_day: 3, quiet_hours: None });
+		assert_eq!(politeness.check("target"), None);
+		politeness.record_sent("target");
+		politeness.record_sent("target");
+		match politeness.check("target") {
+			Some(Hold::PerMinute(wait)) => assert!(wait <= MINUTE && wait > MINUTE / 2),
+			hold => panic!("expected to wait for the minute, got {:?}", hold),
+		}
+		// every target has limits of its own
+		assert_eq!(politeness.check("other"), None);
+
+		politeness.set_limits(ProbeLimits { per_minute: 10, ..politeness.limits() });
+		assert_eq!(politeness.check("target"), None);
+		politeness.record_sent("target");
+		assert_eq!(politeness.check("target"), Some(Hold::PerDay));
+
+		let status = politeness.status();
+		assert_eq!(status.len(), 1);
+		assert_eq!((status[0].last_minute, status[0].last_day), (3, 3));
+	}
+
+	#[test]
+	fn test_backoff() {
+		let politeness = Politeness::new(ProbeLimits::default());
+		let backing_off = |politeness: &Politeness| match politeness.check("target") {
+			Some(Hold::BackingOff(wait)) => wait,
+			hold => panic!("expected a back-off, got {:?}", hold),
+		};
+
+		politeness.record_answer("target", TEMPORARY_NODE_FAILURE);
+		let first = backing_off(&politeness);
+		assert!(first <= BASE_BACKOFF && first > BASE_BACKOFF / 2);
+		politeness.record_answer("target", TEMPORARY_NODE_FAILURE);
+		let second = backing_off(&politeness);
+		assert!(second <= 2 * BASE_BACKOFF && second > BASE_BACKOFF);
+		for _ in 0..20 {
+			politeness.record_answer("target", TEMPORARY_NODE_FAILURE);
+		}
+		assert!(backing_off(&politeness) <= MAX_BACKOFF);
+		assert!(politeness.status()[0].backing_off.is_some());
+
+		// any other answer means the target is fine again
+		politeness.record_answer("target", 0x400f);
+		assert_eq!(politeness.check("target"), None);
+		politeness.record_answer("target", TEMPORARY_NODE_FAILURE);
+		assert!(backing_off(&politeness) <= BASE_BACKOFF);
+	}
+}
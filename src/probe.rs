@@ -1,34 +1,73 @@
 use crate::disk::FilesystemLogger;
+use crate::hidden_graph::HiddenGraph;
+use crate::hint_policy::hint_policy;
+use crate::metrics::Metrics;
+use crate::route_cache::{GuessedHop, ProbeRouteCache};
 
-use crate::{ChannelManager, InvoicePayer, NetworkGraph, PaymentInfo, PaymentInfoStorage};
+use crate::{ChannelManager, NetworkGraph, PaymentInfo, PaymentInfoStorage, ProbeInfo};
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::secp256k1::PublicKey;
+use lightning::chain;
+use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::keysinterface::{KeysInterface, Sign};
+use lightning::ln::channelmanager::{self, ChannelDetails, PaymentId, PaymentSendFailure};
 use lightning::ln::msgs::ErrorAction;
 use lightning::ln::msgs::LightningError;
 use lightning::ln::{PaymentHash, PaymentPreimage};
-use lightning::routing::gossip::RoutingFees;
+use lightning::routing::gossip::{NetworkUpdate, RoutingFees};
 use lightning::routing::router::PaymentParameters;
 use lightning::routing::router::Route;
 use lightning::routing::router::RouteParameters;
-use lightning::routing::router::{find_route, RouteHint, RouteHintHop};
+use lightning::routing::router::{find_route, RouteHint, RouteHop};
 use lightning::routing::scoring::{ProbabilisticScorer, ProbabilisticScoringParameters};
 use lightning::util::logger::Logger;
-use lightning::{log_given_level, log_internal, log_trace, log_warn};
+use lightning::{log_given_level, log_info, log_internal, log_trace, log_warn};
 
-use lightning::util::events::EventHandler;
 use lightning_invoice::payment::Payer;
 
 use rand::Rng;
+use std::ops::Deref;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-pub(crate) fn probe<E: EventHandler>(
-	pubkey_str: &str, channel_id_str: &str, pubkey_guess: &str, invoice_payer: &InvoicePayer<E>,
-	channel_manager: Arc<ChannelManager>, network_graph: &Arc<NetworkGraph>,
-	logger: &Arc<FilesystemLogger>, ldk_data_dir: &String,
+/// Fees we assume for a guessed hop when we know nothing better.
+pub(crate) const GUESSED_FEES: RoutingFees =
+	RoutingFees { base_msat: 1000, proportional_millionths: 1 };
+
+/// The `cltv_expiry_delta` we assume for a guessed hop, the most common one.
+pub(crate) const GUESSED_CLTV_EXPIRY_DELTA: u16 = 40;
+
+/// What a probe delivers to the guessed node, it never gets there anyway.
+pub(crate) const PROBE_AMOUNT_MSAT: u64 = 1000;
+
+/// The final `cltv_expiry_delta` of a probe.
+pub(crate) const PROBE_FINAL_CLTV_EXPIRY_DELTA: u32 = 40;
+
+/// Where probes go out. That is the channel manager on a live node, or a `Simulator` when
+/// trying out probe strategies offline.
+pub(crate) trait ProbeSender {
+	fn send_probe(
+		&self, route: &Route, payment_hash: PaymentHash,
+	) -> Result<PaymentId, PaymentSendFailure>;
+}
+
+impl<P: Payer + ?Sized> ProbeSender for P {
+	fn send_probe(
+		&self, route: &Route, payment_hash: PaymentHash,
+	) -> Result<PaymentId, PaymentSendFailure> {
+		self.send_payment(route, payment_hash, &None)
+	}
+}
+
+pub(crate) fn probe<P: Payer + ?Sized>(
+	pubkey_str: &str, channel_id_str: &str, pubkey_guess: &str, channel_manager: &P,
+	network_graph: &Arc<NetworkGraph>, logger: &Arc<FilesystemLogger>, ldk_data_dir: &String,
 	pending_payment_state: PaymentInfoStorage,
 	scorer: &Arc<Mutex<ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>>>,
+	hidden_graph: &HiddenGraph, route_cache: &ProbeRouteCache, guess_strategy: &'static str,
+	metrics: &Metrics, kind: &'static str,
 ) -> Result<(), Box<dyn std::error::Error>> {
 	let source_pubkey = PublicKey::from_str(pubkey_str).unwrap();
 	let channel_id = channel_id_str.parse::<u64>();
@@ -36,56 +75,128 @@ pub(crate) fn probe<E: EventHandler>(
 		eprintln!("channel_id isn't a number");
 		return Err("channel_id isn't a number")?;
 	}
-	let fake_preimage = rand::thread_rng().gen::<[u8; 32]>();
-	let payment_hash = PaymentHash(Sha256::hash(&fake_preimage).into_inner());
-	// Create the fake route information
-	let guessed_fee = RoutingFees { base_msat: 1000, proportional_millionths: 1 };
-	let next_route_hint = vec![RouteHint(vec![RouteHintHop {
-		src_node_id: source_pubkey, // the source
+	// A direct peer is reached over our own channel, any other private target only through
+	// channels we've already discovered
+	let is_peer = channel_manager
+		.first_hops()
+		.iter()
+		.any(|channel| channel.counterparty.node_id == source_pubkey);
+	let hops = if is_peer {
+		vec![]
+	} else {
+		match hidden_graph.hops_to(&source_pubkey, network_graph) {
+			Some(hops) => hops,
+			None => return Err("no known path to private target")?,
+		}
+	};
+	let pubkey_guess = match PublicKey::from_str(pubkey_guess) {
+		Ok(pubkey) => pubkey,
+		Err(_) => return Err("guessed pubkey isn't a valid pubkey")?,
+	};
+	// send to guessed pubkey instead of who we are intending as the target
+	let policy = hint_policy(&source_pubkey, network_graph, hidden_graph);
+	let hop = GuessedHop {
+		target: source_pubkey,
 		short_channel_id: channel_id.unwrap(),
-		fees: guessed_fee,
-		cltv_expiry_delta: 40, // the most common cltv
-		htlc_minimum_msat: None,
-		htlc_maximum_msat: None,
-	}])];
-	let route = find_routes(
-		invoice_payer,
-		channel_manager.clone(),
-		pubkey_guess, // send to guessed pubkey instead of who we are intending as the target
-		network_graph,
+		guess: pubkey_guess,
+		policy,
+	};
+	let route = route_cache.probe_route(&hop, network_graph, hops, |route_hints| {
+		find_routes(
+			channel_manager,
+			pubkey_str,
+			network_graph,
+			logger,
+			ldk_data_dir.clone(),
+			route_hints,
+			scorer,
+		)
+	});
+	let route = match route {
+		Ok(route) => route,
+		Err(e) => {
+			// if no routes found, check to make sure we are still
+			// connected to our node
+			println!("No route: {:?}", e);
+			return Err("no route")?;
+		}
+	};
+
+	if let Err(e) = send_probe(
+		&route,
+		channel_manager,
+		pending_payment_state,
 		logger,
-		ldk_data_dir.clone(),
-		next_route_hint,
-		scorer,
-	);
+		guess_strategy,
+		metrics,
+		kind,
+	) {
+		route_cache.invalidate_target(&source_pubkey);
+		return Err(e);
+	}
 
-	let route = if let Ok(route) = route {
-		// paths should always be a vec<vec<hops>>
-		route
-	} else {
-		// if no routes found, check to make sure we are still
-		// connected to our node
-		println!("No route: {:?}", route.err().unwrap());
-		return Err("no route")?;
-	};
+	Ok(())
+}
 
-	let preimage = PaymentPreimage(fake_preimage);
-	let payment_info = PaymentInfo {
-		preimage: Some(preimage),
-		secret: None,
-		status: crate::HTLCStatus::Pending,
-		amt_msat: crate::MillisatAmount(Some(10000)),
-	};
+/// Sends a probe for `short_channel_id` again once `target` told us its policy for the channel,
+/// so that it can reach the guessed node. Only targets we've got a cached route to qualify.
+pub(crate) fn resend_probe(
+	target: &PublicKey, short_channel_id: u64, guess: &PublicKey,
+	channel_manager: &Arc<ChannelManager>, network_graph: &NetworkGraph,
+	logger: &Arc<FilesystemLogger>, pending_payment_state: PaymentInfoStorage,
+	hidden_graph: &HiddenGraph, route_cache: &ProbeRouteCache, guess_strategy: &'static str,
+	metrics: &Metrics,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let policy = hint_policy(target, network_graph, hidden_graph);
+	let hop = GuessedHop { target: *target, short_channel_id, guess: *guess, policy };
+	let route = route_cache.probe_route(&hop, network_graph, vec![], |_| {
+		Err(LightningError { err: "no cached route".to_string(), action: ErrorAction::IgnoreError })
+	})?;
+	log_info!(logger, "Probing {}:{} again with {:?}", target, short_channel_id, policy);
+	send_probe(
+		&route,
+		&**channel_manager,
+		pending_payment_state,
+		logger,
+		guess_strategy,
+		metrics,
+		"resend",
+	)
+}
+
+/// Sends a probe along `route` and counts it in `metrics` by `kind`, what it was sent for.
+pub(crate) fn send_probe<S: ProbeSender + ?Sized>(
+	route: &Route, sender: &S, pending_payment_state: PaymentInfoStorage,
+	logger: &Arc<FilesystemLogger>, guess_strategy: &'static str, metrics: &Metrics,
+	kind: &'static str,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let fake_preimage = rand::thread_rng().gen::<[u8; 32]>();
+	let payment_hash = PaymentHash(Sha256::hash(&fake_preimage).into_inner());
+	// keep the lock until the probe is in, so its failure can't be handled before
 	let mut state = pending_payment_state.lock().unwrap();
-	state.insert(payment_hash, payment_info);
-	let payment = channel_manager.send_payment(&route, payment_hash, &None);
+	let payment = sender.send_probe(route, payment_hash);
 	match payment {
 		Ok(payment_id) => {
-			log_trace!(logger, "sent payment {:?}", payment_id)
+			log_trace!(logger, "sent payment {:?}", payment_id);
+			metrics.record_sent(kind);
+			state.insert(
+				payment_hash,
+				PaymentInfo {
+					preimage: Some(PaymentPreimage(fake_preimage)),
+					secret: None,
+					status: crate::HTLCStatus::Pending,
+					amt_msat: crate::MillisatAmount(Some(10000)),
+					probe: Some(ProbeInfo {
+						guess_strategy,
+						payment_id,
+						sent_at: Instant::now(),
+						path: route.paths[0].clone(),
+					}),
+				},
+			);
 		}
 		Err(e) => {
 			log_warn!(logger, "error sending probe {:?}", e);
-			state.remove(&payment_hash);
 			return Err("no route")?;
 		}
 	}
@@ -93,14 +204,89 @@ pub(crate) fn probe<E: EventHandler>(
 	Ok(())
 }
 
-pub(crate) fn find_routes<E: EventHandler>(
-	invoice_payer: &InvoicePayer<E>, channel_manager: Arc<ChannelManager>, payee_pubkey: &str,
-	network: &NetworkGraph, logger: &FilesystemLogger, _ldk_data_dir: String,
-	private_routes: Vec<RouteHint>,
+/// Names the failure a probe's guessed hop came back with. `rejected_by_dest` is set when the
+/// failure came from the end of the path, the guessed node itself.
+pub(crate) fn probe_result(error_code: u16, rejected_by_dest: bool) -> &'static str {
+	match error_code {
+		0x400f => "incorrect_or_unknown_payment_details", // bingo
+		// bingo if the guessed node sent it, LDK wants a payment secret
+		0x6003 if rejected_by_dest => "required_node_feature_missing",
+		0x100c => "fee_insufficient",      // channel found, wrong fee
+		0xc005 => "invalid_onion_hmac",    // channel found, wrong node
+		0x100d => "incorrect_cltv_expiry", // channel found, wrong cltv
+		0x100b => "amount_below_minimum",  // channel found, amount below minimum
+		0x400a => "unknown_next_peer",     // no channel found
+		_ => "unknown",
+	}
+}
+
+/// Whether a failed probe's error came from `target` itself, rather than from a hop on the way to
+/// it or from the guessed node behind it. Node errors name the failing node, the target's other
+/// errors are about the guessed channel `chan_id`.
+pub(crate) fn failed_at_target(
+	target: &PublicKey, chan_id: u64, network_update: &Option<NetworkUpdate>,
+	failed_scid: Option<u64>,
+) -> bool {
+	match network_update {
+		Some(NetworkUpdate::NodeFailure { node_id, .. }) => node_id == target,
+		_ => failed_scid == Some(chan_id),
+	}
+}
+
+/// The node the guessed hop of a failed probe left from. That is the hop before it, or our own
+/// node when the guessed hop went straight out over one of our channels.
+pub(crate) fn probe_origin<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(
+	path_to_target: &[RouteHop], chan_id: u64,
+	channel_manager: &channelmanager::ChannelManager<Signer, M, T, K, F, L>,
+) -> Option<PublicKey>
+where
+	M::Target: chain::Watch<Signer>,
+	T::Target: BroadcasterInterface,
+	K::Target: KeysInterface<Signer = Signer>,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	if let Some(hop) = path_to_target.last() {
+		return Some(hop.pubkey);
+	}
+	let first_hops = channel_manager.list_channels();
+	if first_hops.iter().any(|channel| {
+		channel.short_channel_id == Some(chan_id) || channel.outbound_scid_alias == Some(chan_id)
+	}) {
+		Some(channel_manager.get_our_node_id())
+	} else {
+		None
+	}
+}
+
+pub(crate) fn find_routes<P: Payer + ?Sized>(
+	channel_manager: &P, payee_pubkey: &str, network: &NetworkGraph, logger: &FilesystemLogger,
+	_ldk_data_dir: String, private_routes: Vec<RouteHint>,
 	scorer: &Arc<Mutex<ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>>>,
 ) -> Result<Route, LightningError> {
-	let our_node_pubkey = channel_manager.get_our_node_id();
+	let first_hops = channel_manager.first_hops();
+	let random_bytes = rand::thread_rng().gen::<[u8; 32]>();
+	find_route_from(
+		&channel_manager.node_id(),
+		payee_pubkey,
+		network,
+		Some(&first_hops.iter().collect::<Vec<_>>()),
+		logger,
+		private_routes,
+		scorer,
+		&random_bytes,
+	)
+}
 
+/// Like `find_routes`, but from any node of the graph, over `first_hops` if it has channels
+/// the graph doesn't know about, and with the route randomness given rather than drawn.
+pub(crate) fn find_route_from(
+	payer: &PublicKey, payee_pubkey: &str, network: &NetworkGraph,
+	first_hops: Option<&[&ChannelDetails]>, logger: &FilesystemLogger,
+	private_routes: Vec<RouteHint>,
+	scorer: &Arc<Mutex<ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>>>,
+	random_bytes: &[u8; 32],
+) -> Result<Route, LightningError> {
 	let their_pubkey = match PublicKey::from_str(payee_pubkey) {
 		Ok(pubkey) => pubkey,
 		Err(_e) => {
@@ -110,27 +296,24 @@ pub(crate) fn find_routes<E: EventHandler>(
 
 	let payment_params =
 		PaymentParameters::from_node_id(their_pubkey).with_route_hints(private_routes);
-	let route_params =
-		RouteParameters { payment_params, final_value_msat: 1000, final_cltv_expiry_delta: 40 };
-
-	// Insert the fake hops at the end as route hints
-	let first_hops = channel_manager.first_hops();
+	let route_params = RouteParameters {
+		payment_params,
+		final_value_msat: PROBE_AMOUNT_MSAT,
+		final_cltv_expiry_delta: PROBE_FINAL_CLTV_EXPIRY_DELTA,
+	};
 
 	//let params = ProbabilisticScoringParameters::default();
 	// params.liquidity_penalty_multiplier_msat = 100_000;
 	//let scorer = ProbabilisticScorer::new(params, network);
-	let random_bytes = rand::thread_rng().gen::<[u8; 32]>();
-	let route = find_route(
-		&our_node_pubkey,
+	find_route(
+		payer,
 		&route_params,
 		network,
-		Some(&first_hops.iter().collect::<Vec<_>>()),
+		first_hops,
 		logger,
 		&scorer.lock().unwrap(),
-		&random_bytes,
-	);
-
-	route
+		random_bytes,
+	)
 }
 
 /// Maximum block height that can be used in a `short_channel_id`. This
@@ -145,6 +328,12 @@ pub const MAX_SCID_TX_INDEX: u64 = 0x00ffffff;
 /// value is based on the 2-bytes available for the vout index.
 pub const MAX_SCID_VOUT_INDEX: u64 = 0xffff;
 
+/// Whether a funding output fits the `assumptions` probe type: a round amount in one of the
+/// first two outputs of its transaction.
+pub(crate) fn matches_assumptions(amount: u64, vout: u64) -> bool {
+	amount % 10000 == 0 && vout <= 1
+}
+
 pub fn scid_from_parts(block: u64, tx_index: u64, vout_index: u64) -> u64 {
 	(block << 40) | (tx_index << 16) | vout_index
 }
@@ -163,3 +352,37 @@ pub fn tx_index_from_scid(short_channel_id: &u64) -> u32 {
 pub fn vout_from_scid(short_channel_id: &u64) -> u16 {
 	return ((short_channel_id) & MAX_SCID_VOUT_INDEX) as u16;
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::pubkey;
+
+	#[test]
+	fn test_failed_at_target() {
+		let target = pubkey(1);
+		let chan_id = scid_from_parts(700_000, 1, 0);
+		let node_failure =
+			|node_id| Some(NetworkUpdate::NodeFailure { node_id, is_permanent: false });
+		// temporary_node_failure names the failing node, and scid of the channel into it
+		let into_target = scid_from_parts(700_000, 2, 0);
+		assert!(failed_at_target(&target, chan_id, &node_failure(target), Some(into_target)));
+		assert!(!failed_at_target(&target, chan_id, &node_failure(pubkey(2)), Some(into_target)));
+		// the guessed node's own node errors come with the guessed channel
+		assert!(!failed_at_target(&target, chan_id, &node_failure(pubkey(3)), Some(chan_id)));
+
+		// channel errors from the target are about the guessed channel
+		let channel_failure = |short_channel_id| {
+			Some(NetworkUpdate::ChannelFailure { short_channel_id, is_permanent: true })
+		};
+		assert!(failed_at_target(&target, chan_id, &channel_failure(chan_id), Some(chan_id)));
+		assert!(!failed_at_target(
+			&target,
+			chan_id,
+			&channel_failure(into_target),
+			Some(into_target)
+		));
+		// the guessed node rejecting the payment
+		assert!(!failed_at_target(&target, chan_id, &None, None));
+	}
+}
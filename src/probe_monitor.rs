@@ -0,0 +1,187 @@
+use crate::disk::FilesystemLogger;
+use crate::{ChannelManager, PaymentInfoStorage};
+use bitcoin::secp256k1::PublicKey;
+use lightning::ln::PaymentHash;
+use lightning::routing::router::RouteHop;
+use lightning::util::logger::Logger;
+use lightning::{log_given_level, log_internal, log_warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long a probe may go unanswered before we give up on it.
+pub(crate) const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Nodes need this many stuck probes through them before they get flagged.
+const MIN_STUCK_TO_FLAG: u64 = 3;
+
+/// How often a node was on the path of an answered or a stuck probe, and how long the answered
+/// ones took.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HoldStats {
+	pub(crate) answered: u64,
+	pub(crate) stuck: u64,
+	pub(crate) total_answer_time: Duration,
+}
+
+impl HoldStats {
+	pub(crate) fn mean_answer_time(&self) -> Option<Duration> {
+		if self.answered == 0 {
+			return None;
+		}
+		Some(self.total_answer_time / self.answered as u32)
+	}
+}
+
+/// A probe we abandoned because nobody answered it in time.
+pub(crate) struct StuckProbe {
+	pub(crate) payment_hash: PaymentHash,
+	pub(crate) age: Duration,
+	pub(crate) path: Vec<RouteHop>,
+}
+
+/// Keeps an eye on probes in flight, so that ones held by a node along the way don't take up
+/// HTLC slots forever, and on which nodes tend to hold them.
+pub(crate) struct ProbeMonitor {
+	timeout: Mutex<Duration>,
+	hold_stats: Mutex<HashMap<PublicKey, HoldStats>>,
+}
+
+impl ProbeMonitor {
+	pub(crate) fn new(timeout: Duration) -> Self {
+		ProbeMonitor { timeout: Mutex::new(timeout), hold_stats: Mutex::new(HashMap::new()) }
+	}
+
+	pub(crate) fn timeout(&self) -> Duration {
+		*self.timeout.lock().unwrap()
+	}
+
+	pub(crate) fn set_timeout(&self, timeout: Duration) {
+		*self.timeout.lock().unwrap() = timeout;
+	}
+
+	/// Counts an answer that took `elapsed` for every node on the probe's path.
+	pub(crate) fn record_answer(&self, path: &[RouteHop], elapsed: Duration) {
+		let mut hold_stats = self.hold_stats.lock().unwrap();
+		for hop in forwarding_hops(path) {
+			let stats = hold_stats.entry(hop.pubkey).or_default();
+			stats.answered += 1;
+			stats.total_answer_time += elapsed;
+		}
+	}
+
+	/// Counts a probe that never came back for every node on its path.
+	fn record_stuck(&self, path: &[RouteHop]) {
+		let mut hold_stats = self.hold_stats.lock().unwrap();
+		for hop in forwarding_hops(path) {
+			hold_stats.entry(hop.pubkey).or_default().stuck += 1;
+		}
+	}
+
+	/// Abandons every pending probe older than the timeout, frees its slot and returns it.
+	pub(crate) fn abandon_stuck(
+		&self, channel_manager: &ChannelManager, pending_payments: &PaymentInfoStorage,
+		logger: &FilesystemLogger,
+	) -> Vec<StuckProbe> {
+		let timeout = self.timeout();
+		let mut pending = pending_payments.lock().unwrap();
+		let stuck_hashes: Vec<PaymentHash> = pending
+			.iter()
+			.filter(
+				|(_, info)| matches!(&info.probe, Some(probe) if probe.sent_at.elapsed() > timeout),
+			)
+			.map(|(payment_hash, _)| *payment_hash)
+			.collect();
+
+		let mut stuck = Vec::new();
+		for payment_hash in stuck_hashes {
+			let probe = match pending.remove(&payment_hash).and_then(|info| info.probe) {
+				Some(probe) => probe,
+				None => continue,
+			};
+			channel_manager.abandon_payment(probe.payment_id);
+			self.record_stuck(&probe.path);
+			log_warn!(
+				logger,
+				"Abandoned probe {} after {}s",
+				hex::encode(payment_hash.0),
+				probe.sent_at.elapsed().as_secs()
+			);
+			stuck.push(StuckProbe { payment_hash, age: probe.sent_at.elapsed(), path: probe.path });
+		}
+		stuck
+	}
+
+	/// Nodes that were on the path of stuck probes more often than on answered ones, the most
+	/// suspicious first. Every node on a stuck path gets counted, so the ones that really hold
+	/// HTLCs stand out by also rarely showing up on answered probes.
+	pub(crate) fn holders(&self) -> Vec<(PublicKey, HoldStats)> {
+		let mut holders: Vec<(PublicKey, HoldStats)> = self
+			.hold_stats
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|(_, stats)| stats.stuck >= MIN_STUCK_TO_FLAG && stats.stuck > stats.answered)
+			.map(|(pubkey, stats)| (*pubkey, stats.clone()))
+			.collect();
+		holders.sort_by(|(_, a), (_, b)| {
+			(b.stuck * (a.stuck + a.answered)).cmp(&(a.stuck * (b.stuck + b.answered)))
+		});
+		holders
+	}
+}
+
+/// The nodes of a probe's path that actually forward it. The last hop is our guess, which
+/// mostly isn't there at all and so can't hold anything.
+fn forwarding_hops(path: &[RouteHop]) -> &[RouteHop] {
+	path.split_last().map_or(path, |(_, hops)| hops)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::pubkey;
+	use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+
+	fn path(nodes: &[u8]) -> Vec<RouteHop> {
+		nodes
+			.iter()
+			.enumerate()
+			.map(|(i, node)| RouteHop {
+				pubkey: pubkey(*node),
+				node_features: NodeFeatures::empty(),
+				short_channel_id: i as u64 + 1,
+				channel_features: ChannelFeatures::empty(),
+				fee_msat: 0,
+				cltv_expiry_delta: 0,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_holders() {
+		let monitor = ProbeMonitor::new(DEFAULT_PROBE_TIMEOUT);
+		// 2 forwards everything, 3 holds every probe it gets, 4 holds some, 5 is the guess
+		for _ in 0..4 {
+			monitor.record_stuck(&path(&[2, 3, 5]));
+		}
+		for _ in 0..3 {
+			monitor.record_stuck(&path(&[2, 4, 5]));
+		}
+		monitor.record_answer(&path(&[2, 4, 5]), Duration::from_secs(2));
+		for _ in 0..8 {
+			monitor.record_answer(&path(&[2, 6, 5]), Duration::from_secs(1));
+		}
+		// 7 is only on two stuck probes, too few to tell
+		for _ in 0..2 {
+			monitor.record_stuck(&path(&[7, 6, 5]));
+		}
+
+		let holders = monitor.holders();
+		let flagged: Vec<PublicKey> = holders.iter().map(|(pubkey, _)| *pubkey).collect();
+		assert_eq!(flagged, vec![pubkey(3), pubkey(4)]);
+		assert_eq!(holders[1].1.stuck, 3);
+		assert_eq!(holders[1].1.mean_answer_time(), Some(Duration::from_secs(2)));
+		assert!(monitor.hold_stats.lock().unwrap().get(&pubkey(5)).is_none());
+	}
+}
@@ -0,0 +1,284 @@
+//! Runs probes against real nodes, alice with a public channel to bob and bob with a private
+//! channel to carol, all in-process on top of the fork's functional test utilities.
+
+use crate::cli::get_attempts;
+use crate::control::{Control, ControlProbe};
+use crate::disk::FilesystemLogger;
+use crate::evaluate::EvaluationProbe;
+use crate::mock_chain::MockChain;
+use crate::node_tests::TestNode;
+use crate::probe::probe;
+use crate::NetworkGraph;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use lightning::chain;
+use lightning::ln::features::InitFeatures;
+use lightning::ln::functional_test_utils::*;
+use lightning::ln::msgs::{ChannelMessageHandler, ChannelUpdate};
+use lightning::routing::scoring::{ProbabilisticScorer, ProbabilisticScoringParameters};
+use lightning::util::events::{Event, MessageSendEvent, MessageSendEventsProvider};
+use lightning::{
+	check_added_monitors, commitment_signed_dance, expect_pending_htlcs_forwardable,
+	get_htlc_update_msgs, get_revoke_commit_msgs,
+};
+use std::sync::{Arc, Mutex};
+
+/// Alice's side of the probing. Her lightning traffic goes through the functional test nodes,
+/// everything else, from her view of the graph to the event handler the answers go through, is
+/// a `TestNode`'s. The handler only uses a channel manager of its own to resend probes with a
+/// learned policy, which these tests don't get to.
+struct Prober {
+	node: TestNode,
+	scorer: Arc<Mutex<ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>>>,
+	_chain: Arc<MockChain>,
+}
+
+impl Prober {
+	async fn new() -> Prober {
+		let chain = Arc::new(MockChain::new());
+		let mut node = TestNode::new("probe-tests", &chain, 1).await;
+		// the functional test nodes gossip for testnet
+		let genesis_hash = genesis_block(Network::Testnet).header.block_hash();
+		node.network_graph = Arc::new(NetworkGraph::new(genesis_hash, node.logger.clone()));
+		let scorer = Arc::new(Mutex::new(ProbabilisticScorer::new(
+			ProbabilisticScoringParameters::default(),
+			node.network_graph.clone(),
+			node.logger.clone(),
+		)));
+		Prober { node, scorer, _chain: chain }
+	}
+
+	/// Probes `target` for `short_channel_id` with `guess` on the other side, waits for the
+	/// answer and hands it to the event handler.
+	async fn probe(
+		&self, nodes: &[Node<'_, '_, '_>], target: &PublicKey, short_channel_id: u64,
+		guess: &PublicKey, forwarded: bool,
+	) {
+		probe(
+			&target.to_string(),
+			&short_channel_id.to_string(),
+			&guess.to_string(),
+			nodes[0].node,
+			&self.node.network_graph,
+			&self.node.logger,
+			&String::new(),
+			self.node.payments.clone(),
+			&self.scorer,
+			&self.node.hidden_graph,
+			&self.node.route_cache,
+			"fixed",
+			&self.node.metrics,
+			"probe",
+		)
+		.unwrap();
+		check_added_monitors!(nodes[0], 1);
+
+		let event = deliver_probe(nodes, forwarded);
+		assert!(matches!(event, Event::PaymentPathFailed { .. }));
+		self.node.handle_event(&event).await;
+	}
+}
+
+/// Passes the probe alice just sent on through bob, and to carol if bob forwards it, until
+/// its failure made it back to alice.
+fn deliver_probe(nodes: &[Node], forwarded: bool) -> Event {
+	let payment_event =
+		SendEvent::from_event(nodes[0].node.get_and_clear_pending_msg_events().remove(0));
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+
+	if forwarded {
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+		expect_pending_htlcs_forwardable!(nodes[1]);
+		check_added_monitors!(nodes[1], 1);
+		let forward_event =
+			SendEvent::from_event(nodes[1].node.get_and_clear_pending_msg_events().remove(0));
+		nodes[2]
+			.node
+			.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &forward_event.msgs[0]);
+		commitment_signed_dance!(nodes[2], nodes[1], forward_event.commitment_msg, false, true);
+
+		// carol fails the probe whether she could read the onion or not
+		let updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+		let carol = nodes[2].node.get_our_node_id();
+		for update in updates.update_fail_htlcs.iter() {
+			nodes[1].node.handle_update_fail_htlc(&carol, update);
+		}
+		for update in updates.update_fail_malformed_htlcs.iter() {
+			nodes[1].node.handle_update_fail_malformed_htlc(&carol, update);
+		}
+		commitment_signed_dance!(nodes[1], nodes[2], updates.commitment_signed, false, true);
+		expect_pending_htlcs_forwardable!(nodes[1]);
+		check_added_monitors!(nodes[1], 1);
+	} else {
+		commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+	}
+
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+	nodes[0]
+		.node
+		.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, true, true);
+
+	let mut events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	events.remove(0)
+}
+
+/// Opens alice's public channel to bob and bob's private channel to carol, and returns the
+/// public channel's updates and the private channel's id.
+fn open_channels(nodes: &Vec<Node>) -> (ChannelUpdate, ChannelUpdate, u64) {
+	let (alice_update, bob_update, _, _) = create_announced_chan_between_nodes_with_value(
+		nodes,
+		0,
+		1,
+		1_000_000,
+		500_000_000,
+		InitFeatures::known(),
+		InitFeatures::known(),
+	);
+	create_unannounced_chan_between_nodes_with_value(
+		nodes,
+		1,
+		2,
+		1_000_000,
+		500_000_000,
+		InitFeatures::known(),
+		InitFeatures::known(),
+	);
+	let private_scid = nodes[2].node.list_channels()[0].short_channel_id.unwrap();
+	(alice_update, bob_update, private_scid)
+}
+
+/// Alice learns about the public channel through gossip, like she would on mainnet.
+fn learn_public_channel(
+	prober: &Prober, nodes: &[Node], alice_update: &ChannelUpdate, bob_update: &ChannelUpdate,
+) {
+	let public_scid = alice_update.contents.short_channel_id;
+	let announcement = nodes[0].network_graph.read_only().channels()[&public_scid]
+		.announcement_message
+		.clone()
+		.unwrap();
+	let network_graph = &prober.node.network_graph;
+	network_graph
+		.update_channel_from_announcement(
+			&announcement,
+			&None::<Arc<dyn chain::Access + Send + Sync>>,
+		)
+		.unwrap();
+	network_graph.update_channel(alice_update).unwrap();
+	network_graph.update_channel(bob_update).unwrap();
+}
+
+#[tokio::test]
+async fn test_probe_private_channel() {
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut bob_cfg = test_default_channel_config();
+	bob_cfg.accept_forwards_to_priv_channels = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(bob_cfg), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	let (alice_update, bob_update, private_scid) = open_channels(&nodes);
+
+	let prober = Prober::new().await;
+	learn_public_channel(&prober, &nodes, &alice_update, &bob_update);
+
+	let bob = nodes[1].node.get_our_node_id();
+	let carol = nodes[2].node.get_our_node_id();
+	let secp_ctx = Secp256k1::new();
+	let stranger =
+		PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+
+	prober.probe(&nodes, &bob, private_scid, &carol, true).await;
+	prober.probe(&nodes, &bob, private_scid, &stranger, true).await;
+	prober.probe(&nodes, &bob, private_scid + 1, &carol, false).await;
+
+	let rows: Vec<(String, String, String, String, Option<String>)> =
+		get_attempts(&prober.node.db.lock().unwrap())
+			.unwrap()
+			.into_iter()
+			.map(|attempt| {
+				(
+					attempt.target_pubkey,
+					attempt.guess_pubkey,
+					attempt.channel_id,
+					attempt.result,
+					attempt.guess_strategy,
+				)
+			})
+			.collect();
+	let row = |guess: &PublicKey, scid: u64, result: &str| {
+		(
+			bob.to_string(),
+			guess.to_string(),
+			scid.to_string(),
+			result.to_string(),
+			Some("fixed".to_string()),
+		)
+	};
+	assert_eq!(
+		rows,
+		vec![
+			row(&carol, private_scid, "required_node_feature_missing"),
+			row(&stranger, private_scid, "invalid_onion_hmac"),
+			row(&carol, private_scid + 1, "unknown_next_peer"),
+		]
+	);
+
+	assert_eq!(prober.node.metrics.probes_sent("probe"), 3);
+
+	// the private channel is found, and with it carol
+	let discovered = prober.node.hidden_graph.channels();
+	assert_eq!(discovered.len(), 1);
+	assert_eq!(discovered[0].target_pubkey, bob.to_string());
+	assert_eq!(discovered[0].short_channel_id, private_scid);
+	assert_eq!(discovered[0].counterparty, Some(carol.to_string()));
+}
+
+#[tokio::test]
+async fn test_control_and_evaluation_answers_stay_out_of_the_results() {
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut bob_cfg = test_default_channel_config();
+	bob_cfg.accept_forwards_to_priv_channels = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(bob_cfg), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	let (alice_update, bob_update, private_scid) = open_channels(&nodes);
+
+	let prober = Prober::new().await;
+	learn_public_channel(&prober, &nodes, &alice_update, &bob_update);
+
+	let bob = nodes[1].node.get_our_node_id();
+	let carol = nodes[2].node.get_our_node_id();
+
+	// a control for a channel bob has, answered like one
+	let control =
+		ControlProbe { short_channel_id: private_scid, guess: carol, control: Control::Present };
+	prober.node.controls.record_sent(&bob.to_string(), &control);
+	prober.probe(&nodes, &bob, private_scid, &carol, true).await;
+	let verdict = prober.node.controls.verdict(&bob.to_string()).unwrap();
+	assert_eq!(verdict.answered, 1);
+	assert_eq!(verdict.deviations, 0);
+
+	// an evaluation probe for a channel bob doesn't have
+	let evaluation = EvaluationProbe {
+		target: bob,
+		short_channel_id: private_scid + 1,
+		expected: Control::Absent,
+	};
+	prober.node.evaluations.record_sent(&evaluation);
+	prober.probe(&nodes, &bob, private_scid + 1, &carol, false).await;
+	assert_eq!(prober.node.evaluations.pending(), 0);
+	let report = prober
+		.node
+		.evaluations
+		.finish(&prober.node.db.lock().unwrap(), &[evaluation], "fixed", 0, 0)
+		.unwrap();
+	assert_eq!(report.negatives_answered, 1);
+	assert_eq!(report.false_positives, 0);
+
+	// neither answer is a result
+	assert!(get_attempts(&prober.node.db.lock().unwrap()).unwrap().is_empty());
+	assert!(prober.node.hidden_graph.channels().is_empty());
+}
@@ -0,0 +1,262 @@
+use crate::hint_policy::HintPolicy;
+use crate::probe::{PROBE_AMOUNT_MSAT, PROBE_FINAL_CLTV_EXPIRY_DELTA};
+use crate::NetworkGraph;
+use bitcoin::secp256k1::PublicKey;
+use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+use lightning::ln::msgs::LightningError;
+use lightning::routing::gossip::{NodeId, RoutingFees};
+use lightning::routing::router::{PaymentParameters, Route, RouteHint, RouteHintHop, RouteHop};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A validated path from us to a probe target, along with the policy of every channel on it so
+/// the fees can be worked out again for a longer path.
+#[derive(Clone, Debug)]
+struct CachedPath {
+	hops: Vec<RouteHop>,
+	/// `fees[i]` is what the node of `hops[i - 1]` charges to forward over `hops[i]`'s channel.
+	/// `fees[0]` is our own channel and unused.
+	fees: Vec<RoutingFees>,
+}
+
+/// The hop a probe guesses: `target` forwarding over `short_channel_id` to `guess`, with
+/// `policy` as what we think `target` asks for it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GuessedHop {
+	pub(crate) target: PublicKey,
+	pub(crate) short_channel_id: u64,
+	pub(crate) guess: PublicKey,
+	pub(crate) policy: HintPolicy,
+}
+
+/// Paths to probe targets, so that probing many short channel ids behind the same target only
+/// has to run `find_route` once.
+pub(crate) struct ProbeRouteCache {
+	paths: Mutex<HashMap<PublicKey, CachedPath>>,
+	/// What probes deliver to the guessed node, unless its channel wants more.
+	amount_msat: u64,
+}
+
+impl ProbeRouteCache {
+	pub(crate) fn new() -> Self {
+		Self::with_amount_msat(PROBE_AMOUNT_MSAT)
+	}
+
+	pub(crate) fn with_amount_msat(amount_msat: u64) -> Self {
+		ProbeRouteCache { paths: Mutex::new(HashMap::new()), amount_msat }
+	}
+
+	/// A route to the guessed node of `hop` through its target, ending in `hop`.
+	/// `find_path` is only asked for a route to the target when none is cached yet.
+	pub(crate) fn probe_route<F>(
+		&self, hop: &GuessedHop, network_graph: &NetworkGraph, hidden_hops: Vec<RouteHintHop>,
+		find_path: F,
+	) -> Result<Route, Box<dyn std::error::Error>>
+	where
+		F: FnOnce(Vec<RouteHint>) -> Result<Route, LightningError>,
+	{
+		let GuessedHop { target, short_channel_id, guess, policy } = hop;
+		let cached = self.paths.lock().unwrap().get(target).cloned();
+		let path = match cached {
+			Some(path) => path,
+			None => {
+				let route_hints = if hidden_hops.is_empty() {
+					vec![]
+				} else {
+					vec![RouteHint(hidden_hops.clone())]
+				};
+				let route = find_path(route_hints)
+					.map_err(|e| format!("no route to {}: {}", target, e.err))?;
+				let path = cached_path(route, network_graph, &hidden_hops)?;
+				self.paths.lock().unwrap().insert(*target, path.clone());
+				path
+			}
+		};
+
+		let mut hops = path.hops;
+		let mut fees = path.fees;
+		let amount_msat = self.amount_msat.max(policy.htlc_minimum_msat);
+		hops.last_mut().unwrap().cltv_expiry_delta = policy.cltv_expiry_delta as u32;
+		hops.push(RouteHop {
+			pubkey: *guess,
+			node_features: NodeFeatures::empty(),
+			short_channel_id: *short_channel_id,
+			channel_features: ChannelFeatures::empty(),
+			fee_msat: amount_msat,
+			cltv_expiry_delta: PROBE_FINAL_CLTV_EXPIRY_DELTA,
+		});
+		fees.push(policy.fees);
+
+		// Every hop takes its fee on what it forwards, so work back from the payee.
+		let mut forwarded_msat = amount_msat;
+		for i in (0..hops.len() - 1).rev() {
+			let fee_msat = fees[i + 1].base_msat as u64
+				+ forwarded_msat * fees[i + 1].proportional_millionths as u64 / 1_000_000;
+			hops[i].fee_msat = fee_msat;
+			forwarded_msat += fee_msat;
+		}
+
+		Ok(Route {
+			paths: vec![hops],
+			payment_params: Some(PaymentParameters::from_node_id(*guess)),
+		})
+	}
+
+	/// Forgets every cached path that goes over `short_channel_id`.
+	pub(crate) fn invalidate_channel(&self, short_channel_id: u64) {
+		self.paths
+			.lock()
+			.unwrap()
+			.retain(|_, path| path.hops.iter().all(|hop| hop.short_channel_id != short_channel_id));
+	}
+
+	pub(crate) fn invalidate_target(&self, target: &PublicKey) {
+		self.paths.lock().unwrap().remove(target);
+	}
+}
+
+/// Checks that `route` is a single path and looks up the policy of each channel on it, either in
+/// the public graph or in the hint hops from the hidden graph.
+fn cached_path(
+	route: Route, network_graph: &NetworkGraph, hidden_hops: &[RouteHintHop],
+) -> Result<CachedPath, Box<dyn std::error::Error>> {
+	let hops = match route.paths.into_iter().next() {
+		Some(hops) if !hops.is_empty() => hops,
+		_ => return Err("route has no path".into()),
+	};
+
+	let graph = network_graph.read_only();
+	let mut fees = vec![RoutingFees { base_msat: 0, proportional_millionths: 0 }];
+	for i in 1..hops.len() {
+		let from = NodeId::from_pubkey(&hops[i - 1].pubkey);
+		let scid = hops[i].short_channel_id;
+		let public = graph.channels().get(&scid).and_then(|channel| {
+			if channel.node_one == from {
+				channel.one_to_two.as_ref()
+			} else {
+				channel.two_to_one.as_ref()
+			}
+		});
+		let policy = match public {
+			Some(update) => update.fees,
+			None => match hidden_hops.iter().find(|hop| hop.short_channel_id == scid) {
+				Some(hop) => hop.fees,
+				None => return Err(format!("no policy known for channel {}", scid).into()),
+			},
+		};
+		fees.push(policy);
+	}
+	Ok(CachedPath { hops, fees })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::probe::scid_from_parts;
+	use crate::test_utils::{self, add_public_channel, pubkey, TempDir};
+	use std::sync::Arc;
+
+	fn hop(pubkey: PublicKey, short_channel_id: u64, cltv_expiry_delta: u32) -> RouteHop {
+		RouteHop {
+			pubkey,
+			node_features: NodeFeatures::empty(),
+			short_channel_id,
+			channel_features: ChannelFeatures::empty(),
+			fee_msat: 0,
+			cltv_expiry_delta,
+		}
+	}
+
+	/// Us, two public hops and the target, every channel charging 1000 msat and 1 ppm.
+	struct TestPath {
+		network_graph: Arc<NetworkGraph>,
+		hops: Vec<RouteHop>,
+		_data_dir: TempDir,
+	}
+
+	impl TestPath {
+		fn new() -> TestPath {
+			let data_dir = TempDir::new("route-cache");
+			let network_graph = test_utils::network_graph(&data_dir);
+			let nodes = [pubkey(1), pubkey(2), pubkey(3), pubkey(4)];
+			let mut hops = Vec::new();
+			for i in 1..nodes.len() {
+				let scid = scid_from_parts(700_000, i as u64, 0);
+				add_public_channel(&network_graph, scid, nodes[i - 1], nodes[i]);
+				hops.push(hop(nodes[i], scid, 40));
+			}
+			hops.last_mut().unwrap().cltv_expiry_delta = PROBE_FINAL_CLTV_EXPIRY_DELTA;
+			TestPath { network_graph, hops, _data_dir: data_dir }
+		}
+
+		fn route(&self) -> Result<Route, LightningError> {
+			Ok(Route { paths: vec![self.hops.clone()], payment_params: None })
+		}
+	}
+
+	fn guessed_hop(short_channel_id: u64, policy: HintPolicy) -> GuessedHop {
+		GuessedHop { target: pubkey(4), short_channel_id, guess: pubkey(5), policy }
+	}
+
+	fn no_route(_: Vec<RouteHint>) -> Result<Route, LightningError> {
+		panic!("the path should have been cached")
+	}
+
+	#[test]
+	fn test_fees_and_cltv_over_multiple_hops() {
+		let path = TestPath::new();
+		let cache = ProbeRouteCache::with_amount_msat(1_000_000);
+		let policy = HintPolicy {
+			fees: RoutingFees { base_msat: 2000, proportional_millionths: 100 },
+			cltv_expiry_delta: 50,
+			htlc_minimum_msat: 0,
+		};
+		let scid = scid_from_parts(700_100, 1, 0);
+		let route = cache
+			.probe_route(&guessed_hop(scid, policy), &path.network_graph, vec![], |_| path.route())
+			.unwrap();
+		let hops = &route.paths[0];
+		assert_eq!(hops.len(), 4);
+		assert_eq!(hops[3].pubkey, pubkey(5));
+		assert_eq!(hops[3].short_channel_id, scid);
+		// the target takes 2000 + 1_000_000 * 100 / 1_000_000 for the guessed hop, the public
+		// hops 1000 + 1 ppm on what they forward, rounded down
+		let fees: Vec<u64> = hops.iter().map(|hop| hop.fee_msat).collect();
+		assert_eq!(fees, vec![1001, 1001, 2100, 1_000_000]);
+		// the target's hop carries the delta of the guessed channel, the guess gets the final one
+		let cltv: Vec<u32> = hops.iter().map(|hop| hop.cltv_expiry_delta).collect();
+		assert_eq!(cltv, vec![40, 40, 50, PROBE_FINAL_CLTV_EXPIRY_DELTA]);
+
+		// a channel that wants more than we probe with gets its minimum
+		let policy = HintPolicy { htlc_minimum_msat: 3_000_000, ..policy };
+		let route = cache
+			.probe_route(&guessed_hop(scid, policy), &path.network_graph, vec![], no_route)
+			.unwrap();
+		let fees: Vec<u64> = route.paths[0].iter().map(|hop| hop.fee_msat).collect();
+		assert_eq!(fees, vec![1003, 1003, 2300, 3_000_000]);
+	}
+
+	#[test]
+	fn test_invalidate_channel() {
+		let path = TestPath::new();
+		let cache = ProbeRouteCache::new();
+		let hop = guessed_hop(scid_from_parts(700_100, 1, 0), HintPolicy::default());
+		cache.probe_route(&hop, &path.network_graph, vec![], |_| path.route()).unwrap();
+
+		// a channel the path doesn't go over, or the guessed one, leave it cached
+		cache.invalidate_channel(scid_from_parts(700_000, 9, 0));
+		cache.invalidate_channel(hop.short_channel_id);
+		cache.probe_route(&hop, &path.network_graph, vec![], no_route).unwrap();
+
+		// one of its channels failing means finding a new path
+		cache.invalidate_channel(path.hops[1].short_channel_id);
+		let mut found = false;
+		cache
+			.probe_route(&hop, &path.network_graph, vec![], |_| {
+				found = true;
+				path.route()
+			})
+			.unwrap();
+		assert!(found);
+	}
+}
@@ -0,0 +1,706 @@
+//! A JSON-RPC 2.0 server to drive the node from other programs, over HTTP on a TCP port, a Unix
+//! socket or both. Every request needs the cookie the node writes to `<ldk_data_dir>/.cookie` on
+//! startup, sent as basic auth the way bitcoind's clients send theirs, so only whoever can read
+//! the data directory gets in.
+//!
+//! The methods call the same functions as the prompt, see `METHODS` for their parameters, which
+//! can be given by name or in that order.
+
+use crate::cli::{self, ProbeAllSummary, ProbeContext};
+use crate::config::RpcConfig;
+use crate::disk;
+use crate::guess::GuessStrategy;
+use crate::hex_utils;
+use crate::probe::{find_routes, probe};
+use crate::PeerManager;
+use bitcoin::secp256k1::PublicKey;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use lightning::util::logger::Logger;
+use lightning::{log_given_level, log_info, log_internal};
+use rand::Rng;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The call was fine but what it asked for failed, bitcoind's `RPC_MISC_ERROR`.
+const CALL_FAILED: i64 = -1;
+
+const COOKIE_USER: &str = "__cookie__";
+
+/// Every method with its parameters, in the order positional calls give them.
+const METHODS: &[(&str, &[&str])] = &[
+	("nodeinfo", &[]),
+	("connectpeer", &["peer"]),
+	("openchannel", &["peer", "amount_sat", "public"]),
+	("listchannels", &[]),
+	("findroutes", &["pubkey"]),
+	("probeprivate", &["pubkey", "guess", "channel_id"]),
+	("probeall", &["type", "targets", "candidates", "guess_strategy"]),
+	("listjobs", &[]),
+	("pausejob", &["id"]),
+	("resumejob", &["id"]),
+	("stopjob", &["id"]),
+	("listattempts", &["target"]),
+	("listresults", &[]),
+	("dump_results", &["out", "candidates", "aggregate", "sign"]),
+];
+
+#[derive(Debug, PartialEq)]
+struct RpcError {
+	code: i64,
+	message: String,
+}
+
+impl RpcError {
+	fn invalid_params<M: ToString>(message: M) -> Self {
+		RpcError { code: INVALID_PARAMS, message: message.to_string() }
+	}
+
+	fn failed<M: ToString>(message: M) -> Self {
+		RpcError { code: CALL_FAILED, message: message.to_string() }
+	}
+}
+
+/// The parameters of a call, checked against the names its method takes.
+#[derive(Debug)]
+struct Params {
+	names: &'static [&'static str],
+	values: Value,
+}
+
+impl Params {
+	fn get(&self, name: &str) -> Option<&Value> {
+		let value = match &self.values {
+			Value::Object(values) => values.get(name),
+			Value::Array(values) => {
+				self.names.iter().position(|n| *n == name).and_then(|i| values.get(i))
+			}
+			_ => None,
+		};
+		value.filter(|value| !value.is_null())
+	}
+
+	fn str(&self, name: &str) -> Result<Option<&str>, RpcError> {
+		match self.get(name) {
+			None => Ok(None),
+			Some(Value::String(value)) => Ok(Some(value)),
+			Some(_) => Err(RpcError::invalid_params(format!("{} must be a string", name))),
+		}
+	}
+
+	fn required_str(&self, name: &str) -> Result<&str, RpcError> {
+		self.str(name)?.ok_or_else(|| RpcError::invalid_params(format!("{} is required", name)))
+	}
+
+	/// A number, or a string holding one since short channel ids don't fit in a double.
+	fn u64(&self, name: &str) -> Result<Option<u64>, RpcError> {
+		let number = match self.get(name) {
+			None => return Ok(None),
+			Some(Value::Number(number)) => number.as_u64(),
+			Some(Value::String(number)) => number.parse().ok(),
+			Some(_) => None,
+		};
+		match number {
+			Some(number) => Ok(Some(number)),
+			None => Err(RpcError::invalid_params(format!("{} must be a positive number", name))),
+		}
+	}
+
+	fn bool(&self, name: &str) -> Result<Option<bool>, RpcError> {
+		match self.get(name) {
+			None => Ok(None),
+			Some(Value::Bool(value)) => Ok(Some(*value)),
+			Some(_) => Err(RpcError::invalid_params(format!("{} must be true or false", name))),
+		}
+	}
+
+	fn pubkey(&self, name: &str) -> Result<PublicKey, RpcError> {
+		let pubkey = self.required_str(name)?;
+		PublicKey::from_str(pubkey)
+			.map_err(|_| RpcError::invalid_params(format!("{} is not a pubkey: {}", name, pubkey)))
+	}
+}
+
+/// Checks a request's shape and finds its method.
+fn parse_request(request: &Value) -> Result<(&str, Params), RpcError> {
+	let method = match request.get("method") {
+		Some(Value::String(method)) => method.as_str(),
+		_ => {
+			return Err(RpcError {
+				code: INVALID_REQUEST,
+				message: "method must be a string".to_string(),
+			})
+		}
+	};
+	let names = match METHODS.iter().find(|(name, _)| *name == method) {
+		Some((_, names)) => *names,
+		None => {
+			return Err(RpcError {
+				code: METHOD_NOT_FOUND,
+				message: format!("unknown method {}", method),
+			})
+		}
+	};
+	let values = request.get("params").cloned().unwrap_or(Value::Null);
+	match &values {
+		Value::Null | Value::Array(_) | Value::Object(_) => (),
+		_ => return Err(RpcError::invalid_params("params must be an array or an object")),
+	}
+	if let Value::Object(values) = &values {
+		if let Some(name) = values.keys().find(|name| !names.contains(&name.as_str())) {
+			return Err(RpcError::invalid_params(format!("{} doesn't take {}", method, name)));
+		}
+	}
+	if let Value::Array(values) = &values {
+		if values.len() > names.len() {
+			return Err(RpcError::invalid_params(format!(
+				"{} takes at most {} parameters",
+				method,
+				names.len()
+			)));
+		}
+	}
+	Ok((method, Params { names, values }))
+}
+
+/// Whether an `Authorization` header carries `cookie`.
+fn authorized(header: Option<&str>, cookie: &str) -> bool {
+	match header.and_then(|header| header.strip_prefix("Basic ")) {
+		Some(credentials) => base64::decode(credentials.trim())
+			.map(|credentials| credentials == cookie.as_bytes())
+			.unwrap_or(false),
+		None => false,
+	}
+}
+
+/// A `probeall` started over RPC. It runs on a thread of its own until it's done or stopped.
+struct ProbeJob {
+	id: u64,
+	probetype: String,
+	targets: String,
+	candidates: String,
+	stop: AtomicUsize,
+	paused: AtomicBool,
+	outcome: Mutex<Option<Result<ProbeAllSummary, String>>>,
+	thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Serialize)]
+struct JobStatus {
+	id: u64,
+	#[serde(rename = "type")]
+	probetype: String,
+	targets: String,
+	candidates: String,
+	/// running, paused, stopping, done, stopped or failed
+	state: &'static str,
+	/// Once it's over.
+	probes: Option<u64>,
+	targets_probed: Option<usize>,
+	error: Option<String>,
+}
+
+impl ProbeJob {
+	fn status(&self) -> JobStatus {
+		let outcome = self.outcome.lock().unwrap();
+		let state = match &*outcome {
+			None if self.stop.load(Ordering::SeqCst) > 0 => "stopping",
+			None if self.paused.load(Ordering::SeqCst) => "paused",
+			None => "running",
+			Some(Ok(summary)) if summary.interrupted => "stopped",
+			Some(Ok(_)) => "done",
+			Some(Err(_)) => "failed",
+		};
+		JobStatus {
+			id: self.id,
+			probetype: self.probetype.clone(),
+			targets: self.targets.clone(),
+			candidates: self.candidates.clone(),
+			state,
+			probes: outcome.as_ref().and_then(|outcome| outcome.as_ref().ok()).map(|s| s.probes),
+			targets_probed: outcome
+				.as_ref()
+				.and_then(|outcome| outcome.as_ref().ok())
+				.map(|s| s.targets),
+			error: outcome.as_ref().and_then(|outcome| outcome.as_ref().err()).cloned(),
+		}
+	}
+}
+
+pub(crate) struct RpcServer {
+	ctx: ProbeContext,
+	peer_manager: Arc<PeerManager>,
+	/// `__cookie__:<secret>`, what clients send as basic auth.
+	cookie: String,
+	cookie_path: String,
+	socket_path: Option<String>,
+	jobs: Mutex<Vec<Arc<ProbeJob>>>,
+}
+
+impl RpcServer {
+	/// Writes a fresh cookie and starts listening where `config` says.
+	pub(crate) async fn start(
+		config: &RpcConfig, ctx: ProbeContext, peer_manager: Arc<PeerManager>,
+	) -> Result<Arc<RpcServer>, Box<dyn std::error::Error>> {
+		let cookie_path = format!("{}/.cookie", ctx.ldk_data_dir);
+		let cookie = write_cookie(&cookie_path)
+			.map_err(|e| format!("couldn't write the RPC cookie to {}: {}", cookie_path, e))?;
+		let server = Arc::new(RpcServer {
+			ctx,
+			peer_manager,
+			cookie,
+			cookie_path,
+			socket_path: config.socket.clone(),
+			jobs: Mutex::new(Vec::new()),
+		});
+
+		if let Some(addr) = config.bind {
+			let listener = TcpListener::bind(addr)
+				.await
+				.map_err(|e| format!("couldn't listen for RPC on {}: {}", addr, e))?;
+			log_info!(server.ctx.logger, "Listening for RPC on {}", addr);
+			let server = server.clone();
+			tokio::spawn(async move {
+				loop {
+					match listener.accept().await {
+						Ok((stream, _)) => serve(server.clone(), stream),
+						Err(e) => log_info!(server.ctx.logger, "RPC connection failed: {}", e),
+					}
+				}
+			});
+		}
+		if let Some(path) = &config.socket {
+			// left behind by a node that didn't get to clean up
+			if fs::metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+				fs::remove_file(path)?;
+			}
+			let listener = UnixListener::bind(path)
+				.map_err(|e| format!("couldn't listen for RPC on {}: {}", path, e))?;
+			log_info!(server.ctx.logger, "Listening for RPC on {}", path);
+			let server = server.clone();
+			tokio::spawn(async move {
+				loop {
+					match listener.accept().await {
+						Ok((stream, _)) => serve(server.clone(), stream),
+						Err(e) => log_info!(server.ctx.logger, "RPC connection failed: {}", e),
+					}
+				}
+			});
+		}
+		Ok(server)
+	}
+
+	/// Stops the jobs started over RPC, waits for them and takes the cookie and socket away.
+	pub(crate) fn shutdown(&self) {
+		let jobs = self.jobs.lock().unwrap().clone();
+		for job in &jobs {
+			job.stop.fetch_add(1, Ordering::SeqCst);
+		}
+		for job in jobs {
+			let thread = job.thread.lock().unwrap().take();
+			if let Some(thread) = thread {
+				let _ = thread.join();
+			}
+		}
+		let _ = fs::remove_file(&self.cookie_path);
+		if let Some(path) = &self.socket_path {
+			let _ = fs::remove_file(path);
+		}
+	}
+
+	async fn call(&self, request: Value) -> Value {
+		let id = request.get("id").cloned().unwrap_or(Value::Null);
+		let result = match parse_request(&request) {
+			Ok((method, params)) => self.dispatch(method, params).await,
+			Err(e) => Err(e),
+		};
+		match result {
+			Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+			Err(e) => error_response(e, id),
+		}
+	}
+
+	async fn dispatch(&self, method: &str, params: Params) -> Result<Value, RpcError> {
+		let ctx = &self.ctx;
+		match method {
+			"nodeinfo" => {
+				let channels = ctx.channel_manager.list_channels();
+				Ok(json!({
+					"node_pubkey": ctx.channel_manager.get_our_node_id().to_string(),
+					"num_channels": channels.len(),
+					"num_usable_channels": channels.iter().filter(|c| c.is_usable).count(),
+					"num_peers": self.peer_manager.get_peer_node_ids().len(),
+				}))
+			}
+			"connectpeer" => {
+				let pubkey = self.connect(params.required_str("peer")?).await?;
+				Ok(json!({ "pubkey": pubkey.to_string() }))
+			}
+			"openchannel" => {
+				let peer = params.required_str("peer")?;
+				let amount_sat = params
+					.u64("amount_sat")?
+					.ok_or_else(|| RpcError::invalid_params("amount_sat is required"))?;
+				let public = params.bool("public")?.unwrap_or(false);
+				let pubkey = self.connect(peer).await?;
+				cli::open_channel(pubkey, amount_sat, public, ctx.channel_manager.clone())
+					.map_err(|e| RpcError::failed(format!("failed to open channel: {:?}", e)))?;
+				let peer_data_path = format!("{}/channel_peer_data", ctx.ldk_data_dir);
+				let _ = disk::persist_channel_peer(Path::new(&peer_data_path), peer);
+				Ok(Value::Null)
+			}
+			"listchannels" => {
+				Ok(json!(cli::channel_summaries(&ctx.channel_manager, &ctx.network_graph)))
+			}
+			"findroutes" => {
+				let payee = params.pubkey("pubkey")?;
+				let route = find_routes(
+					&*ctx.channel_manager,
+					&payee.to_string(),
+					&ctx.network_graph,
+					&ctx.logger,
+					ctx.ldk_data_dir.clone(),
+					ctx.hidden_graph.route_hints_to(&payee, &ctx.network_graph),
+					&ctx.scorer,
+				)
+				.map_err(|e| RpcError::failed(format!("no route found: {}", e.err)))?;
+				let paths: Vec<Vec<Value>> = route
+					.paths
+					.iter()
+					.map(|path| {
+						path.iter()
+							.map(|hop| {
+								json!({
+									"pubkey": hop.pubkey.to_string(),
+									"short_channel_id": hop.short_channel_id,
+									"fee_msat": hop.fee_msat,
+									"cltv_expiry_delta": hop.cltv_expiry_delta,
+								})
+							})
+							.collect()
+					})
+					.collect();
+				Ok(json!(paths))
+			}
+			"probeprivate" => {
+				let target = params.pubkey("pubkey")?.to_string();
+				let guess = params.pubkey("guess")?.to_string();
+				let scid = params
+					.u64("channel_id")?
+					.ok_or_else(|| RpcError::invalid_params("channel_id is required"))?;
+				if ctx.denylist.is_denied(&target, scid, Some(&guess)) {
+					log_info!(ctx.logger, "Not probing {}:{}, it is on the denylist", target, scid);
+					return Err(RpcError::failed(format!("{} is on the denylist", target)));
+				}
+				let ctx = ctx.clone();
+				tokio::task::spawn_blocking(move || {
+					probe(
+						&target,
+						&scid.to_string(),
+						&guess,
+						&*ctx.channel_manager,
+						&ctx.network_graph,
+						&ctx.logger,
+						&ctx.ldk_data_dir,
+						ctx.pending_payments.clone(),
+						&ctx.scorer,
+						&ctx.hidden_graph,
+						&ctx.route_cache,
+						"fixed",
+						&ctx.metrics,
+						"probeprivate",
+					)
+					.map_err(|e| e.to_string())
+				})
+				.await
+				.map_err(RpcError::failed)?
+				.map_err(RpcError::failed)?;
+				Ok(Value::Null)
+			}
+			"probeall" => {
+				let probetype = params.str("type")?.unwrap_or("all").to_string();
+				if probetype != "all" && probetype != "assumptions" {
+					return Err(RpcError::invalid_params("type must be all or assumptions"));
+				}
+				let targets = params.required_str("targets")?.to_string();
+				let candidates = params.required_str("candidates")?.to_string();
+				let guess_strategy = GuessStrategy::parse(
+					params.str("guess_strategy")?.unwrap_or(&ctx.probe_config.guess_strategy),
+					&ctx.denylist,
+					&ctx.logger,
+				)
+				.map_err(RpcError::invalid_params)?;
+				Ok(json!(self.start_job(probetype, targets, candidates, guess_strategy).status()))
+			}
+			"listjobs" => {
+				let jobs = self.jobs.lock().unwrap();
+				Ok(json!(jobs.iter().map(|job| job.status()).collect::<Vec<_>>()))
+			}
+			"pausejob" | "resumejob" | "stopjob" => {
+				let id =
+					params.u64("id")?.ok_or_else(|| RpcError::invalid_params("id is required"))?;
+				let job = self
+					.jobs
+					.lock()
+					.unwrap()
+					.iter()
+					.find(|job| job.id == id)
+					.cloned()
+					.ok_or_else(|| RpcError::failed(format!("no job {}", id)))?;
+				match method {
+					"pausejob" => job.paused.store(true, Ordering::SeqCst),
+					"resumejob" => job.paused.store(false, Ordering::SeqCst),
+					_ => {
+						job.stop.fetch_add(1, Ordering::SeqCst);
+					}
+				}
+				Ok(json!(job.status()))
+			}
+			"listattempts" => {
+				let target = params.str("target")?;
+				let attempts =
+					cli::get_attempts(&ctx.db.lock().unwrap()).map_err(RpcError::failed)?;
+				Ok(json!(attempts
+					.into_iter()
+					.filter(|attempt| target.is_none() || target == Some(&attempt.target_pubkey))
+					.filter(|attempt| !ctx.denylist.is_attempt_denied(attempt))
+					.collect::<Vec<_>>()))
+			}
+			"listresults" => {
+				let found =
+					cli::get_attempts_found(&ctx.db.lock().unwrap()).map_err(RpcError::failed)?;
+				Ok(json!(found
+					.into_iter()
+					.filter(|attempt| !ctx.denylist.is_attempt_denied(attempt))
+					.collect::<Vec<_>>()))
+			}
+			"dump_results" => {
+				let out = params.required_str("out")?.to_string();
+				let candidates = params.required_str("candidates")?.to_string();
+				let aggregate = match params.u64("aggregate")? {
+					Some(0) => {
+						return Err(RpcError::invalid_params("aggregate must be at least 1"))
+					}
+					Some(k) => Some(k as usize),
+					None => None,
+				};
+				let sign = params.bool("sign")?.unwrap_or(false);
+				let ctx = ctx.clone();
+				tokio::task::spawn_blocking(move || {
+					cli::dump_results(&ctx, &out, &candidates, aggregate, sign)
+						.map_err(|e| e.to_string())
+				})
+				.await
+				.map_err(RpcError::failed)?
+				.map_err(RpcError::failed)?;
+				Ok(Value::Null)
+			}
+			_ => unreachable!("every method in METHODS is handled"),
+		}
+	}
+
+	async fn connect(&self, peer: &str) -> Result<PublicKey, RpcError> {
+		let (pubkey, peer_addr) =
+			cli::parse_peer_info(peer.to_string()).map_err(RpcError::invalid_params)?;
+		cli::connect_peer_if_necessary(pubkey, peer_addr, self.peer_manager.clone())
+			.await
+			.map_err(|_| RpcError::failed(format!("failed to connect to peer {}", pubkey)))?;
+		Ok(pubkey)
+	}
+
+	fn start_job(
+		&self, probetype: String, targets: String, candidates: String,
+		guess_strategy: GuessStrategy,
+	) -> Arc<ProbeJob> {
+		let mut jobs = self.jobs.lock().unwrap();
+		let job = Arc::new(ProbeJob {
+			id: jobs.len() as u64 + 1,
+			probetype,
+			targets,
+			candidates,
+			stop: AtomicUsize::new(0),
+			paused: AtomicBool::new(false),
+			outcome: Mutex::new(None),
+			thread: Mutex::new(None),
+		});
+		let ctx = self.ctx.clone();
+		let thread_job = job.clone();
+		let thread = thread::spawn(move || {
+			let job = thread_job;
+			let outcome = cli::probe_all(
+				&ctx,
+				&job.probetype,
+				&job.targets,
+				&job.candidates,
+				&guess_strategy,
+				&job.stop,
+				&job.paused,
+			)
+			.map_err(|e| e.to_string());
+			log_info!(
+				ctx.logger,
+				"RPC probeall job {} is over: {:?}",
+				job.id,
+				outcome.as_ref().err()
+			);
+			*job.outcome.lock().unwrap() = Some(outcome);
+		});
+		*job.thread.lock().unwrap() = Some(thread);
+		jobs.push(job.clone());
+		job
+	}
+}
+
+fn error_response(error: RpcError, id: Value) -> Value {
+	json!({
+		"jsonrpc": "2.0",
+		"error": { "code": error.code, "message": error.message },
+		"id": id,
+	})
+}
+
+fn write_cookie(path: &str) -> std::io::Result<String> {
+	let secret = rand::thread_rng().gen::<[u8; 32]>();
+	let cookie = format!("{}:{}", COOKIE_USER, hex_utils::hex_str(&secret));
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+	file.write_all(cookie.as_bytes())?;
+	Ok(cookie)
+}
+
+fn serve<S>(server: Arc<RpcServer>, stream: S)
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	tokio::spawn(async move {
+		let logger = server.ctx.logger.clone();
+		let service = service_fn(move |request| handle(server.clone(), request));
+		if let Err(e) = Http::new().http1_only(true).serve_connection(stream, service).await {
+			log_info!(logger, "RPC connection failed: {}", e);
+		}
+	});
+}
+
+async fn handle(
+	server: Arc<RpcServer>, request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+	let auth = request.headers().get(header::AUTHORIZATION).and_then(|h| h.to_str().ok());
+	if !authorized(auth, &server.cookie) {
+		return Ok(Response::builder()
+			.status(StatusCode::UNAUTHORIZED)
+			.header(header::WWW_AUTHENTICATE, "Basic realm=\"jsonrpc\"")
+			.body(Body::empty())
+			.unwrap());
+	}
+	if request.method() != Method::POST {
+		return Ok(Response::builder()
+			.status(StatusCode::METHOD_NOT_ALLOWED)
+			.body(Body::empty())
+			.unwrap());
+	}
+	let response = match hyper::body::to_bytes(request.into_body()).await {
+		Ok(body) => match serde_json::from_slice::<Value>(&body) {
+			Ok(request) => server.call(request).await,
+			Err(e) => {
+				error_response(RpcError { code: PARSE_ERROR, message: e.to_string() }, Value::Null)
+			}
+		},
+		Err(e) => {
+			error_response(RpcError { code: PARSE_ERROR, message: e.to_string() }, Value::Null)
+		}
+	};
+	Ok(Response::builder()
+		.header(header::CONTENT_TYPE, "application/json")
+		.body(Body::from(response.to_string()))
+		.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(request: Value) -> Result<(String, Params), RpcError> {
+		parse_request(&request).map(|(method, params)| (method.to_string(), params))
+	}
+
+	#[test]
+	fn test_params_by_name_and_position() {
+		let (method, params) = parse(json!({
+			"method": "probeall",
+			"params": {"targets": "all", "candidates": "data/transactions"},
+		}))
+		.unwrap();
+		assert_eq!(method, "probeall");
+		assert_eq!(params.str("type").unwrap(), None);
+		assert_eq!(params.required_str("targets").unwrap(), "all");
+
+		let (_, params) =
+			parse(json!({"method": "probeall", "params": ["assumptions", "all", "txs"]})).unwrap();
+		assert_eq!(params.str("type").unwrap(), Some("assumptions"));
+		assert_eq!(params.required_str("candidates").unwrap(), "txs");
+		assert_eq!(params.str("guess_strategy").unwrap(), None);
+
+		let (_, params) = parse(json!({"method": "probeprivate", "params": {
+			"channel_id": "17293822569102704640",
+			"pubkey": "not a key",
+		}}))
+		.unwrap();
+		assert_eq!(params.u64("channel_id").unwrap(), Some(17293822569102704640));
+		assert_eq!(params.pubkey("pubkey").unwrap_err().code, INVALID_PARAMS);
+		assert_eq!(params.required_str("guess").unwrap_err().message, "guess is required");
+
+		let (_, params) = parse(json!({"method": "listchannels"})).unwrap();
+		assert!(params.get("anything").is_none());
+	}
+
+	#[test]
+	fn test_bad_requests() {
+		let error = |request: Value| parse(request).unwrap_err();
+		assert_eq!(error(json!({"params": []})).code, INVALID_REQUEST);
+		assert_eq!(
+			error(json!({"method": "sendpayment"})),
+			RpcError { code: METHOD_NOT_FOUND, message: "unknown method sendpayment".to_string() }
+		);
+		assert_eq!(error(json!({"method": "listjobs", "params": 1})).code, INVALID_PARAMS);
+		assert_eq!(
+			error(json!({"method": "stopjob", "params": {"job": 1}})).message,
+			"stopjob doesn't take job"
+		);
+		assert_eq!(
+			error(json!({"method": "connectpeer", "params": ["a", "b"]})).message,
+			"connectpeer takes at most 1 parameters"
+		);
+
+		let (_, params) =
+			parse(json!({"method": "openchannel", "params": ["peer", -5, "yes"]})).unwrap();
+		assert!(params.u64("amount_sat").is_err());
+		assert!(params.bool("public").is_err());
+	}
+
+	#[test]
+	fn test_cookie_auth() {
+		let cookie = "__cookie__:00ff";
+		let header = format!("Basic {}", base64::encode(cookie));
+		assert!(authorized(Some(&header), cookie));
+		assert!(!authorized(Some(&format!("Basic {}", base64::encode("__cookie__:00"))), cookie));
+		assert!(!authorized(Some(&format!("Bearer {}", base64::encode(cookie))), cookie));
+		assert!(!authorized(Some("Basic %%%"), cookie));
+		assert!(!authorized(None, cookie));
+	}
+}
@@ -0,0 +1,901 @@
+use crate::disk::FilesystemLogger;
+use crate::graph_import::parse_scid;
+use crate::guess::GuessStrategy;
+use crate::hidden_graph::{is_channel_found, is_counterparty_confirmed};
+use crate::probe::{ProbeSender, GUESSED_CLTV_EXPIRY_DELTA, GUESSED_FEES};
+use crate::NetworkGraph;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use lightning::ln::channelmanager::{PaymentId, PaymentSendFailure};
+use lightning::ln::msgs::{ChannelUpdate, DecodeError, OptionalField, UnsignedChannelUpdate};
+use lightning::ln::PaymentHash;
+use lightning::routing::gossip::{NetworkUpdate, NodeId};
+use lightning::routing::router::{Route, RouteHop};
+use lightning::routing::scoring::{ProbabilisticScorer, ProbabilisticScoringParameters, Score};
+use lightning::util::errors::APIError;
+use lightning::util::events::{Event, EventHandler, EventsProvider};
+use lightning::util::ser::{ReadableArgs, Writeable};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::Cursor;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const UPDATE: u16 = 0x1000;
+const PERM: u16 = 0x4000;
+const BADONION: u16 = 0x8000;
+
+/// What a synthetic private channel does to keep itself hidden.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Countermeasure {
+	#[default]
+	None,
+	/// The channel only forwards over its alias, its real short channel id is unknown.
+	ScidAlias,
+	/// The node runs with `hide_private_channels`, anything over the channel that doesn't make
+	/// it to the counterparty fails as if there was no such channel.
+	Hide,
+	/// The node holds probes over the channel and never answers them.
+	Drop,
+}
+
+/// The policy of a synthetic private channel, the same in both directions.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct SimulatedPolicy {
+	pub(crate) fee_base_msat: u32,
+	pub(crate) fee_proportional_millionths: u32,
+	pub(crate) cltv_expiry_delta: u16,
+	pub(crate) htlc_minimum_msat: u64,
+}
+
+impl Default for SimulatedPolicy {
+	fn default() -> Self {
+		SimulatedPolicy {
+			fee_base_msat: GUESSED_FEES.base_msat,
+			fee_proportional_millionths: GUESSED_FEES.proportional_millionths,
+			cltv_expiry_delta: GUESSED_CLTV_EXPIRY_DELTA,
+			htlc_minimum_msat: 1,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct SimulatedChannel {
+	#[serde(deserialize_with = "deserialize_scid")]
+	pub(crate) short_channel_id: u64,
+	#[serde(deserialize_with = "deserialize_pubkey")]
+	pub(crate) target: PublicKey,
+	#[serde(deserialize_with = "deserialize_pubkey")]
+	pub(crate) counterparty: PublicKey,
+	#[serde(default)]
+	pub(crate) policy: SimulatedPolicy,
+	#[serde(default)]
+	pub(crate) countermeasure: Countermeasure,
+}
+
+/// The private side of a simulated network, read from JSON like
+/// `{"seed": 1, "hop_latency_ms": [20, 200], "private_channels": [{"short_channel_id":
+/// "700000x1x0", "target": "02..", "counterparty": "03.."}]}`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct SimulationConfig {
+	pub(crate) seed: u64,
+	/// The node probes are routed from, ours if not given. Routes are found on the network
+	/// graph alone, so it needs public channels there.
+	#[serde(deserialize_with = "deserialize_optional_pubkey")]
+	pub(crate) start_node: Option<PublicKey>,
+	/// Every hop takes between these many milliseconds each way.
+	pub(crate) hop_latency_ms: (u64, u64),
+	/// How often a public channel on the way fails with `temporary_channel_failure`.
+	pub(crate) intermediate_failure_rate: f64,
+	pub(crate) private_channels: Vec<SimulatedChannel>,
+	/// Nodes that fail every forward with `unknown_next_peer`, public channels included.
+	#[serde(deserialize_with = "deserialize_pubkeys")]
+	pub(crate) uniform_responders: Vec<PublicKey>,
+}
+
+impl SimulationConfig {
+	pub(crate) fn load(path: &str) -> Result<SimulationConfig, Box<dyn std::error::Error>> {
+		Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+	}
+}
+
+/// The channel a node forwards a probe over, and what it expects for doing so.
+struct Outgoing {
+	counterparty: PublicKey,
+	policy: SimulatedPolicy,
+	/// 0 if the forwarding node is `node_one` of the channel, 1 otherwise.
+	direction: u8,
+	/// `None` for public channels.
+	countermeasure: Option<Countermeasure>,
+}
+
+/// Where a probe failed: the hop of the node that failed it, the error code it sent back and
+/// the channel update that came with it.
+struct Failure {
+	hop: usize,
+	error_code: u16,
+	update: Option<UnsignedChannelUpdate>,
+}
+
+/// A probe answer on its way back, due at a point in simulated time.
+struct InFlight {
+	due: Duration,
+	event: Event,
+}
+
+/// Stands in for the channel manager to answer probes offline. Public channels come from a
+/// network graph snapshot, private channels and the countermeasures of their nodes from a
+/// `SimulationConfig`. Probes fail with the BOLT 4 errors a real node would send back, as
+/// `PaymentPathFailed` events once their latency has passed on the simulated clock. Runs with
+/// the same seed give the same answers and latencies.
+pub(crate) struct Simulator {
+	network_graph: Arc<NetworkGraph>,
+	chain_hash: BlockHash,
+	config: SimulationConfig,
+	private_channels: HashMap<u64, SimulatedChannel>,
+	uniform_responders: HashSet<PublicKey>,
+	rng: Mutex<XorShiftRng>,
+	clock: Mutex<Duration>,
+	in_flight: Mutex<Vec<InFlight>>,
+	/// Probes that are never going to be answered.
+	held: Mutex<usize>,
+}
+
+impl Simulator {
+	pub(crate) fn new(
+		network_graph: Arc<NetworkGraph>, network: Network, config: SimulationConfig,
+	) -> Simulator {
+		// xorshift needs a seed that isn't all zeros
+		let seed = [
+			config.seed as u32 ^ 0x9e37_79b9,
+			(config.seed >> 32) as u32,
+			0x243f_6a88,
+			0x85a3_08d3,
+		];
+		Simulator {
+			network_graph,
+			chain_hash: genesis_block(network).header.block_hash(),
+			private_channels: config
+				.private_channels
+				.iter()
+				.map(|channel| (channel.short_channel_id, channel.clone()))
+				.collect(),
+			uniform_responders: config.uniform_responders.iter().copied().collect(),
+			config,
+			rng: Mutex::new(XorShiftRng::from_seed(seed)),
+			clock: Mutex::new(Duration::from_secs(0)),
+			in_flight: Mutex::new(Vec::new()),
+			held: Mutex::new(0),
+		}
+	}
+
+	/// The node probes are routed from, if the config names one.
+	pub(crate) fn start_node(&self) -> Option<PublicKey> {
+		self.config.start_node
+	}
+
+	/// The random bytes for the next route, drawn from the seed so a run can be repeated.
+	pub(crate) fn random_bytes(&self) -> [u8; 32] {
+		self.rng.lock().unwrap().gen()
+	}
+
+	/// The next guess of `strategy`, drawn from the seed like the routes.
+	pub(crate) fn next_guess(&self, strategy: &GuessStrategy) -> PublicKey {
+		strategy.next_guess_from(&mut *self.rng.lock().unwrap())
+	}
+
+	/// The targets of the simulated private channels.
+	pub(crate) fn targets(&self) -> Vec<PublicKey> {
+		let mut targets: Vec<PublicKey> =
+			self.config.private_channels.iter().map(|channel| channel.target).collect();
+		targets.sort_by_key(|target| target.serialize());
+		targets.dedup();
+		targets
+	}
+
+	/// Time on the simulated clock since the simulator was started.
+	pub(crate) fn elapsed(&self) -> Duration {
+		*self.clock.lock().unwrap()
+	}
+
+	#[cfg(test)]
+	pub(crate) fn advance(&self, by: Duration) {
+		*self.clock.lock().unwrap() += by;
+	}
+
+	/// Probes that haven't been answered yet, including those that never will be.
+	pub(crate) fn in_flight(&self) -> usize {
+		self.in_flight.lock().unwrap().len() + *self.held.lock().unwrap()
+	}
+
+	/// Moves the clock on until every probe that is going to be answered was, and hands the
+	/// answers to `handler` in the order they came back.
+	pub(crate) fn run_until_idle<H: Deref>(&self, handler: H)
+	where
+		H::Target: EventHandler,
+	{
+		let last_due = self.in_flight.lock().unwrap().iter().map(|probe| probe.due).max();
+		if let Some(last_due) = last_due {
+			let mut clock = self.clock.lock().unwrap();
+			*clock = (*clock).max(last_due);
+		}
+		self.process_pending_events(handler);
+	}
+
+	/// Follows a probe along `path` and works out where it fails, `None` if it is held forever.
+	fn forward(&self, path: &[RouteHop]) -> Option<Failure> {
+		for (hop, node) in path.iter().enumerate() {
+			let next = match path.get(hop + 1) {
+				Some(next) => next,
+				// the payment hash is made up, so the payee never knows it
+				None => return Some(Failure { hop, error_code: PERM | 15, update: None }),
+			};
+			let unknown_next_peer = Some(Failure { hop, error_code: PERM | 10, update: None });
+			if self.uniform_responders.contains(&node.pubkey) {
+				return unknown_next_peer;
+			}
+			let outgoing = match self.outgoing(&node.pubkey, next.short_channel_id) {
+				Some(outgoing) => outgoing,
+				None => return unknown_next_peer,
+			};
+			let hide = match outgoing.countermeasure {
+				Some(Countermeasure::Drop) => return None,
+				Some(Countermeasure::ScidAlias) => return unknown_next_peer,
+				countermeasure => countermeasure == Some(Countermeasure::Hide),
+			};
+
+			let policy = outgoing.policy;
+			let forwarded_msat: u64 = path[hop + 1..].iter().map(|hop| hop.fee_msat).sum();
+			let fee_msat = policy.fee_base_msat as u64
+				+ forwarded_msat * policy.fee_proportional_millionths as u64 / 1_000_000;
+			let error_code = if forwarded_msat < policy.htlc_minimum_msat {
+				Some(UPDATE | 11)
+			} else if node.fee_msat < fee_msat {
+				Some(UPDATE | 12)
+			} else if (node.cltv_expiry_delta as u16) < policy.cltv_expiry_delta {
+				Some(UPDATE | 13)
+			} else if outgoing.countermeasure.is_none()
+				&& self.rng.lock().unwrap().gen::<f64>() < self.config.intermediate_failure_rate
+			{
+				Some(UPDATE | 7)
+			} else {
+				None
+			};
+			if let Some(error_code) = error_code {
+				if hide {
+					return unknown_next_peer;
+				}
+				let update = self.channel_update(next.short_channel_id, &outgoing);
+				return Some(Failure { hop, error_code, update: Some(update) });
+			}
+
+			// the real counterparty can't read an onion meant for someone else
+			if outgoing.counterparty != next.pubkey {
+				if hide {
+					return unknown_next_peer;
+				}
+				return Some(Failure { hop, error_code: BADONION | PERM | 5, update: None });
+			}
+		}
+		None
+	}
+
+	/// The channel `node` has with `short_channel_id`, private channels first.
+	fn outgoing(&self, node: &PublicKey, short_channel_id: u64) -> Option<Outgoing> {
+		if let Some(channel) = self.private_channels.get(&short_channel_id) {
+			let counterparty = if channel.target == *node {
+				channel.counterparty
+			} else if channel.counterparty == *node {
+				channel.target
+			} else {
+				return None;
+			};
+			return Some(Outgoing {
+				counterparty,
+				policy: channel.policy,
+				direction: (node.serialize() > counterparty.serialize()) as u8,
+				countermeasure: Some(channel.countermeasure),
+			});
+		}
+
+		let graph = self.network_graph.read_only();
+		let channel = graph.channels().get(&short_channel_id)?;
+		let node_id = NodeId::from_pubkey(node);
+		let (other, update, direction) = if channel.node_one == node_id {
+			(channel.node_two, channel.one_to_two.as_ref(), 0)
+		} else if channel.node_two == node_id {
+			(channel.node_one, channel.two_to_one.as_ref(), 1)
+		} else {
+			return None;
+		};
+		let policy = match update {
+			Some(update) => SimulatedPolicy {
+				fee_base_msat: update.fees.base_msat,
+				fee_proportional_millionths: update.fees.proportional_millionths,
+				cltv_expiry_delta: update.cltv_expiry_delta,
+				htlc_minimum_msat: update.htlc_minimum_msat,
+			},
+			None => SimulatedPolicy::default(),
+		};
+		Some(Outgoing {
+			counterparty: PublicKey::from_slice(other.as_slice()).ok()?,
+			policy,
+			direction,
+			countermeasure: None,
+		})
+	}
+
+	fn channel_update(&self, short_channel_id: u64, outgoing: &Outgoing) -> UnsignedChannelUpdate {
+		UnsignedChannelUpdate {
+			chain_hash: self.chain_hash,
+			short_channel_id,
+			timestamp: self.elapsed().as_secs() as u32 + 1,
+			flags: outgoing.direction,
+			cltv_expiry_delta: outgoing.policy.cltv_expiry_delta,
+			htlc_minimum_msat: outgoing.policy.htlc_minimum_msat,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat: outgoing.policy.fee_base_msat,
+			fee_proportional_millionths: outgoing.policy.fee_proportional_millionths,
+			excess_data: Vec::new(),
+		}
+	}
+
+	/// The event the channel manager would give us for `failure`, see `process_onion_failure`.
+	fn failure_event(
+		&self, path: &[RouteHop], payment_hash: PaymentHash, failure: Failure,
+	) -> Event {
+		let failing_hop = path.get(failure.hop + 1);
+		let (network_update, short_channel_id) = if failure.error_code == PERM | 15 {
+			(None, None)
+		} else if let Some(update) = failure.update {
+			let scid = update.short_channel_id;
+			// nothing checks the signature, any will do
+			let secp_ctx = Secp256k1::signing_only();
+			let signature = secp_ctx.sign_ecdsa(
+				&Message::from_slice(&[1; 32]).unwrap(),
+				&SecretKey::from_slice(&[1; 32]).unwrap(),
+			);
+			let msg = ChannelUpdate { signature, contents: update };
+			(Some(NetworkUpdate::ChannelUpdateMessage { msg }), Some(scid))
+		} else {
+			let scid = failing_hop.map(|hop| hop.short_channel_id).unwrap_or_default();
+			(
+				Some(NetworkUpdate::ChannelFailure { short_channel_id: scid, is_permanent: true }),
+				Some(scid),
+			)
+		};
+		Event::PaymentPathFailed {
+			payment_id: Some(PaymentId(payment_hash.0)),
+			payment_hash,
+			rejected_by_dest: failure.hop + 1 == path.len(),
+			network_update,
+			all_paths_failed: true,
+			path: path.to_vec(),
+			short_channel_id,
+			retry: None,
+			error_code: Some(failure.error_code),
+			error_data: Some(Vec::new()),
+		}
+	}
+
+	/// How long the probe takes to get to the hop that fails it and back.
+	fn latency(&self, hops: usize) -> Duration {
+		let (min, max) = self.config.hop_latency_ms;
+		let mut rng = self.rng.lock().unwrap();
+		let one_way: u64 =
+			(0..hops).map(|_| if max > min { rng.gen_range(min, max + 1) } else { min }).sum();
+		Duration::from_millis(2 * one_way)
+	}
+}
+
+/// How a probe strategy did against a simulated network.
+#[derive(Default)]
+pub(crate) struct SimulationReport {
+	pub(crate) probes: usize,
+	pub(crate) answered: usize,
+	/// Private channels found that are in the simulation, and how many of those with their
+	/// counterparty.
+	pub(crate) found: usize,
+	pub(crate) counterparties_found: usize,
+	/// Channels that came back as found but aren't there.
+	pub(crate) false_positives: usize,
+	pub(crate) error_codes: BTreeMap<String, usize>,
+	pub(crate) elapsed: Duration,
+	/// Failures the scorer was told about.
+	pub(crate) penalties: usize,
+	/// Public channel policies the answers brought up to date.
+	pub(crate) policies_updated: usize,
+	/// Public channels the answers had taken out of the graph.
+	pub(crate) channels_removed: usize,
+	/// Probes that needed a new route to their target, because a channel on the old one failed.
+	pub(crate) rerouted: usize,
+	/// Targets given up on for lack of a route.
+	pub(crate) unreachable: usize,
+}
+
+impl SimulationReport {
+	/// Takes in the answer to a probe of `short_channel_id` behind `target`.
+	pub(crate) fn record(
+		&mut self, simulator: &Simulator, target: &PublicKey, short_channel_id: u64,
+		result: &'static str,
+	) {
+		self.answered += 1;
+		*self.error_codes.entry(result.to_string()).or_default() += 1;
+		if !is_channel_found(result) {
+			return;
+		}
+		match simulator.private_channels.get(&short_channel_id) {
+			Some(channel) if channel.target == *target || channel.counterparty == *target => {
+				self.found += 1;
+				self.counterparties_found += is_counterparty_confirmed(result) as usize;
+			}
+			_ => self.false_positives += 1,
+		}
+	}
+
+	/// Probes answered per second of simulated time.
+	pub(crate) fn throughput(&self) -> Option<f64> {
+		if self.elapsed.as_millis() == 0 {
+			return None;
+		}
+		Some(self.answered as f64 / self.elapsed.as_secs_f64())
+	}
+}
+
+type Scorer = ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>;
+
+/// Our side of a simulation: copies of the node's network graph and scorer, which the answers
+/// to simulated probes update the way the real ones would be, so that later routes avoid what
+/// failed. The node's own graph and scorer are left alone.
+pub(crate) struct SimulatedView {
+	pub(crate) network_graph: Arc<NetworkGraph>,
+	pub(crate) scorer: Arc<Mutex<Scorer>>,
+}
+
+impl SimulatedView {
+	pub(crate) fn new(
+		network_graph: &NetworkGraph, scorer: &Mutex<Scorer>, logger: Arc<FilesystemLogger>,
+	) -> Result<SimulatedView, DecodeError> {
+		let network_graph =
+			Arc::new(NetworkGraph::read(&mut Cursor::new(network_graph.encode()), logger.clone())?);
+		let args = (ProbabilisticScoringParameters::default(), network_graph.clone(), logger);
+		let scorer = Scorer::read(&mut Cursor::new(scorer.lock().unwrap().encode()), args)?;
+		Ok(SimulatedView { network_graph, scorer: Arc::new(Mutex::new(scorer)) })
+	}
+
+	/// Takes in what a probe's answer says about the channels on its way, like the invoice
+	/// payer does for the scorer and the network graph does for itself.
+	pub(crate) fn learn(&self, event: &Event, report: &mut SimulationReport) {
+		if let Event::PaymentPathFailed { path, network_update, short_channel_id, .. } = event {
+			if let Some(short_channel_id) = short_channel_id {
+				let path: Vec<&RouteHop> = path.iter().collect();
+				self.scorer.lock().unwrap().payment_path_failed(&path, *short_channel_id);
+				report.penalties += 1;
+			}
+			match network_update {
+				Some(NetworkUpdate::ChannelUpdateMessage { msg }) => {
+					report.policies_updated +=
+						self.network_graph.update_channel(msg).is_ok() as usize;
+				}
+				Some(NetworkUpdate::ChannelFailure { short_channel_id, is_permanent }) => {
+					let known =
+						self.network_graph.read_only().channels().contains_key(short_channel_id);
+					self.network_graph.channel_failed(*short_channel_id, *is_permanent);
+					report.channels_removed += (known && *is_permanent) as usize;
+				}
+				// the graph doesn't do anything with these yet
+				Some(NetworkUpdate::NodeFailure { .. }) | None => {}
+			}
+		}
+	}
+}
+
+impl ProbeSender for Simulator {
+	fn send_probe(
+		&self, route: &Route, payment_hash: PaymentHash,
+	) -> Result<PaymentId, PaymentSendFailure> {
+		let path = match route.paths.first() {
+			Some(path) if !path.is_empty() => path,
+			_ => {
+				return Err(PaymentSendFailure::ParameterError(APIError::RouteError {
+					err: "Route has no paths",
+				}))
+			}
+		};
+		match self.forward(path) {
+			Some(failure) => {
+				let due = self.elapsed() + self.latency(failure.hop + 1);
+				let event = self.failure_event(path, payment_hash, failure);
+				self.in_flight.lock().unwrap().push(InFlight { due, event });
+			}
+			None => *self.held.lock().unwrap() += 1,
+		}
+		Ok(PaymentId(payment_hash.0))
+	}
+}
+
+impl EventsProvider for Simulator {
+	/// Hands over the answers that are due on the simulated clock.
+	fn process_pending_events<H: Deref>(&self, handler: H)
+	where
+		H::Target: EventHandler,
+	{
+		let now = self.elapsed();
+		let mut due = Vec::new();
+		self.in_flight.lock().unwrap().retain(|probe| {
+			if probe.due <= now {
+				due.push((probe.due, probe.event.clone()));
+				false
+			} else {
+				true
+			}
+		});
+		due.sort_by_key(|(due, _)| *due);
+		for (_, event) in due {
+			handler.handle_event(&event);
+		}
+	}
+}
+
+fn deserialize_scid<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+	match serde_json::Value::deserialize(deserializer)? {
+		serde_json::Value::String(scid) => {
+			parse_scid(&scid).ok_or_else(|| D::Error::custom("invalid short channel id"))
+		}
+		scid => scid.as_u64().ok_or_else(|| D::Error::custom("invalid short channel id")),
+	}
+}
+
+fn deserialize_pubkey<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PublicKey, D::Error> {
+	let pubkey = String::deserialize(deserializer)?;
+	PublicKey::from_str(&pubkey).map_err(D::Error::custom)
+}
+
+fn deserialize_optional_pubkey<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<PublicKey>, D::Error> {
+	deserialize_pubkey(deserializer).map(Some)
+}
+
+fn deserialize_pubkeys<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Vec<PublicKey>, D::Error> {
+	Vec::<String>::deserialize(deserializer)?
+		.iter()
+		.map(|pubkey| PublicKey::from_str(pubkey).map_err(D::Error::custom))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::disk::FilesystemLogger;
+	use crate::hint_policy::HintPolicy;
+	use crate::metrics::Metrics;
+	use crate::probe::{
+		probe_result, scid_from_parts, send_probe, PROBE_AMOUNT_MSAT, PROBE_FINAL_CLTV_EXPIRY_DELTA,
+	};
+	use crate::route_cache::{GuessedHop, ProbeRouteCache};
+	use crate::test_utils::{add_public_channel, pubkey, TempDir};
+	use crate::PaymentInfoStorage;
+	use lightning::routing::gossip::RoutingFees;
+	use lightning::routing::router::{find_route, PaymentParameters, RouteParameters};
+	use lightning::routing::scoring::FixedPenaltyScorer;
+	use lightning::util::logger::Level;
+	use std::cell::RefCell;
+
+	/// Us, a public peer, the target behind it and the target's private counterparty.
+	struct TestNetwork {
+		us: PublicKey,
+		target: PublicKey,
+		counterparty: PublicKey,
+		network_graph: Arc<NetworkGraph>,
+		logger: Arc<FilesystemLogger>,
+		route_cache: ProbeRouteCache,
+		pending: PaymentInfoStorage,
+		_data_dir: TempDir,
+	}
+
+	fn private_scid() -> u64 {
+		scid_from_parts(700_002, 1, 0)
+	}
+
+	fn public_target_scid() -> u64 {
+		scid_from_parts(700_001, 1, 0)
+	}
+
+	impl TestNetwork {
+		fn new() -> TestNetwork {
+			let data_dir = TempDir::new("simulator");
+			let logger = Arc::new(FilesystemLogger::new(data_dir.path().to_string(), Level::Info));
+			let genesis_hash = genesis_block(Network::Regtest).header.block_hash();
+			let network_graph = Arc::new(NetworkGraph::new(genesis_hash, logger.clone()));
+			let (us, peer, target) = (pubkey(1), pubkey(2), pubkey(3));
+			add_public_channel(&network_graph, scid_from_parts(700_000, 1, 0), us, peer);
+			add_public_channel(&network_graph, public_target_scid(), peer, target);
+			TestNetwork {
+				us,
+				target,
+				counterparty: pubkey(4),
+				network_graph,
+				logger,
+				route_cache: ProbeRouteCache::new(),
+				pending: Arc::new(Mutex::new(HashMap::new())),
+				_data_dir: data_dir,
+			}
+		}
+
+		fn config(
+			&self, policy: SimulatedPolicy, countermeasure: Countermeasure,
+		) -> SimulationConfig {
+			SimulationConfig {
+				seed: 42,
+				hop_latency_ms: (10, 10),
+				private_channels: vec![SimulatedChannel {
+					short_channel_id: private_scid(),
+					target: self.target,
+					counterparty: self.counterparty,
+					policy,
+					countermeasure,
+				}],
+				..Default::default()
+			}
+		}
+
+		fn simulator(&self, config: SimulationConfig) -> Simulator {
+			Simulator::new(self.network_graph.clone(), Network::Regtest, config)
+		}
+
+		fn probe(&self, simulator: &Simulator, scid: u64, guess: &PublicKey, policy: HintPolicy) {
+			let route = self
+				.route_cache
+				.probe_route(
+					&GuessedHop {
+						target: self.target,
+						short_channel_id: scid,
+						guess: *guess,
+						policy,
+					},
+					&self.network_graph,
+					vec![],
+					|hints| {
+						let route_params = RouteParameters {
+							payment_params: PaymentParameters::from_node_id(self.target)
+								.with_route_hints(hints),
+							final_value_msat: PROBE_AMOUNT_MSAT,
+							final_cltv_expiry_delta: PROBE_FINAL_CLTV_EXPIRY_DELTA,
+						};
+						find_route(
+							&self.us,
+							&route_params,
+							&self.network_graph,
+							None,
+							&*self.logger,
+							&FixedPenaltyScorer::with_penalty(0),
+							&[0; 32],
+						)
+					},
+				)
+				.unwrap();
+			send_probe(
+				&route,
+				simulator,
+				self.pending.clone(),
+				&self.logger,
+				"fixed",
+				&Metrics::new(),
+				"probe",
+			)
+			.unwrap();
+		}
+	}
+
+	/// The error code, failed channel and channel update of every answer.
+	fn answers(simulator: &Simulator) -> Vec<(u16, Option<u64>, Option<UnsignedChannelUpdate>)> {
+		let answers = RefCell::new(Vec::new());
+		simulator.run_until_idle(&|event: &Event| {
+			if let Event::PaymentPathFailed {
+				error_code, short_channel_id, network_update, ..
+			} = event
+			{
+				let update = match network_update {
+					Some(NetworkUpdate::ChannelUpdateMessage { msg }) => Some(msg.contents.clone()),
+					_ => None,
+				};
+				answers.borrow_mut().push((error_code.unwrap(), *short_channel_id, update));
+			}
+		});
+		answers.into_inner()
+	}
+
+	fn results(simulator: &Simulator) -> Vec<&'static str> {
+		let results = RefCell::new(Vec::new());
+		simulator.run_until_idle(&|event: &Event| {
+			if let Event::PaymentPathFailed {
+				rejected_by_dest, error_code: Some(error_code), ..
+			} = event
+			{
+				results.borrow_mut().push(probe_result(*error_code, *rejected_by_dest));
+			}
+		});
+		results.into_inner()
+	}
+
+	#[test]
+	fn test_answers_like_an_honest_node() {
+		let network = TestNetwork::new();
+		let simulator =
+			network.simulator(network.config(SimulatedPolicy::default(), Countermeasure::None));
+		let policy = HintPolicy::default();
+		network.probe(&simulator, private_scid(), &network.counterparty, policy);
+		network.probe(&simulator, private_scid(), &pubkey(5), policy);
+		network.probe(&simulator, scid_from_parts(700_100, 7, 1), &network.counterparty, policy);
+		// answers from the target come back before the one from the counterparty
+		assert_eq!(
+			results(&simulator),
+			vec!["invalid_onion_hmac", "unknown_next_peer", "incorrect_or_unknown_payment_details"]
+		);
+		assert_eq!(simulator.in_flight(), 0);
+	}
+
+	#[test]
+	fn test_policy_errors_carry_the_real_policy() {
+		let network = TestNetwork::new();
+		let policy = SimulatedPolicy { fee_base_msat: 5000, ..Default::default() };
+		let simulator = network.simulator(network.config(policy, Countermeasure::None));
+		network.probe(&simulator, private_scid(), &network.counterparty, HintPolicy::default());
+		let answers = answers(&simulator);
+		let (error_code, scid, update) = &answers[0];
+		assert_eq!(probe_result(*error_code, false), "fee_insufficient");
+		assert_eq!(*scid, Some(private_scid()));
+		assert_eq!(update.as_ref().unwrap().fee_base_msat, 5000);
+
+		// with what the target sent back the probe makes it to the counterparty
+		let learned = HintPolicy {
+			fees: RoutingFees { base_msat: 5000, proportional_millionths: 1 },
+			..Default::default()
+		};
+		network.probe(&simulator, private_scid(), &network.counterparty, learned);
+		assert_eq!(results(&simulator), vec!["incorrect_or_unknown_payment_details"]);
+	}
+
+	#[test]
+	fn test_countermeasures() {
+		let network = TestNetwork::new();
+		let policy = HintPolicy::default();
+
+		let hide = network.simulator(network.config(Default::default(), Countermeasure::Hide));
+		network.probe(&hide, private_scid(), &pubkey(5), policy);
+		network.probe(&hide, private_scid(), &network.counterparty, policy);
+		assert_eq!(
+			results(&hide),
+			vec!["unknown_next_peer", "incorrect_or_unknown_payment_details"]
+		);
+
+		let alias =
+			network.simulator(network.config(Default::default(), Countermeasure::ScidAlias));
+		network.probe(&alias, private_scid(), &network.counterparty, policy);
+		assert_eq!(results(&alias), vec!["unknown_next_peer"]);
+
+		let drop = network.simulator(network.config(Default::default(), Countermeasure::Drop));
+		network.probe(&drop, private_scid(), &network.counterparty, policy);
+		assert!(results(&drop).is_empty());
+		assert_eq!(drop.in_flight(), 1);
+
+		let mut config = network.config(Default::default(), Countermeasure::None);
+		config.uniform_responders = vec![network.target];
+		let uniform = network.simulator(config);
+		network.probe(&uniform, private_scid(), &network.counterparty, policy);
+		assert!(results(&uniform).iter().all(|result| !is_channel_found(result)));
+	}
+
+	#[test]
+	fn test_intermediate_failures() {
+		let network = TestNetwork::new();
+		let mut config = network.config(Default::default(), Countermeasure::None);
+		config.intermediate_failure_rate = 1.0;
+		let simulator = network.simulator(config);
+		network.probe(&simulator, private_scid(), &network.counterparty, HintPolicy::default());
+		let answers = answers(&simulator);
+		assert_eq!(answers[0].0, UPDATE | 7);
+		assert_eq!(answers[0].1, Some(public_target_scid()));
+	}
+
+	#[test]
+	fn test_latency_is_reproducible() {
+		let network = TestNetwork::new();
+		let simulator = network.simulator(network.config(Default::default(), Countermeasure::None));
+		// two hops to the target and back
+		network.probe(&simulator, private_scid(), &pubkey(5), HintPolicy::default());
+		let count = RefCell::new(0);
+		let handler = |_: &Event| *count.borrow_mut() += 1;
+		simulator.advance(Duration::from_millis(39));
+		simulator.process_pending_events(&handler);
+		assert_eq!(*count.borrow(), 0);
+		simulator.advance(Duration::from_millis(1));
+		simulator.process_pending_events(&handler);
+		assert_eq!(*count.borrow(), 1);
+
+		let run = |seed| {
+			let mut config = network.config(Default::default(), Countermeasure::None);
+			config.seed = seed;
+			config.hop_latency_ms = (10, 200);
+			config.intermediate_failure_rate = 0.5;
+			let simulator = network.simulator(config);
+			for _ in 0..20 {
+				network.probe(&simulator, private_scid(), &pubkey(5), HintPolicy::default());
+			}
+			(results(&simulator), simulator.elapsed())
+		};
+		assert_eq!(run(7), run(7));
+	}
+
+	#[test]
+	fn test_routes_and_guesses_are_reproducible() {
+		let network = TestNetwork::new();
+		let strategy = GuessStrategy::Random(Secp256k1::signing_only());
+		let run = |seed| {
+			let mut config = network.config(Default::default(), Countermeasure::None);
+			config.seed = seed;
+			let simulator = network.simulator(config);
+			(simulator.random_bytes(), simulator.next_guess(&strategy), simulator.random_bytes())
+		};
+		assert_eq!(run(7), run(7));
+		assert_ne!(run(7), run(8));
+	}
+
+	#[test]
+	fn test_view_learns_from_answers() {
+		let network = TestNetwork::new();
+		let mut config = network.config(SimulatedPolicy::default(), Countermeasure::None);
+		// our peer fails every forward, the channel on from it gets removed
+		config.uniform_responders = vec![pubkey(2)];
+		let simulator = network.simulator(config);
+		let scorer = Mutex::new(ProbabilisticScorer::new(
+			ProbabilisticScoringParameters::default(),
+			network.network_graph.clone(),
+			network.logger.clone(),
+		));
+		let view =
+			SimulatedView::new(&network.network_graph, &scorer, network.logger.clone()).unwrap();
+
+		network.probe(&simulator, private_scid(), &network.counterparty, HintPolicy::default());
+		let report = RefCell::new(SimulationReport::default());
+		simulator.run_until_idle(&|event: &Event| view.learn(event, &mut report.borrow_mut()));
+		let report = report.into_inner();
+		assert_eq!(report.penalties, 1);
+		assert_eq!(report.channels_removed, 1);
+		assert!(!view.network_graph.read_only().channels().contains_key(&public_target_scid()));
+		// the node's graph stays as it was
+		assert!(network.network_graph.read_only().channels().contains_key(&public_target_scid()));
+	}
+
+	#[test]
+	fn test_reads_config() {
+		let config: SimulationConfig = serde_json::from_str(&format!(
+			r#"{{"seed": 3, "start_node": "{}", "private_channels": [{{"short_channel_id": "700002x1x0",
+			"target": "{}", "counterparty": "{}", "countermeasure": "scid_alias",
+			"policy": {{"fee_base_msat": 0}}}}]}}"#,
+			pubkey(1),
+			pubkey(3),
+			pubkey(4)
+		))
+		.unwrap();
+		assert_eq!(config.start_node, Some(pubkey(1)));
+		let channel = &config.private_channels[0];
+		assert_eq!(channel.short_channel_id, private_scid());
+		assert_eq!(channel.countermeasure, Countermeasure::ScidAlias);
+		assert_eq!(channel.policy.fee_base_msat, 0);
+		assert_eq!(channel.policy.cltv_expiry_delta, GUESSED_CLTV_EXPIRY_DELTA);
+	}
+}
@@ -0,0 +1,268 @@
+use crate::aggregate::{capacity_bucket, capacity_label, BLOCK_RANGE};
+use crate::cli::{Attempt, Transaction};
+use crate::politeness::ProbeLimits;
+use crate::probe::{block_from_scid, matches_assumptions, scid_from_parts, vout_from_scid};
+use crate::NetworkGraph;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+/// The probe rate is measured over this many of the most recent attempts.
+const RATE_WINDOW: usize = 1000;
+
+/// Amounts are as round as the largest of these they're a multiple of.
+const ROUNDNESS: [u64; 6] = [100_000_000, 10_000_000, 1_000_000, 100_000, 10_000, 1_000];
+
+/// How many candidates and public channels fall into one bucket.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Bucket {
+	pub(crate) bucket: String,
+	pub(crate) candidates: usize,
+	pub(crate) public_channels: usize,
+}
+
+/// How many public channels a heuristic would have kept, and how many candidates it keeps.
+#[derive(Serialize, Debug)]
+pub(crate) struct HeuristicMatch {
+	pub(crate) heuristic: String,
+	pub(crate) public_channels: usize,
+	pub(crate) public_fraction: Option<f64>,
+	pub(crate) candidates: usize,
+}
+
+/// What a campaign over the candidate dataset is up against.
+#[derive(Serialize, Debug)]
+pub(crate) struct CandidateStats {
+	pub(crate) candidates: usize,
+	/// Candidates that turned out to be public channels, they're never probed.
+	pub(crate) public_candidates: usize,
+	pub(crate) remaining: usize,
+	/// Public channels with a known capacity, what the heuristics are measured against.
+	pub(crate) public_channels: usize,
+	pub(crate) amounts: Vec<Bucket>,
+	pub(crate) vouts: Vec<Bucket>,
+	pub(crate) block_ranges: Vec<Bucket>,
+	pub(crate) roundness: Vec<Bucket>,
+	pub(crate) heuristics: Vec<HeuristicMatch>,
+	pub(crate) targets: usize,
+	/// Probes per second over the most recent attempts, not counting the time between runs.
+	/// `None` without enough of them.
+	pub(crate) probe_rate: Option<f64>,
+	pub(crate) estimated_duration_secs: Option<u64>,
+	pub(crate) estimated_duration_assumptions_secs: Option<u64>,
+}
+
+type Heuristic = (&'static str, fn(u64, u64) -> bool);
+
+const HEURISTICS: [Heuristic; 3] = [
+	("round_amount", |amount, _| amount % 10000 == 0),
+	("low_vout", |_, vout| vout <= 1),
+	("assumptions", matches_assumptions),
+];
+
+impl CandidateStats {
+	pub(crate) fn build(
+		txs: &[Transaction], network_graph: &NetworkGraph, attempts: &[Attempt],
+		limits: &ProbeLimits, targets: usize, probe_timeout: Duration,
+	) -> CandidateStats {
+		let mut amounts: BTreeMap<usize, Bucket> = BTreeMap::new();
+		let mut vouts: BTreeMap<u64, Bucket> = BTreeMap::new();
+		let mut block_ranges: BTreeMap<u32, Bucket> = BTreeMap::new();
+		let mut roundness: BTreeMap<usize, Bucket> = BTreeMap::new();
+		let mut heuristics: Vec<HeuristicMatch> = HEURISTICS
+			.iter()
+			.map(|(heuristic, _)| HeuristicMatch {
+				heuristic: heuristic.to_string(),
+				public_channels: 0,
+				public_fraction: None,
+				candidates: 0,
+			})
+			.collect();
+
+		let graph = network_graph.read_only();
+		let public_scids: HashSet<u64> = graph.channels().keys().copied().collect();
+		let mut public_candidates = 0;
+		for tx in txs {
+			let scid = scid_from_parts(tx.block_height, tx.block_index, tx.transaction_index);
+			if public_scids.contains(&scid) {
+				public_candidates += 1;
+				continue;
+			}
+			amounts.entry(capacity_bucket(tx.amount)).or_default().candidates += 1;
+			vouts.entry(tx.transaction_index.min(3)).or_default().candidates += 1;
+			let range = tx.block_height as u32 / BLOCK_RANGE;
+			block_ranges.entry(range).or_default().candidates += 1;
+			roundness.entry(roundness_bucket(tx.amount)).or_default().candidates += 1;
+			for (matched, (_, heuristic)) in heuristics.iter_mut().zip(HEURISTICS.iter()) {
+				matched.candidates += heuristic(tx.amount, tx.transaction_index) as usize;
+			}
+		}
+
+		let mut public_channels = 0;
+		for (scid, channel) in graph.channels().iter() {
+			let amount = match channel.capacity_sats {
+				Some(amount) => amount,
+				None => continue,
+			};
+			let vout = vout_from_scid(scid) as u64;
+			public_channels += 1;
+			amounts.entry(capacity_bucket(amount)).or_default().public_channels += 1;
+			vouts.entry(vout.min(3)).or_default().public_channels += 1;
+			let range = block_from_scid(scid) / BLOCK_RANGE;
+			block_ranges.entry(range).or_default().public_channels += 1;
+			roundness.entry(roundness_bucket(amount)).or_default().public_channels += 1;
+			for (matched, (_, heuristic)) in heuristics.iter_mut().zip(HEURISTICS.iter()) {
+				matched.public_channels += heuristic(amount, vout) as usize;
+			}
+		}
+		for matched in heuristics.iter_mut() {
+			if public_channels > 0 {
+				matched.public_fraction =
+					Some(matched.public_channels as f64 / public_channels as f64);
+			}
+		}
+
+		let remaining = txs.len() - public_candidates;
+		let probe_rate = probe_rate(attempts, probe_timeout);
+		let assumption_candidates = heuristics
+			.iter()
+			.find(|matched| matched.heuristic == "assumptions")
+			.map_or(0, |matched| matched.candidates);
+		CandidateStats {
+			candidates: txs.len(),
+			public_candidates,
+			remaining,
+			public_channels,
+			amounts: labelled(amounts, capacity_label),
+			vouts: labelled(vouts, |vout| match vout {
+				3 => "3+".to_string(),
+				vout => vout.to_string(),
+			}),
+			block_ranges: labelled(block_ranges, |range| {
+				format!("{}-{}", range * BLOCK_RANGE, (range + 1) * BLOCK_RANGE - 1)
+			}),
+			roundness: labelled(roundness, |i| match ROUNDNESS.get(i) {
+				Some(multiple) => format!("multiple of {}", multiple),
+				None => "not round".to_string(),
+			}),
+			heuristics,
+			targets,
+			probe_rate,
+			estimated_duration_secs: estimate_duration(remaining, targets, probe_rate, limits),
+			estimated_duration_assumptions_secs: estimate_duration(
+				assumption_candidates,
+				targets,
+				probe_rate,
+				limits,
+			),
+		}
+	}
+}
+
+fn labelled<K: Ord + Copy>(
+	buckets: BTreeMap<K, Bucket>, label: impl Fn(K) -> String,
+) -> Vec<Bucket> {
+	buckets.into_iter().map(|(key, bucket)| Bucket { bucket: label(key), ..bucket }).collect()
+}
+
+/// Index into `ROUNDNESS`, one past the end for amounts that aren't round at all.
+fn roundness_bucket(amount: u64) -> usize {
+	ROUNDNESS
+		.iter()
+		.position(|multiple| amount > 0 && amount % multiple == 0)
+		.unwrap_or(ROUNDNESS.len())
+}
+
+/// Probes per second over the most recent attempts, while probing. A gap longer than the probe
+/// timeout between two attempts can't be waiting on an answer, so it's time between runs and
+/// left out.
+fn probe_rate(attempts: &[Attempt], timeout: Duration) -> Option<f64> {
+	let mut dates: Vec<_> = attempts.iter().map(|attempt| attempt.date_found).collect();
+	dates.sort();
+	let recent = &dates[dates.len().saturating_sub(RATE_WINDOW)..];
+	let timeout = timeout.as_millis() as i64;
+	let gaps: Vec<i64> = recent
+		.windows(2)
+		.map(|pair| (pair[1] - pair[0]).num_milliseconds())
+		.filter(|gap| *gap <= timeout)
+		.collect();
+	let elapsed: i64 = gaps.iter().sum();
+	if elapsed <= 0 {
+		return None;
+	}
+	Some(gaps.len() as f64 * 1000.0 / elapsed as f64)
+}
+
+/// How long probing `candidates` at every one of `targets` would take. Targets are probed one
+/// after the other, each at the measured rate but no faster than the per target limits allow.
+fn estimate_duration(
+	candidates: usize, targets: usize, probe_rate: Option<f64>, limits: &ProbeLimits,
+) -> Option<u64> {
+	let rate = probe_rate?.min(limits.per_minute as f64 / 60.0);
+	if rate <= 0.0 || limits.per_day == 0 {
+		return None;
+	}
+	let per_target =
+		(candidates as f64 / rate).max(candidates as f64 / limits.per_day as f64 * 86_400.0);
+	Some((per_target * targets as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::pubkey;
+	use chrono::Utc;
+
+	#[test]
+	fn test_estimate_duration() {
+		let limits = ProbeLimits { per_minute: 30, per_day: 100_000, quiet_hours: None };
+		assert_eq!(estimate_duration(600, 2, None, &limits), None);
+		assert_eq!(estimate_duration(600, 2, Some(0.0), &limits), None);
+		// slower than the per minute limit, the measured rate it is
+		assert_eq!(estimate_duration(600, 2, Some(0.1), &limits), Some(12_000));
+		// faster, and the per minute limit holds it back to one every two seconds
+		assert_eq!(estimate_duration(600, 2, Some(10.0), &limits), Some(2_400));
+		// the per day limit keeps it to 100 a day
+		let per_day = ProbeLimits { per_day: 100, ..limits };
+		assert_eq!(estimate_duration(600, 2, Some(10.0), &per_day), Some(12 * 86_400));
+		assert_eq!(
+			estimate_duration(600, 2, Some(10.0), &ProbeLimits { per_day: 0, ..limits }),
+			None
+		);
+		assert_eq!(estimate_duration(600, 0, Some(10.0), &limits), Some(0));
+	}
+
+	#[test]
+	fn test_probe_rate() {
+		let start = Utc::now().naive_utc();
+		let attempt = |i: i64, millis: i64| Attempt {
+			target_pubkey: pubkey(1).to_string(),
+			guess_pubkey: pubkey(2).to_string(),
+			channel_id: i.to_string(),
+			result: "unknown_next_peer".to_string(),
+			date_found: start + chrono::Duration::milliseconds(millis),
+			hidden_path: None,
+			guess_strategy: None,
+		};
+		let timeout = Duration::from_secs(60);
+		let mut attempts: Vec<Attempt> = (0..11).map(|i| attempt(i, 500 * i)).collect();
+		assert_eq!(probe_rate(&attempts[..1], timeout), None);
+		assert_eq!(probe_rate(&attempts, timeout), Some(2.0));
+		attempts.reverse();
+		assert_eq!(probe_rate(&attempts, timeout), Some(2.0));
+
+		// a second run a day later, at one probe a second
+		let day = 86_400_000;
+		attempts.extend((11..21).map(|i| attempt(i, day + 1000 * (i - 11))));
+		assert_eq!(probe_rate(&attempts, timeout), Some(19.0 / 14.0));
+	}
+
+	#[test]
+	fn test_roundness_bucket() {
+		assert_eq!(roundness_bucket(200_000_000), 0);
+		assert_eq!(roundness_bucket(2_500_000), 3);
+		assert_eq!(roundness_bucket(1_000), 5);
+		assert_eq!(roundness_bucket(12_345), ROUNDNESS.len());
+		assert_eq!(roundness_bucket(0), ROUNDNESS.len());
+	}
+}
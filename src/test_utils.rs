@@ -0,0 +1,94 @@
+//! Helpers shared by the unit tests.
+
+use crate::disk::FilesystemLogger;
+use crate::graph_import::unsigned_node_announcement;
+use crate::NetworkGraph;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+use lightning::ln::msgs::{OptionalField, UnsignedChannelUpdate};
+use lightning::util::logger::Level;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A fresh, empty directory under the system temp dir, unique to this process and call so
+/// concurrent test runs don't share state. It's removed again on drop.
+pub(crate) struct TempDir {
+	path: String,
+}
+
+impl TempDir {
+	pub(crate) fn new(name: &str) -> TempDir {
+		static NEXT: AtomicUsize = AtomicUsize::new(0);
+		let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+		let dir = std::env::temp_dir().join(format!(
+			"ldk-sample-{}-{}-{}-{}",
+			name,
+			std::process::id(),
+			nanos,
+			NEXT.fetch_add(1, Ordering::SeqCst)
+		));
+		fs::create_dir_all(&dir).unwrap();
+		TempDir { path: dir.to_str().unwrap().to_string() }
+	}
+
+	pub(crate) fn path(&self) -> &str {
+		&self.path
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.path);
+	}
+}
+
+/// The node id `[byte; 32]` is the secret key of.
+pub(crate) fn pubkey(byte: u8) -> PublicKey {
+	PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[byte; 32]).unwrap())
+}
+
+/// Adds a public channel between `a` and `b` to the graph, with the same policy both ways.
+pub(crate) fn add_public_channel(
+	network_graph: &NetworkGraph, scid: u64, a: PublicKey, b: PublicKey,
+) {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+	let (one, two) = if a.serialize() < b.serialize() { (a, b) } else { (b, a) };
+	network_graph
+		.add_channel_from_partial_announcement(scid, now, ChannelFeatures::empty(), one, two)
+		.unwrap();
+	for direction in 0..2 {
+		let update = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Regtest).header.block_hash(),
+			short_channel_id: scid,
+			timestamp: now as u32,
+			flags: direction,
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 1,
+			htlc_maximum_msat: OptionalField::Present(100_000_000),
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 1,
+			excess_data: Vec::new(),
+		};
+		network_graph.update_channel_unsigned(&update).unwrap();
+	}
+}
+
+/// Announces `node`, which needs a channel in the graph already, with `features`. Later
+/// announcements need a later `timestamp` to replace earlier ones.
+pub(crate) fn announce_node(
+	network_graph: &NetworkGraph, node: PublicKey, features: NodeFeatures, timestamp: u32,
+) {
+	let announcement =
+		unsigned_node_announcement(features, timestamp, node, [0; 3], [0; 32], &[]).unwrap();
+	network_graph.update_node_from_unsigned_announcement(&announcement).unwrap();
+}
+
+/// An empty regtest graph, logging into `data_dir`.
+pub(crate) fn network_graph(data_dir: &TempDir) -> Arc<NetworkGraph> {
+	let logger = Arc::new(FilesystemLogger::new(data_dir.path().to_string(), Level::Info));
+	Arc::new(NetworkGraph::new(genesis_block(Network::Regtest).header.block_hash(), logger))
+}